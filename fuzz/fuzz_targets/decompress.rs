@@ -0,0 +1,5 @@
+#![no_main]
+
+libfuzzer_sys::fuzz_target!(|data: &[u8]| {
+    libhammer2::fuzz::decompress(data);
+});
@@ -0,0 +1,7 @@
+fn main() {
+    let dir = std::env::args().nth(1).unwrap_or_else(|| "corpus".to_string());
+    if let Err(e) = libhammer2::fuzz::write_corpus(std::path::Path::new(&dir)) {
+        eprintln!("{e}");
+        std::process::exit(1);
+    }
+}
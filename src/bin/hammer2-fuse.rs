@@ -0,0 +1,17 @@
+fn usage(prog: &str) {
+    eprintln!("usage: {prog} [mount options] <spec> <mountpoint>");
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    let n = args.len();
+    if n < 3 {
+        usage(&args[0]);
+        std::process::exit(1);
+    }
+    let opts: Vec<&str> = args[1..n - 2].iter().map(String::as_str).collect();
+    if let Err(e) = libhammer2::fuse::mount(&args[n - 2], &opts, &args[n - 1]) {
+        eprintln!("{e}");
+        std::process::exit(1);
+    }
+}
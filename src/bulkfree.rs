@@ -0,0 +1,538 @@
+use crate::ErrorExt;
+use crate::OptionExt;
+
+const BMAP_DATA_BYTES: usize = std::mem::size_of::<crate::fs::Hammer2BmapData>();
+
+// In-use state of the media built from live blockrefs.
+#[derive(Debug)]
+struct Scan {
+    bmaps: Vec<crate::fs::Hammer2BmapData>, // per 4MB
+    visited: std::collections::HashSet<u64>,
+    partial: std::collections::HashMap<u64, u64>, // live bytes per 16KB
+    total_scanned: u64,
+}
+
+impl Scan {
+    fn new(allocator_beg: u64, total_size: u64) -> crate::Result<Self> {
+        let mut bmaps = vec![];
+        let hikey = total_size & !crate::fs::HAMMER2_SEGMASK;
+        let mut data = vec![0; crate::fs::HAMMER2_FREEMAP_COUNT * BMAP_DATA_BYTES];
+        let mut key = 0;
+        while key < hikey {
+            // Start from the initial state, which has reserved areas allocated.
+            data.fill(0);
+            crate::freemap::init_leaf(&mut data, key, allocator_beg, total_size)?;
+            for (i, bmap) in crate::fs::media_as::<crate::fs::Hammer2BmapData>(&data)?
+                .iter()
+                .enumerate()
+            {
+                if key + u64::try_from(i).or_range()? * crate::fs::HAMMER2_FREEMAP_LEVEL0_SIZE
+                    >= hikey
+                {
+                    break;
+                }
+                let mut x = crate::fs::Hammer2BmapData::new();
+                x.bitmapq = bmap.bitmapq;
+                bmaps.push(x);
+            }
+            key += crate::fs::HAMMER2_FREEMAP_LEVEL1_SIZE;
+        }
+        Ok(Self {
+            bmaps,
+            visited: std::collections::HashSet::new(),
+            partial: std::collections::HashMap::new(),
+            total_scanned: 0,
+        })
+    }
+
+    fn mark(&mut self, bref: &crate::fs::Hammer2Blockref, radix: u8) -> crate::Result<()> {
+        let offset = bref.get_raw_data_off();
+        let size = 1u64 << radix;
+        let beg = offset / crate::fs::HAMMER2_FREEMAP_BLOCK_SIZE;
+        let end = (offset + size).div_ceil(crate::fs::HAMMER2_FREEMAP_BLOCK_SIZE);
+        for blk in beg..end {
+            let seg = usize::try_from(
+                blk * crate::fs::HAMMER2_FREEMAP_BLOCK_SIZE
+                    / crate::fs::HAMMER2_FREEMAP_LEVEL0_SIZE,
+            )
+            .or_range()?;
+            let Some(bmap) = self.bmaps.get_mut(seg) else {
+                log::error!("{bref} beyond end of filesystem");
+                return Err(nix::errno::Errno::EINVAL.into());
+            };
+            crate::freemap::set_bmap_bits(
+                bmap,
+                usize::try_from(blk).or_range()? % crate::fs::HAMMER2_BMAP_BLOCKS,
+                3,
+            );
+        }
+        if size < crate::fs::HAMMER2_FREEMAP_BLOCK_SIZE {
+            *self.partial.entry(beg).or_insert(0) += size;
+        }
+        self.total_scanned += size;
+        Ok(())
+    }
+}
+
+impl crate::hammer2::Inner {
+    fn bulkfree_scan(
+        &mut self,
+        scan: &mut Scan,
+        bref: &crate::fs::Hammer2Blockref,
+    ) -> crate::Result<()> {
+        if bref.typ == crate::fs::HAMMER2_BREF_TYPE_EMPTY {
+            return Ok(());
+        }
+        let radix = bref.get_radix()?;
+        if radix == 0 {
+            return Ok(());
+        }
+        // Snapshots share blocks.
+        if !scan.visited.insert(bref.data_off) {
+            return Ok(());
+        }
+        scan.mark(bref, radix)?;
+        let descend = match bref.typ {
+            crate::fs::HAMMER2_BREF_TYPE_INODE | crate::fs::HAMMER2_BREF_TYPE_INDIRECT => true,
+            crate::fs::HAMMER2_BREF_TYPE_DATA | crate::fs::HAMMER2_BREF_TYPE_DIRENT => false,
+            _ => {
+                log::error!("bad blockref {bref}");
+                return Err(nix::errno::Errno::EINVAL.into());
+            }
+        };
+        if !descend {
+            return Ok(());
+        }
+        // Blocks under an unreadable block can't be accounted for,
+        // so freeing anything is unsafe.
+        let mut media = self.fso.read_media(bref)?;
+        if !crate::ondisk::verify_media(bref, &media)? {
+            log::error!("{bref} check code mismatch");
+            return Err(nix::errno::Errno::EIO.into());
+        }
+        if !self.voldata.is_hbo()? {
+            crate::ondisk::swap_media(bref, &mut media);
+        }
+        crate::ondisk::check_media(bref, &media)?;
+        if bref.typ == crate::fs::HAMMER2_BREF_TYPE_INODE {
+            let ipdata = crate::ondisk::media_as_inode_data(bref, &media)?;
+            if !ipdata.meta.is_sup_root() && ipdata.meta.has_direct_data() {
+                return Ok(());
+            }
+        }
+        for x in &crate::ondisk::media_as_blockref(bref, &media)? {
+            self.bulkfree_scan(scan, x)?;
+        }
+        Ok(())
+    }
+
+    /// # Errors
+    pub fn bulkfree(&mut self, apply: bool) -> crate::Result<crate::ioctl::IocBulkfree> {
+        if apply && !self.opt.rw {
+            return Err(nix::errno::Errno::EROFS.into());
+        }
+        // Live blockrefs are scanned on media.
+        if self.opt.rw && self.is_dirty() {
+            self.flush()?;
+        }
+        let total_size = self.fso.get_total_size();
+        let mut scan = Scan::new(self.voldata.allocator_beg, total_size)?;
+        // Blocks referenced by older volume headers must stay allocated,
+        // or mounting them by --volhdr or --mirror_tid reads recycled blocks.
+        let mut brefs = self.voldata.sroot_blockset.blockref.to_vec();
+        for (_, vd) in self
+            .fso
+            .get_root_volume()
+            .ok_or(nix::errno::Errno::ENODEV)?
+            .read_volume_headers()?
+        {
+            brefs.extend(vd.sroot_blockset.blockref);
+        }
+        for x in &brefs {
+            self.bulkfree_scan(&mut scan, x)?;
+        }
+        let freemap = self.read_freemap()?;
+        assert_eq!(freemap.segments.len(), scan.bmaps.len());
+
+        let mut bulk = crate::ioctl::IocBulkfree::new();
+        bulk.sstop = total_size;
+        bulk.size = u64::try_from(scan.bmaps.len() * BMAP_DATA_BYTES).or_range()?;
+        bulk.total_scanned = scan.total_scanned;
+        bulk.total_fragmented = scan
+            .partial
+            .values()
+            .map(|x| crate::fs::HAMMER2_FREEMAP_BLOCK_SIZE.saturating_sub(*x))
+            .sum();
+        let mut changed = vec![];
+        for (i, (seg, bmap)) in freemap.segments.iter().zip(&scan.bmaps).enumerate() {
+            let mut modified = false;
+            for blk in 0..crate::fs::HAMMER2_BMAP_BLOCKS {
+                let old = seg.get_block_state(blk);
+                let new = crate::freemap::get_bmap_bits(bmap, blk);
+                if new == 3 {
+                    bulk.total_allocated += crate::fs::HAMMER2_FREEMAP_BLOCK_SIZE;
+                    if old != crate::freemap::BlockState::Allocated {
+                        log::warn!(
+                            "{:016x} in use but not allocated",
+                            seg.offset
+                                + u64::try_from(blk).or_range()?
+                                    * crate::fs::HAMMER2_FREEMAP_BLOCK_SIZE
+                        );
+                        bulk.count_allocated += crate::fs::HAMMER2_FREEMAP_BLOCK_SIZE;
+                        modified = true;
+                    }
+                } else if old != crate::freemap::BlockState::Free {
+                    bulk.count_freed += crate::fs::HAMMER2_FREEMAP_BLOCK_SIZE;
+                    modified = true;
+                }
+            }
+            if modified {
+                changed.push(i);
+            }
+        }
+        log::info!(
+            "bulkfree freed {} allocated {} segments {}",
+            bulk.count_freed,
+            bulk.count_allocated,
+            changed.len()
+        );
+        if apply && !changed.is_empty() {
+            self.bulkfree_apply(&scan.bmaps, &changed)?;
+            self.voldata.allocator_free = (self.voldata.allocator_free + bulk.count_freed)
+                .saturating_sub(bulk.count_allocated);
+            self.flush()?;
+        }
+        Ok(bulk)
+    }
+
+    // Rewrite bitmaps of the changed 4MB segments.
+    fn bulkfree_apply(
+        &mut self,
+        bmaps: &[crate::fs::Hammer2BmapData],
+        changed: &[usize],
+    ) -> crate::Result<()> {
+        let per_leaf = crate::fs::HAMMER2_FREEMAP_COUNT;
+        for i in changed {
+            let key =
+                u64::try_from(i / per_leaf).or_range()? * crate::fs::HAMMER2_FREEMAP_LEVEL1_SIZE;
+            let j = i % per_leaf;
+            let cid = self.get_freemap_leaf(key)?;
+            self.modify_chain(cid)?;
+            let chain = self.cmap.get_mut(&cid).or_range()?;
+            let bmap = libfs::cast::align_to_mut::<crate::fs::Hammer2BmapData>(
+                &mut chain.get_data_mut()[j * BMAP_DATA_BYTES..(j + 1) * BMAP_DATA_BYTES],
+            );
+            let old_avail = u64::from(bmap.avail);
+            bmap.bitmapq = bmaps[*i].bitmapq;
+            let nfree = (0..crate::fs::HAMMER2_BMAP_BLOCKS)
+                .filter(|&x| crate::freemap::get_bmap_bits(bmap, x) == 0)
+                .count();
+            bmap.avail = (u64::try_from(nfree).or_range()? * crate::fs::HAMMER2_FREEMAP_BLOCK_SIZE)
+                .try_into()
+                .or_range()?;
+            // Don't pack into a 16KB block which may have been freed.
+            bmap.linear = 0;
+            if nfree == crate::fs::HAMMER2_BMAP_BLOCKS {
+                bmap.class = 0;
+            }
+            let new_avail = u64::from(bmap.avail);
+            let check = chain
+                .bref
+                .check_as_mut::<crate::fs::Hammer2BlockrefCheckFreemap>();
+            check.avail = (check.avail + new_avail).saturating_sub(old_avail);
+        }
+        Ok(())
+    }
+}
+
+impl crate::hammer2::Hammer2 {
+    /// # Errors
+    pub fn bulkfree(&self, apply: bool) -> crate::Result<crate::ioctl::IocBulkfree> {
+        // Not retried as with_lock does, as it may modify the freemap.
+        self.lock().bulkfree(apply)
+    }
+}
+
+/// # Errors
+pub fn bulkfree(spec: &str, apply: bool) -> crate::Result<crate::ioctl::IocBulkfree> {
+    let args: &[&str] = if apply { &["--rw"] } else { &[] };
+    let mut pmp = crate::hammer2::Hammer2::mount(spec, args)?;
+    let bulk = pmp.bulkfree(apply)?;
+    pmp.unmount()?;
+    Ok(bulk)
+}
+
+#[cfg(test)]
+mod tests {
+    fn pattern(n: usize, seed: u64) -> Vec<u8> {
+        let mut x = seed;
+        (0..n)
+            .map(|_| {
+                x = x.wrapping_mul(6_364_136_223_846_793_005).wrapping_add(1);
+                (x >> 56) as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_bulkfree() {
+        let f = crate::newfs::create_newfs_image("bulkfree", 256 << 20);
+        let bulk = match super::bulkfree(&f, false) {
+            Ok(v) => v,
+            Err(e) => panic!("{e}"),
+        };
+        assert_eq!(bulk.count_freed, 0);
+        assert_eq!(bulk.count_allocated, 0);
+        assert!(bulk.total_scanned > 0);
+
+        // overwrite leaks old blocks
+        let mut pmp = match crate::hammer2::Hammer2::mount(&f, &["--rw"]) {
+            Ok(v) => v,
+            Err(e) => panic!("{e}"),
+        };
+        let inum = match pmp.create(crate::inode::INUM_PFS_ROOT, "file", 0o644) {
+            Ok(v) => v,
+            Err(e) => panic!("{e}"),
+        };
+        for i in 0..3 {
+            if let Err(e) = pmp.pwrite(inum, &pattern(1 << 20, i), 0) {
+                panic!("{e}");
+            }
+            if let Err(e) = pmp.flush() {
+                panic!("{e}");
+            }
+        }
+        if let Err(e) = pmp.unmount() {
+            panic!("{e}");
+        }
+        let bulk = match super::bulkfree(&f, false) {
+            Ok(v) => v,
+            Err(e) => panic!("{e}"),
+        };
+        assert!(bulk.count_freed >= 2 << 20, "{bulk:?}");
+        assert_eq!(bulk.count_allocated, 0);
+
+        let bulk2 = match super::bulkfree(&f, true) {
+            Ok(v) => v,
+            Err(e) => panic!("{e}"),
+        };
+        assert_eq!(bulk2.count_freed, bulk.count_freed);
+        let bulk = match super::bulkfree(&f, false) {
+            Ok(v) => v,
+            Err(e) => panic!("{e}"),
+        };
+        assert_eq!(bulk.count_freed, 0);
+        assert_eq!(bulk.count_allocated, 0);
+
+        match crate::fsck::fsck(&f) {
+            Ok(v) => assert!(v.is_clean(), "{:?}", v.problems),
+            Err(e) => panic!("{e}"),
+        }
+        let mut pmp = match crate::hammer2::Hammer2::mount(&f, &[]) {
+            Ok(v) => v,
+            Err(e) => panic!("{e}"),
+        };
+        match pmp.read_freemap() {
+            Ok(v) => assert_eq!(v.summary().ninconsistent_segments, 0),
+            Err(e) => panic!("{e}"),
+        }
+        let inum = match pmp.nresolve(crate::inode::INUM_PFS_ROOT, "file") {
+            Ok(v) => v,
+            Err(e) => panic!("{e}"),
+        };
+        let mut buf = vec![0; 1 << 20];
+        match pmp.pread(inum, &mut buf, 0) {
+            Ok(v) => assert_eq!(v, 1 << 20),
+            Err(e) => panic!("{e}"),
+        }
+        assert_eq!(buf, pattern(1 << 20, 2));
+        if let Err(e) = pmp.unmount() {
+            panic!("{e}");
+        }
+        let _ = std::fs::remove_file(f);
+    }
+
+    #[test]
+    fn test_bulkfree_abo() {
+        let f = crate::newfs::create_newfs_image("bulkfree_abo", 128 << 20);
+        let mut pmp = match crate::hammer2::Hammer2::mount(&f, &["--rw"]) {
+            Ok(v) => v,
+            Err(e) => panic!("{e}"),
+        };
+        let dinum = match pmp.mkdir(crate::inode::INUM_PFS_ROOT, "d", 0o755) {
+            Ok(v) => v,
+            Err(e) => panic!("{e}"),
+        };
+        let inum = match pmp.create(dinum, "file", 0o644) {
+            Ok(v) => v,
+            Err(e) => panic!("{e}"),
+        };
+        for i in 0..2 {
+            if let Err(e) = pmp.pwrite(inum, &pattern(1 << 20, i), 0) {
+                panic!("{e}");
+            }
+            if let Err(e) = pmp.flush() {
+                panic!("{e}");
+            }
+        }
+        if let Err(e) = pmp.unmount() {
+            panic!("{e}");
+        }
+        let bulk = match super::bulkfree(&f, false) {
+            Ok(v) => v,
+            Err(e) => panic!("{e}"),
+        };
+        assert!(bulk.count_freed >= 1 << 20, "{bulk:?}");
+
+        if let Err(e) = crate::swap::swap_image(&f) {
+            panic!("{e}");
+        }
+        match super::bulkfree(&f, false) {
+            Ok(v) => {
+                assert_eq!(v.total_scanned, bulk.total_scanned, "{v:?}");
+                assert_eq!(v.total_allocated, bulk.total_allocated, "{v:?}");
+                assert_eq!(v.count_freed, bulk.count_freed, "{v:?}");
+                assert_eq!(v.count_allocated, 0, "{v:?}");
+            }
+            Err(e) => panic!("{e}"),
+        }
+        match super::bulkfree(&f, true) {
+            Err(crate::Error::Errno(nix::errno::Errno::EROFS)) => (),
+            Ok(v) => panic!("{v:?}"),
+            Err(e) => panic!("{e}"),
+        }
+        let _ = std::fs::remove_file(f);
+    }
+
+    #[test]
+    fn test_bulkfree_zones() {
+        // 3 zones, each with a volume header in the 1GB reserved area
+        let f = crate::newfs::create_newfs_image("bulkfree_zones", 5 << 30);
+        let mut pmp = match crate::hammer2::Hammer2::mount(&f, &["--rw"]) {
+            Ok(v) => v,
+            Err(e) => panic!("{e}"),
+        };
+        let inum = match pmp.create(crate::inode::INUM_PFS_ROOT, "file", 0o644) {
+            Ok(v) => v,
+            Err(e) => panic!("{e}"),
+        };
+        // rotate through all volume headers
+        for i in 0..4 {
+            if let Err(e) = pmp.pwrite(inum, &pattern(1 << 20, i), 0) {
+                panic!("{e}");
+            }
+            if let Err(e) = pmp.flush() {
+                panic!("{e}");
+            }
+        }
+        match pmp.read_freemap() {
+            Ok(v) => {
+                for key in (0..5 << 30).step_by(1 << 30) {
+                    let Some(seg) = v.segments.iter().find(|x| x.offset == key) else {
+                        panic!("{key:x}");
+                    };
+                    assert_eq!(
+                        seg.get_block_state(0),
+                        crate::freemap::BlockState::Allocated,
+                        "{key:x}"
+                    );
+                }
+            }
+            Err(e) => panic!("{e}"),
+        }
+        if let Err(e) = pmp.unmount() {
+            panic!("{e}");
+        }
+        let bulk = match super::bulkfree(&f, false) {
+            Ok(v) => v,
+            Err(e) => panic!("{e}"),
+        };
+        assert_eq!(bulk.count_allocated, 0, "{bulk:?}");
+        match crate::fsck::fsck(&f) {
+            Ok(v) => assert!(v.is_clean(), "{:?}", v.problems),
+            Err(e) => panic!("{e}"),
+        }
+        let _ = std::fs::remove_file(f);
+    }
+
+    #[test]
+    fn test_bulkfree_volhdr() {
+        // sparse, 4 volume headers
+        let f = crate::newfs::create_newfs_image("bulkfree_volhdr", 8 << 30);
+        let mut pmp = match crate::hammer2::Hammer2::mount(&f, &["--rw"]) {
+            Ok(v) => v,
+            Err(e) => panic!("{e}"),
+        };
+        let inum = match pmp.create(crate::inode::INUM_PFS_ROOT, "a", 0o644) {
+            Ok(v) => v,
+            Err(e) => panic!("{e}"),
+        };
+        if let Err(e) = pmp.pwrite(inum, &pattern(1 << 20, 0), 0) {
+            panic!("{e}");
+        }
+        if let Err(e) = pmp.unmount() {
+            panic!("{e}");
+        }
+        let tid = match crate::volume::read_volume_data(&f) {
+            Ok(v) => v.mirror_tid,
+            Err(e) => panic!("{e}"),
+        };
+
+        // blocks of a are only referenced by the older volume header
+        let mut pmp = match crate::hammer2::Hammer2::mount(&f, &["--rw"]) {
+            Ok(v) => v,
+            Err(e) => panic!("{e}"),
+        };
+        if let Err(e) = pmp.unlink(crate::inode::INUM_PFS_ROOT, "a") {
+            panic!("{e}");
+        }
+        if let Err(e) = pmp.unmount() {
+            panic!("{e}");
+        }
+        let bulk = match super::bulkfree(&f, true) {
+            Ok(v) => v,
+            Err(e) => panic!("{e}"),
+        };
+        assert!(bulk.count_freed < 1 << 20, "{bulk:?}");
+
+        // allocate whatever has been freed
+        let mut pmp = match crate::hammer2::Hammer2::mount(&f, &["--rw"]) {
+            Ok(v) => v,
+            Err(e) => panic!("{e}"),
+        };
+        let inum = match pmp.create(crate::inode::INUM_PFS_ROOT, "b", 0o644) {
+            Ok(v) => v,
+            Err(e) => panic!("{e}"),
+        };
+        if let Err(e) = pmp.pwrite(inum, &pattern(4 << 20, 1), 0) {
+            panic!("{e}");
+        }
+        if let Err(e) = pmp.unmount() {
+            panic!("{e}");
+        }
+
+        let x = tid.to_string();
+        let mut pmp = match crate::hammer2::Hammer2::mount(&f, &["--mirror_tid", &x]) {
+            Ok(v) => v,
+            Err(e) => panic!("{e}"),
+        };
+        let inum = match pmp.nresolve(crate::inode::INUM_PFS_ROOT, "a") {
+            Ok(v) => v,
+            Err(e) => panic!("{e}"),
+        };
+        let mut buf = vec![0; 1 << 20];
+        match pmp.pread(inum, &mut buf, 0) {
+            Ok(v) => assert_eq!(v, 1 << 20),
+            Err(e) => panic!("{e}"),
+        }
+        assert_eq!(buf, pattern(1 << 20, 0));
+        if let Err(e) = pmp.unmount() {
+            panic!("{e}");
+        }
+        match crate::fsck::fsck(&f) {
+            Ok(v) => assert!(v.is_clean(), "{:?}", v.problems),
+            Err(e) => panic!("{e}"),
+        }
+        let _ = std::fs::remove_file(f);
+    }
+}
@@ -7,9 +7,10 @@ pub(crate) const CID_VCHAIN: Cid = 1;
 pub(crate) const CID_FCHAIN: Cid = 2;
 pub(crate) const CID_CHAIN_OFFSET: Cid = 3;
 
-const CHAIN_TESTED_GOOD: u32 = 0x0000_0100; // crc tested good
+pub(crate) const CHAIN_MODIFIED: u32 = 0x0000_0001; // dirty chain data
+pub(crate) const CHAIN_TESTED_GOOD: u32 = 0x0000_0100; // crc tested good
 const CHAIN_COUNTED_BLOCKREF: u32 = 0x0000_2000; // block table stats
-const CHAIN_ALL_MASK: u32 = CHAIN_TESTED_GOOD | CHAIN_COUNTED_BLOCKREF;
+const CHAIN_ALL_MASK: u32 = CHAIN_MODIFIED | CHAIN_TESTED_GOOD | CHAIN_COUNTED_BLOCKREF;
 
 #[derive(Clone, Copy, Debug)]
 pub(crate) struct ChainKey {
@@ -30,9 +31,9 @@ impl ChainKey {
 
 pub(crate) fn cmp_chain_key(x1: &ChainKey, x2: &ChainKey) -> std::cmp::Ordering {
     let x1_beg = x1.key;
-    let x1_end = x1_beg + (1 << x1.keybits) - 1;
+    let x1_end = x1_beg.wrapping_add(crate::extra::conv_keybits_to_mask(x1.keybits));
     let x2_beg = x2.key;
-    let x2_end = x2_beg + (1 << x2.keybits) - 1;
+    let x2_end = x2_beg.wrapping_add(crate::extra::conv_keybits_to_mask(x2.keybits));
     cmp_chain_key_impl(x1_beg, x1_end, x2_beg, x2_end)
 }
 
@@ -102,7 +103,6 @@ impl Chain {
         self.flags |= flags;
     }
 
-    #[allow(dead_code)]
     pub(crate) fn clear_flags(&mut self, flags: u32) {
         assert_eq!(flags & !CHAIN_ALL_MASK, 0);
         self.flags &= !flags;
@@ -117,10 +117,39 @@ impl Chain {
         self.bytes
     }
 
+    pub(crate) fn set_bytes(&mut self, bytes: u64) {
+        self.bytes = bytes;
+    }
+
+    #[must_use]
+    pub fn is_modified(&self) -> bool {
+        self.has_flags(CHAIN_MODIFIED)
+    }
+
+    pub(crate) fn get_data(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub(crate) fn get_data_mut(&mut self) -> &mut [u8] {
+        &mut self.data
+    }
+
     pub(crate) fn set_data(&mut self, data: Vec<u8>) {
         self.data = data;
     }
 
+    pub(crate) fn get_udata(&self) -> &[u8] {
+        &self.udata
+    }
+
+    pub(crate) fn set_udata(&mut self, udata: Vec<u8>) {
+        self.udata = udata;
+    }
+
+    pub(crate) fn clear_udata(&mut self) {
+        self.udata.clear();
+    }
+
     pub(crate) fn has_data(&self) -> bool {
         !self.data.is_empty()
     }
@@ -164,6 +193,19 @@ impl Chain {
         }
     }
 
+    pub(crate) fn get_child_by_key(&self, key: u64, keybits: u8) -> Option<Cid> {
+        self.ccids
+            .iter()
+            .find(|x| x.key == key && x.keybits == keybits)
+            .map(|x| x.cid)
+    }
+
+    // Child chain whose key range contains key.
+    pub(crate) fn get_child_by_range(&self, key: u64) -> Option<Cid> {
+        self.find_child_index_impl(key, key)
+            .map(|i| self.ccids[i].cid)
+    }
+
     pub(crate) fn has_child(&self) -> bool {
         !self.ccids.is_empty()
     }
@@ -171,7 +213,7 @@ impl Chain {
     #[allow(dead_code)]
     fn find_child_index(&self, x: &ChainKey) -> Option<usize> {
         let x_beg = x.key;
-        let x_end = x_beg + (1 << x.keybits) - 1;
+        let x_end = x_beg.wrapping_add(crate::extra::conv_keybits_to_mask(x.keybits));
         self.find_child_index_impl(x_beg, x_end)
     }
 
@@ -184,7 +226,8 @@ impl Chain {
         let mut i = usize::midpoint(beg, end);
         while beg <= end {
             let x2_beg = self.ccids[i].key;
-            let x2_end = x2_beg + (1 << self.ccids[i].keybits) - 1;
+            let x2_end =
+                x2_beg.wrapping_add(crate::extra::conv_keybits_to_mask(self.ccids[i].keybits));
             match cmp_chain_key_impl(x1_beg, x1_end, x2_beg, x2_end) {
                 std::cmp::Ordering::Less => {
                     if i == 0 {
@@ -208,7 +251,7 @@ impl Chain {
     #[allow(dead_code)]
     fn find_child_range(&self, x: &ChainKey) -> nix::Result<Option<(usize, usize)>> {
         let x_beg = x.key;
-        let x_end = x_beg + (1 << x.keybits) - 1;
+        let x_end = x_beg.wrapping_add(crate::extra::conv_keybits_to_mask(x.keybits));
         self.find_child_range_impl(x_beg, x_end)
     }
 
@@ -224,7 +267,7 @@ impl Chain {
         let mut beg = i;
         for j in (0..i).rev() {
             let x2_beg = v[j].key;
-            let x2_end = x2_beg + (1 << v[j].keybits) - 1;
+            let x2_end = x2_beg.wrapping_add(crate::extra::conv_keybits_to_mask(v[j].keybits));
             match cmp_chain_key_impl(x1_beg, x1_end, x2_beg, x2_end) {
                 std::cmp::Ordering::Less => {
                     log::error!("bad key at {j}|{v:#?}");
@@ -240,7 +283,7 @@ impl Chain {
         let mut end = i;
         for j in (i + 1)..v.len() {
             let x2_beg = v[j].key;
-            let x2_end = x2_beg + (1 << v[j].keybits) - 1;
+            let x2_end = x2_beg.wrapping_add(crate::extra::conv_keybits_to_mask(v[j].keybits));
             match cmp_chain_key_impl(x1_beg, x1_end, x2_beg, x2_end) {
                 std::cmp::Ordering::Less => {
                     end = j - 1;
@@ -309,7 +352,10 @@ impl Chain {
                 }
             }
             // Always truncate key_next based on child's end-of-range.
-            let child_end = child.key + (1 << child.keybits);
+            let child_end = child
+                .key
+                .wrapping_add(crate::extra::conv_keybits_to_mask(child.keybits))
+                .wrapping_add(1);
             if child_end != 0 && (key_next > child_end || key_next == 0) {
                 key_next = child_end;
             }
@@ -318,10 +364,11 @@ impl Chain {
         Ok(Some((self.ccids[besti], key_next)))
     }
 
-    pub(crate) fn count_blockref(&mut self) -> nix::Result<()> {
+    pub(crate) fn count_blockref(&mut self) -> crate::Result<()> {
         if self.has_flags(CHAIN_COUNTED_BLOCKREF) {
             return Ok(());
         }
+        self.live_count = 0;
         let base = self.as_blockref()?;
         if base.is_empty() {
             self.live_zero = 0;
@@ -350,7 +397,7 @@ impl Chain {
         &mut self,
         key_next: u64,
         key_beg: u64,
-    ) -> nix::Result<(usize, u64)> {
+    ) -> crate::Result<(usize, u64)> {
         // Require the live chain's already have their core's counted
         // so we can optimize operations.
         assert!(self.has_flags(CHAIN_COUNTED_BLOCKREF));
@@ -386,7 +433,9 @@ impl Chain {
         let base = self.as_blockref()?;
         while i < base.len() {
             if base[i].typ != crate::fs::HAMMER2_BREF_TYPE_EMPTY {
-                let scan_end = base[i].key + (1 << base[i].keybits) - 1;
+                let scan_end = base[i]
+                    .key
+                    .wrapping_add(crate::extra::conv_keybits_to_mask(base[i].keybits));
                 if base[i].key > key_beg || scan_end >= key_beg {
                     break;
                 }
@@ -402,7 +451,10 @@ impl Chain {
             if i >= limit {
                 i = base.len();
             } else {
-                let scan_end = base[i].key + (1 << base[i].keybits);
+                let scan_end = base[i]
+                    .key
+                    .wrapping_add(crate::extra::conv_keybits_to_mask(base[i].keybits))
+                    .wrapping_add(1);
                 if scan_end != 0 && (key_next > scan_end || key_next == 0) {
                     return Ok((i, scan_end));
                 }
@@ -454,72 +506,132 @@ impl Chain {
         }
     }
 
+    // Media read from disk is verified by ondisk::check_media on load,
+    // and media created in memory is of the ondisk size.
     #[must_use]
     pub fn as_inode_data(&self) -> &crate::fs::Hammer2InodeData {
-        crate::ondisk::media_as_inode_data(&self.data)
+        libfs::cast::align_to(&self.data)
+    }
+
+    pub(crate) fn as_inode_data_mut(&mut self) -> &mut crate::fs::Hammer2InodeData {
+        libfs::cast::align_to_mut(&mut self.data)
     }
 
     #[must_use]
     pub fn as_volume_data(&self) -> &crate::fs::Hammer2VolumeData {
-        crate::ondisk::media_as_volume_data(&self.data)
+        libfs::cast::align_to(&self.data)
     }
 
-    pub(crate) fn as_blockref(&self) -> nix::Result<Vec<&crate::fs::Hammer2Blockref>> {
+    pub(crate) fn as_blockref(&self) -> crate::Result<Vec<&crate::fs::Hammer2Blockref>> {
         crate::ondisk::media_as_blockref(&self.bref, &self.data)
     }
 
+    // Returns a copy of non-empty blockrefs in the block table.
+    pub(crate) fn get_blockref_array(&self) -> crate::Result<Vec<crate::fs::Hammer2Blockref>> {
+        Ok(self
+            .as_blockref()?
+            .into_iter()
+            .filter(|x| x.typ != crate::fs::HAMMER2_BREF_TYPE_EMPTY)
+            .copied()
+            .collect())
+    }
+
+    // Rewrites the block table with the given blockrefs sorted by key.
+    // Trailing entries are cleared.
+    pub(crate) fn set_blockref_array(
+        &mut self,
+        base: &[crate::fs::Hammer2Blockref],
+    ) -> crate::Result<()> {
+        let (offset, count) = crate::ondisk::media_as_blockref_range(&self.bref, &self.data)?;
+        if base.len() > count {
+            log::error!("{} entries exceeds {count}", base.len());
+            return Err(nix::errno::Errno::ENOSPC.into());
+        }
+        let mut base = base.to_vec();
+        base.sort_by_key(|x| x.key);
+        base.resize(count, crate::fs::Hammer2Blockref::new_empty());
+        let n = std::mem::size_of::<crate::fs::Hammer2Blockref>();
+        for (i, bref) in base.iter().enumerate() {
+            let beg = offset + i * n;
+            self.data[beg..beg + n].copy_from_slice(libfs::cast::as_u8_slice(bref));
+        }
+        // Block table changed, recount on next lookup.
+        self.clear_flags(CHAIN_COUNTED_BLOCKREF);
+        self.live_zero = 0;
+        self.live_count = 0;
+        self.cache_index = 0;
+        Ok(())
+    }
+
     pub(crate) fn as_blockref_safe(&self) -> Vec<&crate::fs::Hammer2Blockref> {
         crate::ondisk::media_as_blockref_safe(&self.bref, &self.data)
     }
 
-    pub(crate) fn read_cache_data(&mut self) -> nix::Result<Vec<u8>> {
+    pub(crate) fn read_cache_data(&mut self) -> crate::Result<Vec<u8>> {
         if !self.has_udata() {
             self.udata = self.read_data()?;
         }
         Ok(self.udata.clone())
     }
 
-    pub(crate) fn read_data(&mut self) -> nix::Result<Vec<u8>> {
+    pub(crate) fn read_data(&mut self) -> crate::Result<Vec<u8>> {
         match self.bref.typ {
             crate::fs::HAMMER2_BREF_TYPE_INODE => {
                 // Ignore garbage beyond inode size.
                 let ipdata = self.as_inode_data();
-                Ok(ipdata.u[..ipdata.meta.size.try_into().or_nix_range()?].to_vec())
+                let n = ipdata.meta.size.try_into().or_range()?;
+                match ipdata.u.get(..n) {
+                    Some(v) => Ok(v.to_vec()),
+                    None => Err(crate::Corruption::new_error(
+                        &self.bref,
+                        std::mem::offset_of!(crate::fs::Hammer2InodeData, u),
+                        &format!("direct data size {n}"),
+                    )),
+                }
             }
             crate::fs::HAMMER2_BREF_TYPE_DATA => self.decompress_data(),
             _ => {
                 log::error!("bad blockref type {}", self.bref.typ);
-                Err(nix::errno::Errno::EINVAL)
+                Err(nix::errno::Errno::EINVAL.into())
             }
         }
     }
 
-    fn decompress_data(&self) -> nix::Result<Vec<u8>> {
-        let max_size = crate::fs::HAMMER2_PBUFSIZE.try_into().or_nix_range()?;
+    fn decompress_data(&self) -> crate::Result<Vec<u8>> {
+        let max_size = crate::fs::HAMMER2_PBUFSIZE.try_into().or_range()?;
         match crate::fs::dec_comp(self.bref.methods) {
             crate::fs::HAMMER2_COMP_NONE => {
-                let n = usize::try_from(self.bytes).or_nix_range()?;
-                assert!(n <= self.data.len());
-                Ok(self.data[..n].to_vec())
+                let n = usize::try_from(self.bytes).or_range()?;
+                match self.data.get(..n) {
+                    Some(v) => Ok(v.to_vec()),
+                    None => Err(crate::Corruption::new_error(
+                        &self.bref,
+                        0,
+                        &format!("{} bytes data of {n} bytes block", self.data.len()),
+                    )),
+                }
             }
             crate::fs::HAMMER2_COMP_LZ4 => match crate::lz4::decompress(&self.data, max_size) {
                 Ok(v) => Ok(v),
-                Err(e) => {
-                    log::error!("{e}: failed to decompress");
-                    Err(nix::errno::Errno::EIO)
-                }
+                Err(e) => Err(crate::Corruption::new_error(
+                    &self.bref,
+                    0,
+                    &format!("{e}: failed to decompress"),
+                )),
             },
             crate::fs::HAMMER2_COMP_ZLIB => match crate::zlib::decompress(&self.data, max_size) {
                 Ok(v) => Ok(v),
-                Err(e) => {
-                    log::error!("{e}: failed to decompress");
-                    Err(nix::errno::Errno::EIO)
-                }
+                Err(e) => Err(crate::Corruption::new_error(
+                    &self.bref,
+                    0,
+                    &format!("{e}: failed to decompress"),
+                )),
             },
-            _ => {
-                log::error!("bad comp type {:02x}", self.bref.methods);
-                Err(nix::errno::Errno::EINVAL)
-            }
+            _ => Err(crate::Corruption::new_error(
+                &self.bref,
+                0,
+                &format!("bad comp type {:02x}", self.bref.methods),
+            )),
         }
     }
 
@@ -647,6 +759,29 @@ mod tests {
         test_cmp_chain_key(16);
     }
 
+    #[test]
+    fn test_cmp_chain_key_end_of_range() {
+        // key range ends at u64::MAX
+        for (key, keybits) in [(0, 64), (1 << 63, 63), (u64::MAX - 0xFF, 8)] {
+            let x1 = alloc_chain_key(0, key, keybits);
+            let x2 = alloc_chain_key(0, u64::MAX, 0);
+            assert!(
+                super::cmp_chain_key(&x1, &x2) == std::cmp::Ordering::Equal,
+                "{x1:#?} vs {x2:#?}"
+            );
+            assert!(
+                super::cmp_chain_key(&x2, &x1) == std::cmp::Ordering::Equal,
+                "{x2:#?} vs {x1:#?}"
+            );
+        }
+        let x1 = alloc_chain_key(0, 0, 63);
+        let x2 = alloc_chain_key(0, 1 << 63, 63);
+        assert!(
+            super::cmp_chain_key(&x1, &x2) == std::cmp::Ordering::Less,
+            "{x1:#?} vs {x2:#?}"
+        );
+    }
+
     fn test_chain_get_child(n: u8) {
         let c = alloc_empty_chain();
         assert_eq!(c.get_first_child(), None);
@@ -0,0 +1,406 @@
+use crate::ErrorExt;
+use crate::OptionExt;
+use std::io::Write;
+use std::os::unix::fs::FileExt;
+use std::os::unix::fs::OpenOptionsExt;
+
+const MAX_BACKING_DEPTH: usize = 16;
+
+// Alignment of offset, size and buffer address for O_DIRECT.
+const DIRECT_ALIGN: usize = 4096;
+
+#[cfg(any(target_os = "linux", target_os = "freebsd"))]
+const O_DIRECT: Option<libc::c_int> = Some(libc::O_DIRECT);
+#[cfg(not(any(target_os = "linux", target_os = "freebsd")))]
+const O_DIRECT: Option<libc::c_int> = None;
+
+/// Storage backing a volume.
+/// Offsets are relative to the beginning of the device.
+/// I/O takes `&self`, so that a device can be shared by threads.
+pub trait BlockDevice: std::fmt::Debug + Send + Sync {
+    /// Read exactly `buf.len()` bytes at `offset`.
+    /// # Errors
+    fn pread(&self, buf: &mut [u8], offset: u64) -> crate::Result<()>;
+
+    /// Write all of `buf` at `offset`.
+    /// # Errors
+    fn pwrite(&self, buf: &[u8], offset: u64) -> crate::Result<()>;
+
+    /// # Errors
+    fn get_size(&mut self) -> crate::Result<u64>;
+
+    /// # Errors
+    fn flush(&self) -> crate::Result<()>;
+
+    /// Returns metadata of the underlying file if any, which is used to
+    /// detect the same file specified more than once.
+    /// # Errors
+    fn get_metadata(&self) -> crate::Result<Option<std::fs::Metadata>> {
+        Ok(None)
+    }
+
+    /// Returns offset of the device within the underlying file.
+    fn get_base(&self) -> u64 {
+        0
+    }
+}
+
+impl BlockDevice for std::fs::File {
+    fn pread(&self, buf: &mut [u8], offset: u64) -> crate::Result<()> {
+        Ok(self.read_exact_at(buf, offset)?)
+    }
+
+    fn pwrite(&self, buf: &[u8], offset: u64) -> crate::Result<()> {
+        Ok(self.write_all_at(buf, offset)?)
+    }
+
+    fn get_size(&mut self) -> crate::Result<u64> {
+        crate::subs::get_volume_size(self)
+    }
+
+    fn flush(&self) -> crate::Result<()> {
+        Ok(Write::flush(&mut &*self)?)
+    }
+
+    fn get_metadata(&self) -> crate::Result<Option<std::fs::Metadata>> {
+        Ok(Some(self.metadata()?))
+    }
+}
+
+/// File opened with O_DIRECT, bypassing the page cache.
+/// I/O goes through aligned buffers, so callers may use any offset and size.
+#[derive(Debug)]
+pub struct DirectFile {
+    fp: std::fs::File,
+}
+
+impl DirectFile {
+    /// # Errors
+    pub fn open(path: &str, readonly: bool) -> crate::Result<Self> {
+        let Some(flag) = O_DIRECT else {
+            log::error!("O_DIRECT unsupported");
+            return Err(nix::errno::Errno::EOPNOTSUPP.into());
+        };
+        Ok(Self {
+            fp: std::fs::OpenOptions::new()
+                .read(true)
+                .write(!readonly)
+                .custom_flags(flag)
+                .open(path)?,
+        })
+    }
+
+    // Returns aligned range covering len bytes at offset.
+    fn get_range(offset: u64, len: usize) -> crate::Result<(u64, usize)> {
+        let align = u64::try_from(DIRECT_ALIGN).or_range()?;
+        let beg = offset & !(align - 1);
+        let end = offset
+            .checked_add(len.try_into().or_range()?)
+            .and_then(|x| x.checked_next_multiple_of(align))
+            .ok_or(nix::errno::Errno::EINVAL)?;
+        Ok((beg, (end - beg).try_into().or_range()?))
+    }
+
+    // Reads until buf is filled or EOF, and returns the number of bytes read.
+    fn read_full(&self, buf: &mut [u8], offset: u64) -> crate::Result<usize> {
+        let mut total = 0;
+        while total < buf.len() {
+            let n = match self
+                .fp
+                .read_at(&mut buf[total..], offset + u64::try_from(total).or_range()?)
+            {
+                Ok(0) => break,
+                Ok(v) => v,
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e.into()),
+            };
+            // a short read of an aligned size ends at EOF
+            total += n;
+            if total % DIRECT_ALIGN != 0 {
+                break;
+            }
+        }
+        Ok(total)
+    }
+}
+
+impl BlockDevice for DirectFile {
+    fn pread(&self, buf: &mut [u8], offset: u64) -> crate::Result<()> {
+        let (beg, size) = Self::get_range(offset, buf.len())?;
+        let mut b = AlignedBuffer::new(size)?;
+        let i = usize::try_from(offset - beg).or_range()?;
+        if self.read_full(b.as_mut_slice(), beg)? < i + buf.len() {
+            return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+        }
+        buf.copy_from_slice(&b.as_slice()[i..i + buf.len()]);
+        Ok(())
+    }
+
+    // Unaligned head and tail are read, modified and written back, which
+    // isn't atomic against concurrent writes to the same blocks.
+    fn pwrite(&self, buf: &[u8], offset: u64) -> crate::Result<()> {
+        let (beg, size) = Self::get_range(offset, buf.len())?;
+        let mut b = AlignedBuffer::new(size)?;
+        let i = usize::try_from(offset - beg).or_range()?;
+        if i != 0 || buf.len() != size {
+            // bytes beyond EOF remain zero
+            self.read_full(b.as_mut_slice(), beg)?;
+        }
+        b.as_mut_slice()[i..i + buf.len()].copy_from_slice(buf);
+        Ok(self.fp.write_all_at(b.as_slice(), beg)?)
+    }
+
+    fn get_size(&mut self) -> crate::Result<u64> {
+        crate::subs::get_volume_size(&mut self.fp)
+    }
+
+    fn flush(&self) -> crate::Result<()> {
+        Ok(Write::flush(&mut &self.fp)?)
+    }
+
+    fn get_metadata(&self) -> crate::Result<Option<std::fs::Metadata>> {
+        Ok(Some(self.fp.metadata()?))
+    }
+}
+
+// Buffer whose address is aligned to DIRECT_ALIGN.
+struct AlignedBuffer {
+    buf: Vec<u8>,
+    start: usize,
+    size: usize,
+}
+
+impl AlignedBuffer {
+    fn new(size: usize) -> crate::Result<Self> {
+        let buf = vec![0; size.checked_add(DIRECT_ALIGN).or_range()?];
+        let start = buf.as_ptr().align_offset(DIRECT_ALIGN);
+        if start >= DIRECT_ALIGN {
+            return Err(nix::errno::Errno::ENOMEM.into());
+        }
+        Ok(Self { buf, start, size })
+    }
+
+    fn as_slice(&self) -> &[u8] {
+        &self.buf[self.start..self.start + self.size]
+    }
+
+    fn as_mut_slice(&mut self) -> &mut [u8] {
+        &mut self.buf[self.start..self.start + self.size]
+    }
+}
+
+/// Open a file as a device.
+/// qcow2 and Android sparse images are detected by their magic, and are
+/// opened read-only.
+/// If `direct` is set, the file is opened with O_DIRECT.
+/// # Errors
+pub fn open(path: &str, readonly: bool, direct: bool) -> crate::Result<Box<dyn BlockDevice>> {
+    open_impl(path, readonly, direct, 0)
+}
+
+fn open_file(path: &str, readonly: bool, direct: bool) -> crate::Result<Box<dyn BlockDevice>> {
+    Ok(if direct {
+        Box::new(DirectFile::open(path, readonly)?)
+    } else {
+        Box::new(libfs::fs::open(path, readonly)?)
+    })
+}
+
+fn open_impl(
+    path: &str,
+    readonly: bool,
+    direct: bool,
+    depth: usize,
+) -> crate::Result<Box<dyn BlockDevice>> {
+    let fp = open_file(path, readonly, direct)?;
+    let mut magic = [0; 4];
+    // a file smaller than the magic is a raw image
+    if fp.pread(&mut magic, 0).is_err()
+        || !crate::qcow2::is_qcow2(&magic) && !crate::sparse::is_sparse(&magic)
+    {
+        return Ok(fp);
+    }
+    if !readonly {
+        log::error!("{path} is a read-only image");
+        return Err(nix::errno::Errno::EROFS.into());
+    }
+    if crate::sparse::is_sparse(&magic) {
+        return Ok(Box::new(crate::sparse::SparseImage::new(fp)?));
+    }
+    let mut img = crate::qcow2::Qcow2::new(fp)?;
+    if let Some(f) = img.get_backing_file() {
+        if depth >= MAX_BACKING_DEPTH {
+            log::error!("{path}: too many levels of backing files");
+            return Err(nix::errno::Errno::ELOOP.into());
+        }
+        // relative to the directory of the image
+        let f = std::path::Path::new(path)
+            .parent()
+            .unwrap_or_else(|| std::path::Path::new("."))
+            .join(f);
+        let f = f.to_str().ok_or(nix::errno::Errno::EINVAL)?;
+        log::debug!("{path}: backing file {f}");
+        img.set_backing(open_impl(f, true, direct, depth + 1)?)?;
+    }
+    Ok(Box::new(img))
+}
+
+/// Fixed size device in memory.
+/// Clones share the same buffer, so that a filesystem can be created,
+/// mounted and inspected through different handles.
+#[derive(Clone, Default)]
+pub struct MemoryDevice {
+    buf: std::sync::Arc<std::sync::Mutex<Vec<u8>>>,
+}
+
+impl std::fmt::Debug for MemoryDevice {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("MemoryDevice")
+            .field("size", &self.lock().map_or(0, |b| b.len()))
+            .finish()
+    }
+}
+
+impl From<Vec<u8>> for MemoryDevice {
+    fn from(buf: Vec<u8>) -> Self {
+        Self {
+            buf: std::sync::Arc::new(std::sync::Mutex::new(buf)),
+        }
+    }
+}
+
+impl MemoryDevice {
+    /// Returns a zero filled device of `size` bytes.
+    /// # Errors
+    pub fn new(size: u64) -> crate::Result<Self> {
+        Ok(vec![0; size.try_into().or_range()?].into())
+    }
+
+    /// Returns a copy of the device content.
+    /// # Errors
+    pub fn to_vec(&self) -> crate::Result<Vec<u8>> {
+        Ok(self.lock()?.clone())
+    }
+
+    fn lock(&self) -> crate::Result<std::sync::MutexGuard<'_, Vec<u8>>> {
+        self.buf
+            .lock()
+            .map_err(|e| std::io::Error::other(e.to_string()).into())
+    }
+
+    fn get_range(size: usize, offset: u64, len: usize) -> Option<std::ops::Range<usize>> {
+        let beg = usize::try_from(offset).ok()?;
+        let end = beg.checked_add(len)?;
+        if end > size {
+            return None;
+        }
+        Some(beg..end)
+    }
+}
+
+impl BlockDevice for MemoryDevice {
+    fn pread(&self, buf: &mut [u8], offset: u64) -> crate::Result<()> {
+        let b = self.lock()?;
+        let Some(r) = Self::get_range(b.len(), offset, buf.len()) else {
+            return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+        };
+        buf.copy_from_slice(&b[r]);
+        Ok(())
+    }
+
+    fn pwrite(&self, buf: &[u8], offset: u64) -> crate::Result<()> {
+        let mut b = self.lock()?;
+        let Some(r) = Self::get_range(b.len(), offset, buf.len()) else {
+            return Err(nix::errno::Errno::ENOSPC.into());
+        };
+        b[r].copy_from_slice(buf);
+        Ok(())
+    }
+
+    fn get_size(&mut self) -> crate::Result<u64> {
+        self.lock()?.len().try_into().or_range()
+    }
+
+    fn flush(&self) -> crate::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::BlockDevice;
+
+    #[test]
+    fn test_memory_device() {
+        let mut dev = match super::MemoryDevice::new(4096) {
+            Ok(v) => v,
+            Err(e) => panic!("{e}"),
+        };
+        match dev.get_size() {
+            Ok(v) => assert_eq!(v, 4096),
+            Err(e) => panic!("{e}"),
+        }
+        let dev2 = dev.clone();
+        if let Err(e) = dev.pwrite(b"hammer2", 4089) {
+            panic!("{e}");
+        }
+        let mut buf = vec![0; 7];
+        if let Err(e) = dev2.pread(&mut buf, 4089) {
+            panic!("{e}");
+        }
+        assert_eq!(buf, b"hammer2");
+        assert!(dev.pwrite(b"hammer2", 4090).is_err());
+        assert!(dev.pread(&mut buf, 4090).is_err());
+        assert!(dev.pread(&mut buf, u64::MAX).is_err());
+        match dev2.to_vec() {
+            Ok(v) => {
+                assert_eq!(v.len(), 4096);
+                assert_eq!(&v[4089..], b"hammer2");
+            }
+            Err(e) => panic!("{e}"),
+        }
+    }
+
+    #[test]
+    fn test_direct_file() {
+        let f = crate::newfs::get_image_path("direct_file");
+        let data: Vec<u8> = (0..3 * super::DIRECT_ALIGN + 100)
+            .map(|i| (i % 251) as u8)
+            .collect();
+        if let Err(e) = std::fs::write(&f, &data) {
+            panic!("{e}");
+        }
+        let mut dev = match super::DirectFile::open(&f, false) {
+            Ok(v) => v,
+            Err(e) => panic!("{e}"),
+        };
+        match dev.get_size() {
+            Ok(v) => assert_eq!(v, u64::try_from(data.len()).unwrap_or(0)),
+            Err(e) => panic!("{e}"),
+        }
+        // unaligned offset and size, crossing blocks and up to EOF
+        for (offset, size) in [(0, 4096), (1, 10), (4000, 200), (100, 12288), (12000, 388)] {
+            let mut buf = vec![0; size];
+            if let Err(e) = dev.pread(&mut buf, offset) {
+                panic!("{offset} {size} {e}");
+            }
+            let i = usize::try_from(offset).unwrap_or(0);
+            assert_eq!(buf, data[i..i + size], "{offset} {size}");
+        }
+        let mut buf = vec![0; 2];
+        assert!(dev.pread(&mut buf, 12387).is_err());
+        assert!(dev.pread(&mut buf, 1 << 20).is_err());
+
+        // unaligned write keeps surrounding bytes
+        if let Err(e) = dev.pwrite(b"hammer2", 4093) {
+            panic!("{e}");
+        }
+        let mut data = data;
+        data[4093..4100].copy_from_slice(b"hammer2");
+        match std::fs::read(&f) {
+            Ok(v) => assert_eq!(v, data),
+            Err(e) => panic!("{e}"),
+        }
+        let _ = std::fs::remove_file(&f);
+    }
+}
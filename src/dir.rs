@@ -0,0 +1,197 @@
+use crate::OptionExt;
+
+/// An iterator over directory entries, which looks up one entry at a time.
+/// `Dirent::key` of a yielded entry can be passed to `read_dir_from`
+/// (plus 1) to resume the enumeration.
+/// The lock of `Hammer2` is only taken while looking up an entry.
+pub struct ReadDir<'a> {
+    pmp: &'a crate::hammer2::Hammer2,
+    dinum: u64,
+    lkey: u64,
+    done: bool,
+}
+
+impl std::fmt::Debug for ReadDir<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("ReadDir")
+            .field("dinum", &self.dinum)
+            .field("lkey", &self.lkey)
+            .field("done", &self.done)
+            .finish_non_exhaustive()
+    }
+}
+
+impl ReadDir<'_> {
+    #[must_use]
+    pub fn get_lkey(&self) -> u64 {
+        self.lkey
+    }
+
+    fn next_impl(&mut self) -> crate::Result<Option<crate::hammer2::Dirent>> {
+        // "." and ".." use cookies 0 and 1, same as readdir.
+        if self.lkey < 2 {
+            let meta = self.pmp.read().get_inode(self.dinum).or_range()?.meta;
+            let dirent = if self.lkey == 0 {
+                crate::hammer2::Dirent::new(meta.inum, meta.typ, ".", 0)
+            } else {
+                crate::hammer2::Dirent::new(
+                    meta.iparent & crate::fs::HAMMER2_DIRHASH_USERMSK,
+                    crate::fs::HAMMER2_OBJTYPE_DIRECTORY,
+                    "..",
+                    1,
+                )
+            };
+            self.lkey += 1;
+            return Ok(Some(dirent));
+        }
+        let Some(dirent) = self
+            .pmp
+            .with_lock(|pmp| pmp.readdir_next(self.dinum, self.lkey))?
+        else {
+            return Ok(None);
+        };
+        self.lkey = dirent.key.wrapping_add(1);
+        if self.lkey == 0 {
+            self.done = true;
+        }
+        Ok(Some(dirent))
+    }
+}
+
+impl Iterator for ReadDir<'_> {
+    type Item = crate::Result<crate::hammer2::Dirent>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        match self.next_impl() {
+            Ok(Some(v)) => Some(Ok(v)),
+            Ok(None) => {
+                self.done = true;
+                None
+            }
+            Err(e) => {
+                self.done = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+impl crate::hammer2::Hammer2 {
+    /// # Errors
+    pub fn read_dir(&self, dinum: u64) -> crate::Result<ReadDir<'_>> {
+        self.read_dir_from(dinum, 0)
+    }
+
+    /// # Errors
+    pub fn read_dir_from(&self, dinum: u64, lkey: u64) -> crate::Result<ReadDir<'_>> {
+        let typ = self.read().get_inode(dinum).or_range()?.meta.typ;
+        if typ != crate::fs::HAMMER2_OBJTYPE_DIRECTORY {
+            return Err(nix::errno::Errno::ENOTDIR.into());
+        }
+        Ok(ReadDir {
+            pmp: self,
+            dinum,
+            lkey,
+            done: false,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_read_dir() {
+        let f = crate::newfs::create_newfs_image("dir", 128 << 20);
+        let mut pmp = match crate::hammer2::Hammer2::mount(&f, &["--rw"]) {
+            Ok(v) => v,
+            Err(e) => panic!("{e}"),
+        };
+        let dinum = match pmp.mkdir(crate::inode::INUM_PFS_ROOT, "d", 0o755) {
+            Ok(v) => v,
+            Err(e) => panic!("{e}"),
+        };
+        let inum = match pmp.create(crate::inode::INUM_PFS_ROOT, "x", 0o644) {
+            Ok(v) => v,
+            Err(e) => panic!("{e}"),
+        };
+        match pmp.read_dir(inum) {
+            Err(crate::Error::Errno(nix::errno::Errno::ENOTDIR)) => (),
+            Ok(v) => panic!("{v:?}"),
+            Err(e) => panic!("{e}"),
+        }
+        for i in 0..500 {
+            if let Err(e) = pmp.create(dinum, &format!("file{i}"), 0o644) {
+                panic!("{e}");
+            }
+        }
+        if let Err(e) = pmp.unmount() {
+            panic!("{e}");
+        }
+
+        let mut pmp = match crate::hammer2::Hammer2::mount(&f, &[]) {
+            Ok(v) => v,
+            Err(e) => panic!("{e}"),
+        };
+        let dinum = match pmp.nresolve_path("d") {
+            Ok(v) => v,
+            Err(e) => panic!("{e}"),
+        };
+        let nchains = pmp.get_mut().cmap.len();
+        let v: Vec<_> = match pmp.read_dir(dinum) {
+            Ok(v) => match v.collect() {
+                Ok(v) => v,
+                Err(e) => panic!("{e}"),
+            },
+            Err(e) => panic!("{e}"),
+        };
+        assert_eq!(v.len(), 502);
+        assert_eq!(v[0].name, ".");
+        assert_eq!(v[0].inum, dinum);
+        assert_eq!(v[1].name, "..");
+        assert_eq!(v[1].inum, crate::inode::INUM_PFS_ROOT);
+        // entry chains are released, only indirect blocks remain
+        assert!(
+            pmp.get_mut().cmap.len() < nchains + 50,
+            "{nchains} {}",
+            pmp.get_mut().cmap.len()
+        );
+        // same entries as readdir
+        let mut v2 = match pmp.readdir(dinum) {
+            Ok(v) => v,
+            Err(e) => panic!("{e}"),
+        };
+        assert_eq!(v.len(), v2.len());
+        let mut v1: Vec<_> = v.iter().map(|x| (x.key, x.inum, &x.name)).collect();
+        v2.sort_by_key(|x| x.key);
+        v1.sort_unstable();
+        assert_eq!(
+            v1,
+            v2.iter()
+                .map(|x| (x.key, x.inum, &x.name))
+                .collect::<Vec<_>>()
+        );
+        // resume from a cookie
+        for i in [0, 1, 2, 250, 501] {
+            let w: Vec<_> = match pmp.read_dir_from(dinum, v[i].key) {
+                Ok(v) => match v.collect() {
+                    Ok(v) => v,
+                    Err(e) => panic!("{e}"),
+                },
+                Err(e) => panic!("{e}"),
+            };
+            assert_eq!(w.len(), v.len() - i);
+            assert_eq!(w[0].name, v[i].name);
+        }
+        match pmp.read_dir_from(dinum, v[501].key + 1) {
+            Ok(mut v) => assert!(v.next().is_none()),
+            Err(e) => panic!("{e}"),
+        }
+        if let Err(e) = pmp.unmount() {
+            panic!("{e}");
+        }
+        let _ = std::fs::remove_file(f);
+    }
+}
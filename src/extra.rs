@@ -11,6 +11,20 @@ pub(crate) fn conv_offset_to_raw_data_off(x: u64) -> u64 {
     x & !crate::fs::HAMMER2_OFF_MASK_RADIX
 }
 
+pub(crate) fn conv_keybits_to_mask(keybits: u8) -> u64 {
+    if keybits >= 64 {
+        u64::MAX
+    } else {
+        (1 << keybits) - 1
+    }
+}
+
+// Embedded arrays are 8 bytes aligned within the ondisk structures,
+// so casting them is checked at compile time.
+const fn is_castable<T>(size: usize) -> bool {
+    std::mem::size_of::<T>() <= size && std::mem::align_of::<T>() <= 8
+}
+
 impl crate::fs::Hammer2Blockref {
     pub(crate) fn is_node_type(&self) -> bool {
         self.typ == crate::fs::HAMMER2_BREF_TYPE_INDIRECT
@@ -33,21 +47,34 @@ impl crate::fs::Hammer2Blockref {
         conv_offset_to_raw_data_off(self.data_off)
     }
 
+    // inclusive
+    pub(crate) fn get_key_end(&self) -> u64 {
+        self.key.saturating_add(conv_keybits_to_mask(self.keybits))
+    }
+
+    pub(crate) fn is_key_overlapped(&self, key_beg: u64, key_end: u64) -> bool {
+        self.key <= key_end && self.get_key_end() >= key_beg
+    }
+
     #[must_use]
     pub fn embed_as<T>(&self) -> &T {
+        const { assert!(is_castable::<T>(16)) };
         libfs::cast::align_to(&self.embed)
     }
 
     pub fn embed_as_mut<T>(&mut self) -> &mut T {
+        const { assert!(is_castable::<T>(16)) };
         libfs::cast::align_to_mut(&mut self.embed)
     }
 
     #[must_use]
     pub fn check_as<T>(&self) -> &T {
+        const { assert!(is_castable::<T>(64)) };
         libfs::cast::align_to(&self.check)
     }
 
     pub fn check_as_mut<T>(&mut self) -> &mut T {
+        const { assert!(is_castable::<T>(64)) };
         libfs::cast::align_to_mut(&mut self.check)
     }
 }
@@ -126,22 +153,37 @@ impl crate::fs::Hammer2InodeMeta {
 impl crate::fs::Hammer2InodeData {
     #[must_use]
     pub fn u_as<T>(&self) -> &T {
+        const { assert!(is_castable::<T>(crate::fs::HAMMER2_EMBEDDED_BYTES as usize)) };
         libfs::cast::align_to(&self.u)
     }
 
     pub fn u_as_mut<T>(&mut self) -> &mut T {
+        const { assert!(is_castable::<T>(crate::fs::HAMMER2_EMBEDDED_BYTES as usize)) };
         libfs::cast::align_to_mut(&mut self.u)
     }
 
     /// # Errors
     pub fn get_filename_string(&self) -> Result<String, std::string::FromUtf8Error> {
-        String::from_utf8(
-            self.filename[..std::cmp::min(
-                usize::from(self.meta.name_len),
-                crate::fs::HAMMER2_INODE_MAXNAME,
-            )]
-                .to_vec(),
-        )
+        String::from_utf8(self.get_filename().to_vec())
+    }
+
+    // Same as get_filename_string, but invalid UTF-8 bytes are escaped as \xNN.
+    pub(crate) fn get_filename_string_escaped(&self) -> String {
+        let mut s = String::new();
+        for x in self.get_filename().utf8_chunks() {
+            s.push_str(x.valid());
+            for b in x.invalid() {
+                s.push_str(&format!("\\x{b:02x}"));
+            }
+        }
+        s
+    }
+
+    fn get_filename(&self) -> &[u8] {
+        &self.filename[..std::cmp::min(
+            usize::from(self.meta.name_len),
+            crate::fs::HAMMER2_INODE_MAXNAME,
+        )]
     }
 }
 
@@ -181,6 +223,25 @@ impl crate::fs::Hammer2VolumeData {
         let end = (offset + size).try_into().or_nix_range()?;
         Ok(icrc32::iscsi_crc32(&voldata[beg..end]))
     }
+
+    // Set ICRC_SECT0 after all remaining elements of sect0 have been
+    // populated, as ICRC_SECT* (except for SECT0) are part of sect0.
+    /// # Errors
+    pub fn set_crc(&mut self) -> nix::Result<()> {
+        self.icrc_sects[crate::fs::HAMMER2_VOL_ICRC_SECT1] = self.get_crc(
+            crate::fs::HAMMER2_VOLUME_ICRC1_OFF,
+            crate::fs::HAMMER2_VOLUME_ICRC1_SIZE,
+        )?;
+        self.icrc_sects[crate::fs::HAMMER2_VOL_ICRC_SECT0] = self.get_crc(
+            crate::fs::HAMMER2_VOLUME_ICRC0_OFF,
+            crate::fs::HAMMER2_VOLUME_ICRC0_SIZE,
+        )?;
+        self.icrc_volheader = self.get_crc(
+            crate::fs::HAMMER2_VOLUME_ICRCVH_OFF,
+            crate::fs::HAMMER2_VOLUME_ICRCVH_SIZE,
+        )?;
+        Ok(())
+    }
 }
 
 fn copy_bytes(dst: &mut [u8], src: &[u8]) {
@@ -241,15 +302,15 @@ impl crate::ioctl::IocVolumeList2 {
     }
 }
 
-impl crate::hammer2::Hammer2 {
+impl crate::hammer2::Inner {
     #[must_use]
-    pub fn get_volumes(&self) -> Vec<&crate::volume::Volume> {
-        self.fso.get_volumes()
+    pub fn get_volume_data(&self) -> &crate::fs::Hammer2VolumeData {
+        &self.voldata
     }
 
     #[must_use]
-    pub fn get_volume_data(&self) -> &crate::fs::Hammer2VolumeData {
-        &self.voldata
+    pub fn get_volume_data_index(&self) -> usize {
+        self.volhdrno
     }
 
     #[must_use]
@@ -364,18 +425,6 @@ impl crate::hammer2::Hammer2 {
         }
     }
 
-    /// # Errors
-    pub fn preadx(&mut self, inum: u64, size: u64, offset: u64) -> crate::Result<Vec<u8>> {
-        let mut buf = vec![0; size.try_into().or_range()?];
-        let n = self.pread(inum, &mut buf, offset)?;
-        Ok(buf[..n.try_into().or_range()?].to_vec())
-    }
-
-    /// # Errors
-    pub fn read_all(&mut self, inum: u64) -> crate::Result<Vec<u8>> {
-        self.preadx(inum, self.stat(inum)?.st_size, 0)
-    }
-
     /// # Errors
     pub fn prune_chain(&mut self) -> crate::Result<(usize, usize)> {
         let t = (
@@ -397,7 +446,8 @@ impl crate::hammer2::Hammer2 {
             total += self.prune_chain_impl(x)?;
         }
         let chain = self.cmap.get_mut(&cid).ok_or(nix::errno::Errno::ENOENT)?;
-        if chain.bref.typ == crate::fs::HAMMER2_BREF_TYPE_DATA {
+        // dirty chains must stay until flushed
+        if chain.bref.typ == crate::fs::HAMMER2_BREF_TYPE_DATA && !chain.is_modified() {
             let pcid = chain.pcid;
             let cid = chain.cid;
             self.remove_chain(pcid, cid)?;
@@ -408,6 +458,59 @@ impl crate::hammer2::Hammer2 {
     }
 }
 
+impl crate::hammer2::Hammer2 {
+    #[must_use]
+    pub fn get_volumes(&self) -> Vec<&crate::volume::Volume> {
+        self.fso.get_volumes()
+    }
+
+    #[must_use]
+    pub fn get_volume_data(&mut self) -> &crate::fs::Hammer2VolumeData {
+        self.get_mut().get_volume_data()
+    }
+
+    #[must_use]
+    pub fn get_volume_data_index(&mut self) -> usize {
+        self.get_mut().get_volume_data_index()
+    }
+
+    #[must_use]
+    pub fn get_label(&mut self) -> &str {
+        self.get_mut().get_label()
+    }
+
+    #[must_use]
+    pub fn get_chain(&mut self, cid: crate::chain::Cid) -> Option<&crate::chain::Chain> {
+        self.get_mut().get_chain(cid)
+    }
+
+    #[must_use]
+    pub fn get_inode(&mut self, inum: u64) -> Option<&crate::inode::Inode> {
+        self.get_mut().get_inode(inum)
+    }
+
+    pub fn get_inode_mut(&mut self, inum: u64) -> Option<&mut crate::inode::Inode> {
+        self.get_mut().get_inode_mut(inum)
+    }
+
+    /// # Errors
+    pub fn preadx(&self, inum: u64, size: u64, offset: u64) -> crate::Result<Vec<u8>> {
+        let mut buf = vec![0; size.try_into().or_range()?];
+        let n = self.pread(inum, &mut buf, offset)?;
+        Ok(buf[..n.try_into().or_range()?].to_vec())
+    }
+
+    /// # Errors
+    pub fn read_all(&self, inum: u64) -> crate::Result<Vec<u8>> {
+        self.preadx(inum, self.stat(inum)?.st_size, 0)
+    }
+
+    /// # Errors
+    pub fn prune_chain(&mut self) -> crate::Result<(usize, usize)> {
+        self.get_mut().prune_chain()
+    }
+}
+
 #[cfg(test)]
 mod tests {
     macro_rules! eq {
@@ -620,6 +723,14 @@ mod tests {
                 },
                 s
             );
+            assert_eq!(ipdata.get_filename_string_escaped(), s);
         }
+
+        let mut ipdata = crate::fs::Hammer2InodeData::new();
+        let b = b"A\xff\xfeB\xc3\xa9";
+        ipdata.meta.name_len = 6;
+        ipdata.filename[..b.len()].copy_from_slice(b);
+        assert!(ipdata.get_filename_string().is_err());
+        assert_eq!(ipdata.get_filename_string_escaped(), "A\\xff\\xfeB\u{e9}");
     }
 }
@@ -0,0 +1,389 @@
+use crate::ErrorExt;
+use crate::OptionExt;
+
+use std::os::unix::fs::FileExt;
+use std::os::unix::fs::PermissionsExt;
+
+fn get_cstring(path: &std::path::Path) -> crate::Result<std::ffi::CString> {
+    match std::ffi::CString::new(path.as_os_str().as_encoded_bytes()) {
+        Ok(v) => Ok(v),
+        Err(e) => {
+            log::error!("{e}");
+            Err(nix::errno::Errno::EINVAL.into())
+        }
+    }
+}
+
+fn mknod(path: &std::path::Path, meta: &crate::fs::Hammer2InodeMeta) -> crate::Result<()> {
+    let (fmt, dev) = match meta.typ {
+        crate::fs::HAMMER2_OBJTYPE_FIFO => (libc::S_IFIFO, 0),
+        crate::fs::HAMMER2_OBJTYPE_CDEV => (libc::S_IFCHR, libc::makedev(meta.rmajor, meta.rminor)),
+        crate::fs::HAMMER2_OBJTYPE_BDEV => (libc::S_IFBLK, libc::makedev(meta.rmajor, meta.rminor)),
+        v => {
+            log::error!("bad inode type {v}");
+            return Err(nix::errno::Errno::EINVAL.into());
+        }
+    };
+    let s = get_cstring(path)?;
+    let mode = libc::mode_t::try_from(meta.mode & 0o7777).or_range()? | fmt;
+    if unsafe { libc::mknod(s.as_ptr(), mode, dev) } == -1 {
+        return Err(nix::errno::Errno::last().into());
+    }
+    Ok(())
+}
+
+// Apply owner, mode and times without following symlinks.
+fn set_metadata(path: &std::path::Path, meta: &crate::fs::Hammer2InodeMeta) -> crate::Result<()> {
+    let uid = crate::subs::conv_uuid_to_unix_xid_from_bytes(&meta.uid);
+    let gid = crate::subs::conv_uuid_to_unix_xid_from_bytes(&meta.gid);
+    if let Err(e) = std::os::unix::fs::lchown(path, Some(uid), Some(gid)) {
+        // Only root can give files away.
+        if e.kind() != std::io::ErrorKind::PermissionDenied {
+            return Err(e.into());
+        }
+        log::debug!("{}: {e}", path.display());
+    }
+    if meta.typ != crate::fs::HAMMER2_OBJTYPE_SOFTLINK {
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(meta.mode & 0o7777))?;
+    }
+    let s = get_cstring(path)?;
+    let tv = meta.get_utimes_timeval();
+    if unsafe { libc::lutimes(s.as_ptr(), tv.as_ptr()) } == -1 {
+        return Err(nix::errno::Errno::last().into());
+    }
+    Ok(())
+}
+
+impl crate::hammer2::Hammer2 {
+    // Write a regular file, skipping zero filled blocks so that holes
+    // stay sparse.
+    fn extract_regfile(&self, inum: u64, path: &std::path::Path) -> crate::Result<()> {
+        let size = self.read().get_inode(inum).or_range()?.meta.size;
+        let fp = std::fs::File::create(path)?;
+        let mut buf = vec![0; crate::fs::HAMMER2_PBUFSIZE.try_into().or_range()?];
+        let mut offset = 0;
+        while offset < size {
+            let n = self.pread(inum, &mut buf, offset)?;
+            if n == 0 {
+                break;
+            }
+            let b = &buf[..n.try_into().or_range()?];
+            if b.iter().any(|&x| x != 0) {
+                fp.write_all_at(b, offset)?;
+            }
+            offset += n;
+        }
+        fp.set_len(size)?;
+        Ok(())
+    }
+
+    fn extract_entry(
+        &self,
+        inum: u64,
+        path: &std::path::Path,
+        links: &mut std::collections::HashMap<u64, std::path::PathBuf>,
+    ) -> crate::Result<()> {
+        let meta = self.read().get_inode(inum).or_range()?.meta;
+        if meta.typ != crate::fs::HAMMER2_OBJTYPE_DIRECTORY && meta.nlinks > 1 {
+            if let Some(x) = links.get(&inum) {
+                std::fs::hard_link(x, path)?;
+                return Ok(());
+            }
+            links.insert(inum, path.to_path_buf());
+        }
+        match meta.typ {
+            crate::fs::HAMMER2_OBJTYPE_DIRECTORY => {
+                // Writable until the metadata is applied after its entries.
+                if let Err(e) = std::fs::create_dir(path)
+                    && e.kind() != std::io::ErrorKind::AlreadyExists
+                {
+                    return Err(e.into());
+                }
+                std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o700))?;
+                return Ok(());
+            }
+            crate::fs::HAMMER2_OBJTYPE_REGFILE => self.extract_regfile(inum, path)?,
+            crate::fs::HAMMER2_OBJTYPE_SOFTLINK => {
+                std::os::unix::fs::symlink(self.readlinkx(inum)?, path)?;
+            }
+            crate::fs::HAMMER2_OBJTYPE_FIFO
+            | crate::fs::HAMMER2_OBJTYPE_CDEV
+            | crate::fs::HAMMER2_OBJTYPE_BDEV => mknod(path, &meta)?,
+            v => {
+                log::warn!("{}: skip inode type {v}", path.display());
+                return Ok(());
+            }
+        }
+        set_metadata(path, &meta)
+    }
+
+    /// Extract a file or a directory tree at `src_path` under `dst_dir`.
+    /// Contents of a directory are extracted directly under `dst_dir`.
+    /// # Errors
+    pub fn extract(&self, src_path: &str, dst_dir: &str) -> crate::Result<()> {
+        let inum = self.nresolve_path(src_path)?;
+        let dst_dir = std::path::Path::new(dst_dir);
+        std::fs::create_dir_all(dst_dir)?;
+        let mut links = std::collections::HashMap::new();
+
+        if !self.read().get_inode(inum).or_range()?.is_directory() {
+            let Some(name) = libfs::fs::split_path(src_path).pop() else {
+                return Err(nix::errno::Errno::EINVAL.into());
+            };
+            return self.extract_entry(inum, &dst_dir.join(name), &mut links);
+        }
+        let mut dirs = vec![];
+        for x in self.walk(src_path)? {
+            let (path, dirent, _) = x?;
+            // Don't let a crafted name escape dst_dir.
+            crate::hammer2::check_name(&dirent.name)?;
+            let path = dst_dir.join(path[src_path.len()..].trim_start_matches('/'));
+            self.extract_entry(dirent.inum, &path, &mut links)?;
+            if dirent.typ == crate::fs::HAMMER2_OBJTYPE_DIRECTORY {
+                dirs.push((path, dirent.inum));
+            }
+        }
+        // Apply directory metadata bottom-up, as writing entries
+        // updates mtime of the parent.
+        for (path, inum) in dirs.iter().rev() {
+            let meta = self.read().get_inode(*inum).or_range()?.meta;
+            set_metadata(path, &meta)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::FileTypeExt;
+    use std::os::unix::fs::MetadataExt;
+
+    #[test]
+    fn test_extract() {
+        let f = crate::newfs::create_newfs_image("extract", 128 << 20);
+        let d = std::env::temp_dir().join(format!("libhammer2_extract_{}", std::process::id()));
+        let Some(d) = d.to_str() else {
+            panic!("{d:?}");
+        };
+        let _ = std::fs::remove_dir_all(d);
+
+        // /a (0750) with file, sparse, hardlinks f/g, symlink l, fifo p
+        let data: Vec<u8> = (0..100_000u32)
+            .map(|i| (i.wrapping_mul(2_654_435_761) >> 13) as u8)
+            .collect();
+        let hole = 4 * crate::fs::HAMMER2_PBUFSIZE;
+        let mut pmp = match crate::hammer2::Hammer2::mount(&f, &["--rw"]) {
+            Ok(v) => v,
+            Err(e) => panic!("{e}"),
+        };
+        let a = match pmp.mkdir(crate::inode::INUM_PFS_ROOT, "a", 0o750) {
+            Ok(v) => v,
+            Err(e) => panic!("{e}"),
+        };
+        let create = |pmp: &mut crate::hammer2::Hammer2, name, typ, mode, b: &[u8]| {
+            let pmp = pmp.get_mut();
+            let inum = match pmp.create_inode(a, name, crate::fs::HAMMER2_OBJTYPE_REGFILE, mode) {
+                Ok(v) => v,
+                Err(e) => panic!("{e}"),
+            };
+            if let Err(e) = pmp.pwrite(inum, b, 0) {
+                panic!("{e}");
+            }
+            if let Err(e) = pmp.modify_inode_meta(inum, |meta| {
+                meta.typ = typ;
+                meta.mtime = 1_234_567_890_123_456;
+            }) {
+                panic!("{e}");
+            }
+            inum
+        };
+        create(
+            &mut pmp,
+            "file",
+            crate::fs::HAMMER2_OBJTYPE_REGFILE,
+            0o640,
+            &data,
+        );
+        let inum = create(
+            &mut pmp,
+            "sparse",
+            crate::fs::HAMMER2_OBJTYPE_REGFILE,
+            0o600,
+            b"x",
+        );
+        if let Err(e) = pmp.pwrite(inum, b"y", hole) {
+            panic!("{e}");
+        }
+        let inum = create(
+            &mut pmp,
+            "f",
+            crate::fs::HAMMER2_OBJTYPE_REGFILE,
+            0o644,
+            b"f",
+        );
+        if let Err(e) =
+            pmp.get_mut()
+                .create_dirent(a, "g", inum, crate::fs::HAMMER2_OBJTYPE_REGFILE)
+        {
+            panic!("{e}");
+        }
+        if let Err(e) = pmp
+            .get_mut()
+            .modify_inode_meta(inum, |meta| meta.nlinks = 2)
+        {
+            panic!("{e}");
+        }
+        create(
+            &mut pmp,
+            "l",
+            crate::fs::HAMMER2_OBJTYPE_SOFTLINK,
+            0o777,
+            b"file",
+        );
+        create(&mut pmp, "p", crate::fs::HAMMER2_OBJTYPE_FIFO, 0o600, b"");
+        if let Err(e) = pmp.unmount() {
+            panic!("{e}");
+        }
+
+        let mut pmp = match crate::hammer2::Hammer2::mount(&f, &[]) {
+            Ok(v) => v,
+            Err(e) => panic!("{e}"),
+        };
+        if let Err(e) = pmp.extract("/", d) {
+            panic!("{e}");
+        }
+        let p = std::path::Path::new(d).join("a");
+        let st = match std::fs::symlink_metadata(&p) {
+            Ok(v) => v,
+            Err(e) => panic!("{e}"),
+        };
+        assert!(st.is_dir());
+        assert_eq!(st.mode() & 0o7777, 0o750);
+        // regular file
+        match std::fs::read(p.join("file")) {
+            Ok(v) => assert_eq!(v, data),
+            Err(e) => panic!("{e}"),
+        }
+        let st = match std::fs::symlink_metadata(p.join("file")) {
+            Ok(v) => v,
+            Err(e) => panic!("{e}"),
+        };
+        assert_eq!(st.mode() & 0o7777, 0o640);
+        assert_eq!(st.mtime(), 1_234_567_890);
+        assert_eq!(st.mtime_nsec(), 123_456_000);
+        assert_eq!(st.uid(), unsafe { libc::getuid() });
+        // sparse file
+        let st = match std::fs::symlink_metadata(p.join("sparse")) {
+            Ok(v) => v,
+            Err(e) => panic!("{e}"),
+        };
+        assert_eq!(st.len(), hole + 1);
+        assert!(st.blocks() * 512 < hole, "{}", st.blocks());
+        match std::fs::read(p.join("sparse")) {
+            Ok(v) => {
+                assert_eq!(v[0], b'x');
+                assert!(v[1..v.len() - 1].iter().all(|&x| x == 0));
+                assert_eq!(v[v.len() - 1], b'y');
+            }
+            Err(e) => panic!("{e}"),
+        }
+        // hardlink
+        match (
+            std::fs::symlink_metadata(p.join("f")),
+            std::fs::symlink_metadata(p.join("g")),
+        ) {
+            (Ok(x), Ok(y)) => {
+                assert_eq!(x.ino(), y.ino());
+                assert_eq!(x.nlink(), 2);
+            }
+            (x, y) => panic!("{x:?} {y:?}"),
+        }
+        // symlink
+        match std::fs::read_link(p.join("l")) {
+            Ok(v) => assert_eq!(v, std::path::Path::new("file")),
+            Err(e) => panic!("{e}"),
+        }
+        // fifo
+        match std::fs::symlink_metadata(p.join("p")) {
+            Ok(v) => {
+                assert!(v.file_type().is_fifo());
+                assert_eq!(v.mode() & 0o7777, 0o600);
+            }
+            Err(e) => panic!("{e}"),
+        }
+
+        // single file
+        let _ = std::fs::remove_dir_all(d);
+        if let Err(e) = pmp.extract("a/file", d) {
+            panic!("{e}");
+        }
+        match std::fs::read(std::path::Path::new(d).join("file")) {
+            Ok(v) => assert_eq!(v, data),
+            Err(e) => panic!("{e}"),
+        }
+        if let Err(e) = pmp.unmount() {
+            panic!("{e}");
+        }
+        let _ = std::fs::remove_dir_all(d);
+        let _ = std::fs::remove_file(f);
+    }
+
+    #[test]
+    fn test_extract_bad_name() {
+        let f = crate::newfs::create_newfs_image("extract_bad_name", 128 << 20);
+        let d = std::env::temp_dir().join(format!(
+            "libhammer2_extract_bad_name_{}",
+            std::process::id()
+        ));
+        let Some(d) = d.to_str() else {
+            panic!("{d:?}");
+        };
+        let _ = std::fs::remove_dir_all(d);
+
+        // /bN/x with a crafted dirent in each /bN pointing to x
+        let names = ["", ".", "..", "../x", "/x"];
+        let mut pmp = match crate::hammer2::Hammer2::mount(&f, &["--rw"]) {
+            Ok(v) => v,
+            Err(e) => panic!("{e}"),
+        };
+        for (i, name) in names.iter().enumerate() {
+            let b = match pmp.mkdir(crate::inode::INUM_PFS_ROOT, &format!("b{i}"), 0o755) {
+                Ok(v) => v,
+                Err(e) => panic!("{e}"),
+            };
+            let pmp = pmp.get_mut();
+            let inum = match pmp.create_inode(b, "x", crate::fs::HAMMER2_OBJTYPE_REGFILE, 0o644) {
+                Ok(v) => v,
+                Err(e) => panic!("{e}"),
+            };
+            if let Err(e) = pmp.create_dirent(b, name, inum, crate::fs::HAMMER2_OBJTYPE_REGFILE) {
+                panic!("{e}");
+            }
+        }
+        if let Err(e) = pmp.unmount() {
+            panic!("{e}");
+        }
+
+        let mut pmp = match crate::hammer2::Hammer2::mount(&f, &[]) {
+            Ok(v) => v,
+            Err(e) => panic!("{e}"),
+        };
+        let p = std::path::Path::new(d).join("y");
+        for (i, name) in names.iter().enumerate() {
+            let Some(s) = p.to_str() else {
+                panic!("{p:?}");
+            };
+            match pmp.extract(&format!("b{i}"), s) {
+                Err(crate::Error::Errno(nix::errno::Errno::EINVAL)) => (),
+                v => panic!("{name:?} {v:?}"),
+            }
+            // nothing outside dst_dir
+            assert!(!std::path::Path::new(d).join("x").exists(), "{name:?}");
+        }
+        if let Err(e) = pmp.unmount() {
+            panic!("{e}");
+        }
+        let _ = std::fs::remove_dir_all(d);
+        let _ = std::fs::remove_file(f);
+    }
+}
@@ -0,0 +1,247 @@
+use crate::ErrorExt;
+use crate::OptionExt;
+
+/// A regular file opened on a mounted PFS, implementing
+/// `std::io::Read`, `Seek` and `BufRead`.
+/// The current logical block of `HAMMER2_PBUFSIZE` is cached.
+/// The lock of `Hammer2` is only taken while loading a block.
+pub struct File<'a> {
+    pmp: &'a crate::hammer2::Hammer2,
+    inum: u64,
+    size: u64,
+    offset: u64,
+    lbase: Option<u64>,
+    buf: Vec<u8>,
+}
+
+impl std::fmt::Debug for File<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("File")
+            .field("inum", &self.inum)
+            .field("size", &self.size)
+            .field("offset", &self.offset)
+            .field("lbase", &self.lbase)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<'a> File<'a> {
+    fn new(pmp: &'a crate::hammer2::Hammer2, inum: u64) -> crate::Result<Self> {
+        let meta = pmp.read().get_inode(inum).or_range()?.meta;
+        if meta.typ == crate::fs::HAMMER2_OBJTYPE_DIRECTORY {
+            return Err(nix::errno::Errno::EISDIR.into());
+        }
+        if meta.typ != crate::fs::HAMMER2_OBJTYPE_REGFILE {
+            return Err(nix::errno::Errno::EINVAL.into());
+        }
+        let size = meta.size;
+        Ok(Self {
+            pmp,
+            inum,
+            size,
+            offset: 0,
+            lbase: None,
+            buf: vec![],
+        })
+    }
+
+    #[must_use]
+    pub fn get_inum(&self) -> u64 {
+        self.inum
+    }
+
+    #[must_use]
+    pub fn get_size(&self) -> u64 {
+        self.size
+    }
+
+    // Load the logical block containing the current offset,
+    // truncated to the file size.
+    fn load_lblock(&mut self) -> crate::Result<()> {
+        let lbase = self.offset & !crate::fs::HAMMER2_PBUFMASK;
+        if self.lbase == Some(lbase) {
+            return Ok(());
+        }
+        self.lbase = None;
+        let mut buf = self.pmp.read_lblock(self.inum, lbase)?;
+        let n = std::cmp::min(self.size - lbase, buf.len().try_into().or_range()?);
+        buf.truncate(n.try_into().or_range()?);
+        self.buf = buf;
+        self.lbase = Some(lbase);
+        Ok(())
+    }
+}
+
+impl std::io::Read for File<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let b = std::io::BufRead::fill_buf(self)?;
+        let n = std::cmp::min(b.len(), buf.len());
+        buf[..n].copy_from_slice(&b[..n]);
+        std::io::BufRead::consume(self, n);
+        Ok(n)
+    }
+}
+
+impl std::io::BufRead for File<'_> {
+    fn fill_buf(&mut self) -> std::io::Result<&[u8]> {
+        if self.offset >= self.size {
+            return Ok(&[]);
+        }
+        self.load_lblock()?;
+        let i = (self.offset - self.lbase.or_range()?)
+            .try_into()
+            .or_range()?;
+        Ok(self.buf.get(i..).unwrap_or_default())
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.offset += amt as u64;
+    }
+}
+
+impl std::io::Seek for File<'_> {
+    fn seek(&mut self, pos: std::io::SeekFrom) -> std::io::Result<u64> {
+        let offset = match pos {
+            std::io::SeekFrom::Start(v) => Some(v),
+            std::io::SeekFrom::End(v) => self.size.checked_add_signed(v),
+            std::io::SeekFrom::Current(v) => self.offset.checked_add_signed(v),
+        };
+        let Some(offset) = offset else {
+            return Err(std::io::Error::from_raw_os_error(libc::EINVAL));
+        };
+        self.offset = offset;
+        Ok(offset)
+    }
+}
+
+impl crate::hammer2::Hammer2 {
+    /// # Errors
+    pub fn open(&self, path: &str) -> crate::Result<File<'_>> {
+        let inum = self.nresolve_path(path)?;
+        File::new(self, inum)
+    }
+
+    /// # Errors
+    pub fn open_inum(&self, inum: u64) -> crate::Result<File<'_>> {
+        File::new(self, inum)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::BufRead;
+    use std::io::Read;
+    use std::io::Seek;
+
+    #[test]
+    fn test_file() {
+        let f = crate::newfs::create_newfs_image("file", 128 << 20);
+        let data: Vec<u8> = (0..300_000u32)
+            .map(|i| (i.wrapping_mul(2_654_435_761) >> 13) as u8)
+            .collect();
+        let text = b"line1\nline2\n\nline4";
+        let mut pmp = match crate::hammer2::Hammer2::mount(&f, &["--rw"]) {
+            Ok(v) => v,
+            Err(e) => panic!("{e}"),
+        };
+        let dinum = match pmp.mkdir(crate::inode::INUM_PFS_ROOT, "d", 0o755) {
+            Ok(v) => v,
+            Err(e) => panic!("{e}"),
+        };
+        for (dinum, name, b) in [
+            (dinum, "large", data.as_slice()),
+            (crate::inode::INUM_PFS_ROOT, "text", text.as_slice()),
+            (crate::inode::INUM_PFS_ROOT, "empty", &[]),
+        ] {
+            let inum = match pmp.create(dinum, name, 0o644) {
+                Ok(v) => v,
+                Err(e) => panic!("{e}"),
+            };
+            if let Err(e) = pmp.pwrite(inum, b, 0) {
+                panic!("{e}");
+            }
+        }
+        if let Err(e) = pmp.unmount() {
+            panic!("{e}");
+        }
+
+        let mut pmp = match crate::hammer2::Hammer2::mount(&f, &[]) {
+            Ok(v) => v,
+            Err(e) => panic!("{e}"),
+        };
+        // read
+        let mut fp = match pmp.open("/d/large") {
+            Ok(v) => v,
+            Err(e) => panic!("{e}"),
+        };
+        assert_eq!(fp.get_size(), data.len() as u64);
+        let mut v = vec![];
+        match fp.read_to_end(&mut v) {
+            Ok(n) => assert_eq!(n, data.len()),
+            Err(e) => panic!("{e}"),
+        }
+        assert_eq!(v, data);
+        match fp.read(&mut [0; 16]) {
+            Ok(n) => assert_eq!(n, 0),
+            Err(e) => panic!("{e}"),
+        }
+        // seek
+        for (pos, off) in [
+            (std::io::SeekFrom::Start(0x10000 - 3), 0x10000 - 3),
+            (std::io::SeekFrom::Current(-0x8000), 0x8000 + 2),
+            (std::io::SeekFrom::End(-5), data.len() - 5),
+        ] {
+            match fp.seek(pos) {
+                Ok(v) => assert_eq!(v, off as u64),
+                Err(e) => panic!("{e}"),
+            }
+            let mut b = [0; 5];
+            if let Err(e) = fp.read_exact(&mut b) {
+                panic!("{e}");
+            }
+            assert_eq!(b, data[off..off + 5]);
+        }
+        assert!(fp.seek(std::io::SeekFrom::Current(-1_000_000)).is_err());
+        match fp.seek(std::io::SeekFrom::End(100)) {
+            Ok(v) => assert_eq!(v, data.len() as u64 + 100),
+            Err(e) => panic!("{e}"),
+        }
+        match fp.read(&mut [0; 16]) {
+            Ok(n) => assert_eq!(n, 0),
+            Err(e) => panic!("{e}"),
+        }
+        // bufread
+        let fp = match pmp.open("text") {
+            Ok(v) => v,
+            Err(e) => panic!("{e}"),
+        };
+        let v: Vec<String> = match fp.lines().collect() {
+            Ok(v) => v,
+            Err(e) => panic!("{e}"),
+        };
+        assert_eq!(v, ["line1", "line2", "", "line4"]);
+        let mut fp = match pmp.open("empty") {
+            Ok(v) => v,
+            Err(e) => panic!("{e}"),
+        };
+        match fp.fill_buf() {
+            Ok(v) => assert!(v.is_empty()),
+            Err(e) => panic!("{e}"),
+        }
+        // error
+        match pmp.open("d") {
+            Err(crate::Error::Errno(nix::errno::Errno::EISDIR)) => (),
+            Ok(v) => panic!("{v:?}"),
+            Err(e) => panic!("{e}"),
+        }
+        match pmp.open("missing") {
+            Err(crate::Error::Errno(nix::errno::Errno::ENOENT)) => (),
+            Ok(v) => panic!("{v:?}"),
+            Err(e) => panic!("{e}"),
+        }
+        if let Err(e) = pmp.unmount() {
+            panic!("{e}");
+        }
+        let _ = std::fs::remove_file(f);
+    }
+}
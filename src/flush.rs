@@ -0,0 +1,209 @@
+use crate::OptionExt;
+
+// Returns (data_count, inode_count) of the block table.
+fn get_embed_stats(base: &[crate::fs::Hammer2Blockref]) -> nix::Result<(u64, u64)> {
+    let mut data_count = 0;
+    let mut inode_count = 0;
+    for bref in base {
+        match bref.typ {
+            crate::fs::HAMMER2_BREF_TYPE_INODE | crate::fs::HAMMER2_BREF_TYPE_INDIRECT => {
+                let stats = bref.embed_as::<crate::fs::Hammer2BlockrefEmbedStats>();
+                data_count += stats.data_count;
+                inode_count += stats.inode_count;
+                if bref.typ == crate::fs::HAMMER2_BREF_TYPE_INODE {
+                    inode_count += 1;
+                }
+            }
+            crate::fs::HAMMER2_BREF_TYPE_DATA => {
+                let radix = bref.get_radix()?;
+                if radix != 0 {
+                    data_count += 1 << radix;
+                }
+            }
+            _ => (),
+        }
+    }
+    Ok((data_count, inode_count))
+}
+
+impl crate::hammer2::Inner {
+    pub(crate) fn is_dirty(&self) -> bool {
+        [crate::chain::CID_VCHAIN, crate::chain::CID_FCHAIN]
+            .iter()
+            .any(|x| {
+                self.cmap
+                    .get(x)
+                    .is_some_and(crate::chain::Chain::is_modified)
+            })
+    }
+
+    /// # Errors
+    pub fn flush(&mut self) -> crate::Result<()> {
+        if !self.opt.rw {
+            return Err(nix::errno::Errno::EROFS.into());
+        }
+        if !self.is_dirty() {
+            return Ok(());
+        }
+        let tid = self.voldata.mirror_tid + 1;
+        log::debug!("flush tid {tid:016x}");
+        // Flushing the volume topology allocates blocks and modifies
+        // the freemap, so the freemap topology is flushed last.
+        self.flush_chain(crate::chain::CID_VCHAIN, tid)?;
+        self.flush_chain(crate::chain::CID_FCHAIN, tid)?;
+        self.write_volume_data(tid)
+    }
+
+    // Flush, then write the volume header to every other slot too,
+    // so that no older header references blocks about to be freed.
+    pub(crate) fn flush_all_volume_data(&mut self) -> crate::Result<()> {
+        self.flush()?;
+        let n = crate::volume::get_volume_data_count(
+            self.fso
+                .get_root_volume()
+                .ok_or(nix::errno::Errno::ENODEV)?
+                .get_size(),
+        );
+        for _ in 1..n {
+            self.write_volume_data(self.voldata.mirror_tid + 1)?;
+        }
+        Ok(())
+    }
+
+    // Write modified chains in post-order, so that parents see new
+    // blockrefs of their children before they are written.
+    fn flush_chain(&mut self, cid: crate::chain::Cid, tid: u64) -> crate::Result<()> {
+        let chain = self.cmap.get(&cid).or_range()?;
+        if !chain.is_modified() {
+            return Ok(());
+        }
+        for ccid in chain.get_child() {
+            self.flush_chain(ccid, tid)?;
+        }
+        let chain = self.cmap.get_mut(&cid).or_range()?;
+        if cid == crate::chain::CID_VCHAIN || cid == crate::chain::CID_FCHAIN {
+            chain.bref.mirror_tid = tid;
+            chain.bref.modify_tid = tid;
+            chain.clear_flags(crate::chain::CHAIN_MODIFIED);
+            return Ok(());
+        }
+        let mut bref = chain.bref;
+        if bref.typ == crate::fs::HAMMER2_BREF_TYPE_INODE
+            || bref.typ == crate::fs::HAMMER2_BREF_TYPE_INDIRECT
+        {
+            let (data_count, inode_count) = get_embed_stats(&chain.get_blockref_array()?)?;
+            let stats = bref.embed_as_mut::<crate::fs::Hammer2BlockrefEmbedStats>();
+            stats.data_count = data_count;
+            stats.inode_count = inode_count;
+        }
+        // Copy-on-write, old blocks are freed by bulkfree.
+        let radix = bref.get_radix()?;
+        if radix != 0 {
+            bref.data_off = match bref.typ {
+                crate::fs::HAMMER2_BREF_TYPE_FREEMAP_NODE
+                | crate::fs::HAMMER2_BREF_TYPE_FREEMAP_LEAF => {
+                    crate::freemap::get_freemap_offset(&bref)? | u64::from(radix)
+                }
+                _ => self.alloc_block(bref.typ, radix)?,
+            };
+        }
+        bref.mirror_tid = tid;
+        bref.modify_tid = tid;
+        bref.update_tid = tid;
+        let chain = self.cmap.get_mut(&cid).or_range()?;
+        if radix != 0 {
+            crate::ondisk::set_media_check(&mut bref, chain.get_data())?;
+            self.fso.write_media(&bref, chain.get_data())?;
+        }
+        chain.bref = bref;
+        chain.clear_flags(crate::chain::CHAIN_MODIFIED);
+        chain.set_flags(crate::chain::CHAIN_TESTED_GOOD);
+        // Update the parent's block table.
+        let pcid = chain.pcid;
+        let pchain = self.cmap.get_mut(&pcid).or_range()?;
+        assert!(pchain.is_modified());
+        let mut base = pchain.get_blockref_array()?;
+        let Some(x) = base
+            .iter_mut()
+            .find(|x| x.key == bref.key && x.keybits == bref.keybits)
+        else {
+            log::error!("{bref} not found in {}", pchain.bref);
+            return Err(nix::errno::Errno::ENOENT.into());
+        };
+        *x = bref;
+        pchain.set_blockref_array(&base)?;
+        Ok(())
+    }
+
+    // Write the next volume header in rotation.
+    fn write_volume_data(&mut self, tid: u64) -> crate::Result<()> {
+        let mut voldata = *self.voldata;
+        voldata.sroot_blockset = self
+            .cmap
+            .get(&crate::chain::CID_VCHAIN)
+            .or_range()?
+            .as_volume_data()
+            .sroot_blockset;
+        voldata.freemap_blockset = self
+            .cmap
+            .get(&crate::chain::CID_FCHAIN)
+            .or_range()?
+            .as_volume_data()
+            .freemap_blockset;
+        voldata.mirror_tid = tid;
+        voldata.freemap_tid = tid;
+        voldata.set_crc()?;
+        // Data must be on media before the volume header.
+        for vol in self.fso.get_volumes() {
+            vol.fsync()?;
+        }
+        let vol = self
+            .fso
+            .get_root_volume()
+            .ok_or(nix::errno::Errno::ENODEV)?;
+        let n = crate::volume::get_volume_data_count(vol.get_size());
+        let i = (self.volhdrno + 1) % n;
+        vol.pwrite(
+            libfs::cast::as_u8_slice(&voldata),
+            crate::volume::get_volume_data_offset(i)?,
+        )?;
+        vol.fsync()?;
+        log::debug!("volume header #{i} mirror_tid {tid:016x}");
+        self.volhdrno = i;
+        *self.voldata = voldata;
+        Ok(())
+    }
+}
+
+impl crate::hammer2::Hammer2 {
+    /// # Errors
+    pub fn flush(&mut self) -> crate::Result<()> {
+        self.get_mut().flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_get_embed_stats() {
+        assert_eq!(super::get_embed_stats(&[]), Ok((0, 0)));
+
+        let mut inode = crate::fs::Hammer2Blockref::new(crate::fs::HAMMER2_BREF_TYPE_INODE);
+        inode.data_off = 10;
+        let stats = inode.embed_as_mut::<crate::fs::Hammer2BlockrefEmbedStats>();
+        stats.data_count = 0x10000;
+        stats.inode_count = 2;
+        let mut indirect = crate::fs::Hammer2Blockref::new(crate::fs::HAMMER2_BREF_TYPE_INDIRECT);
+        indirect.data_off = 14;
+        let stats = indirect.embed_as_mut::<crate::fs::Hammer2BlockrefEmbedStats>();
+        stats.data_count = 0x20000;
+        stats.inode_count = 3;
+        let mut data = crate::fs::Hammer2Blockref::new(crate::fs::HAMMER2_BREF_TYPE_DATA);
+        data.data_off = 0x1234_0000 | 16;
+        let dirent = crate::fs::Hammer2Blockref::new(crate::fs::HAMMER2_BREF_TYPE_DIRENT);
+        assert_eq!(
+            super::get_embed_stats(&[inode, indirect, data, dirent]),
+            Ok((0x40000, 6))
+        );
+    }
+}
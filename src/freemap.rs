@@ -0,0 +1,679 @@
+use crate::ErrorExt;
+use crate::OptionExt;
+
+const BMAP_DATA_BYTES: usize = std::mem::size_of::<crate::fs::Hammer2BmapData>();
+
+const NFREEMAPS: usize = (crate::fs::HAMMER2_ZONE_FREEMAP_END - crate::fs::HAMMER2_ZONE_FREEMAP_00)
+    / crate::fs::HAMMER2_ZONE_FREEMAP_INC;
+
+// Returns 2 bits of the 16KB block within 4MB.
+// 00 free, 01 (unused), 10 possibly free, 11 allocated.
+pub(crate) fn get_bmap_bits(bmap: &crate::fs::Hammer2BmapData, blk: usize) -> u64 {
+    let i = blk / crate::fs::HAMMER2_BMAP_BLOCKS_PER_ELEMENT;
+    let shift = (blk % crate::fs::HAMMER2_BMAP_BLOCKS_PER_ELEMENT) * 2;
+    (bmap.bitmapq[i] >> shift) & 3
+}
+
+pub(crate) fn set_bmap_bits(bmap: &mut crate::fs::Hammer2BmapData, blk: usize, bits: u64) {
+    let i = blk / crate::fs::HAMMER2_BMAP_BLOCKS_PER_ELEMENT;
+    let shift = (blk % crate::fs::HAMMER2_BMAP_BLOCKS_PER_ELEMENT) * 2;
+    bmap.bitmapq[i] = (bmap.bitmapq[i] & !(3 << shift)) | ((bits & 3) << shift);
+}
+
+// Allocate 2^radix bytes within 4MB and returns offset relative to
+// the beginning of 4MB.  Allocations smaller than 16KB are linearly
+// packed into a 16KB block of the same class.
+pub(crate) fn alloc_bmap(
+    bmap: &mut crate::fs::Hammer2BmapData,
+    class: u16,
+    radix: u8,
+    any_class: bool,
+) -> Option<u64> {
+    if bmap.class != 0 && bmap.class != class && !any_class {
+        return None;
+    }
+    let size = 1u64 << radix;
+    let linear = u64::from(bmap.linear);
+    if size < crate::fs::HAMMER2_FREEMAP_BLOCK_SIZE
+        && bmap.class == class
+        && (linear & crate::fs::HAMMER2_FREEMAP_BLOCK_MASK) != 0
+    {
+        let offset = (linear + size - 1) & !(size - 1);
+        if (offset & crate::fs::HAMMER2_FREEMAP_BLOCK_MASK) != 0
+            && (offset & crate::fs::HAMMER2_FREEMAP_BLOCK_MASK) + size
+                <= crate::fs::HAMMER2_FREEMAP_BLOCK_SIZE
+        {
+            bmap.linear = u32::try_from(offset + size).ok()?;
+            return Some(offset);
+        }
+    }
+    let n = usize::try_from(size / crate::fs::HAMMER2_FREEMAP_BLOCK_SIZE)
+        .ok()?
+        .max(1);
+    let avail =
+        u32::try_from(crate::fs::HAMMER2_FREEMAP_BLOCK_SIZE).ok()? * u32::try_from(n).ok()?;
+    if bmap.avail < avail {
+        return None;
+    }
+    for blk in (0..crate::fs::HAMMER2_BMAP_BLOCKS).step_by(n) {
+        if (blk..blk + n).all(|x| get_bmap_bits(bmap, x) == 0) {
+            for x in blk..blk + n {
+                set_bmap_bits(bmap, x, 3);
+            }
+            let offset = u64::try_from(blk).ok()? * crate::fs::HAMMER2_FREEMAP_BLOCK_SIZE;
+            bmap.avail -= avail;
+            bmap.linear = u32::try_from(offset + size).ok()?;
+            if bmap.class == 0 {
+                bmap.class = class;
+            }
+            return Some(offset);
+        }
+    }
+    None
+}
+
+// Initialize a 1GB freemap leaf and returns available bytes.
+// Segments below allocator_beg, the reserved first 4MB of each 1GB,
+// and segments beyond the end of the filesystem are marked allocated.
+// DragonFly reserves the same per 1GB, not per HAMMER2_ZONE_BYTES.
+pub(crate) fn init_leaf(
+    data: &mut [u8],
+    key: u64,
+    allocator_beg: u64,
+    total_size: u64,
+) -> crate::Result<u64> {
+    let lokey = ((allocator_beg + crate::fs::HAMMER2_SEGMASK) & !crate::fs::HAMMER2_SEGMASK)
+        .max(key + crate::fs::HAMMER2_ZONE_SEG);
+    let hikey = (key + crate::fs::HAMMER2_FREEMAP_LEVEL1_SIZE)
+        .min(total_size & !crate::fs::HAMMER2_SEGMASK);
+    let mut avail = 0;
+    for i in 0..crate::fs::HAMMER2_FREEMAP_COUNT {
+        let bmap = libfs::cast::align_to_mut::<crate::fs::Hammer2BmapData>(
+            &mut data[i * BMAP_DATA_BYTES..(i + 1) * BMAP_DATA_BYTES],
+        );
+        let k = key + u64::try_from(i).or_range()? * crate::fs::HAMMER2_FREEMAP_LEVEL0_SIZE;
+        if k < lokey || k >= hikey {
+            bmap.bitmapq.fill(u64::MAX);
+            bmap.avail = 0;
+            bmap.linear = crate::fs::HAMMER2_SEGSIZE.try_into().or_range()?;
+        } else {
+            bmap.avail = crate::fs::HAMMER2_FREEMAP_LEVEL0_SIZE
+                .try_into()
+                .or_range()?;
+            avail += crate::fs::HAMMER2_FREEMAP_LEVEL0_SIZE;
+        }
+    }
+    Ok(avail)
+}
+
+// Freemap blocks are not allocated by the allocator, but rotate within
+// the reserved area of the level they belong to, so that a crash never
+// overwrites the freemap referenced by older volume headers.
+pub(crate) fn get_freemap_offset(bref: &crate::fs::Hammer2Blockref) -> nix::Result<u64> {
+    let level = match usize::from(bref.keybits) {
+        crate::fs::HAMMER2_FREEMAP_LEVEL1_RADIX => crate::fs::HAMMER2_ZONEFM_LEVEL1,
+        crate::fs::HAMMER2_FREEMAP_LEVEL2_RADIX => crate::fs::HAMMER2_ZONEFM_LEVEL2,
+        crate::fs::HAMMER2_FREEMAP_LEVEL3_RADIX => crate::fs::HAMMER2_ZONEFM_LEVEL3,
+        crate::fs::HAMMER2_FREEMAP_LEVEL4_RADIX => crate::fs::HAMMER2_ZONEFM_LEVEL4,
+        crate::fs::HAMMER2_FREEMAP_LEVEL5_RADIX => crate::fs::HAMMER2_ZONEFM_LEVEL5,
+        _ => {
+            log::error!("bad keybits {}", bref.keybits);
+            return Err(nix::errno::Errno::EINVAL);
+        }
+    };
+    let base = bref.key & !crate::extra::conv_keybits_to_mask(bref.keybits);
+    let zone = crate::fs::HAMMER2_ZONE_FREEMAP_00 + level;
+    let old = bref.get_raw_data_off();
+    let index = if old < base + u64::try_from(zone).or_nix_range()? * crate::fs::HAMMER2_PBUFSIZE {
+        0
+    } else {
+        let x = usize::try_from((old - base) / crate::fs::HAMMER2_PBUFSIZE).or_nix_range()?;
+        ((x - zone) / crate::fs::HAMMER2_ZONE_FREEMAP_INC + 1) % NFREEMAPS
+    };
+    Ok(base
+        + u64::try_from(index * crate::fs::HAMMER2_ZONE_FREEMAP_INC + zone).or_nix_range()?
+            * crate::fs::HAMMER2_PBUFSIZE)
+}
+
+// 2 bits state of a 16KB block.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BlockState {
+    Free,
+    Unused,
+    PossiblyFree,
+    Allocated,
+}
+
+impl BlockState {
+    fn from_bits(bits: u64) -> Self {
+        match bits & 3 {
+            0 => Self::Free,
+            1 => Self::Unused,
+            2 => Self::PossiblyFree,
+            _ => Self::Allocated,
+        }
+    }
+}
+
+// Allocation state of a 4MB segment.
+#[derive(Clone, Debug)]
+pub struct Segment {
+    pub offset: u64,
+    pub class: u16,
+    pub linear: u32,
+    pub avail: u32,
+    pub bitmapq: [u64; crate::fs::HAMMER2_BMAP_ELEMENTS],
+}
+
+impl Segment {
+    fn new(offset: u64, bmap: &crate::fs::Hammer2BmapData) -> Self {
+        Self {
+            offset,
+            class: bmap.class,
+            linear: bmap.linear,
+            avail: bmap.avail,
+            bitmapq: bmap.bitmapq,
+        }
+    }
+
+    #[must_use]
+    pub fn get_block_state(&self, blk: usize) -> BlockState {
+        let i = blk / crate::fs::HAMMER2_BMAP_BLOCKS_PER_ELEMENT;
+        let shift = (blk % crate::fs::HAMMER2_BMAP_BLOCKS_PER_ELEMENT) * 2;
+        BlockState::from_bits(self.bitmapq[i] >> shift)
+    }
+
+    #[must_use]
+    pub fn count_blocks(&self, state: BlockState) -> usize {
+        (0..crate::fs::HAMMER2_BMAP_BLOCKS)
+            .filter(|&x| self.get_block_state(x) == state)
+            .count()
+    }
+
+    #[must_use]
+    pub fn get_free_size(&self) -> u64 {
+        u64::try_from(self.count_blocks(BlockState::Free)).unwrap_or(0)
+            * crate::fs::HAMMER2_FREEMAP_BLOCK_SIZE
+    }
+
+    #[must_use]
+    pub fn is_free(&self) -> bool {
+        self.count_blocks(BlockState::Free) == crate::fs::HAMMER2_BMAP_BLOCKS
+    }
+
+    #[must_use]
+    pub fn is_full(&self) -> bool {
+        self.count_blocks(BlockState::Free) == 0
+    }
+
+    // avail must match the number of free blocks.
+    #[must_use]
+    pub fn is_consistent(&self) -> bool {
+        u64::from(self.avail) == self.get_free_size()
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Summary {
+    pub total_size: u64,
+    pub allocator_free: u64, // volume header
+    pub free_size: u64,      // free blocks in bitmap
+    pub avail_size: u64,     // sum of avail
+    pub possibly_free_size: u64,
+    pub fragmented_size: u64, // free blocks in partially used segments
+    pub nsegments: usize,
+    pub nfree_segments: usize,
+    pub nfull_segments: usize,
+    pub npartial_segments: usize,
+    pub ninconsistent_segments: usize,
+}
+
+impl Summary {
+    // allocator_free is a hint maintained by the allocator,
+    // and may be off by up to a segment after newfs.
+    #[must_use]
+    pub fn get_allocator_free_delta(&self) -> i128 {
+        i128::from(self.allocator_free) - i128::from(self.free_size)
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Freemap {
+    pub total_size: u64,
+    pub allocator_free: u64,
+    pub segments: Vec<Segment>,
+}
+
+impl Freemap {
+    #[must_use]
+    pub fn get_segment(&self, offset: u64) -> Option<&Segment> {
+        self.segments
+            .get(usize::try_from(offset / crate::fs::HAMMER2_FREEMAP_LEVEL0_SIZE).ok()?)
+    }
+
+    #[must_use]
+    pub fn summary(&self) -> Summary {
+        let mut s = Summary {
+            total_size: self.total_size,
+            allocator_free: self.allocator_free,
+            nsegments: self.segments.len(),
+            ..Default::default()
+        };
+        for x in &self.segments {
+            let free_size = x.get_free_size();
+            s.free_size += free_size;
+            s.avail_size += u64::from(x.avail);
+            s.possibly_free_size += u64::try_from(x.count_blocks(BlockState::PossiblyFree))
+                .unwrap_or(0)
+                * crate::fs::HAMMER2_FREEMAP_BLOCK_SIZE;
+            if x.is_free() {
+                s.nfree_segments += 1;
+            } else if x.is_full() {
+                s.nfull_segments += 1;
+            } else {
+                s.npartial_segments += 1;
+                s.fragmented_size += free_size;
+            }
+            if !x.is_consistent() {
+                s.ninconsistent_segments += 1;
+            }
+        }
+        s
+    }
+}
+
+impl crate::hammer2::Inner {
+    fn find_freemap_leaf(&mut self, key: u64) -> crate::Result<Option<crate::chain::Cid>> {
+        let (_, cid, _) = self.lookup_chain(
+            crate::chain::CID_FCHAIN,
+            key,
+            key + crate::fs::HAMMER2_FREEMAP_LEVEL1_MASK,
+            0,
+        )?;
+        if cid == crate::chain::CID_NONE {
+            return Ok(None);
+        }
+        let bref = &self.cmap.get(&cid).or_range()?.bref;
+        if bref.typ != crate::fs::HAMMER2_BREF_TYPE_FREEMAP_LEAF || bref.key != key {
+            log::error!("bad freemap leaf {bref}");
+            return Err(nix::errno::Errno::EINVAL.into());
+        }
+        Ok(Some(cid))
+    }
+
+    pub(crate) fn get_freemap_leaf(&mut self, key: u64) -> crate::Result<crate::chain::Cid> {
+        if let Some(cid) = self.find_freemap_leaf(key)? {
+            return Ok(cid);
+        }
+        // Lazily create a leaf for this 1GB.
+        let mut bref = crate::fs::Hammer2Blockref::new(crate::fs::HAMMER2_BREF_TYPE_FREEMAP_LEAF);
+        bref.methods = crate::fs::enc_check(crate::fs::HAMMER2_CHECK_FREEMAP)
+            | crate::fs::enc_comp(crate::fs::HAMMER2_COMP_NONE);
+        bref.keybits = crate::fs::HAMMER2_FREEMAP_LEVEL1_RADIX
+            .try_into()
+            .or_range()?;
+        bref.key = key;
+        bref.data_off = crate::fs::HAMMER2_FREEMAP_LEVELN_PSIZE
+            .trailing_zeros()
+            .into();
+        let mut data = vec![
+            0;
+            crate::fs::HAMMER2_FREEMAP_LEVELN_PSIZE
+                .try_into()
+                .or_range()?
+        ];
+        let check = bref.check_as_mut::<crate::fs::Hammer2BlockrefCheckFreemap>();
+        check.avail = init_leaf(
+            &mut data,
+            key,
+            self.voldata.allocator_beg,
+            self.fso.get_total_size(),
+        )?;
+        check.bigmask = u32::MAX;
+        log::debug!("create freemap leaf {bref}");
+        self.create_chain(crate::chain::CID_FCHAIN, &bref, data)
+    }
+
+    /// # Errors
+    pub fn read_freemap(&mut self) -> crate::Result<Freemap> {
+        let total_size = self.fso.get_total_size();
+        let hikey = total_size & !crate::fs::HAMMER2_SEGMASK;
+        let mut segments = vec![];
+        let mut key = 0;
+        while key < hikey {
+            // Leaves are lazily created, so a missing leaf is in its initial state.
+            let data = if let Some(cid) = self.find_freemap_leaf(key)? {
+                self.load_chain(cid, crate::hammer2::RESOLVE_ALWAYS)?;
+                self.cmap.get(&cid).or_range()?.get_data().to_vec()
+            } else {
+                let mut data = vec![
+                    0;
+                    crate::fs::HAMMER2_FREEMAP_LEVELN_PSIZE
+                        .try_into()
+                        .or_range()?
+                ];
+                init_leaf(&mut data, key, self.voldata.allocator_beg, total_size)?;
+                data
+            };
+            for (i, bmap) in crate::fs::media_as::<crate::fs::Hammer2BmapData>(&data)?
+                .iter()
+                .enumerate()
+            {
+                let offset =
+                    key + u64::try_from(i).or_range()? * crate::fs::HAMMER2_FREEMAP_LEVEL0_SIZE;
+                if offset >= hikey {
+                    break;
+                }
+                segments.push(Segment::new(offset, bmap));
+            }
+            key += crate::fs::HAMMER2_FREEMAP_LEVEL1_SIZE;
+        }
+        Ok(Freemap {
+            total_size,
+            allocator_free: self.voldata.allocator_free,
+            segments,
+        })
+    }
+
+    // Allocate 2^radix bytes of media and returns data_off with radix.
+    pub(crate) fn alloc_block(&mut self, typ: u8, radix: u8) -> crate::Result<u64> {
+        if usize::from(radix) < crate::fs::HAMMER2_RADIX_MIN
+            || usize::from(radix) > crate::fs::HAMMER2_PBUFRADIX
+        {
+            log::error!("bad radix {radix}");
+            return Err(nix::errno::Errno::EINVAL.into());
+        }
+        let size = 1u64 << radix;
+        let class = (u16::from(typ) << 8) | u16::from(radix);
+        let total_size = self.fso.get_total_size();
+        let nleaf = total_size.div_ceil(crate::fs::HAMMER2_FREEMAP_LEVEL1_SIZE);
+        let hint = if self.alloc_hint < self.voldata.allocator_beg || self.alloc_hint >= total_size
+        {
+            self.voldata.allocator_beg
+        } else {
+            self.alloc_hint
+        };
+        // Prefer 4MB segments of the same class, then any class.
+        for any_class in [false, true] {
+            // Revisit the first leaf at the end to scan below the hint.
+            for i in 0..=nleaf {
+                let key = ((hint & !crate::fs::HAMMER2_FREEMAP_LEVEL1_MASK)
+                    + i * crate::fs::HAMMER2_FREEMAP_LEVEL1_SIZE)
+                    % (nleaf * crate::fs::HAMMER2_FREEMAP_LEVEL1_SIZE);
+                let cid = self.get_freemap_leaf(key)?;
+                self.load_chain(cid, crate::hammer2::RESOLVE_ALWAYS)?;
+                let chain = self.cmap.get_mut(&cid).or_range()?;
+                let mut found = None;
+                for j in 0..crate::fs::HAMMER2_FREEMAP_COUNT {
+                    let offset =
+                        key + u64::try_from(j).or_range()? * crate::fs::HAMMER2_FREEMAP_LEVEL0_SIZE;
+                    if i == 0 && offset + crate::fs::HAMMER2_FREEMAP_LEVEL0_SIZE <= hint {
+                        continue;
+                    }
+                    let bmap = libfs::cast::align_to_mut::<crate::fs::Hammer2BmapData>(
+                        &mut chain.get_data_mut()[j * BMAP_DATA_BYTES..(j + 1) * BMAP_DATA_BYTES],
+                    );
+                    let avail = bmap.avail;
+                    if let Some(x) = alloc_bmap(bmap, class, radix, any_class) {
+                        found = Some((offset + x, u64::from(avail - bmap.avail)));
+                        break;
+                    }
+                }
+                if let Some((offset, used)) = found {
+                    let check = chain
+                        .bref
+                        .check_as_mut::<crate::fs::Hammer2BlockrefCheckFreemap>();
+                    check.avail = check.avail.saturating_sub(used);
+                    self.voldata.allocator_free = self.voldata.allocator_free.saturating_sub(used);
+                    self.modify_chain(cid)?;
+                    self.alloc_hint = offset + size;
+                    return Ok(offset | u64::from(radix));
+                }
+            }
+        }
+        log::error!("no space for radix {radix}");
+        Err(nix::errno::Errno::ENOSPC.into())
+    }
+}
+
+impl crate::hammer2::Hammer2 {
+    /// # Errors
+    pub fn read_freemap(&self) -> crate::Result<Freemap> {
+        self.with_lock(crate::hammer2::Inner::read_freemap)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_bmap_bits() {
+        let mut bmap = crate::fs::Hammer2BmapData::new();
+        for i in 0..crate::fs::HAMMER2_BMAP_BLOCKS {
+            assert_eq!(super::get_bmap_bits(&bmap, i), 0);
+        }
+        super::set_bmap_bits(&mut bmap, 0, 3);
+        super::set_bmap_bits(&mut bmap, 33, 2);
+        super::set_bmap_bits(&mut bmap, 255, 3);
+        assert_eq!(bmap.bitmapq[0], 0x3);
+        assert_eq!(bmap.bitmapq[1], 0x8);
+        assert_eq!(bmap.bitmapq[7], 0xC000_0000_0000_0000);
+        assert_eq!(super::get_bmap_bits(&bmap, 0), 3);
+        assert_eq!(super::get_bmap_bits(&bmap, 1), 0);
+        assert_eq!(super::get_bmap_bits(&bmap, 33), 2);
+        assert_eq!(super::get_bmap_bits(&bmap, 255), 3);
+        super::set_bmap_bits(&mut bmap, 33, 0);
+        assert_eq!(bmap.bitmapq[1], 0);
+    }
+
+    #[test]
+    fn test_alloc_bmap() {
+        let mut bmap = crate::fs::Hammer2BmapData::new();
+        bmap.avail = 1 << crate::fs::HAMMER2_FREEMAP_LEVEL0_RADIX;
+        let class = (u16::from(crate::fs::HAMMER2_BREF_TYPE_DATA) << 8) | 16;
+        // 64KB
+        assert_eq!(super::alloc_bmap(&mut bmap, class, 16, false), Some(0));
+        assert_eq!(bmap.bitmapq[0], 0xFF);
+        assert_eq!(bmap.class, class);
+        assert_eq!(
+            super::alloc_bmap(&mut bmap, class, 16, false),
+            Some(0x10000)
+        );
+        // different class
+        let class2 = (u16::from(crate::fs::HAMMER2_BREF_TYPE_INODE) << 8) | 10;
+        assert_eq!(super::alloc_bmap(&mut bmap, class2, 10, false), None);
+        // sub 16KB
+        let mut bmap = crate::fs::Hammer2BmapData::new();
+        bmap.avail = 1 << crate::fs::HAMMER2_FREEMAP_LEVEL0_RADIX;
+        assert_eq!(super::alloc_bmap(&mut bmap, class2, 10, false), Some(0));
+        for i in 1..16 {
+            assert_eq!(
+                super::alloc_bmap(&mut bmap, class2, 10, false),
+                Some(i * 1024)
+            );
+        }
+        assert_eq!(
+            super::alloc_bmap(&mut bmap, class2, 10, false),
+            Some(0x4000)
+        );
+        assert_eq!(bmap.bitmapq[0], 0xF);
+        assert_eq!(
+            u64::from(bmap.avail),
+            crate::fs::HAMMER2_FREEMAP_LEVEL0_SIZE - 2 * crate::fs::HAMMER2_FREEMAP_BLOCK_SIZE
+        );
+        // full
+        let mut bmap = crate::fs::Hammer2BmapData::new();
+        bmap.bitmapq.fill(u64::MAX);
+        assert_eq!(super::alloc_bmap(&mut bmap, class, 16, true), None);
+    }
+
+    #[test]
+    fn test_init_leaf() {
+        let mut data = vec![0; crate::fs::HAMMER2_FREEMAP_COUNT * super::BMAP_DATA_BYTES];
+        let seg = crate::fs::HAMMER2_FREEMAP_LEVEL0_SIZE;
+        let avail = match super::init_leaf(&mut data, 0, 3 * seg + 1, 100 * seg) {
+            Ok(v) => v,
+            Err(e) => panic!("{e}"),
+        };
+        assert_eq!(avail, 96 * seg);
+        let v = match crate::fs::media_as::<crate::fs::Hammer2BmapData>(&data) {
+            Ok(v) => v,
+            Err(e) => panic!("{e}"),
+        };
+        assert_eq!(v.len(), crate::fs::HAMMER2_FREEMAP_COUNT);
+        for (i, bmap) in v.iter().enumerate() {
+            if (4..100).contains(&i) {
+                assert_eq!(u64::from(bmap.avail), seg, "{i}");
+                assert_eq!(bmap.bitmapq, [0; crate::fs::HAMMER2_BMAP_ELEMENTS], "{i}");
+            } else {
+                assert_eq!(bmap.avail, 0, "{i}");
+                assert_eq!(
+                    bmap.bitmapq,
+                    [u64::MAX; crate::fs::HAMMER2_BMAP_ELEMENTS],
+                    "{i}"
+                );
+            }
+        }
+        // first 4MB of each 1GB is reserved
+        let mut data = vec![0; crate::fs::HAMMER2_FREEMAP_COUNT * super::BMAP_DATA_BYTES];
+        let key = crate::fs::HAMMER2_FREEMAP_LEVEL1_SIZE;
+        let avail = match super::init_leaf(&mut data, key, seg, 3 * key) {
+            Ok(v) => v,
+            Err(e) => panic!("{e}"),
+        };
+        assert_eq!(avail, key - seg);
+    }
+
+    #[test]
+    fn test_get_freemap_offset() {
+        let mut bref = crate::fs::Hammer2Blockref::new(crate::fs::HAMMER2_BREF_TYPE_FREEMAP_LEAF);
+        bref.keybits = 30;
+        let zone = |x: usize| x as u64 * crate::fs::HAMMER2_PBUFSIZE;
+        let mut v = vec![];
+        for _ in 0..super::NFREEMAPS + 1 {
+            bref.data_off = match super::get_freemap_offset(&bref) {
+                Ok(v) => v,
+                Err(e) => panic!("{e}"),
+            };
+            v.push(bref.data_off);
+        }
+        assert_eq!(
+            v,
+            [
+                zone(crate::fs::HAMMER2_ZONE_FREEMAP_00),
+                zone(crate::fs::HAMMER2_ZONE_FREEMAP_01),
+                zone(crate::fs::HAMMER2_ZONE_FREEMAP_02),
+                zone(crate::fs::HAMMER2_ZONE_FREEMAP_03),
+                zone(crate::fs::HAMMER2_ZONE_FREEMAP_04),
+                zone(crate::fs::HAMMER2_ZONE_FREEMAP_05),
+                zone(crate::fs::HAMMER2_ZONE_FREEMAP_06),
+                zone(crate::fs::HAMMER2_ZONE_FREEMAP_07),
+                zone(crate::fs::HAMMER2_ZONE_FREEMAP_00),
+            ]
+        );
+
+        let mut bref = crate::fs::Hammer2Blockref::new(crate::fs::HAMMER2_BREF_TYPE_FREEMAP_NODE);
+        bref.keybits = 38;
+        bref.key = 1 << 38;
+        match super::get_freemap_offset(&bref) {
+            Ok(v) => assert_eq!(
+                v,
+                (1 << 38)
+                    + zone(crate::fs::HAMMER2_ZONE_FREEMAP_00 + crate::fs::HAMMER2_ZONEFM_LEVEL2)
+            ),
+            Err(e) => panic!("{e}"),
+        }
+
+        bref.keybits = 37;
+        assert!(super::get_freemap_offset(&bref).is_err());
+    }
+
+    #[test]
+    fn test_segment() {
+        let mut bmap = crate::fs::Hammer2BmapData::new();
+        bmap.avail = (crate::fs::HAMMER2_FREEMAP_LEVEL0_SIZE
+            - 3 * crate::fs::HAMMER2_FREEMAP_BLOCK_SIZE) as u32;
+        super::set_bmap_bits(&mut bmap, 0, 3);
+        super::set_bmap_bits(&mut bmap, 1, 3);
+        super::set_bmap_bits(&mut bmap, 100, 2);
+        let seg = super::Segment::new(0, &bmap);
+        assert_eq!(seg.get_block_state(0), super::BlockState::Allocated);
+        assert_eq!(seg.get_block_state(2), super::BlockState::Free);
+        assert_eq!(seg.get_block_state(100), super::BlockState::PossiblyFree);
+        assert_eq!(seg.count_blocks(super::BlockState::Allocated), 2);
+        assert_eq!(seg.count_blocks(super::BlockState::PossiblyFree), 1);
+        assert_eq!(seg.count_blocks(super::BlockState::Unused), 0);
+        assert!(!seg.is_free());
+        assert!(!seg.is_full());
+        assert!(seg.is_consistent());
+
+        let freemap = super::Freemap {
+            total_size: 2 * crate::fs::HAMMER2_FREEMAP_LEVEL0_SIZE,
+            allocator_free: 0,
+            segments: vec![
+                seg,
+                super::Segment::new(
+                    crate::fs::HAMMER2_FREEMAP_LEVEL0_SIZE,
+                    &crate::fs::Hammer2BmapData::new(),
+                ),
+            ],
+        };
+        assert!(freemap.get_segment(0).is_some());
+        assert!(
+            freemap
+                .get_segment(2 * crate::fs::HAMMER2_FREEMAP_LEVEL0_SIZE)
+                .is_none()
+        );
+        let s = freemap.summary();
+        assert_eq!(s.nsegments, 2);
+        assert_eq!(s.nfree_segments, 1);
+        assert_eq!(s.npartial_segments, 1);
+        assert_eq!(s.nfull_segments, 0);
+        // avail of the second segment is 0
+        assert_eq!(s.ninconsistent_segments, 1);
+        assert_eq!(
+            s.fragmented_size,
+            crate::fs::HAMMER2_FREEMAP_LEVEL0_SIZE - 3 * crate::fs::HAMMER2_FREEMAP_BLOCK_SIZE
+        );
+        assert_eq!(s.possibly_free_size, crate::fs::HAMMER2_FREEMAP_BLOCK_SIZE);
+    }
+
+    #[test]
+    fn test_read_freemap() {
+        let f = crate::newfs::create_newfs_image("freemap", 256 << 20);
+        let read_summary = |args: &[&str]| {
+            let pmp = match crate::hammer2::Hammer2::mount(&f, args) {
+                Ok(v) => v,
+                Err(e) => panic!("{e}"),
+            };
+            let freemap = match pmp.read_freemap() {
+                Ok(v) => v,
+                Err(e) => panic!("{e}"),
+            };
+            (pmp, freemap.summary())
+        };
+        let (_, s1) = read_summary(&[]);
+        assert_eq!(s1.total_size, 256 << 20);
+        assert_eq!(s1.nsegments, 64);
+        assert_eq!(s1.ninconsistent_segments, 0);
+        assert_eq!(s1.free_size, s1.avail_size);
+        assert!(s1.free_size > 0);
+        assert!(s1.get_allocator_free_delta().abs() <= i128::from(crate::fs::HAMMER2_SEGSIZE));
+
+        let (mut pmp, _) = read_summary(&["--rw"]);
+        let inum = match pmp.create(crate::inode::INUM_PFS_ROOT, "file", 0o644) {
+            Ok(v) => v,
+            Err(e) => panic!("{e}"),
+        };
+        if let Err(e) = pmp.pwrite(inum, &vec![0x5a; 1 << 20], 0) {
+            panic!("{e}");
+        }
+        if let Err(e) = pmp.unmount() {
+            panic!("{e}");
+        }
+        let (_, s2) = read_summary(&[]);
+        assert_eq!(s2.ninconsistent_segments, 0);
+        assert!(s2.free_size < s1.free_size);
+        assert_eq!(s1.get_allocator_free_delta(), s2.get_allocator_free_delta());
+        let _ = std::fs::remove_file(f);
+    }
+}
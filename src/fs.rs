@@ -8,6 +8,8 @@ pub const HAMMER2_PBUFSIZE: u64 = 65536;
 pub const HAMMER2_LBUFRADIX: usize = 14; // logical buf (1<<14) bytes
 pub const HAMMER2_LBUFSIZE: u64 = 16384;
 
+pub const HAMMER2_IND_BYTES_MIN: u64 = 4096;
+pub const HAMMER2_IND_BYTES_NOM: u64 = HAMMER2_LBUFSIZE;
 pub const HAMMER2_IND_BYTES_MAX: u64 = HAMMER2_PBUFSIZE;
 pub const HAMMER2_IND_COUNT_MAX: usize = (HAMMER2_IND_BYTES_MAX / HAMMER2_BLOCKREF_BYTES) as usize;
 
@@ -45,6 +47,12 @@ pub const HAMMER2_ZONE_FREEMAP_END: usize = 41; // non-inclusive
 // zone 41-63 unused
 pub const HAMMER2_ZONE_END: usize = 64; // non-inclusive
 
+pub const HAMMER2_ZONEFM_LEVEL1: usize = 0; // 1GB leafmap
+pub const HAMMER2_ZONEFM_LEVEL2: usize = 1; // 256GB indmap
+pub const HAMMER2_ZONEFM_LEVEL3: usize = 2; // 64TB indmap
+pub const HAMMER2_ZONEFM_LEVEL4: usize = 3; // 16PB indmap
+pub const HAMMER2_ZONEFM_LEVEL5: usize = 4; // 4EB indmap
+
 pub const HAMMER2_FREEMAP_LEVEL6_RADIX: usize = 64; // 16EB (end)
 pub const HAMMER2_FREEMAP_LEVEL5_RADIX: usize = 62; // 4EB
 pub const HAMMER2_FREEMAP_LEVEL4_RADIX: usize = 54; // 16PB
@@ -73,7 +81,16 @@ pub const HAMMER2_FREEMAP_LEVEL0_MASK: u64 = HAMMER2_FREEMAP_LEVEL0_SIZE - 1;
 pub const HAMMER2_FREEMAP_COUNT: usize =
     HAMMER2_FREEMAP_LEVELN_PSIZE as usize / std::mem::size_of::<Hammer2BmapData>();
 
+pub const HAMMER2_FREEMAP_BLOCK_RADIX: usize = 14;
+pub const HAMMER2_FREEMAP_BLOCK_SIZE: u64 = 1 << HAMMER2_FREEMAP_BLOCK_RADIX;
+pub const HAMMER2_FREEMAP_BLOCK_MASK: u64 = HAMMER2_FREEMAP_BLOCK_SIZE - 1;
+
 pub const HAMMER2_BMAP_ELEMENTS: usize = 8;
+pub const HAMMER2_BMAP_BITS_PER_ELEMENT: usize = 64;
+pub const HAMMER2_BMAP_BLOCKS_PER_ELEMENT: usize = 32;
+#[allow(clippy::cast_possible_truncation)]
+pub const HAMMER2_BMAP_BLOCKS: usize =
+    (HAMMER2_FREEMAP_LEVEL0_SIZE / HAMMER2_FREEMAP_BLOCK_SIZE) as usize;
 
 pub const HAMMER2_BOOT_MIN_BYTES: u64 = HAMMER2_VOLUME_ALIGN;
 pub const HAMMER2_BOOT_NOM_BYTES: u64 = 64 * 1024 * 1024;
@@ -445,6 +462,11 @@ pub const HAMMER2_PFSSUBTYPE_AUTOSNAP: u8 = 2; // automatic snapshot
 pub const HAMMER2_VOLUME_ID_HBO: u64 = 0x4841_4d32_0517_2011;
 pub const HAMMER2_VOLUME_ID_ABO: u64 = 0x1120_1705_324d_4148;
 
+pub const HAMMER2_PEER_NONE: u8 = 0;
+pub const HAMMER2_PEER_CLUSTER: u8 = 1; // a cluster controller
+pub const HAMMER2_PEER_BLOCK: u8 = 2; // block devices
+pub const HAMMER2_PEER_HAMMER2: u8 = 3; // hammer2-mounted volumes
+
 pub const HAMMER2_MAX_VOLUMES: u8 = 64;
 pub const HAMMER2_ROOT_VOLUME: u8 = 0;
 
@@ -588,15 +610,30 @@ pub const HAMMER2_VOL_VERSION_WIP: u32 = HAMMER2_VOL_VERSION_MULTI_VOLUMES + 1;
 
 pub const HAMMER2_NUM_VOLHDRS: usize = 4;
 
-#[must_use]
-pub fn media_as<T>(media: &[u8]) -> Vec<&T> {
-    let x = std::mem::size_of::<T>();
-    let n = media.len() / x;
-    let mut v = vec![];
-    for i in 0..n {
-        v.push(libfs::cast::align_to::<T>(&media[i * x..(i + 1) * x]));
+// Trailing bytes shorter than T are ignored.
+/// # Errors
+pub fn media_as<T>(media: &[u8]) -> nix::Result<Vec<&T>> {
+    media
+        .chunks_exact(std::mem::size_of::<T>())
+        .map(|x| try_align_to(x).ok_or(nix::errno::Errno::EFAULT))
+        .collect()
+}
+
+// Returns None if the buffer is too short or misaligned for T.
+pub(crate) fn try_align_to<T>(buf: &[u8]) -> Option<&T> {
+    if buf.len() < std::mem::size_of::<T>() || !buf.as_ptr().cast::<T>().is_aligned() {
+        None
+    } else {
+        Some(libfs::cast::align_to(buf))
+    }
+}
+
+pub(crate) fn try_align_to_mut<T>(buf: &mut [u8]) -> Option<&mut T> {
+    if buf.len() < std::mem::size_of::<T>() || !buf.as_ptr().cast::<T>().is_aligned() {
+        None
+    } else {
+        Some(libfs::cast::align_to_mut(buf))
     }
-    v
 }
 
 #[cfg(test)]
@@ -652,6 +689,14 @@ mod tests {
     #[test]
     fn test_struct_hammer2_bmap_data() {
         assert_eq!(std::mem::size_of::<super::Hammer2BmapData>(), 128);
+        assert_eq!(
+            super::HAMMER2_BMAP_BLOCKS,
+            super::HAMMER2_BMAP_ELEMENTS * super::HAMMER2_BMAP_BLOCKS_PER_ELEMENT
+        );
+        assert_eq!(
+            super::HAMMER2_BMAP_BITS_PER_ELEMENT,
+            super::HAMMER2_BMAP_BLOCKS_PER_ELEMENT * 2
+        );
     }
 
     #[test]
@@ -0,0 +1,554 @@
+use std::fmt;
+
+// Indirect blocks never go this deep unless the topology loops.
+const FSCK_MAX_DEPTH: usize = 64;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ProblemKind {
+    BadType(u8),
+    BadRadix,
+    ReadFailed(String),
+    CheckFailed,
+    TooDeep,
+    KeyNotAligned,
+    KeyOutOfRange { key_beg: u64, key_end: u64 },
+    KeyOverlapped { key: u64, keybits: u8 },
+    InumMismatch { inum: u64 },
+    DuplicateInum { inum: u64 },
+    NameKeyMismatch { name_key: u64, dirhash: u64 },
+    BadName,
+    Corrupted(String),
+    DirentTargetMissing { inum: u64 },
+    DirentTypeMismatch { inum: u64, typ: u8 },
+    IparentMismatch { inum: u64, iparent: u64, dinum: u64 },
+}
+
+impl fmt::Display for ProblemKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::BadType(typ) => write!(f, "bad blockref type {typ}"),
+            Self::BadRadix => write!(f, "bad radix"),
+            Self::ReadFailed(e) => write!(f, "failed to read media: {e}"),
+            Self::CheckFailed => write!(f, "check code mismatch"),
+            Self::TooDeep => write!(f, "topology too deep"),
+            Self::KeyNotAligned => write!(f, "key not aligned to keybits"),
+            Self::KeyOutOfRange { key_beg, key_end } => {
+                write!(f, "key out of parent range {key_beg:016x}-{key_end:016x}")
+            }
+            Self::KeyOverlapped { key, keybits } => write!(f, "key overlaps {key:016x}/{keybits}"),
+            Self::InumMismatch { inum } => write!(f, "key does not match inum {inum:016x}"),
+            Self::DuplicateInum { inum } => write!(f, "duplicate inum {inum:016x}"),
+            Self::NameKeyMismatch { name_key, dirhash } => {
+                write!(f, "name key {name_key:016x} does not match {dirhash:016x}")
+            }
+            Self::BadName => write!(f, "bad name"),
+            Self::Corrupted(e) => write!(f, "{e}"),
+            Self::DirentTargetMissing { inum } => write!(f, "inum {inum:016x} not found"),
+            Self::DirentTypeMismatch { inum, typ } => write!(
+                f,
+                "inum {inum:016x} type {} does not match",
+                crate::subs::get_inode_type_string(*typ)
+            ),
+            Self::IparentMismatch {
+                inum,
+                iparent,
+                dinum,
+            } => write!(
+                f,
+                "inum {inum:016x} iparent {iparent:016x} does not match {dinum:016x}"
+            ),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Problem {
+    pub offset: u64, // physical offset
+    pub bref: crate::fs::Hammer2Blockref,
+    pub pfs: Option<String>,
+    pub kind: ProblemKind,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:016x}", self.offset)?;
+        if let Some(pfs) = &self.pfs {
+            write!(f, " \"{pfs}\"")?;
+        }
+        write!(f, " {}: {}", self.bref, self.kind)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Report {
+    pub problems: Vec<Problem>,
+    pub nblockrefs: u64,
+    pub ninodes: u64,
+    pub ndirents: u64,
+    pub npfs: u64,
+}
+
+impl Report {
+    #[must_use]
+    pub fn is_clean(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+// Owner of a block table.
+#[derive(Clone, Copy, Debug)]
+enum Owner {
+    Volume,
+    Freemap,
+    SupRoot,
+    Inode(u64),
+}
+
+#[derive(Debug)]
+struct InodeEntry {
+    typ: u8,
+    iparent: u64,
+    bref: crate::fs::Hammer2Blockref,
+}
+
+#[derive(Debug)]
+struct DirentEntry {
+    dinum: u64,
+    inum: u64,
+    typ: u8,
+    bref: crate::fs::Hammer2Blockref,
+}
+
+#[derive(Debug)]
+struct Scanner<'a> {
+    fso: &'a mut crate::ondisk::Ondisk,
+    hbo: bool,
+    report: Report,
+    pfs: Option<String>,
+    inodes: std::collections::HashMap<u64, InodeEntry>,
+    dirents: Vec<DirentEntry>,
+}
+
+impl<'a> Scanner<'a> {
+    fn new(fso: &'a mut crate::ondisk::Ondisk, hbo: bool) -> Self {
+        Self {
+            fso,
+            hbo,
+            report: Report::default(),
+            pfs: None,
+            inodes: std::collections::HashMap::new(),
+            dirents: vec![],
+        }
+    }
+
+    fn add_problem(&mut self, bref: &crate::fs::Hammer2Blockref, kind: ProblemKind) {
+        let problem = Problem {
+            offset: bref.get_raw_data_off(),
+            bref: *bref,
+            pfs: self.pfs.clone(),
+            kind,
+        };
+        log::debug!("{problem}");
+        self.report.problems.push(problem);
+    }
+
+    fn is_valid_type(owner: Owner, typ: u8) -> bool {
+        match owner {
+            Owner::Volume | Owner::SupRoot => typ == crate::fs::HAMMER2_BREF_TYPE_INODE,
+            Owner::Freemap => matches!(
+                typ,
+                crate::fs::HAMMER2_BREF_TYPE_FREEMAP_NODE
+                    | crate::fs::HAMMER2_BREF_TYPE_FREEMAP_LEAF
+            ),
+            Owner::Inode(_) => matches!(
+                typ,
+                crate::fs::HAMMER2_BREF_TYPE_INODE
+                    | crate::fs::HAMMER2_BREF_TYPE_INDIRECT
+                    | crate::fs::HAMMER2_BREF_TYPE_DATA
+                    | crate::fs::HAMMER2_BREF_TYPE_DIRENT
+            ),
+        }
+    }
+
+    fn scan_blockref(
+        &mut self,
+        bref: &crate::fs::Hammer2Blockref,
+        owner: Owner,
+        depth: usize,
+    ) -> crate::Result<()> {
+        self.report.nblockrefs += 1;
+        if depth > FSCK_MAX_DEPTH {
+            self.add_problem(bref, ProblemKind::TooDeep);
+            return Ok(());
+        }
+        if !Self::is_valid_type(owner, bref.typ) {
+            self.add_problem(bref, ProblemKind::BadType(bref.typ));
+            return Ok(());
+        }
+        let Ok(radix) = bref.get_radix() else {
+            self.add_problem(bref, ProblemKind::BadRadix);
+            return Ok(());
+        };
+        // Only directory entries may be embedded in the blockref.
+        if radix == 0 {
+            if bref.typ == crate::fs::HAMMER2_BREF_TYPE_DIRENT {
+                self.scan_dirent(bref, owner, &[]);
+            } else {
+                self.add_problem(bref, ProblemKind::BadRadix);
+            }
+            return Ok(());
+        }
+        let mut media = match self.fso.read_media(bref) {
+            Ok(v) => v,
+            Err(e) => {
+                self.add_problem(bref, ProblemKind::ReadFailed(e.to_string()));
+                return Ok(());
+            }
+        };
+        match crate::ondisk::verify_media(bref, &media) {
+            Ok(true) => (),
+            Ok(false) => {
+                self.add_problem(bref, ProblemKind::CheckFailed);
+                return Ok(());
+            }
+            Err(e) => {
+                self.add_problem(bref, ProblemKind::ReadFailed(e.to_string()));
+                return Ok(());
+            }
+        }
+        if !self.hbo {
+            crate::ondisk::swap_media(bref, &mut media);
+        }
+        if let Err(e) = crate::ondisk::check_media(bref, &media) {
+            self.add_problem(bref, ProblemKind::Corrupted(e.to_string()));
+            return Ok(());
+        }
+        match bref.typ {
+            crate::fs::HAMMER2_BREF_TYPE_INODE => self.scan_inode(bref, owner, &media, depth),
+            crate::fs::HAMMER2_BREF_TYPE_INDIRECT | crate::fs::HAMMER2_BREF_TYPE_FREEMAP_NODE => {
+                self.scan_blockref_array(bref, owner, &media, depth)
+            }
+            crate::fs::HAMMER2_BREF_TYPE_DIRENT => {
+                self.scan_dirent(bref, owner, &media);
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn scan_inode(
+        &mut self,
+        bref: &crate::fs::Hammer2Blockref,
+        owner: Owner,
+        media: &[u8],
+        depth: usize,
+    ) -> crate::Result<()> {
+        if media.len() != std::mem::size_of::<crate::fs::Hammer2InodeData>() {
+            self.add_problem(bref, ProblemKind::BadRadix);
+            return Ok(());
+        }
+        self.report.ninodes += 1;
+        let ipdata = crate::ondisk::media_as_inode_data(bref, media)?;
+        let meta = &ipdata.meta;
+        // The super-root has no name.
+        if !matches!(owner, Owner::Volume) {
+            let name = &ipdata.filename[..usize::from(meta.name_len).min(ipdata.filename.len())];
+            let dirhash = crate::subs::dirhash(name);
+            if name.is_empty() {
+                self.add_problem(bref, ProblemKind::BadName);
+            } else if (meta.name_key ^ dirhash) & !crate::fs::HAMMER2_DIRHASH_LOMASK != 0 {
+                self.add_problem(
+                    bref,
+                    ProblemKind::NameKeyMismatch {
+                        name_key: meta.name_key,
+                        dirhash,
+                    },
+                );
+            }
+        }
+        let next = match owner {
+            Owner::Volume => Owner::SupRoot,
+            Owner::SupRoot => {
+                // PFS root inodes are keyed by name.
+                if bref.key != meta.name_key {
+                    self.add_problem(
+                        bref,
+                        ProblemKind::NameKeyMismatch {
+                            name_key: bref.key,
+                            dirhash: meta.name_key,
+                        },
+                    );
+                }
+                self.report.npfs += 1;
+                self.pfs = Some(ipdata.get_filename_string().unwrap_or_default());
+                self.inodes.clear();
+                self.dirents.clear();
+                Owner::Inode(meta.inum)
+            }
+            Owner::Inode(_) => {
+                // Inodes are keyed by inum, unless embedded in a directory.
+                if (bref.key & crate::fs::HAMMER2_DIRHASH_VISIBLE) == 0 && bref.key != meta.inum {
+                    self.add_problem(bref, ProblemKind::InumMismatch { inum: meta.inum });
+                }
+                Owner::Inode(meta.inum)
+            }
+            Owner::Freemap => return Err(nix::errno::Errno::EINVAL.into()),
+        };
+        if let Owner::Inode(inum) = next {
+            if let std::collections::hash_map::Entry::Vacant(e) = self.inodes.entry(inum) {
+                e.insert(InodeEntry {
+                    typ: meta.typ,
+                    iparent: meta.iparent,
+                    bref: *bref,
+                });
+            } else {
+                self.add_problem(bref, ProblemKind::DuplicateInum { inum });
+            }
+        }
+        if meta.is_sup_root() || !meta.has_direct_data() {
+            self.scan_blockref_array(bref, next, media, depth)?;
+        }
+        if matches!(owner, Owner::SupRoot) {
+            self.verify_pfs();
+            self.pfs = None;
+        }
+        Ok(())
+    }
+
+    fn scan_dirent(&mut self, bref: &crate::fs::Hammer2Blockref, owner: Owner, media: &[u8]) {
+        self.report.ndirents += 1;
+        let Owner::Inode(dinum) = owner else {
+            return;
+        };
+        let dirent = bref.embed_as::<crate::fs::Hammer2DirentHead>();
+        let n = usize::from(dirent.namlen);
+        let name = if n <= bref.check.len() {
+            &bref.check[..n]
+        } else if n <= media.len() {
+            &media[..n]
+        } else {
+            &[]
+        };
+        let dirhash = crate::subs::dirhash(name);
+        if name.is_empty() || name.contains(&b'/') || name.contains(&0) {
+            self.add_problem(bref, ProblemKind::BadName);
+        } else if (bref.key ^ dirhash) & !crate::fs::HAMMER2_DIRHASH_LOMASK != 0 {
+            self.add_problem(
+                bref,
+                ProblemKind::NameKeyMismatch {
+                    name_key: bref.key,
+                    dirhash,
+                },
+            );
+        }
+        self.dirents.push(DirentEntry {
+            dinum,
+            inum: dirent.inum,
+            typ: dirent.typ,
+            bref: *bref,
+        });
+    }
+
+    fn scan_blockref_array(
+        &mut self,
+        bref: &crate::fs::Hammer2Blockref,
+        owner: Owner,
+        media: &[u8],
+        depth: usize,
+    ) -> crate::Result<()> {
+        let mut v: Vec<crate::fs::Hammer2Blockref> = crate::ondisk::media_as_blockref(bref, media)?
+            .into_iter()
+            .filter(|x| x.typ != crate::fs::HAMMER2_BREF_TYPE_EMPTY)
+            .copied()
+            .collect();
+        self.verify_blockref_array(bref, &mut v);
+        for x in &v {
+            self.scan_blockref(x, owner, depth + 1)?;
+        }
+        Ok(())
+    }
+
+    // Children must be within the parent's key range and must not overlap,
+    // as chain lookup assumes.
+    fn verify_blockref_array(
+        &mut self,
+        bref: &crate::fs::Hammer2Blockref,
+        v: &mut [crate::fs::Hammer2Blockref],
+    ) {
+        let (key_beg, key_end) = if bref.is_node_type() {
+            (bref.key, bref.get_key_end())
+        } else {
+            (crate::fs::HAMMER2_KEY_MIN, crate::fs::HAMMER2_KEY_MAX)
+        };
+        for x in v.iter() {
+            if x.keybits > 64 || (x.key & crate::extra::conv_keybits_to_mask(x.keybits)) != 0 {
+                self.add_problem(x, ProblemKind::KeyNotAligned);
+            } else if x.key < key_beg || x.get_key_end() > key_end {
+                self.add_problem(x, ProblemKind::KeyOutOfRange { key_beg, key_end });
+            }
+        }
+        v.sort_by_key(|x| x.key);
+        for i in 1..v.len() {
+            let prev = v[i - 1];
+            let prev_end = prev
+                .key
+                .wrapping_add(crate::extra::conv_keybits_to_mask(prev.keybits));
+            if prev_end >= v[i].key || prev_end < prev.key {
+                self.add_problem(
+                    &v[i],
+                    ProblemKind::KeyOverlapped {
+                        key: prev.key,
+                        keybits: prev.keybits,
+                    },
+                );
+            }
+        }
+    }
+
+    // Cross-check directory entries against inodes of the PFS.
+    fn verify_pfs(&mut self) {
+        let dirents = std::mem::take(&mut self.dirents);
+        for x in &dirents {
+            let Some(ip) = self.inodes.get(&x.inum) else {
+                self.add_problem(&x.bref, ProblemKind::DirentTargetMissing { inum: x.inum });
+                continue;
+            };
+            if ip.typ != x.typ {
+                self.add_problem(
+                    &x.bref,
+                    ProblemKind::DirentTypeMismatch {
+                        inum: x.inum,
+                        typ: ip.typ,
+                    },
+                );
+            } else if ip.typ == crate::fs::HAMMER2_OBJTYPE_DIRECTORY && ip.iparent != x.dinum {
+                let (bref, iparent) = (ip.bref, ip.iparent);
+                self.add_problem(
+                    &bref,
+                    ProblemKind::IparentMismatch {
+                        inum: x.inum,
+                        iparent,
+                        dinum: x.dinum,
+                    },
+                );
+            }
+        }
+        self.inodes.clear();
+    }
+}
+
+/// # Errors
+pub fn fsck(spec: &str) -> crate::Result<Report> {
+    let mut fso = crate::ondisk::init_quiet(spec, true)?;
+    fsck_ondisk(&mut fso)
+}
+
+// Scan blockrefs of both the volume and the freemap topology.
+/// # Errors
+pub fn fsck_ondisk(fso: &mut crate::ondisk::Ondisk) -> crate::Result<Report> {
+    let voldata = fso.read_root_volume_data()?;
+    let mut scanner = Scanner::new(fso, voldata.is_hbo()?);
+    let mut vbref = crate::fs::Hammer2Blockref::new(crate::fs::HAMMER2_BREF_TYPE_VOLUME);
+    let mut v = voldata
+        .sroot_blockset
+        .blockref
+        .into_iter()
+        .filter(|x| x.typ != crate::fs::HAMMER2_BREF_TYPE_EMPTY)
+        .collect::<Vec<_>>();
+    scanner.verify_blockref_array(&vbref, &mut v);
+    for x in &v {
+        scanner.scan_blockref(x, Owner::Volume, 0)?;
+    }
+    vbref.typ = crate::fs::HAMMER2_BREF_TYPE_FREEMAP;
+    let mut v = voldata
+        .freemap_blockset
+        .blockref
+        .into_iter()
+        .filter(|x| x.typ != crate::fs::HAMMER2_BREF_TYPE_EMPTY)
+        .collect::<Vec<_>>();
+    scanner.verify_blockref_array(&vbref, &mut v);
+    for x in &v {
+        scanner.scan_blockref(x, Owner::Freemap, 0)?;
+    }
+    Ok(scanner.report)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    #[test]
+    fn test_problem_kind_fmt() {
+        assert_eq!(
+            super::ProblemKind::CheckFailed.to_string(),
+            "check code mismatch"
+        );
+        assert_eq!(
+            super::ProblemKind::InumMismatch { inum: 0x10 }.to_string(),
+            "key does not match inum 0000000000000010"
+        );
+    }
+
+    #[test]
+    fn test_fsck() {
+        let f = crate::newfs::create_image("fsck", 128 << 20);
+        if let Err(e) = crate::ondisk::newfs(&f) {
+            panic!("{e}");
+        }
+        // populate
+        let mut pmp = match crate::hammer2::Hammer2::mount(&f, &["--rw"]) {
+            Ok(v) => v,
+            Err(e) => panic!("{e}"),
+        };
+        let dinum = match pmp.mkdir(crate::inode::INUM_PFS_ROOT, "dir", 0o755) {
+            Ok(v) => v,
+            Err(e) => panic!("{e}"),
+        };
+        for i in 0..20 {
+            let inum = match pmp.create(dinum, &format!("file{i}"), 0o644) {
+                Ok(v) => v,
+                Err(e) => panic!("{e}"),
+            };
+            if let Err(e) = pmp.pwrite(inum, &vec![b'A' + i; 1000 * usize::from(i)], 0) {
+                panic!("{e}");
+            }
+        }
+        if let Err(e) = pmp.unmount() {
+            panic!("{e}");
+        }
+        let report = match super::fsck(&f) {
+            Ok(v) => v,
+            Err(e) => panic!("{e}"),
+        };
+        assert!(report.is_clean(), "{:?}", report.problems);
+        assert_eq!(report.npfs, 2);
+        assert_eq!(report.ndirents, 21);
+        assert_eq!(report.ninodes, 1 + 2 + 21);
+
+        // corrupt the super-root
+        let voldata = match crate::volume::read_volume_data(&f) {
+            Ok(v) => v,
+            Err(e) => panic!("{e}"),
+        };
+        let offset = voldata.sroot_blockset.blockref[0].get_raw_data_off();
+        let mut fp = match std::fs::OpenOptions::new().write(true).open(&f) {
+            Ok(v) => v,
+            Err(e) => panic!("{e}"),
+        };
+        if let Err(e) = libfs::fs::seek_set(&mut fp, offset + 0x100) {
+            panic!("{e}");
+        }
+        if let Err(e) = fp.write_all(b"x") {
+            panic!("{e}");
+        }
+        drop(fp);
+        let report = match super::fsck(&f) {
+            Ok(v) => v,
+            Err(e) => panic!("{e}"),
+        };
+        assert_eq!(report.problems.len(), 1, "{:?}", report.problems);
+        assert_eq!(report.problems[0].offset, offset);
+        assert_eq!(report.problems[0].kind, super::ProblemKind::CheckFailed);
+        let _ = std::fs::remove_file(&f);
+    }
+}
@@ -0,0 +1,401 @@
+use crate::ErrorExt;
+use crate::OptionExt;
+
+const TTL: std::time::Duration = std::time::Duration::from_secs(1);
+
+fn get_errno(e: &crate::Error) -> libc::c_int {
+    match e {
+        crate::Error::Errno(e) => *e as libc::c_int,
+        crate::Error::Error(e) => e.raw_os_error().unwrap_or(libc::EIO),
+        crate::Error::Dyn(_) | crate::Error::Corrupt(_) => libc::EIO,
+    }
+}
+
+fn get_file_type(typ: u8) -> fuser::FileType {
+    match typ {
+        crate::fs::HAMMER2_OBJTYPE_DIRECTORY => fuser::FileType::Directory,
+        crate::fs::HAMMER2_OBJTYPE_FIFO => fuser::FileType::NamedPipe,
+        crate::fs::HAMMER2_OBJTYPE_CDEV => fuser::FileType::CharDevice,
+        crate::fs::HAMMER2_OBJTYPE_BDEV => fuser::FileType::BlockDevice,
+        crate::fs::HAMMER2_OBJTYPE_SOFTLINK => fuser::FileType::Symlink,
+        crate::fs::HAMMER2_OBJTYPE_SOCKET => fuser::FileType::Socket,
+        _ => fuser::FileType::RegularFile,
+    }
+}
+
+fn get_system_time(t: u64) -> std::time::SystemTime {
+    std::time::UNIX_EPOCH + std::time::Duration::from_micros(t)
+}
+
+/// Read-only FUSE filesystem serving the mounted PFS.
+/// FUSE root inode number 1 is the PFS root inode.
+#[derive(Debug)]
+pub struct Hammer2Fuse {
+    pmp: crate::hammer2::Hammer2,
+}
+
+impl Hammer2Fuse {
+    #[must_use]
+    pub fn new(pmp: crate::hammer2::Hammer2) -> Self {
+        Self { pmp }
+    }
+
+    fn getattr_impl(&mut self, ino: u64) -> crate::Result<fuser::FileAttr> {
+        let st = self.pmp.stat(ino)?;
+        let meta = &self.pmp.get_inode(ino).or_range()?.meta;
+        Ok(fuser::FileAttr {
+            ino,
+            size: st.st_size,
+            blocks: st.st_blocks,
+            atime: get_system_time(meta.atime),
+            mtime: get_system_time(meta.mtime),
+            ctime: get_system_time(meta.ctime),
+            crtime: get_system_time(meta.btime),
+            kind: get_file_type(meta.typ),
+            perm: (meta.mode & 0o7777).try_into().or_range()?,
+            nlink: st.st_nlink,
+            uid: st.st_uid,
+            gid: st.st_gid,
+            rdev: st.st_rdev,
+            blksize: st.st_blksize,
+            flags: 0,
+        })
+    }
+
+    fn lookup_impl(
+        &mut self,
+        parent: u64,
+        name: &std::ffi::OsStr,
+    ) -> crate::Result<fuser::FileAttr> {
+        let Some(name) = name.to_str() else {
+            return Err(nix::errno::Errno::ENOENT.into());
+        };
+        // ".." of the PFS root is the PFS root itself, same as readdir.
+        let ino = if parent == crate::inode::INUM_PFS_ROOT && name == ".." {
+            parent
+        } else {
+            self.pmp.nresolve(parent, name)?
+        };
+        self.getattr_impl(ino)
+    }
+
+    fn read_impl(&mut self, ino: u64, offset: i64, size: u32) -> crate::Result<Vec<u8>> {
+        let mut buf = vec![0; size.try_into().or_range()?];
+        let n = self
+            .pmp
+            .pread(ino, &mut buf, offset.try_into().or_range()?)?;
+        buf.truncate(n.try_into().or_range()?);
+        Ok(buf)
+    }
+
+    // The offset of an entry is the key of the next entry.
+    // add returns true if the reply buffer is full, same as ReplyDirectory.
+    fn readdir_impl<F: FnMut(u64, i64, fuser::FileType, &str) -> bool>(
+        &mut self,
+        ino: u64,
+        offset: i64,
+        mut add: F,
+    ) -> crate::Result<()> {
+        for x in self.pmp.read_dir_from(ino, offset.cast_unsigned())? {
+            let dirent = x?;
+            // ".." of the PFS root is the PFS root itself.
+            let inum = if ino == crate::inode::INUM_PFS_ROOT && dirent.name == ".." {
+                ino
+            } else {
+                dirent.inum
+            };
+            if add(
+                inum,
+                dirent.key.wrapping_add(1).cast_signed(),
+                get_file_type(dirent.typ),
+                &dirent.name,
+            ) {
+                break;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl fuser::Filesystem for Hammer2Fuse {
+    fn destroy(&mut self) {
+        if let Err(e) = self.pmp.unmount() {
+            log::error!("{e}");
+        }
+    }
+
+    fn lookup(
+        &mut self,
+        _req: &fuser::Request<'_>,
+        parent: u64,
+        name: &std::ffi::OsStr,
+        reply: fuser::ReplyEntry,
+    ) {
+        match self.lookup_impl(parent, name) {
+            Ok(v) => reply.entry(&TTL, &v, 0),
+            Err(e) => reply.error(get_errno(&e)),
+        }
+    }
+
+    fn getattr(
+        &mut self,
+        _req: &fuser::Request<'_>,
+        ino: u64,
+        _fh: Option<u64>,
+        reply: fuser::ReplyAttr,
+    ) {
+        match self.getattr_impl(ino) {
+            Ok(v) => reply.attr(&TTL, &v),
+            Err(e) => reply.error(get_errno(&e)),
+        }
+    }
+
+    fn readlink(&mut self, _req: &fuser::Request<'_>, ino: u64, reply: fuser::ReplyData) {
+        match self.pmp.readlinkx(ino) {
+            Ok(v) => reply.data(v.as_bytes()),
+            Err(e) => reply.error(get_errno(&e)),
+        }
+    }
+
+    fn read(
+        &mut self,
+        _req: &fuser::Request<'_>,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        size: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: fuser::ReplyData,
+    ) {
+        match self.read_impl(ino, offset, size) {
+            Ok(v) => reply.data(&v),
+            Err(e) => reply.error(get_errno(&e)),
+        }
+    }
+
+    fn readdir(
+        &mut self,
+        _req: &fuser::Request<'_>,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        mut reply: fuser::ReplyDirectory,
+    ) {
+        match self.readdir_impl(ino, offset, |ino, offset, kind, name| {
+            reply.add(ino, offset, kind, name)
+        }) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(get_errno(&e)),
+        }
+    }
+
+    fn statfs(&mut self, _req: &fuser::Request<'_>, _ino: u64, reply: fuser::ReplyStatfs) {
+        match self.pmp.statfs() {
+            Ok(v) => reply.statfs(
+                v.f_blocks,
+                v.f_bfree,
+                v.f_bavail,
+                v.f_files,
+                v.f_ffree,
+                v.f_bsize,
+                crate::fs::HAMMER2_INODE_MAXNAME
+                    .try_into()
+                    .unwrap_or(u32::MAX),
+                v.f_frsize,
+            ),
+            Err(e) => reply.error(get_errno(&e)),
+        }
+    }
+}
+
+/// Mount `spec` read-only on `mountpoint` and serve it until unmounted.
+/// # Errors
+pub fn mount(spec: &str, args: &[&str], mountpoint: &str) -> crate::Result<()> {
+    if args.contains(&"--rw") {
+        log::error!("FUSE mount is read-only");
+        return Err(nix::errno::Errno::EINVAL.into());
+    }
+    let pmp = crate::hammer2::Hammer2::mount(spec, args)?;
+    let opts = [
+        fuser::MountOption::RO,
+        fuser::MountOption::FSName(spec.to_string()),
+        fuser::MountOption::Subtype("hammer2".to_string()),
+        fuser::MountOption::DefaultPermissions,
+    ];
+    fuser::mount2(Hammer2Fuse::new(pmp), mountpoint, &opts)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    fn readdir(
+        fs: &mut super::Hammer2Fuse,
+        ino: u64,
+        offset: i64,
+        n: usize,
+    ) -> Vec<(u64, i64, String)> {
+        let mut v = vec![];
+        let r = fs.readdir_impl(ino, offset, |ino, offset, _, name| {
+            if v.len() == n {
+                return true;
+            }
+            v.push((ino, offset, name.to_string()));
+            false
+        });
+        if let Err(e) = r {
+            panic!("{e}");
+        }
+        v
+    }
+
+    #[test]
+    fn test_hammer2_fuse() {
+        let f = crate::newfs::create_newfs_image("fuse", 128 << 20);
+        let data = b"hammer2".repeat(20000);
+        let mut pmp = match crate::hammer2::Hammer2::mount(&f, &["--rw"]) {
+            Ok(v) => v,
+            Err(e) => panic!("{e}"),
+        };
+        let dinum = match pmp.mkdir(crate::inode::INUM_PFS_ROOT, "d", 0o750) {
+            Ok(v) => v,
+            Err(e) => panic!("{e}"),
+        };
+        for i in 0..10 {
+            if let Err(e) = pmp.create(dinum, &format!("f{i}"), 0o644) {
+                panic!("{e}");
+            }
+        }
+        let inum = match pmp.create(crate::inode::INUM_PFS_ROOT, "x", 0o600) {
+            Ok(v) => v,
+            Err(e) => panic!("{e}"),
+        };
+        if let Err(e) = pmp.pwrite(inum, &data, 0) {
+            panic!("{e}");
+        }
+        if let Err(e) = pmp.unmount() {
+            panic!("{e}");
+        }
+
+        let pmp = match crate::hammer2::Hammer2::mount(&f, &[]) {
+            Ok(v) => v,
+            Err(e) => panic!("{e}"),
+        };
+        let mut fs = super::Hammer2Fuse::new(pmp);
+        let root = crate::inode::INUM_PFS_ROOT;
+        match fs.getattr_impl(root) {
+            Ok(v) => {
+                assert_eq!(v.ino, root);
+                assert_eq!(v.kind, fuser::FileType::Directory);
+            }
+            Err(e) => panic!("{e}"),
+        }
+        // lookup
+        match fs.lookup_impl(root, std::ffi::OsStr::new("d")) {
+            Ok(v) => {
+                assert_eq!(v.ino, dinum);
+                assert_eq!(v.kind, fuser::FileType::Directory);
+                assert_eq!(v.perm, 0o750);
+            }
+            Err(e) => panic!("{e}"),
+        }
+        match fs.lookup_impl(dinum, std::ffi::OsStr::new("..")) {
+            Ok(v) => assert_eq!(v.ino, root),
+            Err(e) => panic!("{e}"),
+        }
+        match fs.lookup_impl(root, std::ffi::OsStr::new("..")) {
+            Ok(v) => assert_eq!(v.ino, root),
+            Err(e) => panic!("{e}"),
+        }
+        match fs.lookup_impl(root, std::ffi::OsStr::new("missing")) {
+            Ok(v) => panic!("{v:?}"),
+            Err(e) => assert_eq!(super::get_errno(&e), libc::ENOENT),
+        }
+        let name = <std::ffi::OsStr as std::os::unix::ffi::OsStrExt>::from_bytes(b"\xff");
+        match fs.lookup_impl(root, name) {
+            Ok(v) => panic!("{v:?}"),
+            Err(e) => assert_eq!(super::get_errno(&e), libc::ENOENT),
+        }
+        // getattr, the kernel looks up an inode before using it
+        match fs.lookup_impl(root, std::ffi::OsStr::new("x")) {
+            Ok(v) => assert_eq!(v.ino, inum),
+            Err(e) => panic!("{e}"),
+        }
+        match fs.getattr_impl(inum) {
+            Ok(v) => {
+                assert_eq!(v.ino, inum);
+                assert_eq!(v.kind, fuser::FileType::RegularFile);
+                assert_eq!(v.size, u64::try_from(data.len()).unwrap_or(0));
+                assert_eq!(v.perm, 0o600);
+                assert_eq!(v.nlink, 1);
+            }
+            Err(e) => panic!("{e}"),
+        }
+        // readdir, ".." of the PFS root is the PFS root itself
+        let v = readdir(&mut fs, root, 0, usize::MAX);
+        let mut v: Vec<_> = v.iter().map(|x| (x.0, x.2.as_str())).collect();
+        v[2..].sort_unstable();
+        assert_eq!(v, [(root, "."), (root, ".."), (dinum, "d"), (inum, "x")]);
+        let v = readdir(&mut fs, dinum, 0, usize::MAX);
+        assert_eq!(v.len(), 12);
+        assert_eq!((v[0].0, v[0].2.as_str()), (dinum, "."));
+        assert_eq!((v[1].0, v[1].2.as_str()), (root, ".."));
+        // resume from the offset of the last entry of a full reply
+        let mut w = vec![];
+        let mut offset = 0;
+        loop {
+            let x = readdir(&mut fs, dinum, offset, 5);
+            let Some(last) = x.last() else {
+                break;
+            };
+            offset = last.1;
+            w.extend(x);
+        }
+        assert_eq!(w, v);
+        // read
+        match fs.read_impl(inum, 0, 4096) {
+            Ok(v) => assert_eq!(v, data[..4096]),
+            Err(e) => panic!("{e}"),
+        }
+        match fs.read_impl(inum, 70000, 131_072) {
+            Ok(v) => assert_eq!(v, data[70000..]),
+            Err(e) => panic!("{e}"),
+        }
+        match fs.read_impl(inum, 1 << 20, 4096) {
+            Ok(v) => assert!(v.is_empty()),
+            Err(e) => panic!("{e}"),
+        }
+        match fs.read_impl(dinum, 0, 4096) {
+            Ok(v) => panic!("{v:?}"),
+            Err(e) => assert_eq!(super::get_errno(&e), libc::EISDIR),
+        }
+        fuser::Filesystem::destroy(&mut fs);
+        let _ = std::fs::remove_file(f);
+    }
+
+    #[test]
+    fn test_get_errno() {
+        let e = crate::Error::Errno(nix::errno::Errno::ENOENT);
+        assert_eq!(super::get_errno(&e), libc::ENOENT);
+        let e = crate::Error::Error(std::io::Error::from_raw_os_error(libc::EROFS));
+        assert_eq!(super::get_errno(&e), libc::EROFS);
+        let e = crate::Error::Error(std::io::Error::other("x"));
+        assert_eq!(super::get_errno(&e), libc::EIO);
+    }
+
+    #[test]
+    fn test_get_file_type() {
+        assert_eq!(
+            super::get_file_type(crate::fs::HAMMER2_OBJTYPE_DIRECTORY),
+            fuser::FileType::Directory
+        );
+        assert_eq!(
+            super::get_file_type(crate::fs::HAMMER2_OBJTYPE_REGFILE),
+            fuser::FileType::RegularFile
+        );
+        assert_eq!(
+            super::get_file_type(crate::fs::HAMMER2_OBJTYPE_SOFTLINK),
+            fuser::FileType::Symlink
+        );
+    }
+}
@@ -0,0 +1,417 @@
+use crate::ErrorExt;
+use crate::device::BlockDevice;
+
+// Seed images are sparse, only chunks with non-zero bytes are kept.
+const IMAGE_SIZE: u64 = 128 << 20;
+const CHUNK_SIZE: usize = crate::subs::DEBUFSIZE;
+
+#[derive(Debug)]
+struct Seed {
+    chunks: Vec<(u64, Vec<u8>)>,
+}
+
+static SEED: std::sync::OnceLock<Result<Seed, String>> = std::sync::OnceLock::new();
+
+#[derive(Debug)]
+struct Image {
+    dev: crate::device::MemoryDevice,
+}
+
+impl Image {
+    fn new() -> crate::Result<Self> {
+        Ok(Self {
+            dev: crate::device::MemoryDevice::new(IMAGE_SIZE)?,
+        })
+    }
+
+    fn from_seed(seed: &Seed, data: &[u8]) -> crate::Result<Self> {
+        let image = Self::new()?;
+        let mut chunks = seed.chunks.clone();
+        apply_patches(&mut chunks, data);
+        for (offset, b) in &chunks {
+            image.dev.pwrite(b, *offset)?;
+        }
+        Ok(image)
+    }
+
+    fn get_devices(&self) -> Vec<Box<dyn crate::device::BlockDevice>> {
+        vec![Box::new(self.dev.clone())]
+    }
+
+    fn read_seed(&self) -> crate::Result<Seed> {
+        let chunks = self
+            .dev
+            .to_vec()?
+            .chunks(CHUNK_SIZE)
+            .zip((0..).step_by(CHUNK_SIZE))
+            .filter(|(b, _)| b.iter().any(|&x| x != 0))
+            .map(|(b, offset)| (offset, b.to_vec()))
+            .collect();
+        Ok(Seed { chunks })
+    }
+}
+
+// A filesystem with check code disabled, so that mutated media reach
+// the parser instead of failing the check code test.
+fn create_seed() -> crate::Result<Seed> {
+    let image = Image::new()?;
+    crate::newfs::Newfs::new()
+        .check_algo(crate::fs::HAMMER2_CHECK_NONE)
+        .quiet(true)
+        .format_devices(image.get_devices())?;
+    let mut pmp = crate::hammer2::Hammer2::mount_devices(image.get_devices(), "", &["--rw"])?;
+    let dinum = pmp.mkdir(crate::inode::INUM_PFS_ROOT, "d", 0o755)?;
+    let text = b"HAMMER2".repeat(40000);
+    let rand: Vec<u8> = (0..200_000u64)
+        .map(|x| (x.wrapping_mul(0x9e37_79b9_7f4a_7c15) >> 56) as u8)
+        .collect();
+    for (name, data, offset) in [
+        ("small", &b"hammer2".to_vec(), 0),
+        ("text", &text, 0),
+        ("rand", &rand, 0),
+        ("sparse", &text, 1 << 20),
+    ] {
+        let inum = pmp.create(dinum, name, 0o644)?;
+        pmp.pwrite(inum, data, offset)?;
+    }
+    pmp.create(dinum, &"x".repeat(100), 0o644)?;
+    for i in 0..50 {
+        pmp.create(crate::inode::INUM_PFS_ROOT, &format!("f{i}"), 0o644)?;
+    }
+    pmp.unmount()?;
+    image.read_seed()
+}
+
+fn get_seed() -> &'static Seed {
+    match SEED.get_or_init(|| create_seed().map_err(|e| e.to_string())) {
+        Ok(v) => v,
+        Err(e) => panic!("failed to create seed image: {e}"),
+    }
+}
+
+// Input is a sequence of (u32 offset, u8 length, bytes) records, each
+// overwriting seed bytes at the offset.  Offsets are relative to the
+// concatenated non-zero chunks excluding the volume header, so that
+// mutations land on metadata and data rather than on unused space.
+fn apply_patches(chunks: &mut [(u64, Vec<u8>)], data: &[u8]) {
+    let mut chunks: Vec<&mut Vec<u8>> = chunks
+        .iter_mut()
+        .filter(|(offset, _)| *offset >= crate::fs::HAMMER2_VOLUME_BYTES)
+        .map(|(_, b)| b)
+        .collect();
+    let total = chunks.len() * CHUNK_SIZE;
+    if total == 0 {
+        return;
+    }
+    let mut data = data;
+    while let Some((hdr, rest)) = data.split_first_chunk::<5>() {
+        let n = usize::from(hdr[4]).min(rest.len());
+        let mut offset = usize::try_from(u32::from_le_bytes([hdr[0], hdr[1], hdr[2], hdr[3]]))
+            .unwrap_or(0)
+            % total;
+        for &x in &rest[..n] {
+            chunks[offset / CHUNK_SIZE][offset % CHUNK_SIZE] = x;
+            offset = (offset + 1) % total;
+        }
+        data = &rest[n..];
+    }
+}
+
+fn get_blockref(data: &[u8]) -> (crate::fs::Hammer2Blockref, &[u8]) {
+    let n = std::mem::size_of::<crate::fs::Hammer2Blockref>();
+    let mut b = vec![0; n];
+    let m = data.len().min(n);
+    b[..m].copy_from_slice(&data[..m]);
+    let bref = match crate::fs::try_align_to::<crate::fs::Hammer2Blockref>(&b) {
+        Some(v) => *v,
+        None => crate::fs::Hammer2Blockref::new_empty(),
+    };
+    (bref, &data[m..])
+}
+
+/// Parse a volume header built from the seed image's header with `data`
+/// overwritten from the beginning.  CRC's are recomputed unless the first
+/// byte is odd, so that the parser gets past the CRC tests.
+/// # Panics
+/// Panics if the seed image can't be created.
+pub fn volume_data(data: &[u8]) {
+    let seed = get_seed();
+    let mut image = match Image::new() {
+        Ok(v) => v,
+        Err(e) => panic!("{e}"),
+    };
+    let mut buf = match seed.chunks.first() {
+        Some((0, b)) => b.clone(),
+        _ => panic!("no volume header in seed image"),
+    };
+    let n = data.len().min(buf.len());
+    buf[..n].copy_from_slice(&data[..n]);
+    if data.first().is_none_or(|x| x & 1 == 0)
+        && let Some(vd) = crate::fs::try_align_to_mut::<crate::fs::Hammer2VolumeData>(&mut buf)
+    {
+        let _ = vd.set_crc();
+    }
+    if let Err(e) = image.dev.pwrite(&buf, 0) {
+        panic!("{e}");
+    }
+    if crate::volume::read_volume_data_from(&mut image.dev, "").is_ok()
+        && let Ok(mut fso) = crate::ondisk::init_devices_quiet(image.get_devices())
+    {
+        let _ = fso.get_best_volume_data();
+    }
+}
+
+/// Parse a blockref from the first 128 bytes of `data` and its media
+/// from the rest.
+pub fn blockref(data: &[u8]) {
+    let (bref, media) = get_blockref(data);
+    let mut media = media.to_vec();
+    let _ = crate::ondisk::verify_media(&bref, &media);
+    let _ = crate::ondisk::media_as_blockref_safe(&bref, &media);
+    if crate::ondisk::check_media(&bref, &media).is_ok() {
+        let _ = crate::ondisk::media_as_blockref(&bref, &media);
+    }
+    crate::ondisk::swap_media(&bref, &mut media);
+    if crate::ondisk::check_media(&bref, &media).is_ok() {
+        let _ = crate::ondisk::media_as_blockref(&bref, &media);
+    }
+}
+
+/// Decompress a data block whose blockref is the first 128 bytes of
+/// `data` and media is the rest.
+pub fn decompress(data: &[u8]) {
+    let (mut bref, media) = get_blockref(data);
+    bref.typ = crate::fs::HAMMER2_BREF_TYPE_DATA;
+    if let Ok(mut chain) = crate::chain::Chain::new(&bref, crate::chain::CID_NONE) {
+        chain.set_data(media.to_vec());
+        let _ = chain.read_data();
+    }
+}
+
+/// Mount the seed image patched with `data` (see `apply_patches`), and
+/// read everything reachable from the PFS root.
+/// # Panics
+/// Panics if the seed image can't be created.
+pub fn mount(data: &[u8]) {
+    let image = match Image::from_seed(get_seed(), data) {
+        Ok(v) => v,
+        Err(e) => panic!("{e}"),
+    };
+    let Ok(mut pmp) = crate::hammer2::Hammer2::mount_devices(image.get_devices(), "", &[]) else {
+        return;
+    };
+    let _ = pmp.statfs();
+    let _ = pmp.list_pfs();
+    let _ = pmp.nresolve_path("/d/text");
+    let mut dirs = vec![crate::inode::INUM_PFS_ROOT];
+    let mut count = 0;
+    while let Some(dinum) = dirs.pop() {
+        let Ok(v) = pmp.readdir(dinum) else {
+            continue;
+        };
+        for dirent in v {
+            count += 1;
+            if count > 1000 {
+                break;
+            }
+            if dirent.name == "." || dirent.name == ".." {
+                continue;
+            }
+            let Ok(inum) = pmp.nresolve(dinum, &dirent.name) else {
+                continue;
+            };
+            let Ok(st) = pmp.stat(inum) else {
+                continue;
+            };
+            match dirent.typ {
+                crate::fs::HAMMER2_OBJTYPE_DIRECTORY => dirs.push(inum),
+                crate::fs::HAMMER2_OBJTYPE_SOFTLINK => {
+                    let mut buf = vec![0; crate::subs::DEBUFSIZE];
+                    let _ = pmp.readlink(inum, &mut buf);
+                }
+                _ => read_file(&pmp, inum, st.st_size),
+            }
+        }
+    }
+    let _ = pmp.unmount();
+}
+
+fn read_file(pmp: &crate::hammer2::Hammer2, inum: u64, size: u64) {
+    let mut buf = vec![0; CHUNK_SIZE];
+    let mut offset = 0;
+    let mut lbn = 0;
+    while offset < size.min(4 << 20) {
+        let _ = pmp.bmap(inum, lbn);
+        match pmp.pread(inum, &mut buf, offset) {
+            Ok(0) | Err(_) => break,
+            Ok(n) => offset += n,
+        }
+        lbn += 1;
+    }
+}
+
+fn collect_blockrefs(
+    fso: &mut crate::ondisk::Ondisk,
+    bref: &crate::fs::Hammer2Blockref,
+    v: &mut Vec<(crate::fs::Hammer2Blockref, Vec<u8>)>,
+) -> crate::Result<()> {
+    if bref.typ == crate::fs::HAMMER2_BREF_TYPE_EMPTY {
+        return Ok(());
+    }
+    if bref.get_radix()? == 0 {
+        v.push((*bref, vec![]));
+        return Ok(());
+    }
+    let media = fso.read_media(bref)?;
+    let brefs: Vec<_> = crate::ondisk::media_as_blockref_safe(bref, &media)
+        .into_iter()
+        .copied()
+        .collect();
+    for x in &brefs {
+        collect_blockrefs(fso, x, v)?;
+    }
+    v.push((*bref, media));
+    Ok(())
+}
+
+// Returns patch space offset of given volume offset, see apply_patches.
+fn get_patch_offset(seed: &Seed, offset: u64) -> Option<u32> {
+    let base = offset & !(u64::try_from(CHUNK_SIZE).ok()? - 1);
+    let i = seed
+        .chunks
+        .iter()
+        .filter(|(x, _)| *x >= crate::fs::HAMMER2_VOLUME_BYTES)
+        .position(|(x, _)| *x == base)?;
+    (i * CHUNK_SIZE + usize::try_from(offset - base).ok()?)
+        .try_into()
+        .ok()
+}
+
+/// Write initial corpus of each fuzz target under `dir`, derived from
+/// blockrefs and media of the seed image.
+/// # Errors
+pub fn write_corpus(dir: &std::path::Path) -> crate::Result<()> {
+    let seed = get_seed();
+    let mut image = Image::from_seed(seed, &[])?;
+    let mut fso = crate::ondisk::init_devices_quiet(image.get_devices())?;
+    let voldata = crate::volume::read_volume_data_from(&mut image.dev, "")?;
+    let mut v = vec![];
+    for bref in voldata
+        .sroot_blockset
+        .blockref
+        .iter()
+        .chain(&voldata.freemap_blockset.blockref)
+    {
+        collect_blockrefs(&mut fso, bref, &mut v)?;
+    }
+
+    let mut l = vec![("volume_data", libfs::cast::as_u8_slice(&voldata).to_vec())];
+    for (bref, media) in &v {
+        let mut b = libfs::cast::as_u8_slice(bref).to_vec();
+        b.extend(media);
+        if bref.typ == crate::fs::HAMMER2_BREF_TYPE_DATA {
+            l.push(("decompress", b));
+        } else {
+            l.push(("blockref", b));
+            // no-op patch at the beginning of media
+            if let Some(x) = get_patch_offset(seed, bref.get_raw_data_off()) {
+                let n = media.len().min(64);
+                let mut b = x.to_le_bytes().to_vec();
+                b.push(n.try_into().or_range()?);
+                b.extend(&media[..n]);
+                l.push(("mount", b));
+            }
+        }
+    }
+    for (i, (target, b)) in l.iter().enumerate() {
+        let d = dir.join(target);
+        std::fs::create_dir_all(&d)?;
+        std::fs::write(d.join(format!("seed{i:04}")), b)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    // xorshift64
+    fn get_random(x: &mut u64) -> u64 {
+        *x ^= *x << 13;
+        *x ^= *x >> 7;
+        *x ^= *x << 17;
+        *x
+    }
+
+    fn mutate(b: &[u8], x: &mut u64) -> Vec<u8> {
+        let mut b = b.to_vec();
+        if b.is_empty() {
+            return b;
+        }
+        for _ in 0..=get_random(x) % 8 {
+            let i = usize::try_from(get_random(x)).unwrap_or(0) % b.len();
+            b[i] = get_random(x).to_le_bytes()[0];
+        }
+        b
+    }
+
+    #[test]
+    fn test_apply_patches() {
+        let mut chunks = vec![
+            (0, vec![1; super::CHUNK_SIZE]),
+            (crate::fs::HAMMER2_VOLUME_BYTES, vec![2; super::CHUNK_SIZE]),
+            (
+                crate::fs::HAMMER2_VOLUME_BYTES * 4,
+                vec![3; super::CHUNK_SIZE],
+            ),
+        ];
+        let offset = u32::try_from(super::CHUNK_SIZE * 2 - 1).unwrap_or(0);
+        let mut data = offset.to_le_bytes().to_vec();
+        data.extend([2, 0xaa, 0xbb]);
+        data.extend([0, 0, 0, 0, 100, 0xcc]); // truncated record
+        super::apply_patches(&mut chunks, &data);
+        assert!(chunks[0].1.iter().all(|&x| x == 1));
+        assert_eq!(chunks[1].1[0], 0xcc); // 0xbb wrapped and overwritten
+        assert_eq!(chunks[1].1[1], 2);
+        assert_eq!(chunks[2].1[super::CHUNK_SIZE - 1], 0xaa);
+    }
+
+    #[test]
+    fn test_fuzz() {
+        let d = std::env::temp_dir().join(format!("libhammer2_fuzz_{}", std::process::id()));
+        if let Err(e) = super::write_corpus(&d) {
+            panic!("{e}");
+        }
+        let mut x = 0x1234_5678_9abc_def0;
+        for target in ["volume_data", "blockref", "decompress", "mount"] {
+            let v = match std::fs::read_dir(d.join(target)) {
+                Ok(v) => v,
+                Err(e) => panic!("{e}"),
+            };
+            let mut n = 0;
+            for e in v {
+                let f = match e {
+                    Ok(v) => v.path(),
+                    Err(e) => panic!("{e}"),
+                };
+                let b = match std::fs::read(&f) {
+                    Ok(v) => v,
+                    Err(e) => panic!("{e}"),
+                };
+                for _ in 0..if target == "mount" { 2 } else { 20 } {
+                    let b = mutate(&b, &mut x);
+                    match target {
+                        "volume_data" => super::volume_data(&b),
+                        "blockref" => super::blockref(&b),
+                        "decompress" => super::decompress(&b),
+                        _ => super::mount(&b),
+                    }
+                }
+                n += 1;
+            }
+            assert!(n > 0, "{target}");
+        }
+        super::mount(&[]);
+        if let Err(e) = std::fs::remove_dir_all(&d) {
+            panic!("{e}");
+        }
+    }
+}
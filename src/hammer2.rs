@@ -11,23 +11,225 @@ const RESOLVE_MASK: u32 = 0x0F;
 
 const NOOFFSET: u64 = u64::MAX;
 
+pub(crate) fn check_name(name: &str) -> nix::Result<()> {
+    if name.is_empty() || name == "." || name == ".." || name.contains(['/', '\0']) {
+        log::error!("invalid name \"{name}\"");
+        return Err(nix::errno::Errno::EINVAL);
+    }
+    if name.len() > crate::fs::HAMMER2_INODE_MAXNAME {
+        return Err(nix::errno::Errno::ENAMETOOLONG);
+    }
+    Ok(())
+}
+
+// Logical size of the block at lbase, which is a power of 2 no smaller
+// than the minimum allocation size, or HAMMER2_PBUFSIZE if not the last one.
+fn get_logical_size(size: u64, lbase: u64) -> crate::Result<usize> {
+    if size <= lbase {
+        log::error!("block at {lbase:#x} beyond size {size:#x}");
+        return Err(nix::errno::Errno::EINVAL.into());
+    }
+    let n = (size - lbase)
+        .min(crate::fs::HAMMER2_PBUFSIZE)
+        .next_power_of_two()
+        .max(u64::try_from(crate::fs::HAMMER2_ALLOC_MIN).or_range()?);
+    n.try_into().or_range()
+}
+
+// Returns compressed data and the compression algorithm used.
+// Compressed data is used only if it saves physical blocks.
+fn compress_block(data: &[u8], comp_algo: u8) -> (Vec<u8>, u8) {
+    let algo = crate::fs::dec_algo(comp_algo);
+    let b = match algo {
+        crate::fs::HAMMER2_COMP_LZ4 => crate::lz4::compress(data).ok(),
+        crate::fs::HAMMER2_COMP_ZLIB => {
+            let level = match crate::fs::dec_level(comp_algo) {
+                0 => 6, // default
+                v => v,
+            };
+            crate::zlib::compress(data, level).ok()
+        }
+        _ => None,
+    };
+    if let Some(mut b) = b {
+        let n = b
+            .len()
+            .next_power_of_two()
+            .max(crate::fs::HAMMER2_ALLOC_MIN);
+        if n < data.len() {
+            b.resize(n, 0);
+            return (b, algo);
+        }
+    }
+    (data.to_vec(), crate::fs::HAMMER2_COMP_NONE)
+}
+
+// Copy logical blocks of a file of ipsize bytes at offset to buf.
+fn read_lblocks<F: FnMut(u64) -> crate::Result<Vec<u8>>>(
+    buf: &mut [u8],
+    offset: u64,
+    ipsize: u64,
+    mut read_lblock: F,
+) -> crate::Result<u64> {
+    let mut buf = buf;
+    let mut resid = buf.len().try_into().or_range()?;
+    let start_offset = offset;
+    let mut offset = offset;
+    let mut total = 0;
+
+    while resid > 0 && offset < ipsize {
+        let lbase = offset & !crate::fs::HAMMER2_PBUFMASK;
+        let b = read_lblock(lbase)?;
+        if b.len() > crate::fs::HAMMER2_PBUFSIZE.try_into().or_range()? {
+            log::error!("{} bytes block at {lbase:#x}", b.len());
+            return Err(nix::errno::Errno::EINVAL.into());
+        }
+        let loff = offset - lbase;
+        let mut n = crate::fs::HAMMER2_PBUFSIZE - loff;
+        if n > resid {
+            n = resid;
+        }
+        if n > ipsize - offset {
+            n = ipsize - offset;
+        }
+        let i = loff.try_into().or_range()?;
+        let x = n.try_into().or_range()?;
+        // Zero-fill beyond a short block, as done for decompressed data.
+        let src = b.get(i..).unwrap_or_default();
+        let m = src.len().min(x);
+        buf[..m].copy_from_slice(&src[..m]);
+        buf[m..x].fill(0);
+        buf = &mut buf[x..];
+        total += n;
+        offset += n;
+        resid -= n;
+    }
+    if total > ipsize.saturating_sub(start_offset) {
+        log::error!("read {total:#x} bytes at {start_offset:#x} beyond size {ipsize:#x}");
+        return Err(nix::errno::Errno::EINVAL.into());
+    }
+    Ok(total)
+}
+
+// Logical block looked up under the lock of Hammer2.
+enum Lblock {
+    Data(Vec<u8>),                         // no media to read
+    Media(Box<crate::chain::Chain>, bool), // copy of data chain, hbo
+}
+
+// Read and verify media of the chain.
+// This only needs the chain itself, so that data blocks can be read
+// without holding the lock of Hammer2.
+fn read_chain_media(
+    fso: &crate::ondisk::Ondisk,
+    hbo: bool,
+    chain: &mut crate::chain::Chain,
+) -> crate::Result<()> {
+    let offset = chain.bref.get_raw_data_off();
+    if offset == 0 || chain.get_bytes() == 0 {
+        // Fine with short dirent names embedded in the blockref,
+        // but not with types which expect the data to be there.
+        return crate::ondisk::check_media(&chain.bref, &[]);
+    }
+    if chain.get_bytes() > crate::fs::HAMMER2_PBUFSIZE {
+        return Err(crate::Corruption::new_error(
+            &chain.bref,
+            0,
+            &format!("{} bytes media", chain.get_bytes()),
+        ));
+    }
+    let vol = fso.get_volume(offset).ok_or(nix::errno::Errno::ENODEV)?;
+    let mut b = vol.preadx(chain.get_bytes(), offset - vol.get_offset())?;
+    if !chain.test_check(&b)? {
+        return Err(nix::errno::Errno::EINVAL.into());
+    }
+    if !hbo {
+        crate::ondisk::swap_media(&chain.bref, &mut b);
+    }
+    crate::ondisk::check_media(&chain.bref, &b)?;
+    match chain.bref.typ {
+        crate::fs::HAMMER2_BREF_TYPE_INODE
+        | crate::fs::HAMMER2_BREF_TYPE_INDIRECT
+        | crate::fs::HAMMER2_BREF_TYPE_DATA
+        | crate::fs::HAMMER2_BREF_TYPE_FREEMAP_NODE
+        | crate::fs::HAMMER2_BREF_TYPE_FREEMAP_LEAF => {
+            chain.set_data(b);
+        }
+        crate::fs::HAMMER2_BREF_TYPE_DIRENT => {
+            chain.set_data(b);
+        }
+        crate::fs::HAMMER2_BREF_TYPE_FREEMAP | crate::fs::HAMMER2_BREF_TYPE_VOLUME => {
+            log::error!("unresolved volume header");
+            return Err(nix::errno::Errno::EINVAL.into());
+        }
+        _ => {
+            log::error!("bad blockref type {}", chain.bref.typ);
+            return Err(nix::errno::Errno::EINVAL.into());
+        }
+    }
+    Ok(())
+}
+
 #[derive(Debug)]
 pub struct Dirent {
     pub inum: u64,
     pub typ: u8,
     pub name: String,
+    pub key: u64, // offset cookie
 }
 
 impl Dirent {
-    fn new(inum: u64, typ: u8, name: &str) -> Self {
+    pub(crate) fn new(inum: u64, typ: u8, name: &str, key: u64) -> Self {
         Self {
             inum,
             typ,
             name: name.to_string(),
+            key,
         }
     }
 }
 
+#[derive(Debug)]
+pub struct Pfs {
+    pub name: String,
+    pub pfs_type: u8,
+    pub pfs_subtype: u8,
+    pub pfs_clid: uuid::Uuid,
+    pub pfs_fsid: uuid::Uuid,
+    pub pfs_inum: u64,
+    pub pfs_lsnap_tid: u64,
+}
+
+impl Pfs {
+    fn new(ipdata: &crate::fs::Hammer2InodeData) -> Self {
+        let meta = &ipdata.meta;
+        Self {
+            // Not fatal, but the PFS can't be mounted by label.
+            name: ipdata.get_filename_string().unwrap_or_else(|e| {
+                log::warn!("{e}");
+                ipdata.get_filename_string_escaped()
+            }),
+            pfs_type: meta.pfs_type,
+            pfs_subtype: meta.pfs_subtype,
+            // same as hammer2(8), see subs::get_uuid_from_str
+            pfs_clid: crate::subs::get_uuid_from_bytes(&meta.pfs_clid),
+            pfs_fsid: crate::subs::get_uuid_from_bytes(&meta.pfs_fsid),
+            pfs_inum: meta.pfs_inum,
+            pfs_lsnap_tid: meta.pfs_lsnap_tid,
+        }
+    }
+
+    #[must_use]
+    pub fn get_pfs_type_string(&self) -> &'static str {
+        crate::subs::get_pfs_type_string(self.pfs_type)
+    }
+
+    #[must_use]
+    pub fn get_pfs_subtype_string(&self) -> &'static str {
+        crate::subs::get_pfs_subtype_string(self.pfs_subtype)
+    }
+}
+
 #[cfg(target_os = "linux")]
 pub type StatMode = u32;
 #[cfg(not(target_os = "linux"))] // FreeBSD
@@ -79,18 +281,35 @@ impl CidMap {
     }
 }
 
+/// Mounted PFS.
+/// Chains and inodes are cached behind a read-write lock, so that read
+/// operations take `&self` and can be called from multiple threads.
+/// Cached data blocks are read under the shared lock, and media is
+/// read and decompressed without holding the lock.
 #[derive(Debug)]
 pub struct Hammer2 {
+    pub(crate) fso: std::sync::Arc<crate::ondisk::Ondisk>,
+    inner: std::sync::RwLock<Inner>,
+}
+
+// Filesystem state behind the lock of Hammer2.
+#[derive(Debug)]
+pub(crate) struct Inner {
     pub(crate) opt: crate::option::Opt,
-    pub(crate) fso: crate::ondisk::Ondisk,
-    pub(crate) voldata: crate::fs::Hammer2VolumeData,
+    pub(crate) fso: std::sync::Arc<crate::ondisk::Ondisk>,
+    pub(crate) voldata: Box<crate::fs::Hammer2VolumeData>, // 64KB
     pub(crate) label: String,
     pub(crate) imap: CidMap,
     pub(crate) cmap: std::collections::HashMap<crate::chain::Cid, crate::chain::Chain>,
     pub(crate) nmap: std::collections::HashMap<u64, crate::inode::Inode>,
+    pub(crate) volhdrno: usize,
+    pub(crate) alloc_hint: u64,
+    defer_media: bool,                          // see Hammer2::with_lock
+    deferred: Option<Box<crate::chain::Chain>>, // chain to read media of
+    media: Vec<crate::chain::Chain>,            // chains with media read
 }
 
-impl Drop for Hammer2 {
+impl Drop for Inner {
     fn drop(&mut self) {
         if !self.cmap.is_empty() {
             log::debug!("unmount {} on drop", self.label);
@@ -101,17 +320,29 @@ impl Drop for Hammer2 {
     }
 }
 
-impl Hammer2 {
-    fn new(fso: crate::ondisk::Ondisk, opt: crate::option::Opt) -> crate::Result<Self> {
-        let voldata = fso.read_root_volume_data()?;
+impl Inner {
+    fn new(mut fso: crate::ondisk::Ondisk, opt: crate::option::Opt) -> crate::Result<Self> {
+        let (volhdrno, voldata) = match &opt.volhdr {
+            Some(x) => fso.read_root_volume_data_with_select(x)?,
+            None => fso.read_root_volume_data_with_index()?,
+        };
+        log::debug!(
+            "volume header #{volhdrno} mirror_tid {:016x}",
+            voldata.mirror_tid
+        );
         Ok(Self {
             opt,
-            fso,
-            voldata,
+            fso: std::sync::Arc::new(fso),
+            voldata: Box::new(voldata),
             label: String::new(),
             nmap: std::collections::HashMap::new(),
             imap: CidMap::new(),
             cmap: std::collections::HashMap::new(),
+            volhdrno,
+            alloc_hint: 0,
+            defer_media: false,
+            deferred: None,
+            media: vec![],
         })
     }
 
@@ -160,27 +391,12 @@ impl Hammer2 {
             self.clear_chain_impl(ccid)?;
             let chain = self.remove_chain(cid, ccid)?;
             if chain.bref.typ == crate::fs::HAMMER2_BREF_TYPE_INODE {
-                let ipdata = chain.as_inode_data();
-                if ipdata.meta.is_pfs_root() {
-                    match ipdata.get_filename_string() {
-                        Ok(s) => {
-                            if s == self.label {
-                                assert_eq!(
-                                    self.remove_inode(ipdata.meta.inum)?.meta.inum,
-                                    ipdata.meta.inum
-                                );
-                            }
-                        }
-                        Err(e) => {
-                            log::error!("{e}");
-                            return Err(nix::errno::Errno::EINVAL);
-                        }
-                    }
-                } else {
-                    assert_eq!(
-                        self.remove_inode(ipdata.meta.inum)?.meta.inum,
-                        ipdata.meta.inum
-                    );
+                // An inode belongs to the chain it was loaded from, which
+                // may not be the only chain with the inode number on
+                // corrupted media.
+                let inum = chain.as_inode_data().meta.inum;
+                if self.nmap.get(&inum).is_some_and(|ip| ip.cid == chain.cid) {
+                    self.remove_inode(inum)?;
                 }
             }
         }
@@ -203,7 +419,7 @@ impl Hammer2 {
         Ok(cid)
     }
 
-    fn load_chain(&mut self, cid: crate::chain::Cid, how: u32) -> crate::Result<()> {
+    pub(crate) fn load_chain(&mut self, cid: crate::chain::Cid, how: u32) -> crate::Result<()> {
         // Do we have to resolve the data?  This is generally only
         // applicable to HAMMER2_BREF_TYPE_DATA which is special-cased.
         // Other blockref types expects the data to be there.
@@ -219,44 +435,20 @@ impl Hammer2 {
                 return Err(nix::errno::Errno::EINVAL.into());
             }
         }
+        let hbo = self.voldata.is_hbo()?;
         let chain = self.cmap.get_mut(&cid).or_range()?;
         if chain.has_data() {
             return Ok(());
         }
-        let offset = chain.bref.get_raw_data_off();
-        if offset == 0 {
+        if let Some(i) = self.media.iter().position(|x| x.bref == chain.bref) {
+            chain.set_data(self.media.swap_remove(i).get_data().to_vec());
             return Ok(());
         }
-        let b = self
-            .fso
-            .get_volume_mut(offset)
-            .ok_or(nix::errno::Errno::ENODEV)?
-            .preadx(chain.get_bytes(), offset)?;
-        if !chain.test_check(&b)? {
-            return Err(nix::errno::Errno::EINVAL.into());
-        }
-        match chain.bref.typ {
-            crate::fs::HAMMER2_BREF_TYPE_INODE
-            | crate::fs::HAMMER2_BREF_TYPE_INDIRECT
-            | crate::fs::HAMMER2_BREF_TYPE_DATA
-            | crate::fs::HAMMER2_BREF_TYPE_FREEMAP_NODE
-            | crate::fs::HAMMER2_BREF_TYPE_FREEMAP_LEAF => {
-                chain.set_data(b);
-            }
-            crate::fs::HAMMER2_BREF_TYPE_DIRENT => {
-                assert_ne!(chain.get_bytes(), 0);
-                chain.set_data(b);
-            }
-            crate::fs::HAMMER2_BREF_TYPE_FREEMAP | crate::fs::HAMMER2_BREF_TYPE_VOLUME => {
-                log::error!("unresolved volume header");
-                return Err(nix::errno::Errno::EINVAL.into());
-            }
-            _ => {
-                log::error!("bad blockref type {}", chain.bref.typ);
-                return Err(nix::errno::Errno::EINVAL.into());
-            }
+        if self.defer_media && chain.bref.get_raw_data_off() != 0 && chain.get_bytes() != 0 {
+            self.deferred = Some(Box::new(crate::chain::Chain::new(&chain.bref, cid)?));
+            return Err(nix::errno::Errno::EAGAIN.into());
         }
-        Ok(())
+        read_chain_media(&self.fso, hbo, chain)
     }
 
     fn repparent_chain(
@@ -289,7 +481,8 @@ impl Hammer2 {
         let mut pchain = self.cmap.get(&pcid).or_range()?;
         while pchain.bref.is_node_type() {
             let scan_beg = pchain.bref.key;
-            let scan_end = scan_beg + (1 << pchain.bref.keybits) - 1;
+            let scan_end =
+                scan_beg.wrapping_add(crate::extra::conv_keybits_to_mask(pchain.bref.keybits));
             // always !CHAIN_DELETED even if existed
             if key_beg >= scan_beg && key_end <= scan_end {
                 break;
@@ -337,7 +530,7 @@ impl Hammer2 {
                 return Ok((
                     pcid,
                     self.cmap.get(&pcid).or_range()?.cid,
-                    key_end + 1,
+                    key_end.wrapping_add(1),
                     u64::MAX,
                 ));
             }
@@ -358,7 +551,11 @@ impl Hammer2 {
             }
             // Calculate next key, stop if we reached the end of the
             // iteration, otherwise go up one level and loop.
-            let key_beg = pchain.bref.key + (1 << pchain.bref.keybits);
+            let key_beg = pchain
+                .bref
+                .key
+                .wrapping_add(crate::extra::conv_keybits_to_mask(pchain.bref.keybits))
+                .wrapping_add(1);
             if key_beg == 0 || key_beg > key_end {
                 return Ok((pcid, crate::chain::CID_NONE, key_next, u64::MAX));
             }
@@ -394,11 +591,49 @@ impl Hammer2 {
         // If the chain element is an indirect block it becomes the new
         // parent and we loop on it.
         if self.cmap.get(&cid).or_range()?.bref.is_node_type() {
+            self.check_node_chain(pcid, cid)?;
             return Ok((cid, crate::chain::CID_NONE, u64::MAX, key_beg));
         }
         Ok((pcid, cid, key_next, u64::MAX))
     }
 
+    // Corrupted media may have indirect blocks which loop back to
+    // themselves, so nodes must be enclosed by the parent node and
+    // can't nest deeper than the key has bits.
+    fn check_node_chain(
+        &self,
+        pcid: crate::chain::Cid,
+        cid: crate::chain::Cid,
+    ) -> crate::Result<()> {
+        let bref = &self.cmap.get(&cid).or_range()?.bref;
+        let mut pchain = self.cmap.get(&pcid).or_range()?;
+        if pchain.bref.is_node_type()
+            && (bref.key < pchain.bref.key || bref.get_key_end() > pchain.bref.get_key_end())
+        {
+            return Err(crate::Corruption::new_error(
+                bref,
+                0,
+                &format!(
+                    "key range {:016x}/{} not enclosed by parent {:016x}/{}",
+                    bref.key, bref.keybits, pchain.bref.key, pchain.bref.keybits
+                ),
+            ));
+        }
+        let mut depth = 1;
+        while pchain.bref.is_node_type() {
+            depth += 1;
+            if depth > u64::BITS {
+                return Err(crate::Corruption::new_error(
+                    bref,
+                    0,
+                    &format!("nodes nested deeper than {}", u64::BITS),
+                ));
+            }
+            pchain = self.cmap.get(&pchain.pcid).or_range()?;
+        }
+        Ok(())
+    }
+
     /// # Errors
     pub fn get_next_chain(
         &mut self,
@@ -419,7 +654,11 @@ impl Hammer2 {
                 return Ok((pcid, crate::chain::CID_NONE, u64::MAX));
             }
             let chain = self.cmap.get(&cid).or_range()?;
-            let key_beg = chain.bref.key + (1 << chain.bref.keybits);
+            let key_beg = chain
+                .bref
+                .key
+                .wrapping_add(crate::extra::conv_keybits_to_mask(chain.bref.keybits))
+                .wrapping_add(1);
             if key_beg == 0 || key_beg > key_end {
                 return Ok((pcid, crate::chain::CID_NONE, u64::MAX));
             }
@@ -433,7 +672,11 @@ impl Hammer2 {
             //
             // (This also handles the case of a deleted, empty indirect
             // node).
-            let key_beg = pchain.bref.key + (1 << pchain.bref.keybits);
+            let key_beg = pchain
+                .bref
+                .key
+                .wrapping_add(crate::extra::conv_keybits_to_mask(pchain.bref.keybits))
+                .wrapping_add(1);
             if key_beg == 0 || key_beg > key_end {
                 return Ok((pcid, crate::chain::CID_NONE, u64::MAX));
             }
@@ -448,10 +691,10 @@ impl Hammer2 {
         pcid: crate::chain::Cid,
         key_beg: u64,
         key_end: u64,
-    ) -> nix::Result<(u64, usize, u64)> {
-        let pchain = self.cmap.get_mut(&pcid).or_nix_range()?;
+    ) -> crate::Result<(u64, usize, u64)> {
+        let pchain = self.cmap.get_mut(&pcid).or_range()?;
         // Lookup in block array.
-        let (i, key_next) = pchain.find_blockref(key_end + 1, key_beg)?;
+        let (i, key_next) = pchain.find_blockref(key_end.wrapping_add(1), key_beg)?;
         // Lookup in chain.
         let base = pchain.as_blockref()?;
         if let Some((x, key_next)) = pchain.find_child(key_next, key_beg, key_end)? {
@@ -504,6 +747,236 @@ impl Hammer2 {
         }
     }
 
+    // Mark the chain and its parents up to the volume header modified.
+    // The chain data must be resolved as it's written on flush.
+    pub(crate) fn modify_chain(&mut self, cid: crate::chain::Cid) -> crate::Result<()> {
+        if !self.opt.rw {
+            return Err(nix::errno::Errno::EROFS.into());
+        }
+        self.load_chain(cid, RESOLVE_ALWAYS)?;
+        let mut cid = cid;
+        loop {
+            let chain = self.cmap.get_mut(&cid).or_range()?;
+            if chain.is_modified() {
+                break;
+            }
+            chain.set_flags(crate::chain::CHAIN_MODIFIED);
+            if cid == crate::chain::CID_VCHAIN || cid == crate::chain::CID_FCHAIN {
+                break;
+            }
+            cid = chain.pcid;
+            assert_ne!(cid, crate::chain::CID_NONE);
+        }
+        Ok(())
+    }
+
+    // Returns an in-memory chain for the blockref in the parent's block
+    // table, loading it if necessary.
+    fn get_child_chain(
+        &mut self,
+        pcid: crate::chain::Cid,
+        bref: &crate::fs::Hammer2Blockref,
+    ) -> crate::Result<crate::chain::Cid> {
+        let pchain = self.cmap.get(&pcid).or_range()?;
+        match pchain.get_child_by_key(bref.key, bref.keybits) {
+            Some(cid) => {
+                self.load_chain(cid, RESOLVE_ALWAYS)?;
+                Ok(cid)
+            }
+            None => self.set_chain(pcid, bref, RESOLVE_ALWAYS),
+        }
+    }
+
+    // Create a new chain with the given blockref and data under the parent,
+    // descending into indirect blocks which enclose the key range.
+    // The parent's block table is split if it's full.
+    pub(crate) fn create_chain(
+        &mut self,
+        pcid: crate::chain::Cid,
+        bref: &crate::fs::Hammer2Blockref,
+        data: Vec<u8>,
+    ) -> crate::Result<crate::chain::Cid> {
+        let key_beg = bref.key;
+        let key_end = bref.get_key_end();
+        let mut pcid = pcid;
+        loop {
+            self.load_chain(pcid, RESOLVE_ALWAYS)?;
+            let pchain = self.cmap.get(&pcid).or_range()?;
+            let base = pchain.get_blockref_array()?;
+            if let Some(x) = base.iter().find(|x| x.is_key_overlapped(key_beg, key_end)) {
+                if x.is_node_type() && x.key <= key_beg && x.get_key_end() >= key_end {
+                    pcid = self.get_child_chain(pcid, x)?;
+                    continue;
+                }
+                log::error!("{bref} overlaps {x}");
+                return Err(nix::errno::Errno::EEXIST.into());
+            }
+            let (_, count) =
+                crate::ondisk::media_as_blockref_range(&pchain.bref, pchain.get_data())?;
+            if base.len() < count {
+                break;
+            }
+            self.split_chain(pcid)?;
+        }
+        let mut chain = crate::chain::Chain::new(bref, self.alloc_cid()?)?;
+        chain.set_data(data);
+        chain.set_flags(crate::chain::CHAIN_MODIFIED);
+        let cid = chain.cid;
+        self.add_chain(pcid, chain)?;
+        let pchain = self.cmap.get_mut(&pcid).or_range()?;
+        let mut base = pchain.get_blockref_array()?;
+        base.push(*bref);
+        pchain.set_blockref_array(&base)?;
+        self.modify_chain(pcid)?;
+        Ok(cid)
+    }
+
+    // Move a half of the parent's full block table to a new indirect block.
+    fn split_chain(&mut self, pcid: crate::chain::Cid) -> crate::Result<()> {
+        let pchain = self.cmap.get(&pcid).or_range()?;
+        let base = pchain.get_blockref_array()?;
+        if base.len() < 2 {
+            log::error!("{} entries in {}", base.len(), pchain.bref);
+            return Err(nix::errno::Errno::EINVAL.into());
+        }
+        let is_freemap = matches!(
+            pchain.bref.typ,
+            crate::fs::HAMMER2_BREF_TYPE_FREEMAP | crate::fs::HAMMER2_BREF_TYPE_FREEMAP_NODE
+        );
+        // Number of bits which encloses all the entries.
+        let lo = base.iter().map(|x| x.key).min().or_range()?;
+        let hi = base
+            .iter()
+            .map(crate::fs::Hammer2Blockref::get_key_end)
+            .max()
+            .or_range()?;
+        let bits = u64::BITS - (lo ^ hi).leading_zeros();
+        let bits = u8::try_from(bits).or_range()?;
+        // Freemap nodes must be on a freemap level boundary.
+        let keybits = if is_freemap {
+            let mut keybits = None;
+            for radix in [
+                crate::fs::HAMMER2_FREEMAP_LEVEL2_RADIX,
+                crate::fs::HAMMER2_FREEMAP_LEVEL3_RADIX,
+                crate::fs::HAMMER2_FREEMAP_LEVEL4_RADIX,
+                crate::fs::HAMMER2_FREEMAP_LEVEL5_RADIX,
+            ] {
+                let radix = u8::try_from(radix).or_range()?;
+                if radix >= bits - 1 {
+                    keybits = Some(radix);
+                    break;
+                }
+            }
+            keybits.or_range()?
+        } else {
+            bits - 1
+        };
+        // Take the half with more entries, or the whole range.
+        let mask = crate::extra::conv_keybits_to_mask(keybits);
+        let key = if keybits >= bits {
+            lo & !mask
+        } else {
+            let lokey = lo & !crate::extra::conv_keybits_to_mask(bits);
+            let hikey = lokey + mask + 1;
+            let locount = base.iter().filter(|x| x.key < hikey).count();
+            if locount >= base.len() - locount {
+                lokey
+            } else {
+                hikey
+            }
+        };
+        let (inner, mut outer): (Vec<_>, Vec<_>) = base
+            .into_iter()
+            .partition(|x| x.key >= key && x.get_key_end() <= key + mask);
+        // Allocate the new node.
+        let (typ, bytes, methods) = if is_freemap {
+            (
+                crate::fs::HAMMER2_BREF_TYPE_FREEMAP_NODE,
+                crate::fs::HAMMER2_FREEMAP_LEVELN_PSIZE,
+                crate::fs::enc_check(crate::fs::HAMMER2_CHECK_FREEMAP)
+                    | crate::fs::enc_comp(crate::fs::HAMMER2_COMP_NONE),
+            )
+        } else {
+            let check = if pchain.bref.typ == crate::fs::HAMMER2_BREF_TYPE_INODE {
+                crate::fs::dec_algo(pchain.as_inode_data().meta.check_algo)
+            } else {
+                crate::fs::dec_check(pchain.bref.methods)
+            };
+            (
+                crate::fs::HAMMER2_BREF_TYPE_INDIRECT,
+                crate::fs::HAMMER2_IND_BYTES_NOM,
+                crate::fs::enc_check(check) | crate::fs::enc_comp(crate::fs::HAMMER2_COMP_NONE),
+            )
+        };
+        let mut bref = crate::fs::Hammer2Blockref::new(typ);
+        bref.methods = methods;
+        bref.keybits = keybits;
+        bref.key = key;
+        bref.data_off = bytes.trailing_zeros().into();
+        let mut chain = crate::chain::Chain::new(&bref, self.alloc_cid()?)?;
+        chain.set_data(vec![0; bytes.try_into().or_range()?]);
+        chain.set_blockref_array(&inner)?;
+        chain.set_flags(crate::chain::CHAIN_MODIFIED);
+        let cid = chain.cid;
+        log::debug!(
+            "split {} into {}",
+            self.cmap.get(&pcid).or_range()?.bref,
+            bref
+        );
+        // Reparent in-memory children.
+        for ccid in self.cmap.get(&pcid).or_range()?.get_child() {
+            let x = &self.cmap.get(&ccid).or_range()?.bref;
+            if x.key >= key && x.get_key_end() <= key + mask {
+                let mut child = self.remove_chain(pcid, ccid)?;
+                child.pcid = cid;
+                chain.add_child(&child);
+                assert!(self.cmap.insert(ccid, child).is_none());
+            }
+        }
+        self.add_chain(pcid, chain)?;
+        outer.push(bref);
+        self.cmap
+            .get_mut(&pcid)
+            .or_range()?
+            .set_blockref_array(&outer)?;
+        self.modify_chain(pcid)
+    }
+
+    // Delete the chain from the parent's block table along with its
+    // in-memory children.  Indirect blocks which become empty are deleted.
+    pub(crate) fn delete_chain(&mut self, cid: crate::chain::Cid) -> crate::Result<()> {
+        let chain = self.cmap.get(&cid).or_range()?;
+        let pcid = chain.pcid;
+        let bref = chain.bref;
+        self.remove_chain_recursive(pcid, cid)?;
+        self.load_chain(pcid, RESOLVE_ALWAYS)?;
+        let pchain = self.cmap.get_mut(&pcid).or_range()?;
+        let mut base = pchain.get_blockref_array()?;
+        let n = base.len();
+        base.retain(|x| x.key != bref.key || x.keybits != bref.keybits);
+        if base.len() == n {
+            log::error!("{bref} not found in {}", pchain.bref);
+            return Err(nix::errno::Errno::ENOENT.into());
+        }
+        pchain.set_blockref_array(&base)?;
+        if base.is_empty() && pchain.bref.is_node_type() && !pchain.has_child() {
+            return self.delete_chain(pcid);
+        }
+        self.modify_chain(pcid)
+    }
+
+    fn remove_chain_recursive(
+        &mut self,
+        pcid: crate::chain::Cid,
+        cid: crate::chain::Cid,
+    ) -> nix::Result<()> {
+        while let Some(ccid) = self.cmap.get(&cid).or_nix_range()?.get_first_child() {
+            self.remove_chain_recursive(cid, ccid)?;
+        }
+        self.remove_chain(pcid, cid)?;
+        Ok(())
+    }
+
     /// # Errors
     pub fn dump_inode_chain(&self, ip: &crate::inode::Inode) -> crate::Result<()> {
         self.dump_chain(ip.cid)
@@ -674,7 +1147,10 @@ impl Hammer2 {
 
     fn set_inode_from_xop(&mut self, head: &crate::xop::XopHeader) -> nix::Result<(u64, bool)> {
         let chain = self.cmap.get(&head.collect()?).or_nix_range()?;
-        assert_eq!(chain.bref.typ, crate::fs::HAMMER2_BREF_TYPE_INODE);
+        if chain.bref.typ != crate::fs::HAMMER2_BREF_TYPE_INODE {
+            log::error!("bad blockref type {}", chain.bref.typ);
+            return Err(nix::errno::Errno::EINVAL);
+        }
         let ipdata = chain.as_inode_data();
         let inum = ipdata.meta.inum;
         if self.nmap.contains_key(&inum) {
@@ -715,6 +1191,25 @@ impl Hammer2 {
         Ok((pcid, cid))
     }
 
+    // Parent of an inode, which may not be loaded yet.
+    fn get_iparent(&mut self, inum: u64) -> crate::Result<u64> {
+        if let Some(ip) = self.nmap.get(&inum) {
+            return Ok(ip.meta.iparent);
+        }
+        let (_, cid) = self.find_inode_chain(inum)?;
+        if cid == crate::chain::CID_NONE {
+            log::error!("inode {inum:016x} not found");
+            return Err(nix::errno::Errno::ENOENT.into());
+        }
+        self.load_chain(cid, RESOLVE_ALWAYS)?;
+        let chain = self.cmap.get(&cid).or_range()?;
+        if chain.bref.typ != crate::fs::HAMMER2_BREF_TYPE_INODE {
+            log::error!("bad blockref type {}", chain.bref.typ);
+            return Err(nix::errno::Errno::EINVAL.into());
+        }
+        Ok(chain.as_inode_data().meta.iparent)
+    }
+
     fn find_inode_chain(
         &mut self,
         inum: u64,
@@ -824,7 +1319,18 @@ impl Hammer2 {
         if cid == crate::chain::CID_NONE {
             return Err(nix::errno::Errno::ENOENT.into());
         }
-        arg.offset = self.cmap.get(&cid).or_range()?.bref.data_off;
+        let bref = &self.cmap.get(&cid).or_range()?.bref;
+        arg.offset = bref.data_off;
+        if arg.offset == crate::fs::HAMMER2_OFF_MASK
+            || arg.offset == NOOFFSET
+            || crate::extra::conv_offset_to_radix(arg.offset)? == 0
+        {
+            return Err(crate::Corruption::new_error(
+                bref,
+                0,
+                &format!("bad data offset {:#x}", arg.offset),
+            ));
+        }
         arg.head.feed(cid);
         Ok(())
     }
@@ -840,11 +1346,11 @@ impl Hammer2 {
         }
         arg.head.feed(cid);
         let chain = self.cmap.get_mut(&arg.head.collect()?).or_range()?;
-        Ok(if self.opt.nodatacache {
+        if self.opt.nodatacache {
             chain.read_data()
         } else {
             chain.read_cache_data()
-        }?)
+        }
     }
 
     /// # Errors
@@ -885,11 +1391,12 @@ impl Hammer2 {
             return Err(nix::errno::Errno::ENOTDIR.into());
         }
         let mut v = vec![
-            Dirent::new(ip.meta.inum, ip.meta.typ, "."),
+            Dirent::new(ip.meta.inum, ip.meta.typ, ".", 0),
             Dirent::new(
                 ip.meta.iparent & crate::fs::HAMMER2_DIRHASH_USERMSK,
                 crate::fs::HAMMER2_OBJTYPE_DIRECTORY,
                 "..",
+                1,
             ),
         ];
         let mut arg = crate::xop::XopReaddir::new(dinum, 2 | crate::fs::HAMMER2_DIRHASH_VISIBLE);
@@ -900,39 +1407,71 @@ impl Hammer2 {
             Err(e) => return Err(e.into()),
         };
         for cid in &dirents {
-            let chain = self.cmap.get(cid).or_range()?;
-            match chain.bref.typ {
-                crate::fs::HAMMER2_BREF_TYPE_INODE => {
-                    let ipdata = chain.as_inode_data();
-                    if let Some(s) = chain.get_name() {
-                        v.push(Dirent::new(
-                            ipdata.meta.inum & crate::fs::HAMMER2_DIRHASH_USERMSK,
-                            ipdata.meta.typ,
-                            &s,
-                        ));
-                    } else {
-                        return Err(nix::errno::Errno::EINVAL.into());
-                    }
-                }
-                crate::fs::HAMMER2_BREF_TYPE_DIRENT => {
-                    let dirent = chain.bref.embed_as::<crate::fs::Hammer2DirentHead>();
-                    if let Some(s) = chain.get_name() {
-                        v.push(Dirent::new(dirent.inum, dirent.typ, &s));
-                    } else {
-                        return Err(nix::errno::Errno::EINVAL.into());
-                    }
+            v.push(self.get_dirent(*cid)?);
+        }
+        Ok(v)
+    }
+
+    fn get_dirent(&self, cid: crate::chain::Cid) -> crate::Result<Dirent> {
+        let chain = self.cmap.get(&cid).or_range()?;
+        match chain.bref.typ {
+            crate::fs::HAMMER2_BREF_TYPE_INODE => {
+                let ipdata = chain.as_inode_data();
+                if let Some(s) = chain.get_name() {
+                    Ok(Dirent::new(
+                        ipdata.meta.inum & crate::fs::HAMMER2_DIRHASH_USERMSK,
+                        ipdata.meta.typ,
+                        &s,
+                        chain.bref.key,
+                    ))
+                } else {
+                    Err(nix::errno::Errno::EINVAL.into())
                 }
-                _ => {
-                    log::error!("bad blockref type {}", chain.bref.typ);
-                    return Err(nix::errno::Errno::EINVAL.into());
+            }
+            crate::fs::HAMMER2_BREF_TYPE_DIRENT => {
+                let dirent = chain.bref.embed_as::<crate::fs::Hammer2DirentHead>();
+                if let Some(s) = chain.get_name() {
+                    Ok(Dirent::new(dirent.inum, dirent.typ, &s, chain.bref.key))
+                } else {
+                    Err(nix::errno::Errno::EINVAL.into())
                 }
             }
+            _ => {
+                log::error!("bad blockref type {}", chain.bref.typ);
+                Err(nix::errno::Errno::EINVAL.into())
+            }
         }
-        Ok(v)
+    }
+
+    // Return the first visible directory entry whose key is >= lkey.
+    // Unlike readdir, the entry chain is released unless it's in use.
+    pub(crate) fn readdir_next(&mut self, dinum: u64, lkey: u64) -> crate::Result<Option<Dirent>> {
+        let pcid = self.get_inode_chain(dinum, RESOLVE_ALWAYS)?;
+        if pcid == crate::chain::CID_NONE {
+            return Err(nix::errno::Errno::EIO.into());
+        }
+        let lkey = lkey | crate::fs::HAMMER2_DIRHASH_VISIBLE;
+        let (pcid, cid, _) =
+            self.lookup_chain(pcid, lkey, crate::fs::HAMMER2_KEY_MAX, LOOKUP_ALWAYS)?;
+        if cid == crate::chain::CID_NONE {
+            return Ok(None);
+        }
+        let dirent = self.get_dirent(cid)?;
+        let chain = self.cmap.get(&cid).or_range()?;
+        let in_use = chain.is_modified()
+            || chain.has_child()
+            || (chain.bref.typ == crate::fs::HAMMER2_BREF_TYPE_INODE
+                && self
+                    .nmap
+                    .get(&chain.as_inode_data().meta.inum)
+                    .is_some_and(|ip| ip.cid == cid));
+        if !in_use {
+            self.remove_chain(pcid, cid)?;
+        }
+        Ok(Some(dirent))
     }
 
     /// # Errors
-    /// # Panics
     pub fn bmap(&mut self, inum: u64, lbn: u64) -> crate::Result<u64> {
         let mut arg = crate::xop::XopBmap::new(inum, lbn);
         if let Err(e) = self.xop_bmap(&mut arg) {
@@ -941,9 +1480,6 @@ impl Hammer2 {
                 _ => return Err(e),
             }
         }
-        assert_ne!(arg.offset, crate::fs::HAMMER2_OFF_MASK);
-        assert_ne!(arg.offset, NOOFFSET);
-        assert_ne!(crate::extra::conv_offset_to_radix(arg.offset)?, 0);
         Ok(arg.offset)
     }
 
@@ -956,8 +1492,7 @@ impl Hammer2 {
         self.pread_impl(inum, buf, 0)
     }
 
-    /// # Errors
-    pub fn pread(&mut self, inum: u64, buf: &mut [u8], offset: u64) -> crate::Result<u64> {
+    fn get_regfile_size(&self, inum: u64) -> crate::Result<u64> {
         let ip = self.nmap.get(&inum).or_range()?;
         if ip.meta.typ == crate::fs::HAMMER2_OBJTYPE_DIRECTORY {
             return Err(nix::errno::Errno::EISDIR.into());
@@ -965,78 +1500,618 @@ impl Hammer2 {
         if ip.meta.typ != crate::fs::HAMMER2_OBJTYPE_REGFILE {
             return Err(nix::errno::Errno::EINVAL.into());
         }
-        self.pread_impl(inum, buf, offset)
+        Ok(ip.meta.size)
     }
 
-    fn pread_impl(&mut self, inum: u64, buf: &mut [u8], offset: u64) -> crate::Result<u64> {
-        let mut buf = buf;
-        let mut resid = buf.len().try_into().or_range()?;
-        let start_offset = offset;
-        let mut offset = offset;
-        let mut total = 0;
-        let ipsize = self.nmap.get(&inum).or_range()?.meta.size;
+    // Read a logical block at lbase, which may be shorter than
+    // HAMMER2_PBUFSIZE (e.g. direct data or the last block).
+    pub(crate) fn read_lblock(&mut self, inum: u64, lbase: u64) -> crate::Result<Vec<u8>> {
+        let mut arg = crate::xop::XopRead::new(inum, lbase);
+        self.xop_read(&mut arg)
+    }
 
-        while resid > 0 && offset < ipsize {
-            let lbase = offset & !crate::fs::HAMMER2_PBUFMASK;
-            let mut arg = crate::xop::XopRead::new(inum, lbase);
-            let b = self.xop_read(&mut arg)?;
-            assert!(b.len() <= crate::fs::HAMMER2_PBUFSIZE.try_into().or_range()?);
-            let loff = offset - lbase;
-            let mut n = crate::fs::HAMMER2_PBUFSIZE - loff;
-            if n > resid {
-                n = resid;
-            }
-            if n > ipsize - offset {
-                n = ipsize - offset;
-            }
-            let i = loff.try_into().or_range()?;
-            let x = n.try_into().or_range()?;
-            buf[..x].copy_from_slice(&b[i..i + x]);
-            buf = &mut buf[x..];
-            total += n;
-            offset += n;
-            resid -= n;
+    // Same as read_lblock, but returns a copy of the data chain instead
+    // if its media hasn't been read yet.
+    fn lookup_lblock(&mut self, inum: u64, lbase: u64) -> crate::Result<Lblock> {
+        let pcid = self.get_inode_chain(inum, RESOLVE_ALWAYS)?;
+        if pcid == crate::chain::CID_NONE {
+            return Err(nix::errno::Errno::EIO.into());
+        }
+        let (_, cid, _) = self.lookup_chain(pcid, lbase, lbase, 0)?;
+        if cid == crate::chain::CID_NONE {
+            return Ok(Lblock::Data(vec![
+                0;
+                crate::fs::HAMMER2_PBUFSIZE
+                    .try_into()
+                    .or_range()?
+            ]));
+        }
+        let hbo = self.voldata.is_hbo()?;
+        let chain = self.cmap.get_mut(&cid).or_range()?;
+        if chain.bref.typ == crate::fs::HAMMER2_BREF_TYPE_DATA && !chain.has_data() {
+            return Ok(Lblock::Media(
+                Box::new(crate::chain::Chain::new(&chain.bref, cid)?),
+                hbo,
+            ));
         }
-        assert!(total <= ipsize - start_offset);
-        Ok(total)
+        Ok(Lblock::Data(if self.opt.nodatacache {
+            chain.read_data()
+        } else {
+            chain.read_cache_data()
+        }?))
     }
 
-    fn init_vchain(&mut self) -> nix::Result<()> {
-        let mut bref = crate::fs::Hammer2Blockref::new(crate::fs::HAMMER2_BREF_TYPE_VOLUME);
-        bref.data_off = crate::fs::HAMMER2_PBUFRADIX.try_into().or_nix_range()?;
-        bref.mirror_tid = self.voldata.mirror_tid;
-        bref.modify_tid = bref.mirror_tid;
-
-        let mut chain = crate::chain::Chain::new(&bref, crate::chain::CID_VCHAIN)?;
-        chain.set_data(libfs::cast::as_u8_slice(&self.voldata).to_vec());
-        assert!(!self.cmap.contains_key(&chain.cid));
-        assert!(self.cmap.insert(chain.cid, chain).is_none());
-        assert!(self.cmap.contains_key(&crate::chain::CID_VCHAIN));
-        Ok(())
+    // Same as lookup_lblock, but only returns data already cached,
+    // which doesn't modify chains and is fine with the shared lock.
+    fn find_cached_lblock(&self, inum: u64, lbase: u64) -> Option<Vec<u8>> {
+        if self.opt.nodatacache {
+            return None;
+        }
+        let mut chain = self.cmap.get(&self.nmap.get(&inum)?.cid)?;
+        if !chain.has_data() || chain.as_inode_data().meta.has_direct_data() {
+            return None;
+        }
+        loop {
+            chain = self.cmap.get(&chain.get_child_by_range(lbase)?)?;
+            if !chain.bref.is_node_type() {
+                break;
+            }
+        }
+        (chain.bref.typ == crate::fs::HAMMER2_BREF_TYPE_DATA
+            && chain.bref.key == lbase
+            && chain.has_udata())
+        .then(|| chain.get_udata().to_vec())
     }
 
-    fn remove_vchain(&mut self) -> nix::Result<()> {
-        if self.cmap.remove(&crate::chain::CID_VCHAIN).is_some() {
-            Ok(())
-        } else {
-            Err(nix::errno::Errno::ENOENT)
+    // Cache media and data read by lookup_lblock caller, unless the chain
+    // has been resolved or replaced meanwhile.
+    fn cache_lblock(&mut self, chain: crate::chain::Chain, data: &[u8]) {
+        let nodatacache = self.opt.nodatacache;
+        if let Some(x) = self.cmap.get_mut(&chain.cid)
+            && x.bref == chain.bref
+            && !x.has_data()
+        {
+            x.set_data(chain.get_data().to_vec());
+            if !nodatacache {
+                x.set_udata(data.to_vec());
+            }
         }
     }
 
-    fn init_fchain(&mut self) -> nix::Result<()> {
-        let mut bref = crate::fs::Hammer2Blockref::new(crate::fs::HAMMER2_BREF_TYPE_FREEMAP);
-        bref.data_off = crate::fs::HAMMER2_PBUFRADIX.try_into().or_nix_range()?;
-        bref.mirror_tid = self.voldata.mirror_tid;
-        bref.modify_tid = bref.mirror_tid;
-        bref.methods = crate::fs::enc_check(crate::fs::HAMMER2_CHECK_FREEMAP)
-            | crate::fs::enc_comp(crate::fs::HAMMER2_COMP_NONE);
-
-        let mut chain = crate::chain::Chain::new(&bref, crate::chain::CID_FCHAIN)?;
-        chain.set_data(libfs::cast::as_u8_slice(&self.voldata).to_vec());
-        assert!(!self.cmap.contains_key(&chain.cid));
-        assert!(self.cmap.insert(chain.cid, chain).is_none());
-        assert!(self.cmap.contains_key(&crate::chain::CID_FCHAIN));
-        Ok(())
+    fn pread_impl(&mut self, inum: u64, buf: &mut [u8], offset: u64) -> crate::Result<u64> {
+        let ipsize = self.nmap.get(&inum).or_range()?.meta.size;
+        read_lblocks(buf, offset, ipsize, |lbase| self.read_lblock(inum, lbase))
+    }
+
+    // Modify inode meta of both the inode chain and the in-memory inode.
+    pub(crate) fn modify_inode_meta<F: FnOnce(&mut crate::fs::Hammer2InodeMeta)>(
+        &mut self,
+        inum: u64,
+        f: F,
+    ) -> crate::Result<()> {
+        let cid = self.get_inode_chain(inum, RESOLVE_ALWAYS)?;
+        if cid == crate::chain::CID_NONE {
+            return Err(nix::errno::Errno::EIO.into());
+        }
+        self.modify_chain(cid)?;
+        let chain = self.cmap.get_mut(&cid).or_range()?;
+        let meta = &mut chain.as_inode_data_mut().meta;
+        f(meta);
+        let meta = *meta;
+        self.nmap.get_mut(&inum).or_range()?.meta = meta;
+        Ok(())
+    }
+
+    fn alloc_inum(&mut self) -> crate::Result<u64> {
+        let pcid = self.get_inode_chain(crate::inode::INUM_PFS_ROOT, RESOLVE_ALWAYS)?;
+        if pcid == crate::chain::CID_NONE {
+            return Err(nix::errno::Errno::EIO.into());
+        }
+        let mut inum = self
+            .cmap
+            .get(&pcid)
+            .or_range()?
+            .as_inode_data()
+            .meta
+            .pfs_inum
+            + 1;
+        loop {
+            if (inum & crate::fs::HAMMER2_DIRHASH_VISIBLE) != 0 {
+                log::error!("inum exhausted");
+                return Err(nix::errno::Errno::ENOSPC.into());
+            }
+            let (_, cid, _) = self.lookup_chain(pcid, inum, inum, 0)?;
+            if cid == crate::chain::CID_NONE {
+                break;
+            }
+            inum += 1;
+        }
+        self.modify_inode_meta(crate::inode::INUM_PFS_ROOT, |meta| meta.pfs_inum = inum)?;
+        Ok(inum)
+    }
+
+    pub(crate) fn create_dirent(
+        &mut self,
+        dinum: u64,
+        name: &str,
+        inum: u64,
+        typ: u8,
+    ) -> crate::Result<()> {
+        let dcid = self.get_inode_chain(dinum, RESOLVE_ALWAYS)?;
+        if dcid == crate::chain::CID_NONE {
+            return Err(nix::errno::Errno::EIO.into());
+        }
+        let check_algo = self
+            .cmap
+            .get(&dcid)
+            .or_range()?
+            .as_inode_data()
+            .meta
+            .check_algo;
+        // Find an unused key in the directory hash collision space.
+        let mut lhc = crate::subs::dirhash(name.as_bytes());
+        loop {
+            let (_, cid, _) = self.lookup_chain(dcid, lhc, lhc, 0)?;
+            if cid == crate::chain::CID_NONE {
+                break;
+            }
+            if (lhc & crate::fs::HAMMER2_DIRHASH_LOMASK) == crate::fs::HAMMER2_DIRHASH_LOMASK {
+                log::error!("dirhash collision space exhausted for {name}");
+                return Err(nix::errno::Errno::ENOSPC.into());
+            }
+            lhc += 1;
+        }
+        let mut bref = crate::fs::Hammer2Blockref::new(crate::fs::HAMMER2_BREF_TYPE_DIRENT);
+        bref.key = lhc;
+        let dirent = bref.embed_as_mut::<crate::fs::Hammer2DirentHead>();
+        dirent.inum = inum;
+        dirent.namlen = name.len().try_into().or_range()?;
+        dirent.typ = typ;
+        // Short names are embedded in the blockref.
+        let n = name.len();
+        let data = if n <= bref.check.len() {
+            bref.check[..n].copy_from_slice(name.as_bytes());
+            vec![]
+        } else {
+            bref.methods = crate::fs::enc_check(crate::fs::dec_algo(check_algo))
+                | crate::fs::enc_comp(crate::fs::HAMMER2_COMP_NONE);
+            bref.data_off = crate::fs::HAMMER2_RADIX_MIN.try_into().or_range()?;
+            let mut v = vec![0; crate::fs::HAMMER2_ALLOC_MIN];
+            v[..n].copy_from_slice(name.as_bytes());
+            v
+        };
+        self.create_chain(dcid, &bref, data)?;
+        Ok(())
+    }
+
+    fn check_modify_dir(&self, dinum: u64, name: &str) -> crate::Result<()> {
+        if !self.opt.rw {
+            return Err(nix::errno::Errno::EROFS.into());
+        }
+        // Only allow mounted PFS to avoid inum collision.
+        if dinum == crate::inode::INUM_SUP_ROOT {
+            return Err(nix::errno::Errno::EINVAL.into());
+        }
+        if !self.nmap.get(&dinum).or_range()?.is_directory() {
+            return Err(nix::errno::Errno::ENOTDIR.into());
+        }
+        Ok(check_name(name)?)
+    }
+
+    // Returns the directory entry chain of the name, not the inode chain.
+    fn lookup_dirent_chain(&mut self, dinum: u64, name: &str) -> crate::Result<crate::chain::Cid> {
+        let pcid = self.get_inode_chain(dinum, RESOLVE_ALWAYS)?;
+        if pcid == crate::chain::CID_NONE {
+            return Err(nix::errno::Errno::EIO.into());
+        }
+        let lhc = crate::subs::dirhash(name.as_bytes());
+        let (mut pcid, mut cid, _) = self.lookup_chain(
+            pcid,
+            lhc,
+            lhc + crate::fs::HAMMER2_DIRHASH_LOMASK,
+            LOOKUP_ALWAYS,
+        )?;
+        while cid != crate::chain::CID_NONE {
+            let chain = self.cmap.get(&cid).or_range()?;
+            if chain.match_name(name) {
+                if chain.bref.typ != crate::fs::HAMMER2_BREF_TYPE_DIRENT {
+                    // Inodes embedded in directories are not supported.
+                    log::error!("{name}: bad blockref type {}", chain.bref.typ);
+                    return Err(nix::errno::Errno::EOPNOTSUPP.into());
+                }
+                return Ok(cid);
+            }
+            (pcid, cid, _) = self.get_next_chain(
+                pcid,
+                cid,
+                lhc + crate::fs::HAMMER2_DIRHASH_LOMASK,
+                LOOKUP_ALWAYS,
+            )?;
+        }
+        Err(nix::errno::Errno::ENOENT.into())
+    }
+
+    fn is_empty_directory(&mut self, inum: u64) -> crate::Result<bool> {
+        let cid = self.get_inode_chain(inum, RESOLVE_ALWAYS)?;
+        if cid == crate::chain::CID_NONE {
+            return Err(nix::errno::Errno::EIO.into());
+        }
+        let (_, cid, _) = self.lookup_chain(cid, 0, crate::fs::HAMMER2_KEY_MAX, 0)?;
+        Ok(cid == crate::chain::CID_NONE)
+    }
+
+    // Drop a link to the inode, and delete the inode on last link.
+    fn drop_inode_link(&mut self, inum: u64, now: u64) -> crate::Result<()> {
+        if self.nmap.get(&inum).or_range()?.meta.nlinks > 1 {
+            return self.modify_inode_meta(inum, |meta| {
+                meta.nlinks -= 1;
+                meta.ctime = now;
+            });
+        }
+        let cid = self.get_inode_chain(inum, RESOLVE_ALWAYS)?;
+        if cid == crate::chain::CID_NONE {
+            return Err(nix::errno::Errno::EIO.into());
+        }
+        self.delete_chain(cid)?;
+        self.remove_inode(inum)?;
+        Ok(())
+    }
+
+    // Remove the directory entry and drop a link to its inode.
+    fn remove_entry(&mut self, dinum: u64, name: &str, isdir: bool) -> crate::Result<()> {
+        self.check_modify_dir(dinum, name)?;
+        let inum = self.nresolve(dinum, name)?;
+        let ip = self.nmap.get(&inum).or_range()?;
+        if isdir && !ip.is_directory() {
+            return Err(nix::errno::Errno::ENOTDIR.into());
+        }
+        if !isdir && ip.is_directory() {
+            return Err(nix::errno::Errno::EISDIR.into());
+        }
+        if isdir && !self.is_empty_directory(inum)? {
+            return Err(nix::errno::Errno::ENOTEMPTY.into());
+        }
+        let now = crate::subs::get_current_time()?;
+        let cid = self.lookup_dirent_chain(dinum, name)?;
+        self.delete_chain(cid)?;
+        self.drop_inode_link(inum, now)?;
+        self.modify_inode_meta(dinum, |meta| {
+            meta.mtime = now;
+            meta.ctime = now;
+        })
+    }
+
+    pub(crate) fn create_inode(
+        &mut self,
+        dinum: u64,
+        name: &str,
+        typ: u8,
+        mode: u32,
+    ) -> crate::Result<u64> {
+        self.check_modify_dir(dinum, name)?;
+        match self.nresolve(dinum, name) {
+            Ok(_) => return Err(nix::errno::Errno::EEXIST.into()),
+            Err(crate::Error::Errno(nix::errno::Errno::ENOENT)) => (),
+            Err(e) => return Err(e),
+        }
+        let dcid = self.get_inode_chain(dinum, RESOLVE_ALWAYS)?;
+        if dcid == crate::chain::CID_NONE {
+            return Err(nix::errno::Errno::EIO.into());
+        }
+        let dmeta = self.cmap.get(&dcid).or_range()?.as_inode_data().meta;
+        let inum = self.alloc_inum()?;
+        let now = crate::subs::get_current_time()?;
+
+        let mut ipdata = crate::fs::Hammer2InodeData::new();
+        let meta = &mut ipdata.meta;
+        meta.version = crate::fs::HAMMER2_INODE_VERSION_ONE;
+        meta.ctime = now;
+        meta.mtime = now;
+        meta.atime = now;
+        meta.btime = now;
+        meta.uid = crate::subs::conv_unix_xid_to_uuid_bytes(unsafe { libc::getuid() });
+        meta.gid = crate::subs::conv_unix_xid_to_uuid_bytes(unsafe { libc::getgid() });
+        meta.typ = typ;
+        meta.mode = mode & 0o7777;
+        meta.inum = inum;
+        meta.nlinks = 1;
+        meta.iparent = dinum;
+        meta.name_key = crate::subs::dirhash(name.as_bytes());
+        meta.name_len = name.len().try_into().or_range()?;
+        meta.comp_algo = dmeta.comp_algo;
+        meta.check_algo = dmeta.check_algo;
+        if typ == crate::fs::HAMMER2_OBJTYPE_REGFILE || typ == crate::fs::HAMMER2_OBJTYPE_SOFTLINK {
+            meta.op_flags |= crate::fs::HAMMER2_OPFLAG_DIRECTDATA;
+        }
+        ipdata.filename[..name.len()].copy_from_slice(name.as_bytes());
+
+        // Inodes are indexed by inum under the PFS root.
+        let mut bref = crate::fs::Hammer2Blockref::new(crate::fs::HAMMER2_BREF_TYPE_INODE);
+        bref.methods = crate::fs::enc_check(crate::fs::dec_algo(dmeta.check_algo))
+            | crate::fs::enc_comp(crate::fs::HAMMER2_COMP_NONE);
+        bref.key = inum;
+        bref.data_off = crate::fs::HAMMER2_INODE_BYTES.trailing_zeros().into();
+        let pcid = self.get_inode_chain(crate::inode::INUM_PFS_ROOT, RESOLVE_ALWAYS)?;
+        let cid = self.create_chain(pcid, &bref, libfs::cast::as_u8_slice(&ipdata).to_vec())?;
+        self.add_inode(crate::inode::Inode::new(&ipdata.meta, cid))?;
+        self.create_dirent(dinum, name, inum, typ)?;
+        self.modify_inode_meta(dinum, |meta| {
+            meta.mtime = now;
+            meta.ctime = now;
+        })?;
+        Ok(inum)
+    }
+
+    /// # Errors
+    pub fn create(&mut self, dinum: u64, name: &str, mode: u32) -> crate::Result<u64> {
+        self.create_inode(dinum, name, crate::fs::HAMMER2_OBJTYPE_REGFILE, mode)
+    }
+
+    /// # Errors
+    pub fn mkdir(&mut self, dinum: u64, name: &str, mode: u32) -> crate::Result<u64> {
+        self.create_inode(dinum, name, crate::fs::HAMMER2_OBJTYPE_DIRECTORY, mode)
+    }
+
+    /// # Errors
+    pub fn unlink(&mut self, dinum: u64, name: &str) -> crate::Result<()> {
+        self.remove_entry(dinum, name, false)
+    }
+
+    /// # Errors
+    pub fn rmdir(&mut self, dinum: u64, name: &str) -> crate::Result<()> {
+        self.remove_entry(dinum, name, true)
+    }
+
+    /// # Errors
+    pub fn rename(
+        &mut self,
+        fdinum: u64,
+        fname: &str,
+        tdinum: u64,
+        tname: &str,
+    ) -> crate::Result<()> {
+        self.check_modify_dir(fdinum, fname)?;
+        self.check_modify_dir(tdinum, tname)?;
+        let inum = self.nresolve(fdinum, fname)?;
+        let isdir = self.nmap.get(&inum).or_range()?.is_directory();
+        // A directory can't be moved under itself.
+        if isdir {
+            let mut x = tdinum;
+            let mut visited = std::collections::HashSet::new();
+            while x != crate::inode::INUM_PFS_ROOT {
+                if x == inum {
+                    return Err(nix::errno::Errno::EINVAL.into());
+                }
+                if x == crate::inode::INUM_SUP_ROOT || !visited.insert(x) {
+                    log::error!("inode {tdinum:016x} not under PFS root");
+                    return Err(nix::errno::Errno::EINVAL.into());
+                }
+                x = self.get_iparent(x)?;
+            }
+        }
+        match self.nresolve(tdinum, tname) {
+            Ok(v) if v == inum => return Ok(()),
+            Ok(_) => self.remove_entry(tdinum, tname, isdir)?,
+            Err(crate::Error::Errno(nix::errno::Errno::ENOENT)) => (),
+            Err(e) => return Err(e),
+        }
+        let now = crate::subs::get_current_time()?;
+        let cid = self.lookup_dirent_chain(fdinum, fname)?;
+        let typ = self
+            .cmap
+            .get(&cid)
+            .or_range()?
+            .bref
+            .embed_as::<crate::fs::Hammer2DirentHead>()
+            .typ;
+        self.delete_chain(cid)?;
+        self.create_dirent(tdinum, tname, inum, typ)?;
+        // Update the name and the parent of the inode.
+        let cid = self.get_inode_chain(inum, RESOLVE_ALWAYS)?;
+        self.modify_chain(cid)?;
+        let ipdata = self.cmap.get_mut(&cid).or_range()?.as_inode_data_mut();
+        ipdata.filename.fill(0);
+        ipdata.filename[..tname.len()].copy_from_slice(tname.as_bytes());
+        let name_len = tname.len().try_into().or_range()?;
+        self.modify_inode_meta(inum, |meta| {
+            meta.name_key = crate::subs::dirhash(tname.as_bytes());
+            meta.name_len = name_len;
+            meta.iparent = tdinum;
+            meta.ctime = now;
+        })?;
+        for dinum in [fdinum, tdinum] {
+            self.modify_inode_meta(dinum, |meta| {
+                meta.mtime = now;
+                meta.ctime = now;
+            })?;
+        }
+        Ok(())
+    }
+
+    // Write a logical block, which is compressed per inode's comp_algo.
+    fn write_block(&mut self, inum: u64, lbase: u64, data: &[u8]) -> crate::Result<()> {
+        let cid = self.get_inode_chain(inum, RESOLVE_ALWAYS)?;
+        if cid == crate::chain::CID_NONE {
+            return Err(nix::errno::Errno::EIO.into());
+        }
+        let meta = self.cmap.get(&cid).or_range()?.as_inode_data().meta;
+        assert!(!meta.has_direct_data());
+        let (_, ccid, _) = self.lookup_chain(cid, lbase, lbase, 0)?;
+        if ccid != crate::chain::CID_NONE {
+            let bref = &self.cmap.get(&ccid).or_range()?.bref;
+            if bref.typ != crate::fs::HAMMER2_BREF_TYPE_DATA || bref.key != lbase {
+                log::error!("bad data chain {bref}");
+                return Err(nix::errno::Errno::EINVAL.into());
+            }
+        }
+        // Zero-fill blocks are not allocated unless compression is disabled.
+        let comp_algo = crate::fs::dec_algo(meta.comp_algo);
+        if comp_algo != crate::fs::HAMMER2_COMP_NONE && data.iter().all(|&x| x == 0) {
+            if ccid != crate::chain::CID_NONE {
+                self.delete_chain(ccid)?;
+            }
+            return Ok(());
+        }
+        let (pdata, comp_algo) = compress_block(data, meta.comp_algo);
+        let methods = crate::fs::enc_check(crate::fs::dec_algo(meta.check_algo))
+            | crate::fs::enc_comp(comp_algo);
+        let vradix = data.len().trailing_zeros().try_into().or_range()?;
+        let radix = pdata.len().trailing_zeros().into();
+        if ccid == crate::chain::CID_NONE {
+            let mut bref = crate::fs::Hammer2Blockref::new(crate::fs::HAMMER2_BREF_TYPE_DATA);
+            bref.methods = methods;
+            bref.keybits = crate::fs::HAMMER2_PBUFRADIX.try_into().or_range()?;
+            bref.vradix = vradix;
+            bref.key = lbase;
+            bref.data_off = radix;
+            self.create_chain(cid, &bref, pdata)?;
+        } else {
+            let chain = self.cmap.get_mut(&ccid).or_range()?;
+            chain.bref.methods = methods;
+            chain.bref.vradix = vradix;
+            chain.bref.data_off = radix;
+            chain.set_bytes(pdata.len().try_into().or_range()?);
+            chain.set_data(pdata);
+            chain.clear_udata();
+            self.modify_chain(ccid)?;
+        }
+        Ok(())
+    }
+
+    /// # Errors
+    pub fn pwrite(&mut self, inum: u64, buf: &[u8], offset: u64) -> crate::Result<u64> {
+        if !self.opt.rw {
+            return Err(nix::errno::Errno::EROFS.into());
+        }
+        let ip = self.nmap.get(&inum).or_range()?;
+        if ip.meta.typ == crate::fs::HAMMER2_OBJTYPE_DIRECTORY {
+            return Err(nix::errno::Errno::EISDIR.into());
+        }
+        if ip.meta.typ != crate::fs::HAMMER2_OBJTYPE_REGFILE {
+            return Err(nix::errno::Errno::EINVAL.into());
+        }
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let len = u64::try_from(buf.len()).or_range()?;
+        let end = offset.checked_add(len).ok_or(nix::errno::Errno::EFBIG)?;
+        let cid = self.get_inode_chain(inum, RESOLVE_ALWAYS)?;
+        if cid == crate::chain::CID_NONE {
+            return Err(nix::errno::Errno::EIO.into());
+        }
+        let ipdata = self.cmap.get(&cid).or_range()?.as_inode_data();
+        let size = ipdata.meta.size;
+        let new_size = size.max(end);
+        let now = crate::subs::get_current_time()?;
+
+        if ipdata.meta.has_direct_data() {
+            if new_size <= crate::fs::HAMMER2_EMBEDDED_BYTES {
+                // Data remains embedded in the inode.
+                self.modify_chain(cid)?;
+                let chain = self.cmap.get_mut(&cid).or_range()?;
+                chain.clear_udata();
+                let ipdata = chain.as_inode_data_mut();
+                let (size, offset, end) = (
+                    usize::try_from(size).or_range()?,
+                    usize::try_from(offset).or_range()?,
+                    usize::try_from(end).or_range()?,
+                );
+                if offset > size {
+                    ipdata.u[size..offset].fill(0);
+                }
+                ipdata.u[offset..end].copy_from_slice(buf);
+                self.modify_inode_meta(inum, |meta| {
+                    meta.size = new_size;
+                    meta.mtime = now;
+                    meta.ctime = now;
+                })?;
+                return Ok(len);
+            }
+            // Convert embedded data to a data block.
+            let mut b = ipdata.u[..usize::try_from(size).or_range()?].to_vec();
+            self.modify_inode_meta(inum, |meta| {
+                meta.op_flags &= !crate::fs::HAMMER2_OPFLAG_DIRECTDATA;
+            })?;
+            let chain = self.cmap.get_mut(&cid).or_range()?;
+            chain.clear_udata();
+            chain.as_inode_data_mut().u.fill(0);
+            if size != 0 {
+                b.resize(get_logical_size(size, 0)?, 0);
+                self.write_block(inum, 0, &b)?;
+            }
+        }
+
+        // Rewrite logical blocks within the range, and the last block
+        // if the file is extended as its logical size may grow.
+        let mut v = vec![];
+        if new_size > size && size != 0 {
+            v.push((size - 1) & !crate::fs::HAMMER2_PBUFMASK);
+        }
+        let mut lbase = offset & !crate::fs::HAMMER2_PBUFMASK;
+        while lbase < end {
+            v.push(lbase);
+            lbase += crate::fs::HAMMER2_PBUFSIZE;
+        }
+        v.sort_unstable();
+        v.dedup();
+        for lbase in v {
+            let mut arg = crate::xop::XopRead::new(inum, lbase);
+            let mut b = self.xop_read(&mut arg)?;
+            b.resize(get_logical_size(new_size, lbase)?, 0);
+            let beg = offset.max(lbase);
+            let end = end.min(lbase + u64::try_from(b.len()).or_range()?);
+            if beg < end {
+                let i = usize::try_from(beg - lbase).or_range()?;
+                let j = usize::try_from(beg - offset).or_range()?;
+                let n = usize::try_from(end - beg).or_range()?;
+                b[i..i + n].copy_from_slice(&buf[j..j + n]);
+            }
+            self.write_block(inum, lbase, &b)?;
+        }
+        self.modify_inode_meta(inum, |meta| {
+            meta.size = new_size;
+            meta.mtime = now;
+            meta.ctime = now;
+        })?;
+        Ok(len)
+    }
+
+    fn init_vchain(&mut self) -> nix::Result<()> {
+        let mut bref = crate::fs::Hammer2Blockref::new(crate::fs::HAMMER2_BREF_TYPE_VOLUME);
+        bref.data_off = crate::fs::HAMMER2_PBUFRADIX.try_into().or_nix_range()?;
+        bref.mirror_tid = self.voldata.mirror_tid;
+        bref.modify_tid = bref.mirror_tid;
+
+        let mut chain = crate::chain::Chain::new(&bref, crate::chain::CID_VCHAIN)?;
+        chain.set_data(libfs::cast::as_u8_slice(self.voldata.as_ref()).to_vec());
+        assert!(!self.cmap.contains_key(&chain.cid));
+        assert!(self.cmap.insert(chain.cid, chain).is_none());
+        assert!(self.cmap.contains_key(&crate::chain::CID_VCHAIN));
+        Ok(())
+    }
+
+    fn remove_vchain(&mut self) -> nix::Result<()> {
+        if self.cmap.remove(&crate::chain::CID_VCHAIN).is_some() {
+            Ok(())
+        } else {
+            Err(nix::errno::Errno::ENOENT)
+        }
+    }
+
+    fn init_fchain(&mut self) -> nix::Result<()> {
+        let mut bref = crate::fs::Hammer2Blockref::new(crate::fs::HAMMER2_BREF_TYPE_FREEMAP);
+        bref.data_off = crate::fs::HAMMER2_PBUFRADIX.try_into().or_nix_range()?;
+        bref.mirror_tid = self.voldata.mirror_tid;
+        bref.modify_tid = bref.mirror_tid;
+        bref.methods = crate::fs::enc_check(crate::fs::HAMMER2_CHECK_FREEMAP)
+            | crate::fs::enc_comp(crate::fs::HAMMER2_COMP_NONE);
+
+        let mut chain = crate::chain::Chain::new(&bref, crate::chain::CID_FCHAIN)?;
+        chain.set_data(libfs::cast::as_u8_slice(self.voldata.as_ref()).to_vec());
+        assert!(!self.cmap.contains_key(&chain.cid));
+        assert!(self.cmap.insert(chain.cid, chain).is_none());
+        assert!(self.cmap.contains_key(&crate::chain::CID_FCHAIN));
+        Ok(())
     }
 
     fn remove_fchain(&mut self) -> nix::Result<()> {
@@ -1063,8 +2138,14 @@ impl Hammer2 {
         let ipdata = chain.as_inode_data();
         let meta = ipdata.meta;
         log::debug!("{ipdata}");
-        assert!(!self.set_inode(ipdata.meta.inum)?);
-        assert!(self.nmap.contains_key(&crate::inode::INUM_PFS_ROOT));
+        if meta.inum != crate::inode::INUM_PFS_ROOT {
+            log::error!("bad PFS root inode number {}", meta.inum);
+            return Err(nix::errno::Errno::EINVAL);
+        }
+        if self.set_inode(meta.inum)? {
+            log::error!("PFS root inode already exists");
+            return Err(nix::errno::Errno::EEXIST);
+        }
         let ip = self
             .nmap
             .get_mut(&crate::inode::INUM_PFS_ROOT)
@@ -1074,64 +2155,33 @@ impl Hammer2 {
         Ok(())
     }
 
-    /// # Errors
-    /// # Panics
-    #[allow(clippy::too_many_lines)]
-    pub fn mount(spec: &str, args: &[&str]) -> crate::Result<Self> {
-        log::debug!("{spec} {args:?}");
-        // Allocate option.
-        let opt = crate::option::Opt::new(args)?;
-        log::debug!("{opt:?}");
-        // Parse label.
-        let (spec, label) = if let Some(i) = spec.find('@') {
-            if i == spec.len() - 1 {
-                (&spec[..i], crate::inode::PFS_LABEL_DEFAULT)
-            } else {
-                (&spec[..i], &spec[i + 1..])
-            }
-        } else {
-            (spec, crate::inode::PFS_LABEL_DEFAULT)
-        };
-        log::debug!("spec \"{spec}\" label \"{label}\"");
-        if spec.is_empty() {
-            log::error!("empty spec");
-            return Err(nix::errno::Errno::EINVAL.into());
-        }
-        assert!(!label.is_empty());
-        // Allocate ondisk.
-        let fso = crate::ondisk::init_quiet(spec, true)?;
-        log::debug!("{fso:?}");
-
-        // Allocate PFS.
-        let mut pmp = Self::new(fso, opt)?;
-        if !pmp.voldata.is_hbo()? {
-            log::error!("reverse-endian not supported");
-            return Err(nix::errno::Errno::EINVAL.into());
+    // Load chains up to the super-root inode.
+    fn init_sup_root(&mut self) -> crate::Result<()> {
+        if !self.voldata.is_hbo()? && self.opt.rw {
+            log::error!("reverse-endian filesystem is read-only");
+            return Err(nix::errno::Errno::EROFS.into());
         }
-        pmp.imap.max = match pmp.opt.cidalloc {
+        self.imap.max = match self.opt.cidalloc {
             crate::option::CidAllocMode::Linear => crate::chain::Cid::MAX - 1,
             crate::option::CidAllocMode::Bitmap => {
                 let x = 4usize << 20; // 512KB
                 let n = x.div_ceil(libfs::bitmap::BLOCK_BITS) * libfs::bitmap::BLOCK_BITS;
                 log::debug!("imap: {} bits, {} bytes", n, n / 8);
-                pmp.imap.chunk = libfs::bitmap::Bitmap::new(n)?;
+                self.imap.chunk = libfs::bitmap::Bitmap::new(n)?;
                 (n - 1).try_into().or_range()?
             }
         };
-        pmp.init_vchain()?;
-        assert_eq!(pmp.cmap.len(), 1);
-        pmp.init_fchain()?;
-        assert_eq!(pmp.cmap.len(), 2);
+        self.init_vchain()?;
+        assert_eq!(self.cmap.len(), 1);
+        self.init_fchain()?;
+        assert_eq!(self.cmap.len(), 2);
 
         // First locate the super-root inode, which is key 0
         // relative to the volume header's blockset.
-        //
-        // Then locate the root inode by scanning the directory keyspace
-        // represented by the label.
-        let chain = pmp.cmap.get(&crate::chain::CID_VCHAIN).or_range()?;
+        let chain = self.cmap.get(&crate::chain::CID_VCHAIN).or_range()?;
         let cid = chain.cid;
-        pmp.load_chain(cid, RESOLVE_ALWAYS)?;
-        let (_, cid, _) = pmp.lookup_chain(
+        self.load_chain(cid, RESOLVE_ALWAYS)?;
+        let (_, cid, _) = self.lookup_chain(
             cid,
             crate::fs::HAMMER2_SROOT_KEY,
             crate::fs::HAMMER2_SROOT_KEY,
@@ -1141,34 +2191,42 @@ impl Hammer2 {
             log::error!("super-root not found");
             return Err(nix::errno::Errno::EINVAL.into());
         }
-        pmp.init_sup_root_inode(cid)?;
-        assert_eq!(pmp.nmap.len(), 1);
+        self.init_sup_root_inode(cid)?;
+        assert_eq!(self.nmap.len(), 1);
+        Ok(())
+    }
 
-        // Scan PFSs under the super-root.
-        let pcid = pmp.get_inode_chain(crate::inode::INUM_SUP_ROOT, RESOLVE_ALWAYS)?;
-        let (mut pcid, mut cid, _) = pmp.lookup_chain(
+    /// # Errors
+    pub fn list_pfs(&mut self) -> crate::Result<Vec<Pfs>> {
+        let pcid = self.get_inode_chain(crate::inode::INUM_SUP_ROOT, RESOLVE_ALWAYS)?;
+        let (mut pcid, mut cid, _) = self.lookup_chain(
             pcid,
             crate::fs::HAMMER2_KEY_MIN,
             crate::fs::HAMMER2_KEY_MAX,
             0,
         )?;
+        let mut v = vec![];
         while cid != crate::chain::CID_NONE {
-            let chain = pmp.cmap.get(&cid).or_range()?;
+            let chain = self.cmap.get(&cid).or_range()?;
             if chain.bref.typ != crate::fs::HAMMER2_BREF_TYPE_INODE {
                 log::error!("non inode chain under super-root: {}", chain.bref);
                 return Err(nix::errno::Errno::EINVAL.into());
             }
             log::debug!("{}", chain.as_inode_data());
-            (pcid, cid, _) = pmp.get_next_chain(pcid, cid, crate::fs::HAMMER2_KEY_MAX, 0)?;
+            v.push(Pfs::new(chain.as_inode_data()));
+            (pcid, cid, _) = self.get_next_chain(pcid, cid, crate::fs::HAMMER2_KEY_MAX, 0)?;
         }
+        Ok(v)
+    }
 
-        // Lookup the mount point under the media-localized super-root.
-        let pcid = pmp.get_inode_chain(crate::inode::INUM_SUP_ROOT, RESOLVE_ALWAYS)?;
+    // Returns CID_NONE if not found.
+    pub(crate) fn lookup_pfs_by_label(&mut self, label: &str) -> crate::Result<crate::chain::Cid> {
+        let pcid = self.get_inode_chain(crate::inode::INUM_SUP_ROOT, RESOLVE_ALWAYS)?;
         let lhc = crate::subs::dirhash(label.as_bytes());
         let (mut pcid, mut cid, _) =
-            pmp.lookup_chain(pcid, lhc, lhc + crate::fs::HAMMER2_DIRHASH_LOMASK, 0)?;
+            self.lookup_chain(pcid, lhc, lhc + crate::fs::HAMMER2_DIRHASH_LOMASK, 0)?;
         while cid != crate::chain::CID_NONE {
-            let chain = pmp.cmap.get(&cid).or_range()?;
+            let chain = self.cmap.get(&cid).or_range()?;
             if chain.bref.typ == crate::fs::HAMMER2_BREF_TYPE_INODE {
                 match chain.as_inode_data().get_filename_string() {
                     Ok(s) => {
@@ -1183,27 +2241,112 @@ impl Hammer2 {
                 }
             }
             (pcid, cid, _) =
-                pmp.get_next_chain(pcid, cid, lhc + crate::fs::HAMMER2_DIRHASH_LOMASK, 0)?;
+                self.get_next_chain(pcid, cid, lhc + crate::fs::HAMMER2_DIRHASH_LOMASK, 0)?;
         }
-        if cid == crate::chain::CID_NONE {
-            log::error!("PFS label \"{label}\" not found");
+        Ok(cid)
+    }
+
+    // Match pfs_fsid, or pfs_clid which may be shared by multiple PFSs.
+    fn lookup_pfs_by_uuid(&mut self, s: &str, clid: bool) -> crate::Result<crate::chain::Cid> {
+        let uuid = match crate::subs::get_uuid_from_str(s) {
+            Ok(v) => v,
+            Err(e) => {
+                log::error!("{e}");
+                return Err(nix::errno::Errno::EINVAL.into());
+            }
+        };
+        let pcid = self.get_inode_chain(crate::inode::INUM_SUP_ROOT, RESOLVE_ALWAYS)?;
+        let (mut pcid, mut cid, _) = self.lookup_chain(
+            pcid,
+            crate::fs::HAMMER2_KEY_MIN,
+            crate::fs::HAMMER2_KEY_MAX,
+            0,
+        )?;
+        let mut found = crate::chain::CID_NONE;
+        while cid != crate::chain::CID_NONE {
+            let chain = self.cmap.get(&cid).or_range()?;
+            if chain.bref.typ == crate::fs::HAMMER2_BREF_TYPE_INODE {
+                let meta = &chain.as_inode_data().meta;
+                let b = if clid { meta.pfs_clid } else { meta.pfs_fsid };
+                if b == *uuid.as_bytes() {
+                    if found != crate::chain::CID_NONE {
+                        log::error!("PFS uuid {s} not unique");
+                        return Err(nix::errno::Errno::EINVAL.into());
+                    }
+                    found = cid;
+                }
+            }
+            (pcid, cid, _) = self.get_next_chain(pcid, cid, crate::fs::HAMMER2_KEY_MAX, 0)?;
+        }
+        if found == crate::chain::CID_NONE {
+            log::error!("PFS uuid {s} not found");
             return Err(nix::errno::Errno::ENOENT.into());
         }
-        pmp.init_pfs_root_inode(cid)?;
-        assert_eq!(pmp.nmap.len(), 2);
-        pmp.label = label.to_string();
-        assert!(!pmp.label.is_empty());
-
-        Ok(pmp)
+        Ok(found)
     }
 
-    /// # Errors
+    fn mount_impl(&mut self, label: &str) -> crate::Result<()> {
+        self.init_sup_root()?;
+
+        // Scan PFSs under the super-root.
+        self.list_pfs()?;
+
+        // Then locate the root inode by scanning the directory keyspace
+        // represented by the label, or by uuid.
+        let cid = if let Some(s) = label.strip_prefix("fsid=") {
+            self.lookup_pfs_by_uuid(s, false)?
+        } else if let Some(s) = label.strip_prefix("clid=") {
+            self.lookup_pfs_by_uuid(s, true)?
+        } else {
+            let cid = self.lookup_pfs_by_label(label)?;
+            if cid == crate::chain::CID_NONE {
+                log::error!("PFS label \"{label}\" not found");
+                return Err(nix::errno::Errno::ENOENT.into());
+            }
+            cid
+        };
+        // The label is used to find the PFS root inode on unmount.
+        let label = match self
+            .cmap
+            .get(&cid)
+            .or_range()?
+            .as_inode_data()
+            .get_filename_string()
+        {
+            Ok(v) => v,
+            Err(e) => {
+                log::error!("{e}");
+                return Err(nix::errno::Errno::EINVAL.into());
+            }
+        };
+        self.init_pfs_root_inode(cid)?;
+        assert_eq!(self.nmap.len(), 2);
+        self.label = label;
+        assert!(!self.label.is_empty());
+
+        Ok(())
+    }
+
+    // Drop chains and inodes of a partially mounted filesystem, which
+    // doesn't satisfy what unmount expects.
+    fn abort_mount(&mut self) {
+        self.nmap.clear();
+        self.cmap.clear();
+    }
+
+    /// # Errors
     /// # Panics
     pub fn unmount(&mut self) -> crate::Result<()> {
+        if self.opt.rw && self.is_dirty() {
+            self.flush()?;
+        }
         assert!(self.cmap.contains_key(&crate::chain::CID_VCHAIN));
         assert!(self.cmap.contains_key(&crate::chain::CID_FCHAIN));
         assert!(self.nmap.contains_key(&crate::inode::INUM_SUP_ROOT));
-        assert!(self.nmap.contains_key(&crate::inode::INUM_PFS_ROOT));
+        // No PFS is mounted when only listing PFSs.
+        if !self.label.is_empty() {
+            assert!(self.nmap.contains_key(&crate::inode::INUM_PFS_ROOT));
+        }
         assert!(!self.nmap.is_empty());
         self.clear_chain()?;
         assert!(self.cmap.contains_key(&crate::chain::CID_VCHAIN));
@@ -1287,131 +2430,1339 @@ impl Hammer2 {
     }
 }
 
+impl From<Inner> for Hammer2 {
+    fn from(inner: Inner) -> Self {
+        Self {
+            fso: std::sync::Arc::clone(&inner.fso),
+            inner: std::sync::RwLock::new(inner),
+        }
+    }
+}
+
+impl Hammer2 {
+    /// # Errors
+    /// # Panics
+    pub fn mount(spec: &str, args: &[&str]) -> crate::Result<Self> {
+        log::debug!("{spec} {args:?}");
+        // Allocate option.
+        let opt = crate::option::Opt::new(args)?;
+        log::debug!("{opt:?}");
+        // Parse label.
+        let (spec, label) = if let Some(i) = spec.find('@') {
+            if i == spec.len() - 1 {
+                (&spec[..i], crate::inode::PFS_LABEL_DEFAULT)
+            } else {
+                (&spec[..i], &spec[i + 1..])
+            }
+        } else {
+            (spec, crate::inode::PFS_LABEL_DEFAULT)
+        };
+        log::debug!("spec \"{spec}\" label \"{label}\"");
+        if spec.is_empty() {
+            log::error!("empty spec");
+            return Err(nix::errno::Errno::EINVAL.into());
+        }
+        assert!(!label.is_empty());
+        // Allocate ondisk.
+        let fso = crate::ondisk::init_quiet_direct(spec, !opt.rw, opt.direct)?;
+        Self::mount_ondisk(fso, opt, label)
+    }
+
+    // Same as mount, but with devices instead of a spec.
+    // An empty label mounts the default PFS.
+    /// # Errors
+    pub fn mount_devices(
+        devs: Vec<Box<dyn crate::device::BlockDevice>>,
+        label: &str,
+        args: &[&str],
+    ) -> crate::Result<Self> {
+        log::debug!("{devs:?} {label} {args:?}");
+        let opt = crate::option::Opt::new(args)?;
+        log::debug!("{opt:?}");
+        let label = if label.is_empty() {
+            crate::inode::PFS_LABEL_DEFAULT
+        } else {
+            label
+        };
+        let fso = crate::ondisk::init_devices_quiet(devs)?;
+        Self::mount_ondisk(fso, opt, label)
+    }
+
+    fn mount_ondisk(
+        fso: crate::ondisk::Ondisk,
+        opt: crate::option::Opt,
+        label: &str,
+    ) -> crate::Result<Self> {
+        log::debug!("{fso:?}");
+
+        // Allocate PFS.
+        let mut pmp = Inner::new(fso, opt)?;
+        if let Err(e) = pmp.mount_impl(label) {
+            pmp.abort_mount();
+            return Err(e);
+        }
+        Ok(pmp.into())
+    }
+
+    // A panic while holding the lock leaves the state as is, same as
+    // a panic caught by a caller with a single thread.
+    pub(crate) fn lock(&self) -> std::sync::RwLockWriteGuard<'_, Inner> {
+        self.inner
+            .write()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+    }
+
+    // Shared lock for operations which don't modify cached state.
+    pub(crate) fn read(&self) -> std::sync::RwLockReadGuard<'_, Inner> {
+        self.inner
+            .read()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+    }
+
+    pub(crate) fn get_mut(&mut self) -> &mut Inner {
+        self.inner
+            .get_mut()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+    }
+
+    // Run a read operation under the lock.  Media of a chain the operation
+    // needs is read without the lock, and the operation is retried with it,
+    // so that only chains in memory are accessed with the lock.
+    // Operations must be fine with retries, i.e. not modify the filesystem.
+    pub(crate) fn with_lock<T, F: FnMut(&mut Inner) -> crate::Result<T>>(
+        &self,
+        mut f: F,
+    ) -> crate::Result<T> {
+        let mut brefs = vec![];
+        let mut media = vec![];
+        let mut defer = true;
+        let mut pmp = self.lock();
+        loop {
+            pmp.defer_media = defer;
+            pmp.media = std::mem::take(&mut media);
+            let res = f(&mut pmp);
+            pmp.defer_media = false;
+            pmp.media.clear();
+            let Some(mut chain) = pmp.deferred.take() else {
+                return res;
+            };
+            if brefs.contains(&chain.bref) {
+                // Needed again after its media was used, so read it with
+                // the lock for retries to make progress.
+                defer = false;
+                continue;
+            }
+            brefs.push(chain.bref);
+            let hbo = pmp.voldata.is_hbo()?;
+            drop(pmp);
+            read_chain_media(&self.fso, hbo, &mut chain)?;
+            media.push(*chain);
+            pmp = self.lock();
+        }
+    }
+
+    /// # Errors
+    /// # Panics
+    pub fn lookup_chain(
+        &self,
+        pcid: crate::chain::Cid,
+        key_beg: u64,
+        key_end: u64,
+        flags: u32,
+    ) -> crate::Result<(crate::chain::Cid, crate::chain::Cid, u64)> {
+        self.with_lock(|pmp| pmp.lookup_chain(pcid, key_beg, key_end, flags))
+    }
+
+    /// # Errors
+    pub fn get_next_chain(
+        &self,
+        pcid: crate::chain::Cid,
+        cid: crate::chain::Cid,
+        key_end: u64,
+        flags: u32,
+    ) -> crate::Result<(crate::chain::Cid, crate::chain::Cid, u64)> {
+        self.with_lock(|pmp| pmp.get_next_chain(pcid, cid, key_end, flags))
+    }
+
+    /// # Errors
+    pub fn dump_inode_chain(&self, ip: &crate::inode::Inode) -> crate::Result<()> {
+        self.lock().dump_inode_chain(ip)
+    }
+
+    /// # Errors
+    pub fn dump_chain(&self, cid: crate::chain::Cid) -> crate::Result<()> {
+        self.lock().dump_chain(cid)
+    }
+
+    /// # Errors
+    pub fn get_inode_chain(&self, inum: u64, how: u32) -> crate::Result<crate::chain::Cid> {
+        self.with_lock(|pmp| pmp.get_inode_chain(inum, how))
+    }
+
+    /// # Errors
+    pub fn get_inode_embed_stats(
+        &self,
+        inum: u64,
+    ) -> nix::Result<crate::fs::Hammer2BlockrefEmbedStats> {
+        self.read().get_inode_embed_stats(inum).copied()
+    }
+
+    /// # Errors
+    pub fn nresolve_path(&self, path: &str) -> crate::Result<u64> {
+        self.with_lock(|pmp| pmp.nresolve_path(path))
+    }
+
+    /// # Errors
+    pub fn nresolve(&self, dinum: u64, cnp: &str) -> crate::Result<u64> {
+        self.with_lock(|pmp| pmp.nresolve(dinum, cnp))
+    }
+
+    /// # Errors
+    pub fn readdir(&self, dinum: u64) -> crate::Result<Vec<Dirent>> {
+        self.with_lock(|pmp| pmp.readdir(dinum))
+    }
+
+    /// # Errors
+    pub fn bmap(&self, inum: u64, lbn: u64) -> crate::Result<u64> {
+        self.with_lock(|pmp| pmp.bmap(inum, lbn))
+    }
+
+    /// # Errors
+    pub fn readlink(&self, inum: u64, buf: &mut [u8]) -> crate::Result<u64> {
+        self.with_lock(|pmp| pmp.readlink(inum, buf))
+    }
+
+    /// # Errors
+    pub fn readlinkx(&self, inum: u64) -> crate::Result<String> {
+        self.with_lock(|pmp| pmp.readlinkx(inum))
+    }
+
+    /// # Errors
+    pub fn pread(&self, inum: u64, buf: &mut [u8], offset: u64) -> crate::Result<u64> {
+        let ipsize = self.read().get_regfile_size(inum)?;
+        read_lblocks(buf, offset, ipsize, |lbase| self.read_lblock(inum, lbase))
+    }
+
+    // Media of data blocks is read and decompressed without the lock,
+    // so that threads reading different files don't wait for each other.
+    pub(crate) fn read_lblock(&self, inum: u64, lbase: u64) -> crate::Result<Vec<u8>> {
+        if let Some(v) = self.read().find_cached_lblock(inum, lbase) {
+            return Ok(v);
+        }
+        let (mut chain, hbo) = match self.with_lock(|pmp| pmp.lookup_lblock(inum, lbase))? {
+            Lblock::Data(v) => return Ok(v),
+            Lblock::Media(chain, hbo) => (chain, hbo),
+        };
+        read_chain_media(&self.fso, hbo, &mut chain)?;
+        let b = chain.read_data()?;
+        self.lock().cache_lblock(*chain, &b);
+        Ok(b)
+    }
+
+    /// # Errors
+    pub fn create(&mut self, dinum: u64, name: &str, mode: u32) -> crate::Result<u64> {
+        self.get_mut().create(dinum, name, mode)
+    }
+
+    /// # Errors
+    pub fn mkdir(&mut self, dinum: u64, name: &str, mode: u32) -> crate::Result<u64> {
+        self.get_mut().mkdir(dinum, name, mode)
+    }
+
+    /// # Errors
+    pub fn unlink(&mut self, dinum: u64, name: &str) -> crate::Result<()> {
+        self.get_mut().unlink(dinum, name)
+    }
+
+    /// # Errors
+    pub fn rmdir(&mut self, dinum: u64, name: &str) -> crate::Result<()> {
+        self.get_mut().rmdir(dinum, name)
+    }
+
+    /// # Errors
+    pub fn rename(
+        &mut self,
+        fdinum: u64,
+        fname: &str,
+        tdinum: u64,
+        tname: &str,
+    ) -> crate::Result<()> {
+        self.get_mut().rename(fdinum, fname, tdinum, tname)
+    }
+
+    /// # Errors
+    pub fn pwrite(&mut self, inum: u64, buf: &[u8], offset: u64) -> crate::Result<u64> {
+        self.get_mut().pwrite(inum, buf, offset)
+    }
+
+    /// # Errors
+    pub fn list_pfs(&self) -> crate::Result<Vec<Pfs>> {
+        self.with_lock(Inner::list_pfs)
+    }
+
+    /// # Errors
+    /// # Panics
+    pub fn unmount(&mut self) -> crate::Result<()> {
+        self.get_mut().unmount()
+    }
+
+    /// # Errors
+    pub fn stat(&self, inum: u64) -> crate::Result<Stat> {
+        self.read().stat(inum)
+    }
+
+    /// # Errors
+    pub fn statfs(&self) -> crate::Result<StatFs> {
+        self.lock().statfs()
+    }
+}
+
+// Mount only the super-root, without any PFS.
+fn mount_sup_root(spec: &str, args: &[&str]) -> crate::Result<Hammer2> {
+    let spec = spec.split('@').next().unwrap_or_default();
+    if spec.is_empty() {
+        log::error!("empty spec");
+        return Err(nix::errno::Errno::EINVAL.into());
+    }
+    let opt = crate::option::Opt::new(args)?;
+    let fso = crate::ondisk::init_quiet_direct(spec, !opt.rw, opt.direct)?;
+    let mut pmp = Inner::new(fso, opt)?;
+    if let Err(e) = pmp.init_sup_root() {
+        pmp.abort_mount();
+        return Err(e);
+    }
+    Ok(pmp.into())
+}
+
+/// # Errors
+pub fn list_pfs(spec: &str) -> crate::Result<Vec<Pfs>> {
+    let mut pmp = mount_sup_root(spec, &[])?;
+    let v = pmp.list_pfs()?;
+    pmp.unmount()?;
+    Ok(v)
+}
+
+/// # Errors
+pub fn pfs_create(spec: &str, pfs: &mut crate::ioctl::IocPfs) -> crate::Result<()> {
+    let mut pmp = mount_sup_root(spec, &["--rw"])?;
+    pmp.pfs_create(pfs)?;
+    pmp.unmount()
+}
+
+/// # Errors
+pub fn pfs_delete(spec: &str, pfs: &crate::ioctl::IocPfs) -> crate::Result<()> {
+    let mut pmp = mount_sup_root(spec, &["--rw"])?;
+    pmp.pfs_delete(pfs)?;
+    pmp.unmount()
+}
+
+/// # Errors
+pub fn pfs_snapshot(spec: &str, pfs: &mut crate::ioctl::IocPfs) -> crate::Result<()> {
+    let mut pmp = Hammer2::mount(spec, &["--rw"])?;
+    pmp.pfs_snapshot(pfs)?;
+    pmp.unmount()
+}
+
 #[cfg(test)]
 mod tests {
     use crate::ErrorExt;
 
-    const HAMMER2_NODATACACHE: &str = "HAMMER2_NODATACACHE"; // option
-    const HAMMER2_DEBUG: &str = "HAMMER2_DEBUG"; // option
-    const HAMMER2_DEVICE: &str = "HAMMER2_DEVICE";
-    const HAMMER2_PATH: &str = "HAMMER2_PATH";
+    const HAMMER2_NODATACACHE: &str = "HAMMER2_NODATACACHE"; // option
+    const HAMMER2_DEBUG: &str = "HAMMER2_DEBUG"; // option
+    const HAMMER2_DEVICE: &str = "HAMMER2_DEVICE";
+    const HAMMER2_PATH: &str = "HAMMER2_PATH";
+
+    fn init_std_logger() -> Result<(), log::SetLoggerError> {
+        let env = env_logger::Env::default().filter_or("RUST_LOG", "trace");
+        env_logger::try_init_from_env(env)
+    }
+
+    fn read_all(pmp: &mut super::Hammer2, inum: u64) -> crate::Result<Vec<u8>> {
+        let st = pmp.stat(inum)?;
+        let mut resid = st.st_size;
+        let size = if resid / 10 > 0 { resid / 10 } else { resid };
+        let mut offset = 0;
+        let mut v = vec![];
+        while resid > 0 {
+            let b = pmp.preadx(inum, size, offset)?;
+            let n = u64::try_from(b.len()).or_range()?;
+            v.extend(b);
+            offset += n;
+            resid -= n;
+        }
+        assert_eq!(pmp.preadx(inum, size, offset)?.len(), 0);
+        Ok(v)
+    }
+
+    fn is_zero(v: &[u8]) -> bool {
+        if v.is_empty() {
+            return true;
+        }
+        for x in v {
+            if *x != 0 {
+                return false;
+            }
+        }
+        true
+    }
+
+    fn test_hammer2_path(pmp: &mut super::Hammer2, f: &str) {
+        log::info!("{f}");
+        match pmp.nresolve_path(f) {
+            Ok(inum) => {
+                log::info!("{inum} {inum:#x}");
+                match pmp.stat(inum) {
+                    Ok(st) => {
+                        log::info!("{st:?}");
+                        match st.st_mode & libc::S_IFMT {
+                            libc::S_IFDIR => match pmp.readdir(inum) {
+                                Ok(v) => log::info!("{}: {v:?}", v.len()),
+                                Err(e) => panic!("{e}"),
+                            },
+                            libc::S_IFREG => {
+                                let (sum1, is_zero1) = match pmp.read_all(inum) {
+                                    Ok(v) => {
+                                        assert_eq!(
+                                            v.len(),
+                                            match st.st_size.try_into() {
+                                                Ok(v) => v,
+                                                Err(e) => panic!("{e}"),
+                                            }
+                                        );
+                                        match libfs::string::b2s(&v) {
+                                            Ok(v) => println!("{v}"),
+                                            Err(e) => panic!("{e}"),
+                                        }
+                                        (hex::encode(crate::sha::sha256(&v)), is_zero(&v))
+                                    }
+                                    Err(e) => panic!("{e}"),
+                                };
+                                log::info!("sha256: {sum1}");
+                                let (sum2, is_zero2) = match read_all(pmp, inum) {
+                                    Ok(v) => {
+                                        assert_eq!(
+                                            v.len(),
+                                            match st.st_size.try_into() {
+                                                Ok(v) => v,
+                                                Err(e) => panic!("{e}"),
+                                            }
+                                        );
+                                        match libfs::string::b2s(&v) {
+                                            Ok(v) => println!("{v}"),
+                                            Err(e) => panic!("{e}"),
+                                        }
+                                        (hex::encode(crate::sha::sha256(&v)), is_zero(&v))
+                                    }
+                                    Err(e) => panic!("{e}"),
+                                };
+                                log::info!("sha256: {sum2}");
+                                assert_eq!(sum1, sum2);
+                                assert_eq!(is_zero1, is_zero2);
+                                match pmp.bmap(inum, 0) {
+                                    Ok(v) => log::info!("{v:016x}"),
+                                    Err(e) => panic!("{e}"),
+                                }
+                            }
+                            libc::S_IFLNK => match pmp.readlinkx(inum) {
+                                Ok(v) => {
+                                    log::info!("\"{v}\"");
+                                    assert_eq!(
+                                        v.len(),
+                                        match st.st_size.try_into() {
+                                            Ok(v) => v,
+                                            Err(e) => panic!("{e}"),
+                                        }
+                                    );
+                                    match pmp.bmap(inum, 0) {
+                                        Ok(v) => log::info!("{v:016x}"),
+                                        Err(e) => panic!("{e}"),
+                                    }
+                                }
+                                Err(e) => panic!("{e}"),
+                            },
+                            _ => (),
+                        }
+                    }
+                    Err(e) => panic!("{e}"),
+                }
+            }
+            Err(e) => panic!("{e}"),
+        }
+    }
+
+    #[test]
+    fn test_check_name() {
+        assert_eq!(super::check_name("a"), Ok(()));
+        assert_eq!(super::check_name("a.b"), Ok(()));
+        assert_eq!(super::check_name(""), Err(nix::errno::Errno::EINVAL));
+        assert_eq!(super::check_name("."), Err(nix::errno::Errno::EINVAL));
+        assert_eq!(super::check_name(".."), Err(nix::errno::Errno::EINVAL));
+        assert_eq!(super::check_name("a/b"), Err(nix::errno::Errno::EINVAL));
+        assert_eq!(super::check_name("a\0"), Err(nix::errno::Errno::EINVAL));
+        let name = "x".repeat(crate::fs::HAMMER2_INODE_MAXNAME);
+        assert_eq!(super::check_name(&name), Ok(()));
+        assert_eq!(
+            super::check_name(&format!("{name}x")),
+            Err(nix::errno::Errno::ENAMETOOLONG)
+        );
+    }
+
+    #[test]
+    fn test_get_logical_size() {
+        let l = [
+            (1, 0, 1024),
+            (1024, 0, 1024),
+            (1025, 0, 2048),
+            (0x10000, 0, 0x10000),
+            (0x10001, 0, 0x10000),
+            (0x10001, 0x10000, 1024),
+            (0x18000, 0x10000, 0x8000),
+        ];
+        for (size, lbase, n) in l {
+            match super::get_logical_size(size, lbase) {
+                Ok(v) => assert_eq!(v, n, "{size:x} {lbase:x}"),
+                Err(e) => panic!("{e}"),
+            }
+        }
+        for (size, lbase) in [(0, 0), (0x10000, 0x10000)] {
+            assert_errno(
+                super::get_logical_size(size, lbase),
+                nix::errno::Errno::EINVAL,
+            );
+        }
+    }
+
+    #[test]
+    fn test_read_lblocks() {
+        let pbufsize = usize::try_from(crate::fs::HAMMER2_PBUFSIZE).unwrap_or(0);
+        let mut buf = vec![0; 100];
+        match super::read_lblocks(&mut buf, 10, 50, |_| Ok(vec![1; 20])) {
+            Ok(v) => assert_eq!(v, 40),
+            Err(e) => panic!("{e}"),
+        }
+        assert!(buf[..10].iter().all(|&x| x == 1));
+        assert!(buf[10..40].iter().all(|&x| x == 0));
+        // a block larger than HAMMER2_PBUFSIZE
+        assert_errno(
+            super::read_lblocks(&mut buf, 0, 50, |_| Ok(vec![0; pbufsize + 1])),
+            nix::errno::Errno::EINVAL,
+        );
+    }
+
+    #[test]
+    fn test_compress_block() {
+        let b = vec![b'A'; 0x10000];
+        for algo in [crate::fs::HAMMER2_COMP_LZ4, crate::fs::HAMMER2_COMP_ZLIB] {
+            let (v, x) = super::compress_block(&b, crate::fs::enc_algo(algo));
+            assert_eq!(x, algo);
+            assert!(v.len() < b.len());
+            assert!(v.len().is_power_of_two());
+            assert!(v.len() >= crate::fs::HAMMER2_ALLOC_MIN);
+        }
+        let (v, x) = super::compress_block(&b, crate::fs::enc_algo(crate::fs::HAMMER2_COMP_NONE));
+        assert_eq!(x, crate::fs::HAMMER2_COMP_NONE);
+        assert_eq!(v, b);
+        // incompressible data
+        let b: Vec<u8> = (0..1024)
+            .map(|i: u32| (i.wrapping_mul(2_654_435_761) >> 13) as u8)
+            .collect();
+        let (v, x) = super::compress_block(&b, crate::fs::enc_algo(crate::fs::HAMMER2_COMP_LZ4));
+        assert_eq!(x, crate::fs::HAMMER2_COMP_NONE);
+        assert_eq!(v, b);
+    }
+
+    fn assert_errno<T: std::fmt::Debug>(r: crate::Result<T>, errno: nix::errno::Errno) {
+        match r {
+            Err(crate::Error::Errno(e)) if e == errno => (),
+            Ok(v) => panic!("{errno} expected, got {v:?}"),
+            Err(e) => panic!("{errno} expected, got {e}"),
+        }
+    }
+
+    fn assert_inode(pmp: &mut super::Hammer2, dinum: u64, name: &str, inum: u64, nlinks: u64) {
+        match pmp.nresolve(dinum, name) {
+            Ok(v) => assert_eq!(v, inum, "{name}"),
+            Err(e) => panic!("{name}: {e}"),
+        }
+        match pmp.get_inode(inum) {
+            Some(ip) => {
+                assert_eq!(ip.meta.nlinks, nlinks, "{name}");
+                assert_eq!(ip.meta.iparent, dinum, "{name}");
+                assert_eq!(ip.meta.name_key, crate::subs::dirhash(name.as_bytes()));
+            }
+            None => panic!("{name}"),
+        }
+    }
+
+    #[test]
+    fn test_namespace_ops() {
+        let f = crate::newfs::create_newfs_image("namespace", 128 << 20);
+        let root = crate::inode::INUM_PFS_ROOT;
+        let mut pmp = match super::Hammer2::mount(&f, &["--rw"]) {
+            Ok(v) => v,
+            Err(e) => panic!("{e}"),
+        };
+        // /a/c, /a/x, /b/y
+        let mkdir = |pmp: &mut super::Hammer2, dinum, name| match pmp.mkdir(dinum, name, 0o755) {
+            Ok(v) => v,
+            Err(e) => panic!("{name}: {e}"),
+        };
+        let a = mkdir(&mut pmp, root, "a");
+        let b = mkdir(&mut pmp, root, "b");
+        let c = mkdir(&mut pmp, a, "c");
+        let create = |pmp: &mut super::Hammer2, dinum, name, b: &[u8]| {
+            let inum = match pmp.create(dinum, name, 0o644) {
+                Ok(v) => v,
+                Err(e) => panic!("{name}: {e}"),
+            };
+            if let Err(e) = pmp.pwrite(inum, b, 0) {
+                panic!("{name}: {e}");
+            }
+            inum
+        };
+        let x = create(&mut pmp, a, "x", &vec![b'x'; 100_000]);
+        let y = create(&mut pmp, b, "y", b"y");
+
+        assert_errno(pmp.rmdir(root, "a"), nix::errno::Errno::ENOTEMPTY);
+        assert_errno(pmp.rmdir(a, "x"), nix::errno::Errno::ENOTDIR);
+        assert_errno(pmp.unlink(root, "a"), nix::errno::Errno::EISDIR);
+        assert_errno(pmp.unlink(a, "z"), nix::errno::Errno::ENOENT);
+        assert_errno(pmp.unlink(x, "z"), nix::errno::Errno::ENOTDIR);
+        // a directory into its own subtree
+        assert_errno(pmp.rename(root, "a", a, "a"), nix::errno::Errno::EINVAL);
+        assert_errno(pmp.rename(root, "a", c, "a"), nix::errno::Errno::EINVAL);
+        // type mismatch with an existing entry
+        assert_errno(pmp.rename(a, "x", root, "b"), nix::errno::Errno::EISDIR);
+        assert_errno(pmp.rename(root, "b", a, "x"), nix::errno::Errno::ENOTDIR);
+        assert_errno(pmp.rename(a, "c", root, "b"), nix::errno::Errno::ENOTEMPTY);
+        assert_errno(pmp.rename(a, "z", root, "z"), nix::errno::Errno::ENOENT);
+        // broken parent chains end with EINVAL
+        for iparent in [c, crate::inode::INUM_SUP_ROOT] {
+            if let Err(e) = pmp
+                .get_mut()
+                .modify_inode_meta(a, |meta| meta.iparent = iparent)
+            {
+                panic!("{e}");
+            }
+            assert_errno(pmp.rename(root, "b", c, "b"), nix::errno::Errno::EINVAL);
+        }
+        if let Err(e) = pmp
+            .get_mut()
+            .modify_inode_meta(a, |meta| meta.iparent = root)
+        {
+            panic!("{e}");
+        }
+        // an ancestor not loaded yet
+        if let Err(e) = pmp.get_mut().remove_inode(a) {
+            panic!("{e}");
+        }
+        for (fdinum, tdinum) in [(root, c), (c, root)] {
+            if let Err(e) = pmp.rename(fdinum, "b", tdinum, "b") {
+                panic!("{e}");
+            }
+            assert_inode(&mut pmp, tdinum, "b", b, 1);
+        }
+        assert_inode(&mut pmp, root, "a", a, 1);
+        // nothing changed by failed operations
+        assert_inode(&mut pmp, root, "a", a, 1);
+        assert_inode(&mut pmp, root, "b", b, 1);
+        assert_inode(&mut pmp, a, "c", c, 1);
+        assert_inode(&mut pmp, a, "x", x, 1);
+        assert_inode(&mut pmp, b, "y", y, 1);
+
+        // across directories
+        if let Err(e) = pmp.rename(a, "x", b, "x") {
+            panic!("{e}");
+        }
+        assert_errno(pmp.nresolve(a, "x"), nix::errno::Errno::ENOENT);
+        assert_inode(&mut pmp, b, "x", x, 1);
+        // over an existing file, which is removed
+        if let Err(e) = pmp.rename(b, "x", b, "y") {
+            panic!("{e}");
+        }
+        assert_errno(pmp.nresolve(b, "x"), nix::errno::Errno::ENOENT);
+        assert_errno(pmp.stat(y), nix::errno::Errno::ENOENT);
+        assert_inode(&mut pmp, b, "y", x, 1);
+        // a directory over an empty directory
+        let e = mkdir(&mut pmp, root, "e");
+        if let Err(e) = pmp.rename(a, "c", root, "e") {
+            panic!("{e}");
+        }
+        assert_errno(pmp.stat(e), nix::errno::Errno::ENOENT);
+        assert_inode(&mut pmp, root, "e", c, 1);
+        // to itself
+        if let Err(e) = pmp.rename(root, "e", root, "e") {
+            panic!("{e}");
+        }
+        assert_inode(&mut pmp, root, "e", c, 1);
+
+        // hardlinks
+        if let Err(e) = pmp
+            .get_mut()
+            .create_dirent(a, "h", x, crate::fs::HAMMER2_OBJTYPE_REGFILE)
+        {
+            panic!("{e}");
+        }
+        if let Err(e) = pmp.get_mut().modify_inode_meta(x, |meta| meta.nlinks = 2) {
+            panic!("{e}");
+        }
+        if let Err(e) = pmp.unlink(b, "y") {
+            panic!("{e}");
+        }
+        match pmp.nresolve(a, "h") {
+            Ok(v) => assert_eq!(v, x),
+            Err(e) => panic!("{e}"),
+        }
+        match pmp.stat(x) {
+            Ok(v) => assert_eq!(v.st_nlink, 1),
+            Err(e) => panic!("{e}"),
+        }
+        if let Err(e) = pmp.rename(a, "h", root, "x") {
+            panic!("{e}");
+        }
+        assert_inode(&mut pmp, root, "x", x, 1);
+        if let Err(e) = pmp.rmdir(root, "a") {
+            panic!("{e}");
+        }
+        assert_errno(pmp.stat(a), nix::errno::Errno::ENOENT);
+        if let Err(e) = pmp.unmount() {
+            panic!("{e}");
+        }
+
+        match crate::fsck::fsck(&f) {
+            Ok(v) => assert!(v.is_clean(), "{:?}", v.problems),
+            Err(e) => panic!("{e}"),
+        }
+        let mut pmp = match super::Hammer2::mount(&f, &[]) {
+            Ok(v) => v,
+            Err(e) => panic!("{e}"),
+        };
+        match pmp.readdir(root) {
+            Ok(v) => {
+                let mut v: Vec<_> = v.iter().map(|x| x.name.as_str()).collect();
+                v.sort_unstable();
+                assert_eq!(v, [".", "..", "b", "e", "x"]);
+            }
+            Err(e) => panic!("{e}"),
+        }
+        assert_inode(&mut pmp, root, "b", b, 1);
+        assert_inode(&mut pmp, root, "e", c, 1);
+        assert_inode(&mut pmp, root, "x", x, 1);
+        match pmp.readdir(b) {
+            Ok(v) => assert_eq!(v.len(), 2, "{v:?}"),
+            Err(e) => panic!("{e}"),
+        }
+        let mut buf = vec![0; 100_000];
+        match pmp.pread(x, &mut buf, 0) {
+            Ok(v) => assert_eq!(v, 100_000),
+            Err(e) => panic!("{e}"),
+        }
+        assert!(buf.iter().all(|&v| v == b'x'));
+        if let Err(e) = pmp.unmount() {
+            panic!("{e}");
+        }
+        let _ = std::fs::remove_file(f);
+    }
+
+    #[test]
+    fn test_list_pfs() {
+        let f = crate::newfs::create_image("list_pfs", 128 << 20);
+        let labels = ["DATA", "LOCAL", "BACKUP"];
+        if let Err(e) = crate::newfs::Newfs::new()
+            .labels(&labels)
+            .quiet(true)
+            .format(&f)
+        {
+            panic!("{e}");
+        }
+        let v = match super::list_pfs(&format!("{f}@LOCAL")) {
+            Ok(v) => v,
+            Err(e) => panic!("{e}"),
+        };
+        assert_eq!(v.len(), labels.len());
+        for label in labels {
+            let Some(pfs) = v.iter().find(|x| x.name == label) else {
+                panic!("{label}");
+            };
+            assert_eq!(pfs.get_pfs_type_string(), "MASTER");
+            assert_eq!(pfs.get_pfs_subtype_string(), "NONE");
+            assert!(pfs.pfs_inum >= crate::inode::INUM_PFS_ROOT);
+            assert!(!pfs.pfs_clid.is_nil());
+            assert!(!pfs.pfs_fsid.is_nil());
+        }
+        assert_ne!(v[0].pfs_fsid, v[1].pfs_fsid);
+
+        let mut pmp = match super::Hammer2::mount(&f, &[]) {
+            Ok(v) => v,
+            Err(e) => panic!("{e}"),
+        };
+        match pmp.list_pfs() {
+            Ok(x) => assert_eq!(x.len(), v.len()),
+            Err(e) => panic!("{e}"),
+        }
+        if let Err(e) = pmp.unmount() {
+            panic!("{e}");
+        }
+
+        // a name with invalid UTF-8 doesn't fail the listing
+        let mut pmp = match super::mount_sup_root(&f, &["--rw"]) {
+            Ok(v) => v,
+            Err(e) => panic!("{e}"),
+        };
+        let pmp_inner = pmp.get_mut();
+        let cid = match pmp_inner.lookup_pfs_by_label("BACKUP") {
+            Ok(v) => v,
+            Err(e) => panic!("{e}"),
+        };
+        if let Err(e) = pmp_inner.modify_chain(cid) {
+            panic!("{e}");
+        }
+        match pmp_inner.cmap.get_mut(&cid) {
+            Some(v) => v.as_inode_data_mut().filename[5] = 0xff,
+            None => panic!("{cid}"),
+        }
+        if let Err(e) = pmp.unmount() {
+            panic!("{e}");
+        }
+        match super::list_pfs(&f) {
+            Ok(x) => {
+                assert_eq!(x.len(), v.len());
+                assert!(x.iter().any(|x| x.name == "BACKU\\xff"), "{x:?}");
+            }
+            Err(e) => panic!("{e}"),
+        }
+        let _ = std::fs::remove_file(f);
+    }
+
+    #[test]
+    fn test_mount_direct() {
+        let f = crate::newfs::create_newfs_image("direct", 128 << 20);
+        let data = b"hammer2".repeat(1000);
+        let mut pmp = match super::Hammer2::mount(&f, &["--rw", "--direct"]) {
+            Ok(v) => v,
+            Err(e) => panic!("{e}"),
+        };
+        let inum = match pmp.create(crate::inode::INUM_PFS_ROOT, "f", 0o644) {
+            Ok(v) => v,
+            Err(e) => panic!("{e}"),
+        };
+        match pmp.pwrite(inum, &data, 0) {
+            Ok(v) => assert_eq!(v, u64::try_from(data.len()).unwrap_or(0)),
+            Err(e) => panic!("{e}"),
+        }
+        if let Err(e) = pmp.unmount() {
+            panic!("{e}");
+        }
+        for args in [&["--direct"][..], &[]] {
+            let mut pmp = match super::Hammer2::mount(&f, args) {
+                Ok(v) => v,
+                Err(e) => panic!("{args:?} {e}"),
+            };
+            let inum = match pmp.nresolve(crate::inode::INUM_PFS_ROOT, "f") {
+                Ok(v) => v,
+                Err(e) => panic!("{args:?} {e}"),
+            };
+            let mut buf = vec![0; data.len()];
+            match pmp.pread(inum, &mut buf, 0) {
+                Ok(v) => assert_eq!(v, u64::try_from(data.len()).unwrap_or(0)),
+                Err(e) => panic!("{args:?} {e}"),
+            }
+            assert_eq!(buf, data);
+            if let Err(e) = pmp.unmount() {
+                panic!("{e}");
+            }
+        }
+        let _ = std::fs::remove_file(f);
+    }
+
+    #[test]
+    fn test_mount_shared() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<super::Hammer2>();
+
+        let f = crate::newfs::create_newfs_image("shared", 128 << 20);
+        let n = 8;
+        let data = |i: usize| format!("hammer2_{i}").repeat(20000 + i * 1000).into_bytes();
+        let mut pmp = match super::Hammer2::mount(&f, &["--rw"]) {
+            Ok(v) => v,
+            Err(e) => panic!("{e}"),
+        };
+        for i in 0..n {
+            let inum = match pmp.create(crate::inode::INUM_PFS_ROOT, &format!("f{i}"), 0o644) {
+                Ok(v) => v,
+                Err(e) => panic!("{e}"),
+            };
+            if let Err(e) = pmp.pwrite(inum, &data(i), 0) {
+                panic!("{e}");
+            }
+        }
+        if let Err(e) = pmp.unmount() {
+            panic!("{e}");
+        }
+        for args in [&[][..], &["--nodatacache"]] {
+            let mut pmp = match super::Hammer2::mount(&f, args) {
+                Ok(v) => v,
+                Err(e) => panic!("{args:?} {e}"),
+            };
+            // each thread reads every file, starting from a different one
+            std::thread::scope(|s| {
+                let pmp = &pmp;
+                for t in 0..n {
+                    s.spawn(move || {
+                        for i in (0..n).map(|i| (i + t) % n) {
+                            let inum =
+                                match pmp.nresolve(crate::inode::INUM_PFS_ROOT, &format!("f{i}")) {
+                                    Ok(v) => v,
+                                    Err(e) => panic!("{args:?} {e}"),
+                                };
+                            let data = data(i);
+                            match pmp.stat(inum) {
+                                Ok(v) => {
+                                    assert_eq!(v.st_size, u64::try_from(data.len()).unwrap_or(0))
+                                }
+                                Err(e) => panic!("{args:?} {e}"),
+                            }
+                            let mut buf = vec![0; data.len()];
+                            match pmp.pread(inum, &mut buf, 0) {
+                                Ok(v) => assert_eq!(v, u64::try_from(data.len()).unwrap_or(0)),
+                                Err(e) => panic!("{args:?} {e}"),
+                            }
+                            assert_eq!(buf, data, "{args:?} f{i}");
+                            // streaming handles only lock per block or entry
+                            let mut fp = match pmp.open(&format!("f{i}")) {
+                                Ok(v) => v,
+                                Err(e) => panic!("{args:?} {e}"),
+                            };
+                            let mut buf = vec![];
+                            if let Err(e) = std::io::Read::read_to_end(&mut fp, &mut buf) {
+                                panic!("{args:?} {e}");
+                            }
+                            assert_eq!(buf, data, "{args:?} f{i}");
+                            match pmp.read_dir(crate::inode::INUM_PFS_ROOT) {
+                                Ok(v) => assert_eq!(v.count(), n + 2, "{args:?}"),
+                                Err(e) => panic!("{args:?} {e}"),
+                            }
+                        }
+                    });
+                }
+            });
+            if let Err(e) = pmp.unmount() {
+                panic!("{e}");
+            }
+        }
+        let _ = std::fs::remove_file(f);
+    }
+
+    #[test]
+    fn test_concurrent_readers() {
+        let f = crate::newfs::create_newfs_image("concurrent", 128 << 20);
+        let (ndirs, nfiles) = (4, 4);
+        // incompressible and large enough for indirect blocks
+        let data = |i: usize, j: usize| {
+            let mut x = u64::try_from(i * nfiles + j + 1).unwrap_or(0);
+            (0..(1 << 20) + i * 1000 + j)
+                .map(|_| {
+                    x ^= x << 13;
+                    x ^= x >> 7;
+                    x ^= x << 17;
+                    x.to_le_bytes()[0]
+                })
+                .collect::<Vec<u8>>()
+        };
+        let mut pmp = match super::Hammer2::mount(&f, &["--rw"]) {
+            Ok(v) => v,
+            Err(e) => panic!("{e}"),
+        };
+        for i in 0..ndirs {
+            let dinum = match pmp.mkdir(crate::inode::INUM_PFS_ROOT, &format!("d{i}"), 0o755) {
+                Ok(v) => v,
+                Err(e) => panic!("{e}"),
+            };
+            for j in 0..nfiles {
+                let inum = match pmp.create(dinum, &format!("f{j}"), 0o644) {
+                    Ok(v) => v,
+                    Err(e) => panic!("{e}"),
+                };
+                if let Err(e) = pmp.pwrite(inum, &data(i, j), 0) {
+                    panic!("{e}");
+                }
+            }
+        }
+        if let Err(e) = pmp.unmount() {
+            panic!("{e}");
+        }
+
+        // all threads start with nothing cached
+        let mut pmp = match super::Hammer2::mount(&f, &[]) {
+            Ok(v) => v,
+            Err(e) => panic!("{e}"),
+        };
+        let inums = std::thread::scope(|s| {
+            let pmp = &pmp;
+            let v: Vec<_> = (0..ndirs * nfiles)
+                .map(|t| {
+                    s.spawn(move || {
+                        let (i, j) = (t / nfiles, t % nfiles);
+                        let inum = match pmp.nresolve_path(&format!("d{i}/f{j}")) {
+                            Ok(v) => v,
+                            Err(e) => panic!("{e}"),
+                        };
+                        match pmp.read_all(inum) {
+                            Ok(v) => assert!(v == data(i, j), "d{i}/f{j}"),
+                            Err(e) => panic!("{e}"),
+                        }
+                        let dinum = match pmp.nresolve_path(&format!("d{i}")) {
+                            Ok(v) => v,
+                            Err(e) => panic!("{e}"),
+                        };
+                        match pmp.readdir(dinum) {
+                            Ok(v) => assert_eq!(v.len(), nfiles + 2),
+                            Err(e) => panic!("{e}"),
+                        }
+                        inum
+                    })
+                })
+                .collect();
+            v.into_iter()
+                .map(|x| match x.join() {
+                    Ok(v) => v,
+                    Err(e) => std::panic::resume_unwind(e),
+                })
+                .collect::<Vec<_>>()
+        });
+
+        // cached data blocks are read with only the shared lock
+        let (tx, rx) = std::sync::mpsc::channel();
+        std::thread::scope(|s| {
+            let guard = pmp.read();
+            let pmp = &pmp;
+            let inums = &inums;
+            s.spawn(move || {
+                for (t, &inum) in inums.iter().enumerate() {
+                    let (i, j) = (t / nfiles, t % nfiles);
+                    match pmp.read_all(inum) {
+                        Ok(v) => assert!(v == data(i, j), "d{i}/f{j}"),
+                        Err(e) => panic!("{e}"),
+                    }
+                }
+                let _ = tx.send(());
+            });
+            let res = rx.recv_timeout(std::time::Duration::from_secs(60));
+            drop(guard);
+            assert!(res.is_ok(), "{res:?}");
+        });
+        if let Err(e) = pmp.unmount() {
+            panic!("{e}");
+        }
+        let _ = std::fs::remove_file(f);
+    }
+
+    #[test]
+    fn test_mount_by_uuid() {
+        let f = crate::newfs::create_newfs_image("uuid", 128 << 20);
+        let v = match super::list_pfs(&f) {
+            Ok(v) => v,
+            Err(e) => panic!("{e}"),
+        };
+        for pfs in &v {
+            for (k, u) in [("fsid", &pfs.pfs_fsid), ("clid", &pfs.pfs_clid)] {
+                let spec = format!("{f}@{k}={u}");
+                let mut pmp = match super::Hammer2::mount(&spec, &[]) {
+                    Ok(v) => v,
+                    Err(e) => panic!("{spec} {e}"),
+                };
+                assert_eq!(pmp.get_label(), pfs.name);
+                if let Err(e) = pmp.unmount() {
+                    panic!("{e}");
+                }
+            }
+        }
+        let spec = format!("{f}@fsid={}", uuid::Uuid::new_v4());
+        match super::Hammer2::mount(&spec, &[]) {
+            Err(crate::Error::Errno(nix::errno::Errno::ENOENT)) => (),
+            Ok(_) => panic!("{spec}"),
+            Err(e) => panic!("{e}"),
+        }
+        let spec = format!("{f}@clid=xxx");
+        match super::Hammer2::mount(&spec, &[]) {
+            Err(crate::Error::Errno(nix::errno::Errno::EINVAL)) => (),
+            Ok(_) => panic!("{spec}"),
+            Err(e) => panic!("{e}"),
+        }
+        let _ = std::fs::remove_file(f);
+    }
+
+    #[test]
+    fn test_mount_volhdr() {
+        // sparse, 4 volume headers
+        let f = crate::newfs::create_newfs_image("volhdr", 8 << 30);
+        for name in ["a", "b"] {
+            let mut pmp = match super::Hammer2::mount(&f, &["--rw"]) {
+                Ok(v) => v,
+                Err(e) => panic!("{e}"),
+            };
+            if let Err(e) = pmp.create(crate::inode::INUM_PFS_ROOT, name, 0o644) {
+                panic!("{e}");
+            }
+            if let Err(e) = pmp.unmount() {
+                panic!("{e}");
+            }
+        }
+        let v = match crate::volume::read_volume_headers(&f) {
+            Ok(v) => v,
+            Err(e) => panic!("{e}"),
+        };
+        assert_eq!(v.len(), crate::fs::HAMMER2_NUM_VOLHDRS);
+        let tid = v[0].1.mirror_tid;
+        assert_eq!(v[1].1.mirror_tid, tid + 1);
+        assert_eq!(v[2].1.mirror_tid, tid + 2);
+
+        let lookup = |args: &[&str]| {
+            let mut pmp = match super::Hammer2::mount(&f, args) {
+                Ok(v) => v,
+                Err(e) => panic!("{e}"),
+            };
+            let mut v = vec![];
+            for name in ["a", "b"] {
+                match pmp.nresolve(crate::inode::INUM_PFS_ROOT, name) {
+                    Ok(_) => v.push(name),
+                    Err(crate::Error::Errno(nix::errno::Errno::ENOENT)) => (),
+                    Err(e) => panic!("{e}"),
+                }
+            }
+            let index = pmp.get_volume_data_index();
+            if let Err(e) = pmp.unmount() {
+                panic!("{e}");
+            }
+            (index, v)
+        };
+        assert_eq!(lookup(&[]), (2, vec!["a", "b"]));
+        assert_eq!(lookup(&["--volhdr", "1"]), (1, vec!["a"]));
+        assert_eq!(lookup(&["--volhdr", "0"]), (0, vec![]));
+        let x = format!("{:#x}", tid + 1);
+        assert_eq!(lookup(&["--mirror_tid", &x]), (1, vec!["a"]));
+        let x = format!("{}", tid + 100);
+        match super::Hammer2::mount(&f, &["--mirror_tid", &x]) {
+            Err(crate::Error::Errno(nix::errno::Errno::ENOENT)) => (),
+            Ok(_) => panic!("{x}"),
+            Err(e) => panic!("{e}"),
+        }
+        let _ = std::fs::remove_file(f);
+    }
+
+    #[test]
+    fn test_pfs_snapshot() {
+        let f = crate::newfs::create_newfs_image("snapshot", 128 << 20);
+        let read_file = |pmp: &mut super::Hammer2, name| {
+            let inum = pmp.nresolve(crate::inode::INUM_PFS_ROOT, name)?;
+            let mut buf = vec![0; 6];
+            let n = pmp.pread(inum, &mut buf, 0)?;
+            buf.truncate(n.try_into().or_range()?);
+            Ok::<Vec<u8>, crate::Error>(buf)
+        };
+        let mut pmp = match super::Hammer2::mount(&f, &["--rw"]) {
+            Ok(v) => v,
+            Err(e) => panic!("{e}"),
+        };
+        let inum = match pmp.create(crate::inode::INUM_PFS_ROOT, "x", 0o644) {
+            Ok(v) => v,
+            Err(e) => panic!("{e}"),
+        };
+        if let Err(e) = pmp.pwrite(inum, &b"before".repeat(20000), 0) {
+            panic!("{e}");
+        }
+        let mut pfs = crate::ioctl::IocPfs::new();
+        pfs.copy_name(b"snap");
+        if let Err(e) = pmp.pfs_snapshot(&mut pfs) {
+            panic!("{e}");
+        }
+        assert_eq!(pfs.pfs_subtype, crate::fs::HAMMER2_PFSSUBTYPE_SNAPSHOT);
+        match pmp.pfs_snapshot(&mut pfs) {
+            Err(crate::Error::Errno(nix::errno::Errno::EEXIST)) => (),
+            Ok(()) => panic!("EEXIST expected"),
+            Err(e) => panic!("{e}"),
+        }
+        if let Err(e) = pmp.pwrite(inum, &b"after!".repeat(20000), 0) {
+            panic!("{e}");
+        }
+        if let Err(e) = pmp.create(crate::inode::INUM_PFS_ROOT, "y", 0o644) {
+            panic!("{e}");
+        }
+        if let Err(e) = pmp.unmount() {
+            panic!("{e}");
+        }
+
+        let v = match super::list_pfs(&f) {
+            Ok(v) => v,
+            Err(e) => panic!("{e}"),
+        };
+        let (Some(data), Some(snap)) = (
+            v.iter().find(|x| x.name == "DATA"),
+            v.iter().find(|x| x.name == "snap"),
+        ) else {
+            panic!("{v:?}");
+        };
+        assert_eq!(snap.get_pfs_subtype_string(), "SNAPSHOT");
+        assert_eq!(
+            snap.pfs_fsid,
+            crate::subs::get_uuid_from_bytes(&pfs.pfs_fsid)
+        );
+        assert_ne!(snap.pfs_fsid, data.pfs_fsid);
+        assert_ne!(snap.pfs_clid, data.pfs_clid);
+        assert_ne!(data.pfs_lsnap_tid, 0);
+        assert_eq!(data.pfs_lsnap_tid, snap.pfs_lsnap_tid);
+        assert!(data.pfs_inum > snap.pfs_inum);
 
-    fn init_std_logger() -> Result<(), log::SetLoggerError> {
-        let env = env_logger::Env::default().filter_or("RUST_LOG", "trace");
-        env_logger::try_init_from_env(env)
+        let mut pmp = match super::Hammer2::mount(&format!("{f}@snap"), &[]) {
+            Ok(v) => v,
+            Err(e) => panic!("{e}"),
+        };
+        match read_file(&mut pmp, "x") {
+            Ok(v) => assert_eq!(v, b"before"),
+            Err(e) => panic!("{e}"),
+        }
+        assert!(read_file(&mut pmp, "y").is_err());
+        if let Err(e) = pmp.unmount() {
+            panic!("{e}");
+        }
+        let mut pmp = match super::Hammer2::mount(&f, &[]) {
+            Ok(v) => v,
+            Err(e) => panic!("{e}"),
+        };
+        match read_file(&mut pmp, "x") {
+            Ok(v) => assert_eq!(v, b"after!"),
+            Err(e) => panic!("{e}"),
+        }
+        assert!(read_file(&mut pmp, "y").is_ok());
+        if let Err(e) = pmp.unmount() {
+            panic!("{e}");
+        }
+        // blocks shared with the snapshot are not freed
+        match crate::bulkfree::bulkfree(&f, true) {
+            Ok(v) => assert_eq!(v.count_allocated, 0),
+            Err(e) => panic!("{e}"),
+        }
+        match crate::fsck::fsck(&f) {
+            Ok(v) => assert!(v.is_clean(), "{:?}", v.problems),
+            Err(e) => panic!("{e}"),
+        }
+        let _ = std::fs::remove_file(f);
     }
 
-    fn read_all(pmp: &mut super::Hammer2, inum: u64) -> crate::Result<Vec<u8>> {
-        let st = pmp.stat(inum)?;
-        let mut resid = st.st_size;
-        let size = if resid / 10 > 0 { resid / 10 } else { resid };
-        let mut offset = 0;
-        let mut v = vec![];
-        while resid > 0 {
-            let b = pmp.preadx(inum, size, offset)?;
-            let n = u64::try_from(b.len()).or_range()?;
-            v.extend(b);
-            offset += n;
-            resid -= n;
+    #[test]
+    fn test_pfs_create_delete() {
+        // older volume headers must not keep blocks of the deleted PFS
+        let f = crate::newfs::create_newfs_image("pfs_create", 8 << 30);
+        let clid = uuid::Uuid::new_v4();
+        let mut pfs = crate::ioctl::IocPfs::new();
+        pfs.copy_name(b"NEW");
+        pfs.pfs_type = crate::fs::HAMMER2_PFSTYPE_SLAVE;
+        pfs.pfs_clid = *clid.as_bytes();
+        if let Err(e) = super::pfs_create(&f, &mut pfs) {
+            panic!("{e}");
+        }
+        assert_eq!(pfs.pfs_clid, *clid.as_bytes());
+        assert_ne!(pfs.pfs_fsid, [0; 16]);
+        match super::pfs_create(&f, &mut pfs) {
+            Err(crate::Error::Errno(nix::errno::Errno::EEXIST)) => (),
+            Ok(()) => panic!("EEXIST expected"),
+            Err(e) => panic!("{e}"),
         }
-        assert_eq!(pmp.preadx(inum, size, offset)?.len(), 0);
-        Ok(v)
-    }
+        let mut bad = crate::ioctl::IocPfs::new();
+        bad.copy_name(b"BAD");
+        bad.pfs_type = crate::fs::HAMMER2_PFSTYPE_SUPROOT;
+        match super::pfs_create(&f, &mut bad) {
+            Err(crate::Error::Errno(nix::errno::Errno::EINVAL)) => (),
+            Ok(()) => panic!("EINVAL expected"),
+            Err(e) => panic!("{e}"),
+        }
+        let v = match super::list_pfs(&f) {
+            Ok(v) => v,
+            Err(e) => panic!("{e}"),
+        };
+        let Some(x) = v.iter().find(|x| x.name == "NEW") else {
+            panic!("{v:?}");
+        };
+        assert_eq!(x.get_pfs_type_string(), "SLAVE");
+        assert_eq!(
+            x.pfs_clid,
+            crate::subs::get_uuid_from_bytes(clid.as_bytes())
+        );
+        assert_eq!(x.pfs_fsid, crate::subs::get_uuid_from_bytes(&pfs.pfs_fsid));
 
-    fn is_zero(v: &[u8]) -> bool {
-        if v.is_empty() {
-            return true;
+        let mut pmp = match super::Hammer2::mount(&format!("{f}@NEW"), &["--rw"]) {
+            Ok(v) => v,
+            Err(e) => panic!("{e}"),
+        };
+        let inum = match pmp.create(crate::inode::INUM_PFS_ROOT, "x", 0o644) {
+            Ok(v) => v,
+            Err(e) => panic!("{e}"),
+        };
+        // incompressible (xorshift64), so that freeing it shows in the freemap
+        let mut x = 0x2545_f491_4f6c_dd1d_u64;
+        let b: Vec<u8> = (0..1 << 20)
+            .map(|_| {
+                x ^= x << 13;
+                x ^= x >> 7;
+                x ^= x << 17;
+                x.to_le_bytes()[0]
+            })
+            .collect();
+        if let Err(e) = pmp.pwrite(inum, &b, 0) {
+            panic!("{e}");
+        }
+        match pmp.pfs_delete(&pfs) {
+            Err(crate::Error::Errno(nix::errno::Errno::EBUSY)) => (),
+            Ok(()) => panic!("EBUSY expected"),
+            Err(e) => panic!("{e}"),
         }
-        for x in v {
-            if *x != 0 {
-                return false;
-            }
+        if let Err(e) = pmp.unmount() {
+            panic!("{e}");
+        }
+        match crate::fsck::fsck(&f) {
+            Ok(v) => assert!(v.is_clean(), "{:?}", v.problems),
+            Err(e) => panic!("{e}"),
+        }
+        let mut pmp = match super::Hammer2::mount(&f, &[]) {
+            Ok(v) => v,
+            Err(e) => panic!("{e}"),
+        };
+        let free_size = match pmp.read_freemap() {
+            Ok(v) => v.summary().free_size,
+            Err(e) => panic!("{e}"),
+        };
+        if let Err(e) = pmp.unmount() {
+            panic!("{e}");
         }
-        true
-    }
 
-    fn test_hammer2_path(pmp: &mut super::Hammer2, f: &str) {
-        log::info!("{f}");
-        match pmp.nresolve_path(f) {
-            Ok(inum) => {
-                log::info!("{inum} {inum:#x}");
-                match pmp.stat(inum) {
-                    Ok(st) => {
-                        log::info!("{st:?}");
-                        match st.st_mode & libc::S_IFMT {
-                            libc::S_IFDIR => match pmp.readdir(inum) {
-                                Ok(v) => log::info!("{}: {v:?}", v.len()),
-                                Err(e) => panic!("{e}"),
-                            },
-                            libc::S_IFREG => {
-                                let (sum1, is_zero1) = match pmp.read_all(inum) {
-                                    Ok(v) => {
-                                        assert_eq!(
-                                            v.len(),
-                                            match st.st_size.try_into() {
-                                                Ok(v) => v,
-                                                Err(e) => panic!("{e}"),
-                                            }
-                                        );
-                                        match libfs::string::b2s(&v) {
-                                            Ok(v) => println!("{v}"),
-                                            Err(e) => panic!("{e}"),
-                                        }
-                                        (hex::encode(crate::sha::sha256(&v)), is_zero(&v))
-                                    }
-                                    Err(e) => panic!("{e}"),
-                                };
-                                log::info!("sha256: {sum1}");
-                                let (sum2, is_zero2) = match read_all(pmp, inum) {
-                                    Ok(v) => {
-                                        assert_eq!(
-                                            v.len(),
-                                            match st.st_size.try_into() {
-                                                Ok(v) => v,
-                                                Err(e) => panic!("{e}"),
-                                            }
-                                        );
-                                        match libfs::string::b2s(&v) {
-                                            Ok(v) => println!("{v}"),
-                                            Err(e) => panic!("{e}"),
-                                        }
-                                        (hex::encode(crate::sha::sha256(&v)), is_zero(&v))
-                                    }
-                                    Err(e) => panic!("{e}"),
-                                };
-                                log::info!("sha256: {sum2}");
-                                assert_eq!(sum1, sum2);
-                                assert_eq!(is_zero1, is_zero2);
-                                match pmp.bmap(inum, 0) {
-                                    Ok(v) => log::info!("{v:016x}"),
-                                    Err(e) => panic!("{e}"),
-                                }
-                            }
-                            libc::S_IFLNK => match pmp.readlinkx(inum) {
-                                Ok(v) => {
-                                    log::info!("\"{v}\"");
-                                    assert_eq!(
-                                        v.len(),
-                                        match st.st_size.try_into() {
-                                            Ok(v) => v,
-                                            Err(e) => panic!("{e}"),
-                                        }
-                                    );
-                                    match pmp.bmap(inum, 0) {
-                                        Ok(v) => log::info!("{v:016x}"),
-                                        Err(e) => panic!("{e}"),
-                                    }
-                                }
-                                Err(e) => panic!("{e}"),
-                            },
-                            _ => (),
-                        }
-                    }
-                    Err(e) => panic!("{e}"),
-                }
-            }
+        if let Err(e) = super::pfs_delete(&f, &pfs) {
+            panic!("{e}");
+        }
+        match super::pfs_delete(&f, &pfs) {
+            Err(crate::Error::Errno(nix::errno::Errno::ENOENT)) => (),
+            Ok(()) => panic!("ENOENT expected"),
+            Err(e) => panic!("{e}"),
+        }
+        match super::list_pfs(&f) {
+            Ok(v) => assert!(!v.iter().any(|x| x.name == "NEW"), "{v:?}"),
+            Err(e) => panic!("{e}"),
+        }
+        let mut pmp = match super::Hammer2::mount(&f, &[]) {
+            Ok(v) => v,
+            Err(e) => panic!("{e}"),
+        };
+        match pmp.read_freemap() {
+            Ok(v) => assert!(v.summary().free_size >= free_size + (1 << 20)),
+            Err(e) => panic!("{e}"),
+        }
+        if let Err(e) = pmp.unmount() {
+            panic!("{e}");
+        }
+        match crate::fsck::fsck(&f) {
+            Ok(v) => assert!(v.is_clean(), "{:?}", v.problems),
             Err(e) => panic!("{e}"),
         }
+        let _ = std::fs::remove_file(f);
     }
 
     #[test]
@@ -1431,10 +3782,10 @@ mod tests {
                 Err(e) => panic!("{e}"),
             };
             // dump_chain
-            if let Err(e) = pmp.dump_vchain() {
+            if let Err(e) = pmp.get_mut().dump_vchain() {
                 panic!("{e}");
             }
-            if let Err(e) = pmp.dump_fchain() {
+            if let Err(e) = pmp.get_mut().dump_fchain() {
                 panic!("{e}");
             }
             // statfs
@@ -1511,10 +3862,10 @@ mod tests {
                 test_hammer2_path(&mut pmp, &f);
             }
             // dump_chain
-            if let Err(e) = pmp.dump_vchain() {
+            if let Err(e) = pmp.get_mut().dump_vchain() {
                 panic!("{e}");
             }
-            if let Err(e) = pmp.dump_fchain() {
+            if let Err(e) = pmp.get_mut().dump_fchain() {
                 panic!("{e}");
             }
             // unmount
@@ -1523,4 +3874,191 @@ mod tests {
             }
         }
     }
+
+    #[test]
+    fn test_indirect_loop() {
+        let f = crate::newfs::create_image("loop", 128 << 20);
+        if let Err(e) = crate::newfs::Newfs::new()
+            .check_algo(crate::fs::HAMMER2_CHECK_NONE)
+            .quiet(true)
+            .format(&f)
+        {
+            panic!("{e}");
+        }
+        let mut pmp = match super::Hammer2::mount(&f, &["--rw"]) {
+            Ok(v) => v,
+            Err(e) => panic!("{e}"),
+        };
+        for i in 0..50 {
+            if let Err(e) = pmp.create(crate::inode::INUM_PFS_ROOT, &format!("f{i}"), 0o644) {
+                panic!("{e}");
+            }
+        }
+        if let Err(e) = pmp.unmount() {
+            panic!("{e}");
+        }
+
+        // make the first entry of an indirect block of inodes point to itself
+        let mut pmp = match super::Hammer2::mount(&f, &[]) {
+            Ok(v) => v,
+            Err(e) => panic!("{e}"),
+        };
+        let cid = match pmp.get_inode_chain(crate::inode::INUM_PFS_ROOT, super::RESOLVE_ALWAYS) {
+            Ok(v) => v,
+            Err(e) => panic!("{e}"),
+        };
+        let bref = match pmp
+            .get_mut()
+            .cmap
+            .get(&cid)
+            .map(crate::chain::Chain::as_blockref)
+        {
+            Some(Ok(v)) => match v.iter().find(|x| x.is_node_type()) {
+                Some(x) => **x,
+                None => panic!("no indirect block"),
+            },
+            Some(Err(e)) => panic!("{e}"),
+            None => panic!("{cid}"),
+        };
+        if let Err(e) = pmp.unmount() {
+            panic!("{e}");
+        }
+        let fso = match crate::ondisk::init_quiet(&f, false) {
+            Ok(v) => v,
+            Err(e) => panic!("{e}"),
+        };
+        let mut media = match fso.read_media(&bref) {
+            Ok(v) => v,
+            Err(e) => panic!("{e}"),
+        };
+        let n = std::mem::size_of::<crate::fs::Hammer2Blockref>();
+        media[..n].copy_from_slice(libfs::cast::as_u8_slice(&bref));
+        if let Err(e) = fso.write_media(&bref, &media) {
+            panic!("{e}");
+        }
+        drop(fso);
+
+        let mut pmp = match super::Hammer2::mount(&f, &[]) {
+            Ok(v) => v,
+            Err(e) => panic!("{e}"),
+        };
+        match pmp.nresolve(crate::inode::INUM_PFS_ROOT, "f0") {
+            Err(crate::Error::Corrupt(e)) => assert_eq!(e.bref, bref),
+            Ok(v) => panic!("{v:?}"),
+            Err(e) => panic!("{e}"),
+        }
+        if let Err(e) = pmp.unmount() {
+            panic!("{e}");
+        }
+        let _ = std::fs::remove_file(f);
+    }
+
+    // Fails reads once the given number of reads succeeded.
+    #[derive(Debug)]
+    struct FaultDevice {
+        dev: crate::device::MemoryDevice,
+        nreads: std::sync::atomic::AtomicUsize,
+    }
+
+    impl crate::device::BlockDevice for FaultDevice {
+        fn pread(&self, buf: &mut [u8], offset: u64) -> crate::Result<()> {
+            if self
+                .nreads
+                .fetch_update(
+                    std::sync::atomic::Ordering::Relaxed,
+                    std::sync::atomic::Ordering::Relaxed,
+                    |x| x.checked_sub(1),
+                )
+                .is_err()
+            {
+                return Err(nix::errno::Errno::EIO.into());
+            }
+            self.dev.pread(buf, offset)
+        }
+
+        fn pwrite(&self, buf: &[u8], offset: u64) -> crate::Result<()> {
+            self.dev.pwrite(buf, offset)
+        }
+
+        fn get_size(&mut self) -> crate::Result<u64> {
+            self.dev.get_size()
+        }
+
+        fn flush(&self) -> crate::Result<()> {
+            self.dev.flush()
+        }
+    }
+
+    #[test]
+    fn test_mount_devices() {
+        let dev = match crate::device::MemoryDevice::new(128 << 20) {
+            Ok(v) => v,
+            Err(e) => panic!("{e}"),
+        };
+        assert!(super::Hammer2::mount_devices(vec![Box::new(dev.clone())], "", &[]).is_err());
+        if let Err(e) = crate::newfs::Newfs::new()
+            .quiet(true)
+            .format_devices(vec![Box::new(dev.clone())])
+        {
+            panic!("{e}");
+        }
+        let mut pmp =
+            match super::Hammer2::mount_devices(vec![Box::new(dev.clone())], "", &["--rw"]) {
+                Ok(v) => v,
+                Err(e) => panic!("{e}"),
+            };
+        let data = b"HAMMER2".repeat(10000);
+        match pmp.create(crate::inode::INUM_PFS_ROOT, "f", 0o644) {
+            Ok(v) => {
+                if let Err(e) = pmp.pwrite(v, &data, 0) {
+                    panic!("{e}");
+                }
+            }
+            Err(e) => panic!("{e}"),
+        }
+        if let Err(e) = pmp.unmount() {
+            panic!("{e}");
+        }
+
+        let mut pmp = match super::Hammer2::mount_devices(
+            vec![Box::new(dev.clone())],
+            crate::inode::PFS_LABEL_DEFAULT,
+            &[],
+        ) {
+            Ok(v) => v,
+            Err(e) => panic!("{e}"),
+        };
+        match pmp.nresolve_path("/f") {
+            Ok(v) => match read_all(&mut pmp, v) {
+                Ok(v) => assert_eq!(v, data),
+                Err(e) => panic!("{e}"),
+            },
+            Err(e) => panic!("{e}"),
+        }
+        if let Err(e) = pmp.unmount() {
+            panic!("{e}");
+        }
+        assert!(super::Hammer2::mount_devices(vec![Box::new(dev.clone())], "xxx", &[]).is_err());
+
+        // read errors at any point of mount are returned
+        let mut nreads = 0;
+        loop {
+            let fdev = FaultDevice {
+                dev: dev.clone(),
+                nreads: nreads.into(),
+            };
+            match super::Hammer2::mount_devices(vec![Box::new(fdev)], "", &[]) {
+                Ok(mut pmp) => {
+                    if let Err(e) = pmp.unmount() {
+                        panic!("{e}");
+                    }
+                    break;
+                }
+                Err(crate::Error::Errno(nix::errno::Errno::EIO)) => nreads += 1,
+                Err(e) => panic!("{nreads}: {e}"),
+            }
+            assert!(nreads < 1000);
+        }
+        assert!(nreads > 0);
+    }
 }
@@ -1,15 +1,35 @@
+pub mod bulkfree;
 pub mod chain;
+pub mod device;
+pub mod dir;
 mod extra;
+pub mod extract;
+pub mod file;
+mod flush;
+pub mod freemap;
 pub mod fs;
+pub mod fsck;
+#[cfg(feature = "fuse")]
+pub mod fuse;
+#[cfg(feature = "fuzz")]
+pub mod fuzz;
 pub mod hammer2;
 pub mod inode;
 pub mod ioctl;
 pub mod lz4;
+pub mod newfs;
 pub mod ondisk;
 mod option;
+pub mod partition;
+mod pfs;
+pub mod qcow2;
 pub mod sha;
+pub mod sparse;
 pub mod subs;
+mod swap;
+pub mod tar;
 pub mod volume;
+pub mod walk;
 mod xop;
 pub mod xxhash;
 pub mod zlib;
@@ -25,6 +45,7 @@ pub enum Error {
     Error(std::io::Error),
     Errno(nix::errno::Errno),
     Dyn(Box<dyn std::error::Error + Send + Sync + 'static>),
+    Corrupt(Box<Corruption>),
 }
 
 impl Display for Error {
@@ -33,10 +54,45 @@ impl Display for Error {
             Self::Error(e) => write!(f, "{e}"),
             Self::Errno(e) => write!(f, "{e}"),
             Self::Dyn(e) => write!(f, "{e}"),
+            Self::Corrupt(e) => write!(f, "{e}"),
         }
     }
 }
 
+// Damaged ondisk data found while parsing media of a blockref.
+// offset is the byte offset of the bad data on the volumes.
+#[derive(Debug)]
+pub struct Corruption {
+    pub bref: fs::Hammer2Blockref,
+    pub offset: u64,
+    pub reason: String,
+}
+
+impl Corruption {
+    // offset is relative to the media of the blockref.
+    pub(crate) fn new_error(bref: &fs::Hammer2Blockref, offset: usize, reason: &str) -> Error {
+        Error::Corrupt(Box::new(Self {
+            bref: *bref,
+            offset: bref
+                .get_raw_data_off()
+                .saturating_add(u64::try_from(offset).unwrap_or(u64::MAX)),
+            reason: reason.to_string(),
+        }))
+    }
+}
+
+impl Display for Corruption {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "corrupted {} at {:#018x}: {}",
+            subs::get_blockref_type_string(self.bref.typ),
+            self.offset,
+            self.reason
+        )
+    }
+}
+
 impl std::error::Error for Error {}
 
 impl From<std::io::Error> for Error {
@@ -51,6 +107,17 @@ impl From<nix::errno::Errno> for Error {
     }
 }
 
+impl From<Error> for std::io::Error {
+    fn from(e: Error) -> Self {
+        match e {
+            Error::Error(e) => e,
+            Error::Errno(e) => Self::from_raw_os_error(e as i32),
+            Error::Dyn(e) => Self::other(e),
+            Error::Corrupt(e) => Self::new(std::io::ErrorKind::InvalidData, e.to_string()),
+        }
+    }
+}
+
 pub trait OptionExt<T> {
     /// # Errors
     fn or_range(self) -> Result<T>;
@@ -9,8 +9,8 @@ pub fn compress(buf: &[u8]) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
             (dst.len() - 8).try_into()?,
         )
     };
-    if res >= 0 {
-        dst[..4].copy_from_slice(&res.to_le_bytes());
+    if res > 0 {
+        dst[..4].copy_from_slice(&res.to_ne_bytes());
         Ok(dst[..(4 + res).try_into()?].to_vec())
     } else {
         Err(Box::new(nix::errno::Errno::EINVAL))
@@ -18,12 +18,18 @@ pub fn compress(buf: &[u8]) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
 }
 
 /// # Errors
-/// # Panics
 pub fn decompress(buf: &[u8], max_size: usize) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
-    assert!(max_size <= crate::subs::DEBUFSIZE);
+    if max_size > crate::subs::DEBUFSIZE {
+        return Err(Box::new(nix::errno::Errno::EINVAL));
+    }
     let mut dst = vec![0; crate::subs::DEBUFSIZE + 128];
-    let cinsize = i32::from_le_bytes(buf[..4].try_into()?);
-    assert!(cinsize <= buf.len().try_into()?);
+    let Some(b) = buf.get(..4) else {
+        return Err(format!("{} bytes input", buf.len()).into());
+    };
+    let cinsize = i32::from_ne_bytes(b.try_into()?);
+    if cinsize < 0 || usize::try_from(cinsize)? > buf.len() - 4 {
+        return Err(format!("bad compressed size {cinsize}").into());
+    }
     let res = unsafe {
         lz4::liblz4::LZ4_decompress_safe(
             std::ptr::from_ref::<[u8]>(&buf[4..]).cast::<i8>(),
@@ -62,4 +68,19 @@ mod tests {
             assert_eq!(d, b, "{b:?}");
         }
     }
+
+    #[test]
+    fn test_decompress_corrupted() {
+        assert!(super::decompress(&[], crate::subs::DEBUFSIZE).is_err());
+        assert!(super::decompress(&[1, 2], crate::subs::DEBUFSIZE).is_err());
+        assert!(super::decompress(&[0xff; 64], crate::subs::DEBUFSIZE).is_err());
+        assert!(super::decompress(b"x", crate::subs::DEBUFSIZE + 1).is_err());
+
+        let mut c = match super::compress(b"HAMMER2") {
+            Ok(v) => v,
+            Err(e) => panic!("{e}"),
+        };
+        c.truncate(c.len() - 1);
+        assert!(super::decompress(&c, crate::subs::DEBUFSIZE).is_err());
+    }
 }
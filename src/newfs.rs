@@ -0,0 +1,697 @@
+use crate::ErrorExt;
+
+// All blockref mirror TIDs are set to 16.
+const NEWFS_MIRROR_TID: u64 = 16;
+// First allocatable inode number of a PFS.
+const NEWFS_PFS_INUM: u64 = 16;
+
+#[derive(Debug)]
+pub struct Newfs {
+    version: u32,
+    labels: Vec<String>,
+    comp_algo: u8,
+    check_algo: u8,
+    boot_area_size: u64,
+    aux_area_size: u64,
+    quiet: bool,
+}
+
+impl Default for Newfs {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Newfs {
+    #[must_use]
+    pub fn new() -> Self {
+        Self {
+            version: crate::fs::HAMMER2_VOL_VERSION_DEFAULT,
+            labels: vec![
+                crate::inode::PFS_LABEL_DEFAULT.to_string(),
+                crate::inode::PFS_LABEL_LOCAL.to_string(),
+            ],
+            comp_algo: crate::fs::HAMMER2_COMP_DEFAULT,
+            check_algo: crate::fs::HAMMER2_CHECK_DEFAULT,
+            boot_area_size: 0,
+            aux_area_size: 0,
+            quiet: false,
+        }
+    }
+
+    #[must_use]
+    pub fn version(mut self, version: u32) -> Self {
+        self.version = version;
+        self
+    }
+
+    #[must_use]
+    pub fn labels(mut self, labels: &[&str]) -> Self {
+        self.labels = labels.iter().map(ToString::to_string).collect();
+        self
+    }
+
+    #[must_use]
+    pub fn comp_algo(mut self, comp_algo: u8) -> Self {
+        self.comp_algo = comp_algo;
+        self
+    }
+
+    #[must_use]
+    pub fn check_algo(mut self, check_algo: u8) -> Self {
+        self.check_algo = check_algo;
+        self
+    }
+
+    // 0 for default size
+    #[must_use]
+    pub fn boot_area_size(mut self, size: u64) -> Self {
+        self.boot_area_size = size;
+        self
+    }
+
+    // 0 for default size
+    #[must_use]
+    pub fn aux_area_size(mut self, size: u64) -> Self {
+        self.aux_area_size = size;
+        self
+    }
+
+    #[must_use]
+    pub fn quiet(mut self, quiet: bool) -> Self {
+        self.quiet = quiet;
+        self
+    }
+
+    fn verify(&self) -> crate::Result<()> {
+        if self.version < crate::fs::HAMMER2_VOL_VERSION_MIN
+            || self.version >= crate::fs::HAMMER2_VOL_VERSION_WIP
+        {
+            log::error!("bad version {}", self.version);
+            return Err(nix::errno::Errno::EINVAL.into());
+        }
+        if self.labels.is_empty() || self.labels.len() > crate::fs::HAMMER2_SET_COUNT {
+            log::error!("bad number of labels {}", self.labels.len());
+            return Err(nix::errno::Errno::EINVAL.into());
+        }
+        for (i, s) in self.labels.iter().enumerate() {
+            if s.is_empty() || s.len() > crate::fs::HAMMER2_INODE_MAXNAME {
+                log::error!("bad label \"{s}\"");
+                return Err(nix::errno::Errno::EINVAL.into());
+            }
+            if self.labels[..i].contains(s) {
+                log::error!("label \"{s}\" specified more than once");
+                return Err(nix::errno::Errno::EINVAL.into());
+            }
+        }
+        if !matches!(
+            self.comp_algo,
+            crate::fs::HAMMER2_COMP_NONE
+                | crate::fs::HAMMER2_COMP_AUTOZERO
+                | crate::fs::HAMMER2_COMP_LZ4
+                | crate::fs::HAMMER2_COMP_ZLIB
+        ) {
+            log::error!("bad compression algorithm {}", self.comp_algo);
+            return Err(nix::errno::Errno::EINVAL.into());
+        }
+        if !matches!(
+            self.check_algo,
+            crate::fs::HAMMER2_CHECK_NONE
+                | crate::fs::HAMMER2_CHECK_DISABLED
+                | crate::fs::HAMMER2_CHECK_ISCSI32
+                | crate::fs::HAMMER2_CHECK_XXHASH64
+                | crate::fs::HAMMER2_CHECK_SHA192
+        ) {
+            log::error!("bad check algorithm {}", self.check_algo);
+            return Err(nix::errno::Errno::EINVAL.into());
+        }
+        Ok(())
+    }
+
+    // Collect volumes of the colon separated spec.
+    fn install_volumes(&self, spec: &str) -> crate::Result<crate::ondisk::Ondisk> {
+        let mut v = vec![];
+        for path in spec.split(':') {
+            v.push((
+                path.to_string(),
+                crate::partition::open(path, false, false)?,
+            ));
+        }
+        self.install_devices(v)
+    }
+
+    fn install_devices(
+        &self,
+        v: Vec<(String, Box<dyn crate::device::BlockDevice>)>,
+    ) -> crate::Result<crate::ondisk::Ondisk> {
+        let mut fso = if self.quiet {
+            crate::ondisk::Ondisk::new_quiet(Some(self.version))
+        } else {
+            crate::ondisk::Ondisk::new(Some(self.version))
+        };
+        if v.len() > crate::fs::HAMMER2_MAX_VOLUMES.into() {
+            log::error!(
+                "exceeds maximum supported number of volumes {}",
+                crate::fs::HAMMER2_MAX_VOLUMES
+            );
+            return Err(nix::errno::Errno::EINVAL.into());
+        }
+        if v.len() > 1 && self.version < crate::fs::HAMMER2_VOL_VERSION_MULTI_VOLUMES {
+            log::error!("version {} doesn't support multiple volumes", self.version);
+            return Err(nix::errno::Errno::EINVAL.into());
+        }
+        let n = v.len();
+        let mut offset = 0;
+        for (i, (path, mut dev)) in v.into_iter().enumerate() {
+            let size = dev.get_size()?;
+            // Non-last volumes are 1GB aligned for the freemap.
+            let size = if i == n - 1 {
+                size & !crate::fs::HAMMER2_VOLUME_ALIGNMASK
+            } else {
+                size & !crate::fs::HAMMER2_FREEMAP_LEVEL1_MASK
+            };
+            if size == 0 {
+                log::error!("{path} is too small");
+                return Err(nix::errno::Errno::EINVAL.into());
+            }
+            fso.install_volume_device(i.try_into().or_range()?, &path, dev, offset, size);
+            offset += size;
+        }
+        Ok(fso)
+    }
+
+    /// # Errors
+    pub fn format(&self, spec: &str) -> crate::Result<()> {
+        self.verify()?;
+        let fso = self.install_volumes(spec)?;
+        self.format_ondisk(fso)
+    }
+
+    // Same as format, but with devices instead of a spec.
+    /// # Errors
+    pub fn format_devices(
+        &self,
+        devs: Vec<Box<dyn crate::device::BlockDevice>>,
+    ) -> crate::Result<()> {
+        self.verify()?;
+        let v = devs
+            .into_iter()
+            .enumerate()
+            .map(|(i, dev)| (crate::ondisk::get_device_name(i), dev))
+            .collect();
+        let fso = self.install_devices(v)?;
+        self.format_ondisk(fso)
+    }
+
+    fn format_ondisk(&self, mut fso: crate::ondisk::Ondisk) -> crate::Result<()> {
+        let total_size = fso.get_total_size();
+        let boot_area_size = get_area_size(
+            self.boot_area_size,
+            total_size,
+            crate::fs::HAMMER2_BOOT_NOM_BYTES,
+            crate::fs::HAMMER2_BOOT_MIN_BYTES,
+        );
+        let aux_area_size = get_area_size(
+            self.aux_area_size,
+            total_size,
+            crate::fs::HAMMER2_AUX_NOM_BYTES,
+            crate::fs::HAMMER2_AUX_MIN_BYTES,
+        );
+        // 4MB is reserved at the beginning of every 1GB, which also
+        // includes the boot and aux areas.  Like newfs_hammer2, this is
+        // per freemap level1 rather than per 2GB zone, so the volume
+        // header at the beginning of each zone is reserved either way.
+        let reserved_size = total_size.div_ceil(crate::fs::HAMMER2_FREEMAP_LEVEL1_SIZE)
+            * crate::fs::HAMMER2_ZONE_SEG;
+        let Some(free_size) =
+            total_size.checked_sub(reserved_size + boot_area_size + aux_area_size)
+        else {
+            log::error!("not enough free space");
+            return Err(nix::errno::Errno::ENOSPC.into());
+        };
+        // Initial allocations must be within the first 1GB of the root volume.
+        let boot_base = crate::fs::HAMMER2_ZONE_SEG;
+        let aux_base = boot_base + boot_area_size;
+        let alloc_base = aux_base + aux_area_size;
+        if alloc_base + crate::fs::HAMMER2_PBUFSIZE
+            > crate::fs::HAMMER2_FREEMAP_LEVEL1_SIZE.min(fso[0].get_size())
+        {
+            log::error!("boot and aux areas too large");
+            return Err(nix::errno::Errno::EINVAL.into());
+        }
+        if !self.quiet {
+            for s in &fso.fmt_volumes() {
+                println!("{s}");
+            }
+            println!("boot area size {boot_area_size:#018x}");
+            println!("aux area size  {aux_area_size:#018x}");
+            println!("free size      {free_size:#018x}");
+        }
+
+        let vol = &mut fso[0];
+        // Clear the reserved area of the first 1GB and the boot and aux areas.
+        let buf = vec![0; crate::fs::HAMMER2_PBUFSIZE.try_into().or_range()?];
+        let mut offset = 0;
+        while offset < alloc_base {
+            vol.pwrite(&buf, offset)?;
+            offset += crate::fs::HAMMER2_PBUFSIZE;
+        }
+        let (sroot_blockref, allocator_beg) = self.format_inode(vol, alloc_base)?;
+        let freemap_blockref = format_freemap(vol, allocator_beg, total_size)?;
+
+        let mut voldata = crate::fs::Hammer2VolumeData::new();
+        voldata.magic = crate::fs::HAMMER2_VOLUME_ID_HBO;
+        voldata.version = self.version;
+        voldata.peer_type = crate::fs::HAMMER2_PEER_HAMMER2;
+        voldata.fsid = *uuid::Uuid::new_v4().as_bytes();
+        voldata.fstype = *crate::subs::get_uuid_from_str(crate::fs::HAMMER2_UUID_STRING)
+            .or_range()?
+            .as_bytes();
+        voldata.allocator_size = free_size;
+        voldata.mirror_tid = NEWFS_MIRROR_TID;
+        voldata.freemap_tid = NEWFS_MIRROR_TID;
+        if self.version >= crate::fs::HAMMER2_VOL_VERSION_MULTI_VOLUMES {
+            voldata.nvolumes = fso.get_nvolumes().try_into().or_range()?;
+            voldata.total_size = total_size;
+            voldata.volu_loff.fill(u64::MAX);
+            for (i, vol) in fso.get_volumes().iter().enumerate() {
+                voldata.volu_loff[i] = vol.get_offset();
+            }
+        }
+        for vol in fso.get_volumes_mut() {
+            let mut voldata = voldata;
+            voldata.volu_size = vol.get_size();
+            if vol.get_id() == crate::fs::HAMMER2_ROOT_VOLUME.into() {
+                voldata.boot_beg = boot_base;
+                voldata.boot_end = boot_base + boot_area_size;
+                voldata.aux_beg = aux_base;
+                voldata.aux_end = aux_base + aux_area_size;
+                voldata.allocator_free = free_size;
+                voldata.allocator_beg = allocator_beg;
+                voldata.sroot_blockset.blockref[0] = sroot_blockref;
+                voldata.freemap_blockset.blockref[0] = freemap_blockref;
+            }
+            if self.version >= crate::fs::HAMMER2_VOL_VERSION_MULTI_VOLUMES {
+                voldata.volu_id = vol.get_id().try_into().or_range()?;
+            }
+            voldata.set_crc()?;
+            // Write the volume header and all alternates.
+            let n = crate::volume::get_volume_data_count(vol.get_size());
+            for i in 0..n {
+                vol.pwrite(
+                    libfs::cast::as_u8_slice(&voldata),
+                    crate::volume::get_volume_data_offset(i)?,
+                )?;
+            }
+            vol.fsync()?;
+        }
+        fso.verify_volumes(true)
+    }
+
+    // Format the super-root inode and PFS root inodes within a 64KB block.
+    fn format_inode(
+        &self,
+        vol: &mut crate::volume::Volume,
+        alloc_base: u64,
+    ) -> crate::Result<(crate::fs::Hammer2Blockref, u64)> {
+        let now = crate::subs::get_current_time()?;
+        let mut alloc_base = alloc_base;
+        let mut buf = vec![0; crate::fs::HAMMER2_PBUFSIZE.try_into().or_range()?];
+        let base = alloc_base;
+        let sroot_data_off = alloc_direct(&mut alloc_base, crate::fs::HAMMER2_INODE_BYTES)?;
+
+        let mut root_blockref = vec![];
+        for label in &self.labels {
+            let data_off = alloc_direct(&mut alloc_base, crate::fs::HAMMER2_INODE_BYTES)?;
+            // Do not allow compression for BOOT.
+            let ipdata = if label.eq_ignore_ascii_case(crate::inode::PFS_LABEL_BOOT) {
+                init_pfs_inode_data(
+                    label,
+                    crate::fs::HAMMER2_COMP_AUTOZERO,
+                    crate::fs::HAMMER2_CHECK_XXHASH64,
+                    now,
+                )?
+            } else {
+                init_pfs_inode_data(label, self.comp_algo, self.check_algo, now)?
+            };
+
+            let mut bref = crate::fs::Hammer2Blockref::new(crate::fs::HAMMER2_BREF_TYPE_INODE);
+            bref.key = ipdata.meta.name_key;
+            bref.copyid = crate::fs::HAMMER2_COPYID_LOCAL;
+            bref.methods = crate::fs::enc_check(crate::fs::HAMMER2_CHECK_XXHASH64)
+                | crate::fs::enc_comp(crate::fs::HAMMER2_COMP_NONE);
+            bref.mirror_tid = NEWFS_MIRROR_TID;
+            bref.flags = crate::fs::HAMMER2_BREF_FLAG_PFSROOT;
+            bref.data_off = data_off;
+            let media = libfs::cast::as_u8_slice(&ipdata);
+            crate::ondisk::set_media_check(&mut bref, media)?;
+            copy_media(&mut buf, base, &bref, media)?;
+            root_blockref.push(bref);
+        }
+        root_blockref.sort_by_key(|x| x.key);
+
+        let mut ipdata = crate::fs::Hammer2InodeData::new();
+        let meta = &mut ipdata.meta;
+        meta.version = crate::fs::HAMMER2_INODE_VERSION_ONE;
+        meta.ctime = now;
+        meta.mtime = now;
+        meta.btime = now;
+        meta.typ = crate::fs::HAMMER2_OBJTYPE_DIRECTORY;
+        meta.mode = 0o700; // super-root - root only
+        meta.inum = crate::inode::INUM_SUP_ROOT;
+        meta.nlinks = 2;
+        meta.comp_algo = crate::fs::enc_algo(crate::fs::HAMMER2_COMP_AUTOZERO);
+        meta.check_algo = crate::fs::enc_algo(crate::fs::HAMMER2_CHECK_XXHASH64);
+        meta.pfs_clid = *uuid::Uuid::new_v4().as_bytes();
+        meta.pfs_fsid = *uuid::Uuid::new_v4().as_bytes();
+        meta.pfs_type = crate::fs::HAMMER2_PFSTYPE_SUPROOT;
+        let name = b"SUPROOT";
+        meta.name_len = name.len().try_into().or_range()?;
+        ipdata.filename[..name.len()].copy_from_slice(name);
+        let blockset = ipdata.u_as_mut::<crate::fs::Hammer2Blockset>();
+        blockset.blockref[..root_blockref.len()].copy_from_slice(&root_blockref);
+
+        let mut bref = crate::fs::Hammer2Blockref::new(crate::fs::HAMMER2_BREF_TYPE_INODE);
+        bref.copyid = crate::fs::HAMMER2_COPYID_LOCAL;
+        bref.methods = crate::fs::enc_check(crate::fs::HAMMER2_CHECK_XXHASH64)
+            | crate::fs::enc_comp(crate::fs::HAMMER2_COMP_AUTOZERO);
+        bref.mirror_tid = NEWFS_MIRROR_TID;
+        bref.data_off = sroot_data_off;
+        let media = libfs::cast::as_u8_slice(&ipdata);
+        crate::ondisk::set_media_check(&mut bref, media)?;
+        copy_media(&mut buf, base, &bref, media)?;
+
+        // Write out the 64KB block containing the super-root and PFS roots.
+        vol.pwrite(&buf, base)?;
+        Ok((bref, alloc_base))
+    }
+}
+
+// PFS root inode of a new MASTER PFS.
+pub(crate) fn init_pfs_inode_data(
+    label: &str,
+    comp_algo: u8,
+    check_algo: u8,
+    now: u64,
+) -> crate::Result<crate::fs::Hammer2InodeData> {
+    let mut ipdata = crate::fs::Hammer2InodeData::new();
+    let meta = &mut ipdata.meta;
+    meta.version = crate::fs::HAMMER2_INODE_VERSION_ONE;
+    meta.ctime = now;
+    meta.mtime = now;
+    meta.btime = now;
+    meta.typ = crate::fs::HAMMER2_OBJTYPE_DIRECTORY;
+    meta.mode = 0o755;
+    meta.inum = crate::inode::INUM_PFS_ROOT;
+    meta.nlinks = 1;
+    meta.name_len = label.len().try_into().or_range()?;
+    meta.name_key = crate::subs::dirhash(label.as_bytes());
+    meta.comp_algo = crate::fs::enc_algo(comp_algo);
+    meta.check_algo = crate::fs::enc_algo(check_algo);
+    meta.pfs_clid = *uuid::Uuid::new_v4().as_bytes();
+    meta.pfs_fsid = *uuid::Uuid::new_v4().as_bytes();
+    meta.pfs_type = crate::fs::HAMMER2_PFSTYPE_MASTER;
+    meta.op_flags |= crate::fs::HAMMER2_OPFLAG_PFSROOT;
+    meta.pfs_inum = NEWFS_PFS_INUM;
+    ipdata.filename[..label.len()].copy_from_slice(label.as_bytes());
+    Ok(ipdata)
+}
+
+// Area size defaults to nominal size, which is reduced to 1/20 of
+// the filesystem, and rounded up to the volume alignment.
+fn get_area_size(size: u64, total_size: u64, nom: u64, min: u64) -> u64 {
+    let size = if size == 0 {
+        let mut size = nom;
+        while size > total_size / 20 {
+            size >>= 1;
+        }
+        size
+    } else {
+        size
+    };
+    (size.max(min) + crate::fs::HAMMER2_VOLUME_ALIGNMASK) & !crate::fs::HAMMER2_VOLUME_ALIGNMASK
+}
+
+fn alloc_direct(base: &mut u64, bytes: u64) -> nix::Result<u64> {
+    let radix = u64::from(bytes.trailing_zeros())
+        .max(crate::fs::HAMMER2_RADIX_MIN.try_into().or_nix_range()?);
+    let data_off = *base | radix;
+    *base += 1 << radix;
+    Ok(data_off)
+}
+
+fn copy_media(
+    buf: &mut [u8],
+    base: u64,
+    bref: &crate::fs::Hammer2Blockref,
+    media: &[u8],
+) -> crate::Result<()> {
+    let i = usize::try_from(bref.get_raw_data_off() - base).or_range()?;
+    buf[i..i + media.len()].copy_from_slice(media);
+    Ok(())
+}
+
+// Format a freemap leaf of the first 1GB, which covers the initial
+// allocations.  Leaves of other 1GB are created on allocation.
+fn format_freemap(
+    vol: &mut crate::volume::Volume,
+    allocator_beg: u64,
+    total_size: u64,
+) -> crate::Result<crate::fs::Hammer2Blockref> {
+    let mut bref = crate::fs::Hammer2Blockref::new(crate::fs::HAMMER2_BREF_TYPE_FREEMAP_LEAF);
+    bref.methods = crate::fs::enc_check(crate::fs::HAMMER2_CHECK_FREEMAP)
+        | crate::fs::enc_comp(crate::fs::HAMMER2_COMP_NONE);
+    bref.keybits = crate::fs::HAMMER2_FREEMAP_LEVEL1_RADIX
+        .try_into()
+        .or_range()?;
+    bref.mirror_tid = NEWFS_MIRROR_TID;
+    bref.modify_tid = NEWFS_MIRROR_TID;
+    bref.data_off = crate::freemap::get_freemap_offset(&bref)?
+        | u64::from(crate::fs::HAMMER2_FREEMAP_LEVELN_PSIZE.trailing_zeros());
+    let mut data = vec![
+        0;
+        crate::fs::HAMMER2_FREEMAP_LEVELN_PSIZE
+            .try_into()
+            .or_range()?
+    ];
+    let check = bref.check_as_mut::<crate::fs::Hammer2BlockrefCheckFreemap>();
+    check.avail = crate::freemap::init_leaf(&mut data, 0, allocator_beg, total_size)?;
+    check.bigmask = u32::MAX;
+    crate::ondisk::set_media_check(&mut bref, &data)?;
+    vol.pwrite(&data, bref.get_raw_data_off())?;
+    Ok(bref)
+}
+
+// Path of a per-process temporary image for tests.
+#[cfg(test)]
+pub(crate) fn get_image_path(name: &str) -> String {
+    let f = std::env::temp_dir().join(format!("libhammer2_{name}_{}.img", std::process::id()));
+    match f.to_str() {
+        Some(v) => v.to_string(),
+        None => panic!("{f:?}"),
+    }
+}
+
+// Create a sparse image of size bytes for tests.
+#[cfg(test)]
+pub(crate) fn create_image(name: &str, size: u64) -> String {
+    let f = get_image_path(name);
+    let fp = match std::fs::File::create(&f) {
+        Ok(v) => v,
+        Err(e) => panic!("{e}"),
+    };
+    if let Err(e) = fp.set_len(size) {
+        panic!("{e}");
+    }
+    f
+}
+
+// Same as create_image, but formatted with default options.
+#[cfg(test)]
+pub(crate) fn create_newfs_image(name: &str, size: u64) -> String {
+    let f = create_image(name, size);
+    if let Err(e) = crate::ondisk::newfs(&f) {
+        panic!("{e}");
+    }
+    f
+}
+
+#[cfg(test)]
+mod tests {
+    use super::create_image;
+
+    fn init_std_logger() -> Result<(), log::SetLoggerError> {
+        let env = env_logger::Env::default().filter_or("RUST_LOG", "trace");
+        env_logger::try_init_from_env(env)
+    }
+
+    #[test]
+    fn test_get_area_size() {
+        let x = 1 << 30;
+        assert_eq!(
+            super::get_area_size(
+                0,
+                x,
+                crate::fs::HAMMER2_BOOT_NOM_BYTES,
+                crate::fs::HAMMER2_BOOT_MIN_BYTES
+            ),
+            32 << 20
+        );
+        assert_eq!(
+            super::get_area_size(
+                0,
+                100 * x,
+                crate::fs::HAMMER2_BOOT_NOM_BYTES,
+                crate::fs::HAMMER2_BOOT_MIN_BYTES
+            ),
+            crate::fs::HAMMER2_BOOT_NOM_BYTES
+        );
+        assert_eq!(
+            super::get_area_size(
+                0,
+                x / 16,
+                crate::fs::HAMMER2_AUX_NOM_BYTES,
+                crate::fs::HAMMER2_AUX_MIN_BYTES
+            ),
+            crate::fs::HAMMER2_AUX_MIN_BYTES
+        );
+        assert_eq!(
+            super::get_area_size(
+                1,
+                x,
+                crate::fs::HAMMER2_AUX_NOM_BYTES,
+                crate::fs::HAMMER2_AUX_MIN_BYTES
+            ),
+            crate::fs::HAMMER2_AUX_MIN_BYTES
+        );
+        assert_eq!(
+            super::get_area_size(
+                crate::fs::HAMMER2_VOLUME_ALIGN * 3 - 1,
+                x,
+                crate::fs::HAMMER2_AUX_NOM_BYTES,
+                crate::fs::HAMMER2_AUX_MIN_BYTES
+            ),
+            crate::fs::HAMMER2_VOLUME_ALIGN * 3
+        );
+    }
+
+    #[test]
+    fn test_alloc_direct() {
+        let mut base = 0x10000;
+        assert_eq!(super::alloc_direct(&mut base, 1024), Ok(0x10000 | 10));
+        assert_eq!(super::alloc_direct(&mut base, 1024), Ok(0x10400 | 10));
+        assert_eq!(super::alloc_direct(&mut base, 1), Ok(0x10800 | 10));
+        assert_eq!(super::alloc_direct(&mut base, 0x4000), Ok(0x10c00 | 14));
+        assert_eq!(base, 0x14c00);
+    }
+
+    #[test]
+    fn test_newfs_verify() {
+        assert!(super::Newfs::new().verify().is_ok());
+        assert!(super::Newfs::new().version(0).verify().is_err());
+        assert!(
+            super::Newfs::new()
+                .version(crate::fs::HAMMER2_VOL_VERSION_WIP)
+                .verify()
+                .is_err()
+        );
+        assert!(super::Newfs::new().labels(&[]).verify().is_err());
+        assert!(super::Newfs::new().labels(&[""]).verify().is_err());
+        assert!(super::Newfs::new().labels(&["A", "A"]).verify().is_err());
+        assert!(
+            super::Newfs::new()
+                .labels(&["A", "B", "C", "D", "E"])
+                .verify()
+                .is_err()
+        );
+        assert!(
+            super::Newfs::new()
+                .labels(&[
+                    crate::inode::PFS_LABEL_BOOT,
+                    crate::inode::PFS_LABEL_ROOT,
+                    crate::inode::PFS_LABEL_DATA,
+                    crate::inode::PFS_LABEL_LOCAL
+                ])
+                .verify()
+                .is_ok()
+        );
+        assert!(super::Newfs::new().comp_algo(4).verify().is_err());
+        assert!(
+            super::Newfs::new()
+                .check_algo(crate::fs::HAMMER2_CHECK_FREEMAP)
+                .verify()
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_newfs_format() {
+        let _ = init_std_logger();
+        let labels = [
+            crate::inode::PFS_LABEL_BOOT,
+            crate::inode::PFS_LABEL_ROOT,
+            crate::inode::PFS_LABEL_DATA,
+            crate::inode::PFS_LABEL_LOCAL,
+        ];
+        let f = create_image("newfs", 256 << 20);
+        let newfs = super::Newfs::new().labels(&labels).quiet(true);
+        if let Err(e) = newfs.format(&f) {
+            panic!("{e}");
+        }
+        match crate::volume::read_volume_data(&f) {
+            Ok(v) => {
+                assert_eq!(v.mirror_tid, super::NEWFS_MIRROR_TID);
+                assert_eq!(v.volu_size, 256 << 20);
+                assert_eq!(v.total_size, 256 << 20);
+                assert_eq!(v.nvolumes, 1);
+            }
+            Err(e) => panic!("{e}"),
+        }
+        for label in labels {
+            let mut pmp = match crate::hammer2::Hammer2::mount(&format!("{f}@{label}"), &[]) {
+                Ok(v) => v,
+                Err(e) => panic!("{e}"),
+            };
+            match pmp.readdir(crate::inode::INUM_PFS_ROOT) {
+                Ok(v) => assert_eq!(v.len(), 2), // "." and ".."
+                Err(e) => panic!("{e}"),
+            }
+            if let Err(e) = pmp.unmount() {
+                panic!("{e}");
+            }
+        }
+        let _ = std::fs::remove_file(&f);
+    }
+
+    #[test]
+    fn test_newfs_format_multi_volumes() {
+        let _ = init_std_logger();
+        let f1 = create_image("newfs_multi1", (1 << 30) + (8 << 20));
+        let f2 = create_image("newfs_multi2", 64 << 20);
+        let spec = format!("{f1}:{f2}");
+        if let Err(e) = super::Newfs::new().quiet(true).format(&spec) {
+            panic!("{e}");
+        }
+        match crate::volume::read_volume_data(&f2) {
+            Ok(v) => {
+                assert_eq!(v.volu_id, 1);
+                assert_eq!(v.nvolumes, 2);
+                assert_eq!(v.volu_loff[0], 0);
+                assert_eq!(v.volu_loff[1], 1 << 30);
+                assert_eq!(v.volu_loff[2], u64::MAX);
+                assert_eq!(v.total_size, (1 << 30) + (64 << 20));
+            }
+            Err(e) => panic!("{e}"),
+        }
+        match crate::hammer2::Hammer2::mount(&spec, &[]) {
+            Ok(mut v) => {
+                if let Err(e) = v.unmount() {
+                    panic!("{e}");
+                }
+            }
+            Err(e) => panic!("{e}"),
+        }
+        let _ = std::fs::remove_file(&f1);
+        let _ = std::fs::remove_file(&f2);
+    }
+}
@@ -4,6 +4,7 @@ use std::os::unix::fs::FileTypeExt;
 
 #[derive(Debug, Default)]
 struct VolumeIdentifier {
+    magic: u64,
     version: u32,
     nvolumes: u8,
     fsid: [u8; 16],
@@ -25,6 +26,7 @@ pub struct Ondisk {
     total_size: u64,
     ident: VolumeIdentifier, // mostly unused by newfs_hammer2
     quiet: bool,
+    direct: bool, // open volumes with O_DIRECT
 }
 
 impl std::ops::Index<usize> for Ondisk {
@@ -58,6 +60,11 @@ impl Ondisk {
         }
     }
 
+    /// Open volumes added after this with O_DIRECT.
+    pub fn set_direct(&mut self, direct: bool) {
+        self.direct = direct;
+    }
+
     #[must_use]
     pub fn get_nvolumes(&self) -> usize {
         self.volumes.len()
@@ -78,19 +85,48 @@ impl Ondisk {
         size: u64,
     ) -> crate::Result<()> {
         let vol = crate::volume::Volume::new(id, path, readonly, offset, size)?;
+        self.push_volume(vol);
+        Ok(())
+    }
+
+    // Same as above, but with a device instead of a path.
+    // path is only used as a name of the device.
+    pub fn install_volume_device(
+        &mut self,
+        id: u8,
+        path: &str,
+        dev: Box<dyn crate::device::BlockDevice>,
+        offset: u64,
+        size: u64,
+    ) {
+        let vol = crate::volume::Volume::new_with_device(id, path, dev, offset, size);
+        self.push_volume(vol);
+    }
+
+    fn push_volume(&mut self, vol: crate::volume::Volume) {
+        self.total_size += vol.get_size();
         self.volumes.push(vol);
         self.volumes.sort_by_key(crate::volume::Volume::get_id);
-        self.total_size += size;
-        Ok(())
     }
 
     /// # Errors
     pub fn add_volume(&mut self, path: &str, readonly: bool) -> crate::Result<()> {
-        let t = std::fs::metadata(path)?.file_type();
+        let t = std::fs::metadata(crate::partition::split_path(path).0)?.file_type();
         if !t.is_block_device() && !t.is_char_device() && !t.is_file() {
             log::error!("unsupported file type {t:?}");
             return Err(nix::errno::Errno::EINVAL.into());
         }
+        self.add_volume_device(path, crate::partition::open(path, readonly, self.direct)?)
+    }
+
+    // Same as above, but with a device instead of a path.
+    // path is only used as a name of the device.
+    /// # Errors
+    pub fn add_volume_device(
+        &mut self,
+        path: &str,
+        mut dev: Box<dyn crate::device::BlockDevice>,
+    ) -> crate::Result<()> {
         if self.volumes.len() >= crate::fs::HAMMER2_MAX_VOLUMES.into() {
             log::error!(
                 "exceeds maximum supported number of volumes {}",
@@ -98,18 +134,27 @@ impl Ondisk {
             );
             return Err(nix::errno::Errno::EINVAL.into());
         }
-        let voldata = crate::volume::read_volume_data(path)?;
+        let voldata = crate::volume::read_volume_data_from(dev.as_mut(), path)?;
         if voldata.volu_id >= crate::fs::HAMMER2_MAX_VOLUMES {
             log::error!("{path} has bad volume id {}", voldata.volu_id);
             return Err(nix::errno::Errno::EINVAL.into());
         }
-        // all headers must have the same version, nvolumes and uuid
+        // all headers must have the same byte order, version, nvolumes and uuid
         if self.ident.nvolumes == 0 {
+            self.ident.magic = voldata.magic;
             self.ident.version = voldata.version;
             self.ident.nvolumes = voldata.nvolumes;
             self.ident.fsid = voldata.fsid;
             self.ident.fstype = voldata.fstype;
         } else {
+            if self.ident.magic != voldata.magic {
+                log::error!(
+                    "volume byte order mismatch {:#018x} vs {:#018x}",
+                    self.ident.magic,
+                    voldata.magic
+                );
+                return Err(nix::errno::Errno::EINVAL.into());
+            }
             if self.ident.version != voldata.version {
                 log::error!(
                     "volume version mismatch {} vs {}",
@@ -144,17 +189,17 @@ impl Ondisk {
             }
         }
         // all per-volume tests passed
-        self.install_volume(
+        self.install_volume_device(
             voldata.volu_id,
             path,
-            readonly,
+            dev,
             voldata.volu_loff[usize::from(voldata.volu_id)],
             voldata.volu_size,
-        )?;
+        );
         Ok(())
     }
 
-    fn verify_volumes_common(&self, verify_rootvol: bool) -> crate::Result<()> {
+    fn verify_volumes_common(&mut self, verify_rootvol: bool) -> crate::Result<()> {
         // check volume header
         if verify_rootvol {
             let rootvoldata = self.read_root_volume_data()?;
@@ -177,18 +222,23 @@ impl Ondisk {
             }
         }
         let mut st = vec![];
-        for (i, vol) in self.volumes.iter().enumerate() {
+        for (i, vol) in self.volumes.iter_mut().enumerate() {
             assert!(vol.get_id() < crate::fs::HAMMER2_MAX_VOLUMES.into());
             // check volumes are unique
-            st.push(std::fs::metadata(vol.get_path())?);
-            for j in 0..i {
-                if st[i].st_ino() == st[j].st_ino() && st[i].st_dev() == st[j].st_dev() {
-                    log::error!("{} specified more than once", vol.get_path());
-                    return Err(nix::errno::Errno::EINVAL.into());
+            st.push(
+                vol.get_device_metadata()?
+                    .map(|x| (x.st_ino(), x.st_dev(), vol.get_base())),
+            );
+            if let Some(a) = &st[i] {
+                for b in st[..i].iter().flatten() {
+                    if a == b {
+                        log::error!("{} specified more than once", vol.get_path());
+                        return Err(nix::errno::Errno::EINVAL.into());
+                    }
                 }
             }
             // check volume size vs block device size
-            let size = crate::subs::get_volume_size_from_path(vol.get_path())?;
+            let size = vol.get_device_size()?;
             if !self.quiet {
                 println!("checkvolu header {i} {:016x}/{:016x}", vol.get_size(), size);
             }
@@ -209,7 +259,7 @@ impl Ondisk {
         Ok(())
     }
 
-    fn verify_volumes_1(&self, verify_rootvol: bool) -> crate::Result<()> {
+    fn verify_volumes_1(&mut self, verify_rootvol: bool) -> crate::Result<()> {
         // check initialized volume count
         if self.volumes.len() != 1 {
             log::error!("only 1 volume supported");
@@ -260,7 +310,7 @@ impl Ondisk {
     }
 
     #[allow(clippy::too_many_lines)]
-    fn verify_volumes_2(&self, verify_rootvol: bool) -> crate::Result<()> {
+    fn verify_volumes_2(&mut self, verify_rootvol: bool) -> crate::Result<()> {
         // check volume header
         if verify_rootvol {
             let rootvoldata = self.read_root_volume_data()?;
@@ -378,7 +428,7 @@ impl Ondisk {
     }
 
     /// # Errors
-    pub fn verify_volumes(&self, verify_rootvol: bool) -> crate::Result<()> {
+    pub fn verify_volumes(&mut self, verify_rootvol: bool) -> crate::Result<()> {
         self.verify_volumes_common(verify_rootvol)?;
         if self.ident.version >= crate::fs::HAMMER2_VOL_VERSION_MULTI_VOLUMES {
             self.verify_volumes_2(verify_rootvol)
@@ -447,12 +497,41 @@ impl Ondisk {
         self.get_volume_mut(0)
     }
 
-    pub(crate) fn read_root_volume_data(&self) -> crate::Result<crate::fs::Hammer2VolumeData> {
-        crate::volume::read_volume_data(
-            self.get_root_volume()
-                .ok_or(nix::errno::Errno::ENODEV)?
-                .get_path(),
-        )
+    pub(crate) fn read_root_volume_data(&mut self) -> crate::Result<crate::fs::Hammer2VolumeData> {
+        Ok(self.read_root_volume_data_with_index()?.1)
+    }
+
+    pub(crate) fn read_root_volume_data_with_index(
+        &mut self,
+    ) -> crate::Result<(usize, crate::fs::Hammer2VolumeData)> {
+        let vol = self
+            .get_root_volume_mut()
+            .ok_or(nix::errno::Errno::ENODEV)?;
+        let path = vol.get_path().to_string();
+        crate::volume::read_volume_data_with_index_from(vol.get_device_mut(), &path)
+    }
+
+    // Select a volume header other than the best one.
+    pub(crate) fn read_root_volume_data_with_select(
+        &mut self,
+        sel: &crate::option::VolhdrSelect,
+    ) -> crate::Result<(usize, crate::fs::Hammer2VolumeData)> {
+        let vol = self
+            .get_root_volume_mut()
+            .ok_or(nix::errno::Errno::ENODEV)?;
+        let path = vol.get_path().to_string();
+        let v = crate::volume::read_volume_headers_from(vol.get_device_mut(), &path)?;
+        for (i, vd) in v {
+            let found = match sel {
+                crate::option::VolhdrSelect::Index(x) => i == *x,
+                crate::option::VolhdrSelect::MirrorTid(x) => vd.mirror_tid == *x,
+            };
+            if found {
+                return Ok((i, vd));
+            }
+        }
+        log::error!("volume header {sel:?} not found");
+        Err(nix::errno::Errno::ENOENT.into())
     }
 
     #[must_use]
@@ -464,42 +543,41 @@ impl Ondisk {
         v
     }
 
+    pub fn get_volumes_mut(&mut self) -> Vec<&mut crate::volume::Volume> {
+        let mut v = vec![];
+        for vol in &mut self.volumes {
+            v.push(vol);
+        }
+        v
+    }
+
     /// # Errors
-    /// # Panics
     pub fn get_best_volume_data(
         &mut self,
     ) -> crate::Result<Vec<(usize, crate::fs::Hammer2VolumeData)>> {
         let mut bests = vec![];
-        for i in 0..self.get_nvolumes() {
-            let vol = &mut self.volumes[i];
-            let mut index = usize::MAX;
-            let mut best = crate::fs::Hammer2VolumeData::new();
-            for j in 0..crate::fs::HAMMER2_NUM_VOLHDRS {
-                let offset = crate::volume::get_volume_data_offset(j)?;
-                if offset < vol.get_size() {
-                    let buf = vol.preadx(crate::fs::HAMMER2_VOLUME_BYTES, offset)?;
-                    let voldata = crate::ondisk::media_as_volume_data(&buf);
-                    assert!(
-                        voldata.magic == crate::fs::HAMMER2_VOLUME_ID_HBO
-                            || voldata.magic == crate::fs::HAMMER2_VOLUME_ID_ABO
-                    );
-                    if j == 0 || best.mirror_tid < voldata.mirror_tid {
-                        index = j;
-                        best = *voldata;
-                    }
+        for vol in &self.volumes {
+            let mut best: Option<(usize, crate::fs::Hammer2VolumeData)> = None;
+            for (i, vd) in vol.read_volume_headers()? {
+                if vd.mirror_tid == 0 {
+                    log::error!("{} #{i}: zero mirror_tid", vol.get_path());
+                    continue;
+                }
+                if best.as_ref().is_none_or(|x| x.1.mirror_tid < vd.mirror_tid) {
+                    best = Some((i, vd));
                 }
             }
-            bests.push((index, best));
-        }
-        for best in &bests {
-            assert_ne!(best.0, usize::MAX);
-            assert_ne!(best.1.mirror_tid, 0);
+            let Some(best) = best else {
+                log::error!("{}: no valid volume header", vol.get_path());
+                return Err(nix::errno::Errno::EINVAL.into());
+            };
+            bests.push(best);
         }
         Ok(bests)
     }
 
     /// # Errors
-    pub fn read_media(&mut self, bref: &crate::fs::Hammer2Blockref) -> crate::Result<Vec<u8>> {
+    pub fn read_media(&self, bref: &crate::fs::Hammer2Blockref) -> crate::Result<Vec<u8>> {
         let radix = bref.get_radix()?;
         let bytes = if radix == 0 { 0 } else { 1 << radix };
         if bytes == 0 {
@@ -516,30 +594,100 @@ impl Ondisk {
             return Err(nix::errno::Errno::EINVAL.into());
         }
         let vol = self
-            .get_volume_mut(io_off)
+            .get_volume(io_off)
             .ok_or::<crate::Error>(nix::errno::Errno::ENODEV.into())?;
         let beg = usize::try_from(boff).or_range()?;
         let end = usize::try_from(boff + bytes).or_range()?;
         Ok(vol.preadx(io_bytes, io_base - vol.get_offset())?[beg..end].to_vec())
     }
+
+    /// # Errors
+    pub fn write_media(
+        &self,
+        bref: &crate::fs::Hammer2Blockref,
+        media: &[u8],
+    ) -> crate::Result<()> {
+        let radix = bref.get_radix()?;
+        let bytes = if radix == 0 { 0 } else { 1 << radix };
+        if bytes == 0 {
+            return Ok(());
+        }
+        if media.len() != usize::try_from(bytes).or_range()? {
+            log::error!("media size {} vs {bytes}", media.len());
+            return Err(nix::errno::Errno::EINVAL.into());
+        }
+        let io_off = bref.get_raw_data_off();
+        let vol = self
+            .get_volume(io_off)
+            .ok_or::<crate::Error>(nix::errno::Errno::ENODEV.into())?;
+        if io_off + bytes > vol.get_offset() + vol.get_size() {
+            log::error!("{io_off:016x} crosses volume boundary");
+            return Err(nix::errno::Errno::EINVAL.into());
+        }
+        vol.pwrite(media, io_off - vol.get_offset())
+    }
 }
 
 /// # Errors
 pub fn init(spec: &str, readonly: bool) -> crate::Result<Ondisk> {
-    init_impl(spec, readonly, false)
+    init_impl(spec, readonly, false, false)
 }
 
 /// # Errors
 pub fn init_quiet(spec: &str, readonly: bool) -> crate::Result<Ondisk> {
-    init_impl(spec, readonly, true)
+    init_impl(spec, readonly, false, true)
+}
+
+// Same as init_quiet, but volumes are opened with O_DIRECT if direct is set.
+pub(crate) fn init_quiet_direct(spec: &str, readonly: bool, direct: bool) -> crate::Result<Ondisk> {
+    init_impl(spec, readonly, direct, true)
+}
+
+// Format volumes with default options, see newfs::Newfs for options.
+/// # Errors
+pub fn newfs(spec: &str) -> crate::Result<()> {
+    crate::newfs::Newfs::new().quiet(true).format(spec)
+}
+
+// Same as init, but with devices instead of a spec.
+/// # Errors
+pub fn init_devices(devs: Vec<Box<dyn crate::device::BlockDevice>>) -> crate::Result<Ondisk> {
+    init_devices_impl(devs, false)
+}
+
+/// # Errors
+pub fn init_devices_quiet(devs: Vec<Box<dyn crate::device::BlockDevice>>) -> crate::Result<Ondisk> {
+    init_devices_impl(devs, true)
 }
 
-fn init_impl(spec: &str, readonly: bool, quiet: bool) -> crate::Result<Ondisk> {
-    let mut fso = if quiet {
+fn new_ondisk(quiet: bool) -> Ondisk {
+    if quiet {
         Ondisk::new_quiet(None)
     } else {
         Ondisk::new(None)
-    };
+    }
+}
+
+fn init_devices_impl(
+    devs: Vec<Box<dyn crate::device::BlockDevice>>,
+    quiet: bool,
+) -> crate::Result<Ondisk> {
+    let mut fso = new_ondisk(quiet);
+    for (i, dev) in devs.into_iter().enumerate() {
+        fso.add_volume_device(&get_device_name(i), dev)?;
+    }
+    fso.verify_volumes(true)?;
+    Ok(fso)
+}
+
+// Name of a volume given as a device.
+pub(crate) fn get_device_name(index: usize) -> String {
+    format!("<device{index}>")
+}
+
+fn init_impl(spec: &str, readonly: bool, direct: bool, quiet: bool) -> crate::Result<Ondisk> {
+    let mut fso = new_ondisk(quiet);
+    fso.set_direct(direct);
     let spec = if let Some(i) = spec.find('@') {
         &spec[..i]
     } else {
@@ -552,28 +700,86 @@ fn init_impl(spec: &str, readonly: bool, quiet: bool) -> crate::Result<Ondisk> {
     Ok(fso)
 }
 
-#[must_use]
-pub fn media_as_inode_data(media: &[u8]) -> &crate::fs::Hammer2InodeData {
-    libfs::cast::align_to(media)
+fn media_as<'a, T>(
+    bref: &crate::fs::Hammer2Blockref,
+    media: &'a [u8],
+    offset: usize,
+) -> crate::Result<&'a T> {
+    media
+        .get(offset..)
+        .and_then(crate::fs::try_align_to)
+        .ok_or_else(|| get_media_error::<T>(bref, media.len(), offset))
 }
 
-#[must_use]
-pub fn media_as_volume_data(media: &[u8]) -> &crate::fs::Hammer2VolumeData {
-    libfs::cast::align_to(media)
+fn media_as_mut<'a, T>(
+    bref: &crate::fs::Hammer2Blockref,
+    media: &'a mut [u8],
+    offset: usize,
+) -> crate::Result<&'a mut T> {
+    let n = media.len();
+    media
+        .get_mut(offset..)
+        .and_then(crate::fs::try_align_to_mut)
+        .ok_or_else(|| get_media_error::<T>(bref, n, offset))
+}
+
+fn get_media_error<T>(
+    bref: &crate::fs::Hammer2Blockref,
+    len: usize,
+    offset: usize,
+) -> crate::Error {
+    crate::Corruption::new_error(
+        bref,
+        offset,
+        &format!(
+            "{len} bytes media can't hold {} bytes at {offset}",
+            std::mem::size_of::<T>()
+        ),
+    )
+}
+
+/// # Errors
+pub fn media_as_inode_data<'a>(
+    bref: &crate::fs::Hammer2Blockref,
+    media: &'a [u8],
+) -> crate::Result<&'a crate::fs::Hammer2InodeData> {
+    media_as(bref, media, 0)
+}
+
+/// # Errors
+pub fn media_as_inode_data_mut<'a>(
+    bref: &crate::fs::Hammer2Blockref,
+    media: &'a mut [u8],
+) -> crate::Result<&'a mut crate::fs::Hammer2InodeData> {
+    media_as_mut(bref, media, 0)
+}
+
+/// # Errors
+pub fn media_as_volume_data<'a>(
+    bref: &crate::fs::Hammer2Blockref,
+    media: &'a [u8],
+) -> crate::Result<&'a crate::fs::Hammer2VolumeData> {
+    media_as(bref, media, 0)
+}
+
+/// # Errors
+pub fn media_as_volume_data_mut<'a>(
+    bref: &crate::fs::Hammer2Blockref,
+    media: &'a mut [u8],
+) -> crate::Result<&'a mut crate::fs::Hammer2VolumeData> {
+    media_as_mut(bref, media, 0)
 }
 
 /// # Errors
 pub fn media_as_blockref<'a>(
     bref: &crate::fs::Hammer2Blockref,
     media: &'a [u8],
-) -> nix::Result<Vec<&'a crate::fs::Hammer2Blockref>> {
-    match media_as_blockref_impl(bref, media) {
-        Ok(v) => Ok(v),
-        Err(e) => {
-            log::error!("bad blockref type {}", bref.typ);
-            Err(e)
-        }
-    }
+) -> crate::Result<Vec<&'a crate::fs::Hammer2Blockref>> {
+    let (offset, count) = media_as_blockref_range(bref, media)?;
+    let n = std::mem::size_of::<crate::fs::Hammer2Blockref>();
+    (0..count)
+        .map(|i| media_as(bref, media, offset + i * n))
+        .collect()
 }
 
 #[must_use]
@@ -581,38 +787,202 @@ pub fn media_as_blockref_safe<'a>(
     bref: &crate::fs::Hammer2Blockref,
     media: &'a [u8],
 ) -> Vec<&'a crate::fs::Hammer2Blockref> {
-    media_as_blockref_impl(bref, media).unwrap_or_default()
+    let Ok(Some((offset, count))) = get_blockref_range(bref, media) else {
+        return vec![];
+    };
+    let n = std::mem::size_of::<crate::fs::Hammer2Blockref>();
+    (0..count)
+        .map_while(|i| media_as(bref, media, offset + i * n).ok())
+        .collect()
 }
 
-fn media_as_blockref_impl<'a>(
+// Returns byte offset and number of entries of the block table.
+/// # Errors
+pub fn media_as_blockref_range(
     bref: &crate::fs::Hammer2Blockref,
-    media: &'a [u8],
-) -> nix::Result<Vec<&'a crate::fs::Hammer2Blockref>> {
-    match bref.typ {
+    media: &[u8],
+) -> crate::Result<(usize, usize)> {
+    if let Some(v) = get_blockref_range(bref, media)? {
+        Ok(v)
+    } else {
+        log::error!("bad blockref type {}", bref.typ);
+        Err(nix::errno::Errno::EINVAL.into())
+    }
+}
+
+fn get_blockref_range(
+    bref: &crate::fs::Hammer2Blockref,
+    media: &[u8],
+) -> crate::Result<Option<(usize, usize)>> {
+    Ok(Some(match bref.typ {
         crate::fs::HAMMER2_BREF_TYPE_INODE => {
-            let ipdata = media_as_inode_data(media);
+            let ipdata = media_as_inode_data(bref, media)?;
             if ipdata.meta.is_sup_root() || !ipdata.meta.has_direct_data() {
-                Ok(ipdata
-                    .u_as::<crate::fs::Hammer2Blockset>()
-                    .as_blockref()
-                    .to_vec())
+                (
+                    std::mem::offset_of!(crate::fs::Hammer2InodeData, u),
+                    crate::fs::HAMMER2_SET_COUNT,
+                )
             } else {
-                Ok(vec![])
+                (std::mem::offset_of!(crate::fs::Hammer2InodeData, u), 0)
+            }
+        }
+        crate::fs::HAMMER2_BREF_TYPE_INDIRECT | crate::fs::HAMMER2_BREF_TYPE_FREEMAP_NODE => (
+            0,
+            media.len() / std::mem::size_of::<crate::fs::Hammer2Blockref>(),
+        ),
+        crate::fs::HAMMER2_BREF_TYPE_FREEMAP => {
+            media_as_volume_data(bref, media)?;
+            (
+                std::mem::offset_of!(crate::fs::Hammer2VolumeData, freemap_blockset),
+                crate::fs::HAMMER2_SET_COUNT,
+            )
+        }
+        crate::fs::HAMMER2_BREF_TYPE_VOLUME => {
+            media_as_volume_data(bref, media)?;
+            (
+                std::mem::offset_of!(crate::fs::Hammer2VolumeData, sroot_blockset),
+                crate::fs::HAMMER2_SET_COUNT,
+            )
+        }
+        _ => return Ok(None),
+    }))
+}
+
+// Verify layout of media of given blockref, so that media can be parsed
+// without bounds checking afterwards.  Must be done after swap_media.
+/// # Errors
+pub fn check_media(bref: &crate::fs::Hammer2Blockref, media: &[u8]) -> crate::Result<()> {
+    match bref.typ {
+        crate::fs::HAMMER2_BREF_TYPE_INODE => {
+            if media.len() != std::mem::size_of::<crate::fs::Hammer2InodeData>() {
+                return Err(crate::Corruption::new_error(
+                    bref,
+                    0,
+                    &format!("{} bytes inode", media.len()),
+                ));
+            }
+            let meta = &media_as_inode_data(bref, media)?.meta;
+            if usize::from(meta.name_len) > crate::fs::HAMMER2_INODE_MAXNAME {
+                return Err(crate::Corruption::new_error(
+                    bref,
+                    std::mem::offset_of!(crate::fs::Hammer2InodeMeta, name_len),
+                    &format!("name length {}", meta.name_len),
+                ));
+            }
+            if !meta.is_sup_root()
+                && meta.has_direct_data()
+                && meta.size > crate::fs::HAMMER2_EMBEDDED_BYTES
+            {
+                return Err(crate::Corruption::new_error(
+                    bref,
+                    std::mem::offset_of!(crate::fs::Hammer2InodeMeta, size),
+                    &format!("direct data size {}", meta.size),
+                ));
+            }
+        }
+        crate::fs::HAMMER2_BREF_TYPE_INDIRECT | crate::fs::HAMMER2_BREF_TYPE_FREEMAP_NODE => {
+            let n = std::mem::size_of::<crate::fs::Hammer2Blockref>();
+            if media.is_empty() || !media.len().is_multiple_of(n) {
+                return Err(crate::Corruption::new_error(
+                    bref,
+                    0,
+                    &format!("{} bytes block table", media.len()),
+                ));
+            }
+        }
+        crate::fs::HAMMER2_BREF_TYPE_FREEMAP_LEAF => {
+            let n = std::mem::size_of::<crate::fs::Hammer2BmapData>();
+            if media.is_empty() || !media.len().is_multiple_of(n) {
+                return Err(crate::Corruption::new_error(
+                    bref,
+                    0,
+                    &format!("{} bytes freemap leaf", media.len()),
+                ));
+            }
+        }
+        crate::fs::HAMMER2_BREF_TYPE_DIRENT => {
+            // Short names are embedded in the blockref.
+            let n = usize::from(bref.embed_as::<crate::fs::Hammer2DirentHead>().namlen);
+            if n > crate::fs::HAMMER2_INODE_MAXNAME || (n > bref.check.len() && n > media.len()) {
+                return Err(crate::Corruption::new_error(
+                    bref,
+                    0,
+                    &format!("name length {n} with {} bytes media", media.len()),
+                ));
+            }
+        }
+        _ => (),
+    }
+    Ok(())
+}
+
+// Byte-swap media of given blockref between host and reverse byte order.
+// Must be done after verify_media on read, and before set_media_check on write.
+pub fn swap_media(bref: &crate::fs::Hammer2Blockref, media: &mut [u8]) {
+    match bref.typ {
+        crate::fs::HAMMER2_BREF_TYPE_INODE => {
+            if let Some(ipdata) = crate::fs::try_align_to_mut::<crate::fs::Hammer2InodeData>(media)
+            {
+                ipdata.swap_bytes();
             }
         }
         crate::fs::HAMMER2_BREF_TYPE_INDIRECT | crate::fs::HAMMER2_BREF_TYPE_FREEMAP_NODE => {
-            Ok(crate::fs::media_as(media))
+            for x in media.chunks_exact_mut(std::mem::size_of::<crate::fs::Hammer2Blockref>()) {
+                if let Some(x) = crate::fs::try_align_to_mut::<crate::fs::Hammer2Blockref>(x) {
+                    x.swap_bytes();
+                }
+            }
+        }
+        crate::fs::HAMMER2_BREF_TYPE_FREEMAP_LEAF => {
+            for x in media.chunks_exact_mut(std::mem::size_of::<crate::fs::Hammer2BmapData>()) {
+                if let Some(x) = crate::fs::try_align_to_mut::<crate::fs::Hammer2BmapData>(x) {
+                    x.swap_bytes();
+                }
+            }
+        }
+        // LZ4 compressed size is stored in host byte order.
+        crate::fs::HAMMER2_BREF_TYPE_DATA
+            if crate::fs::dec_comp(bref.methods) == crate::fs::HAMMER2_COMP_LZ4
+                && media.len() >= 4 =>
+        {
+            media[..4].reverse();
+        }
+        _ => (), // raw data, dirent name or no media
+    }
+}
+
+// Counterpart of verify_media.
+// Note that freemap avail and bigmask are preserved.
+/// # Errors
+pub fn set_media_check(bref: &mut crate::fs::Hammer2Blockref, media: &[u8]) -> crate::Result<()> {
+    match crate::fs::dec_check(bref.methods) {
+        crate::fs::HAMMER2_CHECK_NONE | crate::fs::HAMMER2_CHECK_DISABLED => (),
+        crate::fs::HAMMER2_CHECK_ISCSI32 => {
+            bref.check.fill(0);
+            bref.check_as_mut::<crate::fs::Hammer2BlockrefCheckIscsi>()
+                .value = icrc32::iscsi_crc32(media);
+        }
+        crate::fs::HAMMER2_CHECK_XXHASH64 => {
+            bref.check.fill(0);
+            bref.check_as_mut::<crate::fs::Hammer2BlockrefCheckXxhash64>()
+                .value = crate::xxhash::xxh64(media);
+        }
+        crate::fs::HAMMER2_CHECK_SHA192 => {
+            bref.check.fill(0);
+            bref.check_as_mut::<crate::fs::Hammer2BlockrefCheckSha256>()
+                .data
+                .copy_from_slice(&crate::sha::sha256(media));
+        }
+        crate::fs::HAMMER2_CHECK_FREEMAP => {
+            bref.check_as_mut::<crate::fs::Hammer2BlockrefCheckFreemap>()
+                .icrc32 = icrc32::iscsi_crc32(media);
+        }
+        _ => {
+            log::error!("bad check type {:02x}", bref.methods);
+            return Err(nix::errno::Errno::EINVAL.into());
         }
-        crate::fs::HAMMER2_BREF_TYPE_FREEMAP => Ok(media_as_volume_data(media)
-            .freemap_blockset
-            .as_blockref()
-            .to_vec()),
-        crate::fs::HAMMER2_BREF_TYPE_VOLUME => Ok(media_as_volume_data(media)
-            .sroot_blockset
-            .as_blockref()
-            .to_vec()),
-        _ => Err(nix::errno::Errno::EINVAL),
     }
+    Ok(())
 }
 
 /// # Errors
@@ -651,6 +1021,95 @@ mod tests {
         env_logger::try_init_from_env(env)
     }
 
+    fn assert_corrupt<T: std::fmt::Debug>(res: crate::Result<T>, offset: u64) {
+        match res {
+            Err(crate::Error::Corrupt(e)) => assert_eq!(e.offset, offset, "{e}"),
+            v => panic!("{v:?}"),
+        }
+    }
+
+    #[test]
+    fn test_check_media() {
+        let mut bref = crate::fs::Hammer2Blockref::new(crate::fs::HAMMER2_BREF_TYPE_INODE);
+        bref.data_off = 0x10000 | 10;
+        assert_corrupt(super::check_media(&bref, &[]), 0x10000);
+        assert_corrupt(super::check_media(&bref, &[0; 512]), 0x10000);
+        assert_corrupt(super::media_as_inode_data(&bref, &[0; 512]), 0x10000);
+        assert!(super::media_as_blockref_safe(&bref, &[0; 512]).is_empty());
+
+        let mut media = vec![0; std::mem::size_of::<crate::fs::Hammer2InodeData>()];
+        if let Err(e) = super::check_media(&bref, &media) {
+            panic!("{e}");
+        }
+        match super::media_as_inode_data_mut(&bref, &mut media) {
+            Ok(v) => v.meta.name_len = 300,
+            Err(e) => panic!("{e}"),
+        }
+        assert_corrupt(
+            super::check_media(&bref, &media),
+            0x10000
+                + u64::try_from(std::mem::offset_of!(crate::fs::Hammer2InodeMeta, name_len))
+                    .unwrap_or(u64::MAX),
+        );
+
+        let mut bref = crate::fs::Hammer2Blockref::new(crate::fs::HAMMER2_BREF_TYPE_INDIRECT);
+        bref.data_off = 0x20000 | 10;
+        assert_corrupt(super::check_media(&bref, &[]), 0x20000);
+        assert_corrupt(super::check_media(&bref, &[0; 200]), 0x20000);
+        if let Err(e) = super::check_media(&bref, &[0; 256]) {
+            panic!("{e}");
+        }
+        match super::media_as_blockref(&bref, &vec![0; 256]) {
+            Ok(v) => assert_eq!(v.len(), 2),
+            Err(e) => panic!("{e}"),
+        }
+    }
+
+    #[test]
+    fn test_get_best_volume_data() {
+        let f = crate::newfs::create_newfs_image("best_volume_data", 128 << 20);
+        let mut fso = match super::init_quiet(&f, true) {
+            Ok(v) => v,
+            Err(e) => panic!("{e}"),
+        };
+        match fso.get_best_volume_data() {
+            Ok(v) => {
+                assert_eq!(v.len(), 1);
+                assert_eq!(v[0].0, 0);
+                assert_ne!(v[0].1.mirror_tid, 0);
+            }
+            Err(e) => panic!("{e}"),
+        }
+
+        // A header with zero mirror_tid is skipped rather than asserted.
+        let Some(vol) = fso.get_root_volume() else {
+            panic!("")
+        };
+        let mut buf = match vol.preadx(crate::fs::HAMMER2_VOLUME_BYTES, 0) {
+            Ok(v) => v,
+            Err(e) => panic!("{e}"),
+        };
+        let Some(vd) = crate::fs::try_align_to_mut::<crate::fs::Hammer2VolumeData>(&mut buf) else {
+            panic!("")
+        };
+        vd.mirror_tid = 0;
+        if let Err(e) = vd.set_crc() {
+            panic!("{e}");
+        }
+        let fp = match std::fs::OpenOptions::new().write(true).open(&f) {
+            Ok(v) => v,
+            Err(e) => panic!("{e}"),
+        };
+        if let Err(e) = std::os::unix::fs::FileExt::write_all_at(&fp, &buf, 0) {
+            panic!("{e}");
+        }
+        match fso.get_best_volume_data() {
+            Err(crate::Error::Errno(nix::errno::Errno::EINVAL)) => (),
+            v => panic!("{v:?}"),
+        }
+        let _ = std::fs::remove_file(&f);
+    }
+
     #[test]
     fn test_init() {
         if let Ok(spec) = std::env::var(HAMMER2_DEVICE) {
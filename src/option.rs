@@ -4,10 +4,19 @@ pub(crate) enum CidAllocMode {
     Bitmap,
 }
 
+#[derive(Debug)]
+pub(crate) enum VolhdrSelect {
+    Index(usize),
+    MirrorTid(u64),
+}
+
 #[derive(Debug)]
 pub(crate) struct Opt {
     pub(crate) nodatacache: bool,
     pub(crate) cidalloc: CidAllocMode,
+    pub(crate) rw: bool,
+    pub(crate) volhdr: Option<VolhdrSelect>,
+    pub(crate) direct: bool,
     #[allow(dead_code)]
     pub(crate) debug: bool,
 }
@@ -17,6 +26,10 @@ impl Opt {
         let mut gopt = getopts::Options::new();
         gopt.optflag("", "nodatacache", "");
         gopt.optopt("", "cidalloc", "", "<linear|bitmap>");
+        gopt.optflag("", "rw", "");
+        gopt.optopt("", "volhdr", "", "<index>");
+        gopt.optopt("", "mirror_tid", "", "<tid>");
+        gopt.optflag("", "direct", "");
         gopt.optflag("h", "help", "");
         gopt.optflag("", "debug", "");
         gopt
@@ -44,10 +57,38 @@ impl Opt {
             },
             None => CidAllocMode::Linear,
         };
+        let rw = matches.opt_present("rw");
+        let volhdr = match (matches.opt_str("volhdr"), matches.opt_str("mirror_tid")) {
+            (Some(_), Some(_)) => return Err(nix::errno::Errno::EINVAL),
+            (Some(v), None) => match v.parse() {
+                Ok(v) if v < crate::fs::HAMMER2_NUM_VOLHDRS => Some(VolhdrSelect::Index(v)),
+                _ => return Err(nix::errno::Errno::EINVAL),
+            },
+            (None, Some(v)) => {
+                let tid = match v.strip_prefix("0x") {
+                    Some(x) => u64::from_str_radix(x, 16),
+                    None => v.parse(),
+                };
+                match tid {
+                    Ok(v) => Some(VolhdrSelect::MirrorTid(v)),
+                    Err(_) => return Err(nix::errno::Errno::EINVAL),
+                }
+            }
+            (None, None) => None,
+        };
+        // Flushing on top of an older volume header discards newer ones.
+        if rw && volhdr.is_some() {
+            log::error!("historical mount is read-only");
+            return Err(nix::errno::Errno::EINVAL);
+        }
+        let direct = matches.opt_present("direct");
         let debug = matches.opt_present("debug");
         Ok(Self {
             nodatacache,
             cidalloc,
+            rw,
+            volhdr,
+            direct,
             debug,
         })
     }
@@ -90,6 +131,72 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_opt_direct() {
+        match super::Opt::new(&["--direct"]) {
+            Ok(v) => assert!(v.direct),
+            Err(e) => panic!("{e}"),
+        }
+        match super::Opt::new(&[]) {
+            Ok(v) => assert!(!v.direct),
+            Err(e) => panic!("{e}"),
+        }
+    }
+
+    #[test]
+    fn test_opt_rw() {
+        match super::Opt::new(&["--rw"]) {
+            Ok(v) => assert!(v.rw),
+            Err(e) => panic!("{e}"),
+        }
+        match super::Opt::new(&[]) {
+            Ok(v) => assert!(!v.rw),
+            Err(e) => panic!("{e}"),
+        }
+    }
+
+    #[test]
+    fn test_opt_volhdr() {
+        match super::Opt::new(&["--volhdr", "2"]) {
+            Ok(v) => match v.volhdr {
+                Some(super::VolhdrSelect::Index(2)) => (),
+                v => panic!("{v:?}"),
+            },
+            Err(e) => panic!("{e}"),
+        }
+        match super::Opt::new(&["--mirror_tid", "0x1a"]) {
+            Ok(v) => match v.volhdr {
+                Some(super::VolhdrSelect::MirrorTid(0x1a)) => (),
+                v => panic!("{v:?}"),
+            },
+            Err(e) => panic!("{e}"),
+        }
+        match super::Opt::new(&["--mirror_tid", "26"]) {
+            Ok(v) => match v.volhdr {
+                Some(super::VolhdrSelect::MirrorTid(0x1a)) => (),
+                v => panic!("{v:?}"),
+            },
+            Err(e) => panic!("{e}"),
+        }
+        match super::Opt::new(&[]) {
+            Ok(v) => assert!(v.volhdr.is_none()),
+            Err(e) => panic!("{e}"),
+        }
+        for args in [
+            &["--volhdr", "4"][..],
+            &["--volhdr", "x"],
+            &["--mirror_tid", "0xg"],
+            &["--volhdr", "1", "--mirror_tid", "1"],
+            &["--volhdr", "1", "--rw"],
+        ] {
+            match super::Opt::new(args) {
+                Ok(v) => panic!("{v:?}"),
+                Err(nix::errno::Errno::EINVAL) => (),
+                Err(e) => panic!("{e}"),
+            }
+        }
+    }
+
     #[test]
     fn test_opt_help() {
         match super::Opt::new(&["-h"]) {
@@ -0,0 +1,644 @@
+use crate::ErrorExt;
+use byteorder::ByteOrder;
+
+const SECTOR_SIZE: u64 = 512;
+
+const MBR_SIGNATURE: [u8; 2] = [0x55, 0xaa];
+const MBR_PART_OFF: usize = 446;
+const MBR_PART_SIZE: usize = 16;
+const MBR_NPARTS: usize = 4;
+const MBR_MAX_LOGICAL: usize = 128;
+const MBR_TYPE_EXTENDED: [u8; 3] = [0x05, 0x0f, 0x85];
+const MBR_TYPE_GPT: u8 = 0xee;
+
+const GPT_SIGNATURE: &[u8; 8] = b"EFI PART";
+const GPT_SECTOR_SIZES: [u64; 2] = [512, 4096];
+const GPT_MAX_ENTRIES: u32 = 1024;
+const GPT_MIN_ENTRY_SIZE: u32 = 128;
+const GPT_MAX_ENTRY_SIZE: u32 = 4096;
+
+// DragonFly's 64 bit disklabel at the beginning of a slice.
+// Offsets and sizes are in bytes and in host byteorder.
+const DISKLABEL64_MAGIC: u32 = 0xc446_4c59;
+const DISKLABEL64_MAGIC_OFF: usize = 4;
+const DISKLABEL64_NPARTS_OFF: usize = 16;
+const DISKLABEL64_PART_OFF: usize = 208;
+const DISKLABEL64_PART_SIZE: usize = 64;
+const DISKLABEL64_MAX_PARTS: u32 = 16;
+
+/// A partition found in a disk image.
+/// `name` is "p<N>" for GPT or MBR partitions (MBR logical partitions
+/// start from p5), "p<N><x>" for disklabel64 partition x within p<N>, or
+/// "<x>" for disklabel64 partition x of an unpartitioned disk.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Partition {
+    pub name: String,
+    pub uuid: Option<String>,
+    pub offset: u64,
+    pub size: u64,
+}
+
+/// A region of a device, which can be used as a device.
+#[derive(Debug)]
+pub struct Slice {
+    dev: Box<dyn crate::device::BlockDevice>,
+    base: u64,
+    size: u64,
+}
+
+impl Slice {
+    #[must_use]
+    pub fn new(dev: Box<dyn crate::device::BlockDevice>, base: u64, size: u64) -> Self {
+        Self { dev, base, size }
+    }
+
+    fn get_offset(&self, offset: u64, len: usize) -> Option<u64> {
+        let end = offset.checked_add(len.try_into().ok()?)?;
+        if end > self.size {
+            return None;
+        }
+        self.base.checked_add(offset)
+    }
+}
+
+impl crate::device::BlockDevice for Slice {
+    fn pread(&self, buf: &mut [u8], offset: u64) -> crate::Result<()> {
+        let Some(offset) = self.get_offset(offset, buf.len()) else {
+            return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+        };
+        self.dev.pread(buf, offset)
+    }
+
+    fn pwrite(&self, buf: &[u8], offset: u64) -> crate::Result<()> {
+        let Some(offset) = self.get_offset(offset, buf.len()) else {
+            return Err(nix::errno::Errno::ENOSPC.into());
+        };
+        self.dev.pwrite(buf, offset)
+    }
+
+    fn get_size(&mut self) -> crate::Result<u64> {
+        Ok(self.size)
+    }
+
+    fn flush(&self) -> crate::Result<()> {
+        self.dev.flush()
+    }
+
+    fn get_metadata(&self) -> crate::Result<Option<std::fs::Metadata>> {
+        self.dev.get_metadata()
+    }
+
+    fn get_base(&self) -> u64 {
+        self.dev.get_base() + self.base
+    }
+}
+
+/// Split a volume path into a file path and a partition selector.
+/// A path which exists as is has no selector.
+#[must_use]
+pub fn split_path(path: &str) -> (&str, Option<&str>) {
+    if std::fs::metadata(path).is_err()
+        && let Some((f, sel)) = path.rsplit_once('#')
+    {
+        (f, Some(sel))
+    } else {
+        (path, None)
+    }
+}
+
+/// Open a volume path, which may select a partition by its name or UUID
+/// after '#', e.g. "disk.img#p3".
+/// If `direct` is set, the file is opened with O_DIRECT.
+/// # Errors
+pub fn open(
+    path: &str,
+    readonly: bool,
+    direct: bool,
+) -> crate::Result<Box<dyn crate::device::BlockDevice>> {
+    let (f, sel) = split_path(path);
+    let dev = crate::device::open(f, readonly, direct)?;
+    match sel {
+        Some(sel) => open_partition(dev, sel),
+        None => Ok(dev),
+    }
+}
+
+/// Returns a slice of `dev` for partition `sel`, see `Partition`.
+/// # Errors
+pub fn open_partition(
+    mut dev: Box<dyn crate::device::BlockDevice>,
+    sel: &str,
+) -> crate::Result<Box<dyn crate::device::BlockDevice>> {
+    let uuid = uuid::Uuid::parse_str(sel).ok().map(|x| x.to_string());
+    let Some(part) = get_partitions(dev.as_mut())?
+        .into_iter()
+        .find(|x| x.name == sel || (uuid.is_some() && x.uuid == uuid))
+    else {
+        log::error!("partition \"{sel}\" not found");
+        return Err(nix::errno::Errno::ENOENT.into());
+    };
+    log::debug!("{part:?}");
+    Ok(Box::new(Slice::new(dev, part.offset, part.size)))
+}
+
+/// Returns partitions of GPT, MBR and disklabel64 found in `dev`.
+/// # Errors
+pub fn get_partitions(dev: &mut dyn crate::device::BlockDevice) -> crate::Result<Vec<Partition>> {
+    let size = dev.get_size()?;
+    let mut v = match read_gpt(dev, size)? {
+        Some(v) => v,
+        None => read_mbr(dev, size)?,
+    };
+    if v.is_empty() {
+        v.extend(read_disklabel64(dev, size, "", 0, size)?);
+    } else {
+        let mut l = vec![];
+        for x in &v {
+            l.extend(read_disklabel64(dev, size, &x.name, x.offset, x.size)?);
+        }
+        v.extend(l);
+    }
+    Ok(v)
+}
+
+fn read_at(
+    dev: &mut dyn crate::device::BlockDevice,
+    size: u64,
+    offset: u64,
+    len: usize,
+) -> crate::Result<Option<Vec<u8>>> {
+    match offset.checked_add(len.try_into().or_range()?) {
+        Some(end) if end <= size => {
+            let mut buf = vec![0; len];
+            dev.pread(&mut buf, offset)?;
+            Ok(Some(buf))
+        }
+        _ => Ok(None),
+    }
+}
+
+// Partitions outside of the device are ignored.
+fn new_partition(
+    size: u64,
+    name: String,
+    uuid: Option<String>,
+    offset: u64,
+    psize: u64,
+) -> Option<Partition> {
+    if psize == 0 || offset.checked_add(psize)? > size {
+        log::debug!("{name}: ignore {offset:#x}/{psize:#x}");
+        return None;
+    }
+    Some(Partition {
+        name,
+        uuid,
+        offset,
+        size: psize,
+    })
+}
+
+fn read_gpt(
+    dev: &mut dyn crate::device::BlockDevice,
+    size: u64,
+) -> crate::Result<Option<Vec<Partition>>> {
+    for secsize in GPT_SECTOR_SIZES {
+        let Some(hdr) = read_at(dev, size, secsize, 92)? else {
+            continue;
+        };
+        if &hdr[..8] != GPT_SIGNATURE {
+            continue;
+        }
+        let lba = byteorder::LittleEndian::read_u64(&hdr[72..]);
+        let n = byteorder::LittleEndian::read_u32(&hdr[80..]);
+        let entsize = byteorder::LittleEndian::read_u32(&hdr[84..]);
+        if n > GPT_MAX_ENTRIES
+            || !(GPT_MIN_ENTRY_SIZE..=GPT_MAX_ENTRY_SIZE).contains(&entsize)
+            || entsize % 8 != 0
+        {
+            log::error!("bad GPT header: {n} entries of {entsize} bytes");
+            return Err(nix::errno::Errno::EINVAL.into());
+        }
+        let Some(buf) = read_at(
+            dev,
+            size,
+            lba.saturating_mul(secsize),
+            (n * entsize).try_into().or_range()?,
+        )?
+        else {
+            log::error!("GPT entries at LBA {lba} beyond device size");
+            return Err(nix::errno::Errno::EINVAL.into());
+        };
+        let mut v = vec![];
+        for (i, b) in buf.chunks(entsize.try_into().or_range()?).enumerate() {
+            if b[..16].iter().all(|&x| x == 0) {
+                continue; // unused entry
+            }
+            let uuid = uuid::Uuid::from_bytes_le(b[16..32].try_into().or_range()?);
+            let first = byteorder::LittleEndian::read_u64(&b[32..]);
+            let last = byteorder::LittleEndian::read_u64(&b[40..]);
+            if last < first {
+                continue;
+            }
+            v.extend(new_partition(
+                size,
+                format!("p{}", i + 1),
+                Some(uuid.to_string()),
+                first.saturating_mul(secsize),
+                (last - first + 1).saturating_mul(secsize),
+            ));
+        }
+        return Ok(Some(v));
+    }
+    Ok(None)
+}
+
+fn read_mbr(dev: &mut dyn crate::device::BlockDevice, size: u64) -> crate::Result<Vec<Partition>> {
+    let mut v = vec![];
+    let Some(buf) = read_at(dev, size, 0, SECTOR_SIZE.try_into().or_range()?)? else {
+        return Ok(v);
+    };
+    if buf[510..512] != MBR_SIGNATURE {
+        return Ok(v);
+    }
+    let mut ext = None;
+    for i in 0..MBR_NPARTS {
+        let b = &buf[MBR_PART_OFF + i * MBR_PART_SIZE..];
+        let (typ, start, nsects) = get_mbr_partition(b);
+        if typ == 0 || nsects == 0 {
+            continue;
+        }
+        if typ == MBR_TYPE_GPT {
+            log::debug!("protective MBR without GPT");
+            continue;
+        }
+        if MBR_TYPE_EXTENDED.contains(&typ) {
+            ext.get_or_insert(start);
+            continue;
+        }
+        v.extend(new_partition(
+            size,
+            format!("p{}", i + 1),
+            None,
+            start * SECTOR_SIZE,
+            nsects * SECTOR_SIZE,
+        ));
+    }
+    // Logical partitions are chained by extended boot records, with
+    // the next one relative to the beginning of the extended partition.
+    if let Some(base) = ext {
+        let mut ebr = base;
+        for i in 0..MBR_MAX_LOGICAL {
+            let Some(buf) = read_at(
+                dev,
+                size,
+                ebr * SECTOR_SIZE,
+                SECTOR_SIZE.try_into().or_range()?,
+            )?
+            else {
+                break;
+            };
+            if buf[510..512] != MBR_SIGNATURE {
+                break;
+            }
+            let (typ, start, nsects) = get_mbr_partition(&buf[MBR_PART_OFF..]);
+            if typ != 0 && nsects != 0 {
+                v.extend(new_partition(
+                    size,
+                    format!("p{}", MBR_NPARTS + i + 1),
+                    None,
+                    (ebr + start) * SECTOR_SIZE,
+                    nsects * SECTOR_SIZE,
+                ));
+            }
+            let (typ, start, _) = get_mbr_partition(&buf[MBR_PART_OFF + MBR_PART_SIZE..]);
+            if !MBR_TYPE_EXTENDED.contains(&typ) || start == 0 {
+                break;
+            }
+            ebr = base + start;
+        }
+    }
+    Ok(v)
+}
+
+fn get_mbr_partition(b: &[u8]) -> (u8, u64, u64) {
+    (
+        b[4],
+        byteorder::LittleEndian::read_u32(&b[8..]).into(),
+        byteorder::LittleEndian::read_u32(&b[12..]).into(),
+    )
+}
+
+fn read_disklabel64(
+    dev: &mut dyn crate::device::BlockDevice,
+    size: u64,
+    name: &str,
+    base: u64,
+    psize: u64,
+) -> crate::Result<Vec<Partition>> {
+    let mut v = vec![];
+    let Some(hdr) = read_at(dev, size.min(base + psize), base, DISKLABEL64_PART_OFF)? else {
+        return Ok(v);
+    };
+    if byteorder::NativeEndian::read_u32(&hdr[DISKLABEL64_MAGIC_OFF..]) != DISKLABEL64_MAGIC {
+        return Ok(v);
+    }
+    let n = byteorder::NativeEndian::read_u32(&hdr[DISKLABEL64_NPARTS_OFF..]);
+    if n > DISKLABEL64_MAX_PARTS {
+        log::error!("{name}: bad disklabel64 with {n} partitions");
+        return Err(nix::errno::Errno::EINVAL.into());
+    }
+    let n = usize::try_from(n).or_range()?;
+    let Some(buf) = read_at(
+        dev,
+        size.min(base + psize),
+        base,
+        DISKLABEL64_PART_OFF + n * DISKLABEL64_PART_SIZE,
+    )?
+    else {
+        return Ok(v);
+    };
+    for (i, b) in buf[DISKLABEL64_PART_OFF..]
+        .chunks(DISKLABEL64_PART_SIZE)
+        .enumerate()
+    {
+        let boffset = byteorder::NativeEndian::read_u64(b);
+        let bsize = byteorder::NativeEndian::read_u64(&b[8..]);
+        let uuid = b[48..64].try_into().or_range()?;
+        let uuid = if b[48..64].iter().all(|&x| x == 0) {
+            None
+        } else {
+            Some(crate::subs::get_uuid_string_from_bytes(&uuid))
+        };
+        let letter = char::from(b'a' + u8::try_from(i).or_range()?);
+        if bsize > psize || boffset > psize - bsize {
+            log::debug!("{name}{letter}: ignore {boffset:#x}/{bsize:#x}");
+            continue;
+        }
+        v.extend(new_partition(
+            size,
+            format!("{name}{letter}"),
+            uuid,
+            base + boffset,
+            bsize,
+        ));
+    }
+    Ok(v)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::device::BlockDevice;
+    use byteorder::ByteOrder;
+
+    fn pwrite(dev: &mut crate::device::MemoryDevice, buf: &[u8], offset: u64) {
+        if let Err(e) = dev.pwrite(buf, offset) {
+            panic!("{e}");
+        }
+    }
+
+    fn get_partitions(dev: &mut crate::device::MemoryDevice) -> Vec<super::Partition> {
+        match super::get_partitions(dev) {
+            Ok(v) => v,
+            Err(e) => panic!("{e}"),
+        }
+    }
+
+    fn new_device(size: u64) -> crate::device::MemoryDevice {
+        match crate::device::MemoryDevice::new(size) {
+            Ok(v) => v,
+            Err(e) => panic!("{e}"),
+        }
+    }
+
+    fn set_mbr_partition(b: &mut [u8], typ: u8, start: u32, nsects: u32) {
+        b[4] = typ;
+        byteorder::LittleEndian::write_u32(&mut b[8..], start);
+        byteorder::LittleEndian::write_u32(&mut b[12..], nsects);
+    }
+
+    // 64 bytes per partition of (offset, size, stor_uuid)
+    fn new_disklabel64(parts: &[(u64, u64, [u8; 16])]) -> Vec<u8> {
+        let mut b = vec![0; super::DISKLABEL64_PART_OFF + parts.len() * 64];
+        byteorder::NativeEndian::write_u32(
+            &mut b[super::DISKLABEL64_MAGIC_OFF..],
+            super::DISKLABEL64_MAGIC,
+        );
+        byteorder::NativeEndian::write_u32(
+            &mut b[super::DISKLABEL64_NPARTS_OFF..],
+            match parts.len().try_into() {
+                Ok(v) => v,
+                Err(e) => panic!("{e}"),
+            },
+        );
+        for (i, (offset, size, uuid)) in parts.iter().enumerate() {
+            let x = &mut b[super::DISKLABEL64_PART_OFF + i * 64..];
+            byteorder::NativeEndian::write_u64(x, *offset);
+            byteorder::NativeEndian::write_u64(&mut x[8..], *size);
+            x[48..64].copy_from_slice(uuid);
+        }
+        b
+    }
+
+    // (first LBA, last LBA, unique GUID) of 512 bytes sectors
+    fn new_gpt(parts: &[(u64, u64, uuid::Uuid)]) -> Vec<u8> {
+        let mut b = vec![0; 34 * 512];
+        b[510..512].copy_from_slice(&super::MBR_SIGNATURE);
+        set_mbr_partition(&mut b[446..], super::MBR_TYPE_GPT, 1, u32::MAX);
+        b[512..520].copy_from_slice(super::GPT_SIGNATURE);
+        byteorder::LittleEndian::write_u64(&mut b[512 + 72..], 2);
+        byteorder::LittleEndian::write_u32(&mut b[512 + 80..], 128);
+        byteorder::LittleEndian::write_u32(&mut b[512 + 84..], 128);
+        for (i, (first, last, uuid)) in parts.iter().enumerate() {
+            if uuid.is_nil() {
+                continue; // unused entry
+            }
+            let x = &mut b[1024 + i * 128..];
+            x[..16].fill(0xff); // type
+            x[16..32].copy_from_slice(&uuid.to_bytes_le());
+            byteorder::LittleEndian::write_u64(&mut x[32..], *first);
+            byteorder::LittleEndian::write_u64(&mut x[40..], *last);
+        }
+        b
+    }
+
+    #[test]
+    fn test_gpt() {
+        let mut dev = new_device(1 << 20);
+        let u1 = uuid::Uuid::new_v4();
+        let u2 = uuid::Uuid::new_v4();
+        let gpt = new_gpt(&[
+            (34, 99, u1),
+            (0, 0, uuid::Uuid::nil()),
+            (100, 2047, u2),
+            (2000, 4000, u1), // beyond device
+        ]);
+        pwrite(&mut dev, &gpt, 0);
+        // disklabel64 within p3
+        let label = new_disklabel64(&[(4096, 8192, [0; 16]), (0, 0, [0; 16])]);
+        pwrite(&mut dev, &label, 100 * 512);
+        let v = get_partitions(&mut dev);
+        assert_eq!(v.len(), 3, "{v:?}");
+        assert_eq!(v[0].name, "p1");
+        assert_eq!(v[0].uuid, Some(u1.to_string()));
+        assert_eq!(v[0].offset, 34 * 512);
+        assert_eq!(v[0].size, 66 * 512);
+        assert_eq!(v[1].name, "p3");
+        assert_eq!(v[1].uuid, Some(u2.to_string()));
+        assert_eq!(v[1].offset, 100 * 512);
+        assert_eq!(v[1].size, 1948 * 512);
+        assert_eq!(v[2].name, "p3a");
+        assert_eq!(v[2].uuid, None);
+        assert_eq!(v[2].offset, 100 * 512 + 4096);
+        assert_eq!(v[2].size, 8192);
+
+        let s = u2.to_string().to_uppercase();
+        for sel in ["p3", s.as_str()] {
+            let mut slice = match super::open_partition(Box::new(dev.clone()), sel) {
+                Ok(v) => v,
+                Err(e) => panic!("{e}"),
+            };
+            match slice.get_size() {
+                Ok(v) => assert_eq!(v, 1948 * 512),
+                Err(e) => panic!("{e}"),
+            }
+            assert_eq!(slice.get_base(), 100 * 512);
+            let mut buf = vec![0; 4];
+            if let Err(e) = slice.pread(&mut buf, 4) {
+                panic!("{e}");
+            }
+            assert_eq!(buf, super::DISKLABEL64_MAGIC.to_ne_bytes());
+            assert!(slice.pread(&mut buf, 1948 * 512 - 3).is_err());
+            assert!(slice.pwrite(&buf, 1948 * 512 - 3).is_err());
+        }
+        for sel in ["p2", "p4", "p3b", &uuid::Uuid::new_v4().to_string()] {
+            assert!(
+                super::open_partition(Box::new(dev.clone()), sel).is_err(),
+                "{sel}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_mbr() {
+        let mut dev = new_device(1 << 20);
+        let mut b = vec![0; 512];
+        b[510..512].copy_from_slice(&super::MBR_SIGNATURE);
+        set_mbr_partition(&mut b[446..], 0xa5, 64, 64);
+        set_mbr_partition(&mut b[446 + 32..], 0x0f, 256, 1024);
+        pwrite(&mut dev, &b, 0);
+        // two logical partitions
+        let mut b = vec![0; 512];
+        b[510..512].copy_from_slice(&super::MBR_SIGNATURE);
+        set_mbr_partition(&mut b[446..], 0x83, 1, 99);
+        set_mbr_partition(&mut b[446 + 16..], 0x05, 100, 200);
+        pwrite(&mut dev, &b, 256 * 512);
+        let mut b = vec![0; 512];
+        b[510..512].copy_from_slice(&super::MBR_SIGNATURE);
+        set_mbr_partition(&mut b[446..], 0x83, 2, 50);
+        pwrite(&mut dev, &b, 356 * 512);
+        // disklabel64 within p1
+        let uuid = *uuid::Uuid::new_v4().as_bytes();
+        let label = new_disklabel64(&[(0, 0, [0; 16]), (512, 1024, uuid)]);
+        pwrite(&mut dev, &label, 64 * 512);
+
+        let v = get_partitions(&mut dev);
+        assert_eq!(
+            v.iter()
+                .map(|x| (x.name.as_str(), x.offset, x.size))
+                .collect::<Vec<_>>(),
+            [
+                ("p1", 64 * 512, 64 * 512),
+                ("p5", 257 * 512, 99 * 512),
+                ("p6", 358 * 512, 50 * 512),
+                ("p1b", 64 * 512 + 512, 1024),
+            ]
+        );
+        assert_eq!(
+            v[3].uuid,
+            Some(crate::subs::get_uuid_string_from_bytes(&uuid))
+        );
+    }
+
+    #[test]
+    fn test_disklabel64() {
+        let mut dev = new_device(1 << 20);
+        assert!(get_partitions(&mut dev).is_empty());
+        let label = new_disklabel64(&[(8192, 8192, [0; 16]), (1 << 20, 1, [0; 16])]);
+        pwrite(&mut dev, &label, 0);
+        let v = get_partitions(&mut dev);
+        assert_eq!(v.len(), 1, "{v:?}");
+        assert_eq!(v[0].name, "a");
+        assert_eq!(v[0].offset, 8192);
+        assert_eq!(v[0].size, 8192);
+    }
+
+    #[test]
+    fn test_split_path() {
+        assert_eq!(super::split_path("/"), ("/", None));
+        assert_eq!(
+            super::split_path("/nonexistent/disk.img#p3"),
+            ("/nonexistent/disk.img", Some("p3"))
+        );
+        assert_eq!(
+            super::split_path("/nonexistent/disk.img"),
+            ("/nonexistent/disk.img", None)
+        );
+    }
+
+    #[test]
+    fn test_mount_partition() {
+        // 1MB gap, and then 128MB partition
+        let f = crate::newfs::create_image("gpt", (130 << 20) + 34 * 512);
+        let fp = match std::fs::File::options().write(true).open(&f) {
+            Ok(v) => v,
+            Err(e) => panic!("{e}"),
+        };
+        let uuid = uuid::Uuid::new_v4();
+        let gpt = new_gpt(&[(2048, 2048 + (128 << 11) - 1, uuid)]);
+        if let Err(e) = fp.pwrite(&gpt, 0) {
+            panic!("{e}");
+        }
+        drop(fp);
+
+        let spec = format!("{f}#p1");
+        if let Err(e) = crate::ondisk::newfs(&spec) {
+            panic!("{e}");
+        }
+        let mut pmp = match crate::hammer2::Hammer2::mount(&spec, &["--rw"]) {
+            Ok(v) => v,
+            Err(e) => panic!("{e}"),
+        };
+        if let Err(e) = pmp.mkdir(crate::inode::INUM_PFS_ROOT, "d", 0o755) {
+            panic!("{e}");
+        }
+        if let Err(e) = pmp.unmount() {
+            panic!("{e}");
+        }
+        // partition table is intact
+        match std::fs::read(&f) {
+            Ok(v) => assert_eq!(v[..gpt.len()], gpt),
+            Err(e) => panic!("{e}"),
+        }
+        match crate::volume::read_volume_headers(&spec) {
+            Ok(v) => assert_eq!(v.len(), 1),
+            Err(e) => panic!("{e}"),
+        }
+
+        let spec = format!("{f}#{uuid}@DATA");
+        let mut pmp = match crate::hammer2::Hammer2::mount(&spec, &[]) {
+            Ok(v) => v,
+            Err(e) => panic!("{e}"),
+        };
+        assert!(pmp.nresolve_path("/d").is_ok());
+        assert_eq!(
+            pmp.fso.get_root_volume().map(|x| x.get_base()),
+            Some(1 << 20)
+        );
+        if let Err(e) = pmp.unmount() {
+            panic!("{e}");
+        }
+        assert!(crate::hammer2::Hammer2::mount(&format!("{f}#p2"), &[]).is_err());
+        assert!(crate::hammer2::Hammer2::mount(&f, &[]).is_err());
+        let _ = std::fs::remove_file(&f);
+    }
+}
@@ -0,0 +1,183 @@
+use crate::ErrorExt;
+use crate::OptionExt;
+
+impl crate::hammer2::Inner {
+    fn get_pfs_name(pfs: &crate::ioctl::IocPfs) -> crate::Result<String> {
+        let name = match std::str::from_utf8(pfs.get_name()?) {
+            Ok(v) => v.to_string(),
+            Err(e) => {
+                log::error!("{e}");
+                return Err(nix::errno::Errno::EINVAL.into());
+            }
+        };
+        crate::hammer2::check_name(&name)?;
+        Ok(name)
+    }
+
+    // Create a PFS root inode under the super-root.
+    // The key is the name hash, with collisions resolved like directories.
+    fn create_pfs_inode(
+        &mut self,
+        ipdata: &mut crate::fs::Hammer2InodeData,
+        name: &str,
+    ) -> crate::Result<crate::chain::Cid> {
+        let scid =
+            self.get_inode_chain(crate::inode::INUM_SUP_ROOT, crate::hammer2::RESOLVE_ALWAYS)?;
+        if scid == crate::chain::CID_NONE {
+            return Err(nix::errno::Errno::EIO.into());
+        }
+        if self.lookup_pfs_by_label(name)? != crate::chain::CID_NONE {
+            return Err(nix::errno::Errno::EEXIST.into());
+        }
+        let mut lhc = crate::subs::dirhash(name.as_bytes());
+        loop {
+            let (_, cid, _) = self.lookup_chain(scid, lhc, lhc, 0)?;
+            if cid == crate::chain::CID_NONE {
+                break;
+            }
+            if (lhc & crate::fs::HAMMER2_DIRHASH_LOMASK) == crate::fs::HAMMER2_DIRHASH_LOMASK {
+                log::error!("dirhash collision space exhausted for {name}");
+                return Err(nix::errno::Errno::ENOSPC.into());
+            }
+            lhc += 1;
+        }
+        let meta = &mut ipdata.meta;
+        meta.name_key = lhc;
+        meta.name_len = name.len().try_into().or_range()?;
+        meta.op_flags |= crate::fs::HAMMER2_OPFLAG_PFSROOT;
+        ipdata.filename.fill(0);
+        ipdata.filename[..name.len()].copy_from_slice(name.as_bytes());
+
+        let mut bref = crate::fs::Hammer2Blockref::new(crate::fs::HAMMER2_BREF_TYPE_INODE);
+        bref.methods = crate::fs::enc_check(crate::fs::dec_algo(ipdata.meta.check_algo))
+            | crate::fs::enc_comp(crate::fs::HAMMER2_COMP_NONE);
+        bref.key = lhc;
+        bref.flags = crate::fs::HAMMER2_BREF_FLAG_PFSROOT;
+        bref.data_off = crate::fs::HAMMER2_INODE_BYTES.trailing_zeros().into();
+        self.create_chain(scid, &bref, libfs::cast::as_u8_slice(ipdata).to_vec())
+    }
+
+    /// # Errors
+    pub fn pfs_snapshot(&mut self, pfs: &mut crate::ioctl::IocPfs) -> crate::Result<()> {
+        if !self.opt.rw {
+            return Err(nix::errno::Errno::EROFS.into());
+        }
+        let name = Self::get_pfs_name(pfs)?;
+        // The snapshot shares the flushed blockset of the PFS root.
+        self.flush()?;
+        let tid = self.voldata.mirror_tid;
+        let cid =
+            self.get_inode_chain(crate::inode::INUM_PFS_ROOT, crate::hammer2::RESOLVE_ALWAYS)?;
+        let mut ipdata = *self.cmap.get(&cid).or_range()?.as_inode_data();
+        let meta = &mut ipdata.meta;
+        meta.pfs_type = crate::fs::HAMMER2_PFSTYPE_MASTER;
+        meta.pfs_subtype = crate::fs::HAMMER2_PFSSUBTYPE_SNAPSHOT;
+        meta.pfs_fsid = *uuid::Uuid::new_v4().as_bytes();
+        // No further synchronization with the original cluster.
+        meta.pfs_clid = *uuid::Uuid::new_v4().as_bytes();
+        meta.pfs_lsnap_tid = tid;
+        self.create_pfs_inode(&mut ipdata, &name)?;
+        self.modify_inode_meta(crate::inode::INUM_PFS_ROOT, |meta| {
+            meta.pfs_lsnap_tid = tid;
+        })?;
+        self.flush()?;
+
+        let meta = &ipdata.meta;
+        pfs.name_key = meta.name_key;
+        pfs.pfs_type = meta.pfs_type;
+        pfs.pfs_subtype = meta.pfs_subtype;
+        pfs.pfs_fsid = meta.pfs_fsid;
+        pfs.pfs_clid = meta.pfs_clid;
+        log::debug!("snapshot \"{name}\" of \"{}\" tid {tid:016x}", self.label);
+        Ok(())
+    }
+
+    /// # Errors
+    pub fn pfs_create(&mut self, pfs: &mut crate::ioctl::IocPfs) -> crate::Result<()> {
+        if !self.opt.rw {
+            return Err(nix::errno::Errno::EROFS.into());
+        }
+        let name = Self::get_pfs_name(pfs)?;
+        match pfs.pfs_type {
+            crate::fs::HAMMER2_PFSTYPE_CACHE
+            | crate::fs::HAMMER2_PFSTYPE_SLAVE
+            | crate::fs::HAMMER2_PFSTYPE_SOFT_SLAVE
+            | crate::fs::HAMMER2_PFSTYPE_SOFT_MASTER
+            | crate::fs::HAMMER2_PFSTYPE_MASTER
+            | crate::fs::HAMMER2_PFSTYPE_DUMMY => (),
+            v => {
+                log::error!("invalid PFS type {v}");
+                return Err(nix::errno::Errno::EINVAL.into());
+            }
+        }
+        let mut ipdata = crate::newfs::init_pfs_inode_data(
+            &name,
+            crate::fs::HAMMER2_COMP_DEFAULT,
+            crate::fs::HAMMER2_CHECK_DEFAULT,
+            crate::subs::get_current_time()?,
+        )?;
+        let meta = &mut ipdata.meta;
+        meta.pfs_type = pfs.pfs_type;
+        meta.pfs_subtype = pfs.pfs_subtype;
+        // Keep the caller's uuids, e.g. a SLAVE joining an existing cluster.
+        if pfs.pfs_fsid != [0; 16] {
+            meta.pfs_fsid = pfs.pfs_fsid;
+        }
+        if pfs.pfs_clid != [0; 16] {
+            meta.pfs_clid = pfs.pfs_clid;
+        }
+        self.create_pfs_inode(&mut ipdata, &name)?;
+        self.flush()?;
+
+        let meta = &ipdata.meta;
+        pfs.name_key = meta.name_key;
+        pfs.pfs_fsid = meta.pfs_fsid;
+        pfs.pfs_clid = meta.pfs_clid;
+        log::debug!("create \"{name}\" type {}", pfs.pfs_type);
+        Ok(())
+    }
+
+    /// # Errors
+    pub fn pfs_delete(&mut self, pfs: &crate::ioctl::IocPfs) -> crate::Result<()> {
+        if !self.opt.rw {
+            return Err(nix::errno::Errno::EROFS.into());
+        }
+        let name = Self::get_pfs_name(pfs)?;
+        if name == self.label {
+            log::error!("PFS \"{name}\" is mounted");
+            return Err(nix::errno::Errno::EBUSY.into());
+        }
+        let cid = self.lookup_pfs_by_label(&name)?;
+        if cid == crate::chain::CID_NONE {
+            log::error!("PFS label \"{name}\" not found");
+            return Err(nix::errno::Errno::ENOENT.into());
+        }
+        self.delete_chain(cid)?;
+        // Blocks only reachable from the deleted PFS are freed by bulkfree,
+        // which keeps blocks referenced by any valid volume header.
+        self.flush_all_volume_data()?;
+        let bulk = self.bulkfree(true)?;
+        log::debug!("delete \"{name}\" freed {}", bulk.count_freed);
+        Ok(())
+    }
+}
+
+impl crate::hammer2::Hammer2 {
+    /// # Errors
+    pub fn pfs_snapshot(&mut self, pfs: &mut crate::ioctl::IocPfs) -> crate::Result<()> {
+        self.get_mut().pfs_snapshot(pfs)
+    }
+
+    /// # Errors
+    pub fn pfs_create(&mut self, pfs: &mut crate::ioctl::IocPfs) -> crate::Result<()> {
+        self.get_mut().pfs_create(pfs)
+    }
+
+    /// Delete a PFS and free blocks only reachable from it.
+    /// Older volume headers are overwritten, so they can't be mounted
+    /// to recover the deleted PFS.
+    /// # Errors
+    pub fn pfs_delete(&mut self, pfs: &crate::ioctl::IocPfs) -> crate::Result<()> {
+        self.get_mut().pfs_delete(pfs)
+    }
+}
@@ -0,0 +1,489 @@
+use crate::ErrorExt;
+use crate::OptionExt;
+use byteorder::ByteOrder;
+
+// Header fields are in big-endian.
+const QCOW2_MAGIC: &[u8; 4] = b"QFI\xfb";
+const QCOW2_HEADER_V2_SIZE: usize = 72;
+const QCOW2_HEADER_V3_SIZE: usize = 104;
+const QCOW2_MIN_CLUSTER_BITS: u32 = 9;
+const QCOW2_MAX_CLUSTER_BITS: u32 = 21;
+const QCOW2_MAX_L1_SIZE: u32 = 1 << 22;
+const QCOW2_MAX_BACKING_FILE_SIZE: u32 = 1023;
+
+const QCOW2_INCOMPAT_DIRTY: u64 = 1 << 0;
+const QCOW2_INCOMPAT_CORRUPT: u64 = 1 << 1;
+const QCOW2_INCOMPAT_COMPRESSION: u64 = 1 << 3;
+
+const QCOW2_OFLAG_COPIED: u64 = 1 << 63;
+const QCOW2_OFLAG_COMPRESSED: u64 = 1 << 62;
+const QCOW2_OFLAG_ZERO: u64 = 1;
+const QCOW2_OFFSET_MASK: u64 = 0x00ff_ffff_ffff_fe00;
+
+const QCOW2_L2_CACHE_SIZE: usize = 64;
+
+#[must_use]
+pub fn is_qcow2(magic: &[u8]) -> bool {
+    magic.starts_with(QCOW2_MAGIC)
+}
+
+/// Read-only qcow2 (version 2 and 3) image.
+/// Encrypted images, external data files and compression other than
+/// deflate are unsupported.
+#[derive(Debug)]
+pub struct Qcow2 {
+    dev: Box<dyn crate::device::BlockDevice>,
+    dev_size: u64,
+    backing_file: Option<String>,
+    backing: Option<(Box<dyn crate::device::BlockDevice>, u64)>, // device and size
+    cluster_bits: u32,
+    size: u64,
+    l1: Vec<u64>,
+    l2_cache: std::sync::Mutex<std::collections::HashMap<u64, Vec<u64>>>,
+    cluster_cache: std::sync::Mutex<Option<(u64, Vec<u8>)>>, // last decompressed cluster
+}
+
+impl Qcow2 {
+    /// # Errors
+    pub fn new(mut dev: Box<dyn crate::device::BlockDevice>) -> crate::Result<Self> {
+        let mut hdr = vec![0; QCOW2_HEADER_V3_SIZE];
+        dev.pread(&mut hdr[..QCOW2_HEADER_V2_SIZE], 0)?;
+        if !is_qcow2(&hdr) {
+            log::error!("bad qcow2 magic {:02x?}", &hdr[..4]);
+            return Err(nix::errno::Errno::EINVAL.into());
+        }
+        let version = byteorder::BigEndian::read_u32(&hdr[4..]);
+        if version == 3 {
+            dev.pread(&mut hdr, 0)?;
+        } else if version != 2 {
+            log::error!("unsupported qcow2 version {version}");
+            return Err(nix::errno::Errno::EOPNOTSUPP.into());
+        }
+        let backing_file_offset = byteorder::BigEndian::read_u64(&hdr[8..]);
+        let backing_file_size = byteorder::BigEndian::read_u32(&hdr[16..]);
+        let cluster_bits = byteorder::BigEndian::read_u32(&hdr[20..]);
+        let size = byteorder::BigEndian::read_u64(&hdr[24..]);
+        let crypt_method = byteorder::BigEndian::read_u32(&hdr[32..]);
+        let l1_size = byteorder::BigEndian::read_u32(&hdr[36..]);
+        let l1_table_offset = byteorder::BigEndian::read_u64(&hdr[40..]);
+        let incompat = byteorder::BigEndian::read_u64(&hdr[72..]);
+        let header_length = byteorder::BigEndian::read_u32(&hdr[100..]);
+
+        if !(QCOW2_MIN_CLUSTER_BITS..=QCOW2_MAX_CLUSTER_BITS).contains(&cluster_bits) {
+            log::error!("bad qcow2 cluster bits {cluster_bits}");
+            return Err(nix::errno::Errno::EINVAL.into());
+        }
+        if crypt_method != 0 {
+            log::error!("encrypted qcow2 unsupported");
+            return Err(nix::errno::Errno::EOPNOTSUPP.into());
+        }
+        if incompat & QCOW2_INCOMPAT_CORRUPT != 0 {
+            log::warn!("qcow2 image marked corrupt");
+        }
+        if incompat & QCOW2_INCOMPAT_COMPRESSION != 0 && header_length > 104 {
+            let mut b = [0; 1];
+            dev.pread(&mut b, 104)?;
+            if b[0] != 0 {
+                log::error!("qcow2 compression type {} unsupported", b[0]);
+                return Err(nix::errno::Errno::EOPNOTSUPP.into());
+            }
+        }
+        let x = incompat
+            & !(QCOW2_INCOMPAT_DIRTY | QCOW2_INCOMPAT_CORRUPT | QCOW2_INCOMPAT_COMPRESSION);
+        if x != 0 {
+            log::error!("qcow2 incompatible features {x:#x} unsupported");
+            return Err(nix::errno::Errno::EOPNOTSUPP.into());
+        }
+        // L1 table must cover the virtual size.
+        let l2_bits = cluster_bits - 3;
+        let n = size.div_ceil(1 << (cluster_bits + l2_bits));
+        if l1_size > QCOW2_MAX_L1_SIZE || u64::from(l1_size) < n {
+            log::error!("bad qcow2 L1 size {l1_size} for {size:#x} bytes");
+            return Err(nix::errno::Errno::EINVAL.into());
+        }
+        let mut b = vec![0; usize::try_from(l1_size).or_range()? * 8];
+        dev.pread(&mut b, l1_table_offset)?;
+        let l1 = b.chunks(8).map(byteorder::BigEndian::read_u64).collect();
+
+        let backing_file = if backing_file_offset == 0 {
+            None
+        } else {
+            if backing_file_size > QCOW2_MAX_BACKING_FILE_SIZE {
+                log::error!("bad qcow2 backing file name size {backing_file_size}");
+                return Err(nix::errno::Errno::EINVAL.into());
+            }
+            let mut b = vec![0; backing_file_size.try_into().or_range()?];
+            dev.pread(&mut b, backing_file_offset)?;
+            match libfs::string::b2s(&b) {
+                Ok(v) => Some(v),
+                Err(e) => {
+                    log::error!("bad qcow2 backing file name: {e}");
+                    return Err(nix::errno::Errno::EINVAL.into());
+                }
+            }
+        };
+        Ok(Self {
+            dev_size: dev.get_size()?,
+            dev,
+            backing_file,
+            backing: None,
+            cluster_bits,
+            size,
+            l1,
+            l2_cache: std::sync::Mutex::new(std::collections::HashMap::new()),
+            cluster_cache: std::sync::Mutex::new(None),
+        })
+    }
+
+    /// Returns the backing file name as recorded in the image.
+    #[must_use]
+    pub fn get_backing_file(&self) -> Option<&str> {
+        self.backing_file.as_deref()
+    }
+
+    /// Set a device of the backing file for unallocated clusters.
+    /// # Errors
+    pub fn set_backing(
+        &mut self,
+        mut dev: Box<dyn crate::device::BlockDevice>,
+    ) -> crate::Result<()> {
+        let size = dev.get_size()?;
+        self.backing = Some((dev, size));
+        Ok(())
+    }
+
+    fn get_cluster_size(&self) -> u64 {
+        1 << self.cluster_bits
+    }
+
+    fn lock<T>(m: &std::sync::Mutex<T>) -> crate::Result<std::sync::MutexGuard<'_, T>> {
+        m.lock()
+            .map_err(|e| std::io::Error::other(e.to_string()).into())
+    }
+
+    fn get_l2_entry(&self, cluster: u64) -> crate::Result<u64> {
+        let l2_bits = self.cluster_bits - 3;
+        let l1_index = usize::try_from(cluster >> l2_bits).or_range()?;
+        let l2_index = usize::try_from(cluster & ((1 << l2_bits) - 1)).or_range()?;
+        let Some(&l1_entry) = self.l1.get(l1_index) else {
+            return Ok(0);
+        };
+        let l2_offset = l1_entry & QCOW2_OFFSET_MASK;
+        if l2_offset == 0 {
+            return Ok(0);
+        }
+        let mut l2_cache = Self::lock(&self.l2_cache)?;
+        if !l2_cache.contains_key(&l2_offset) {
+            if l2_cache.len() >= QCOW2_L2_CACHE_SIZE {
+                l2_cache.clear();
+            }
+            let mut b = vec![0; self.get_cluster_size().try_into().or_range()?];
+            self.dev.pread(&mut b, l2_offset)?;
+            let l2 = b.chunks(8).map(byteorder::BigEndian::read_u64).collect();
+            l2_cache.insert(l2_offset, l2);
+        }
+        Ok(l2_cache.get(&l2_offset).or_range()?[l2_index])
+    }
+
+    fn read_compressed(&self, buf: &mut [u8], entry: u64, coff: usize) -> crate::Result<()> {
+        let x = 62 - (self.cluster_bits - 8);
+        let offset = entry & ((1 << x) - 1);
+        let mut cluster_cache = Self::lock(&self.cluster_cache)?;
+        if cluster_cache.as_ref().is_none_or(|c| c.0 != offset) {
+            let nsects = (entry >> x) & ((1 << (self.cluster_bits - 8)) - 1);
+            // Compressed data may end beyond the end of the file.
+            let size =
+                ((nsects + 1) * 512 - (offset & 511)).min(self.dev_size.saturating_sub(offset));
+            let mut b = vec![0; size.try_into().or_range()?];
+            self.dev.pread(&mut b, offset)?;
+            let n = self.get_cluster_size().try_into().or_range()?;
+            let mut b = match miniz_oxide::inflate::decompress_to_vec_with_limit(&b, n) {
+                Ok(v) => v,
+                Err(e) => {
+                    log::error!("qcow2 cluster at {offset:#x}: {e}");
+                    return Err(nix::errno::Errno::EIO.into());
+                }
+            };
+            b.resize(n, 0);
+            *cluster_cache = Some((offset, b));
+        }
+        let b = &cluster_cache.as_ref().or_range()?.1;
+        buf.copy_from_slice(b.get(coff..coff + buf.len()).or_range()?);
+        Ok(())
+    }
+
+    fn read_unallocated(&self, buf: &mut [u8], offset: u64) -> crate::Result<()> {
+        buf.fill(0);
+        let Some((dev, size)) = &self.backing else {
+            if let Some(f) = &self.backing_file {
+                log::error!("qcow2 backing file {f} not opened");
+                return Err(nix::errno::Errno::EIO.into());
+            }
+            return Ok(());
+        };
+        // Backing file may be smaller than the image.
+        let size = *size;
+        if offset < size {
+            let n = usize::try_from(size - offset)
+                .unwrap_or(usize::MAX)
+                .min(buf.len());
+            dev.pread(&mut buf[..n], offset)?;
+        }
+        Ok(())
+    }
+}
+
+impl crate::device::BlockDevice for Qcow2 {
+    fn pread(&self, buf: &mut [u8], offset: u64) -> crate::Result<()> {
+        if offset
+            .checked_add(buf.len().try_into().or_range()?)
+            .is_none_or(|x| x > self.size)
+        {
+            return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+        }
+        let cluster_size = self.get_cluster_size();
+        let mut buf = buf;
+        let mut offset = offset;
+        while !buf.is_empty() {
+            let cluster = offset >> self.cluster_bits;
+            let coff = offset & (cluster_size - 1);
+            let n = usize::try_from(cluster_size - coff)
+                .unwrap_or(usize::MAX)
+                .min(buf.len());
+            let (b, rest) = buf.split_at_mut(n);
+            let entry = self.get_l2_entry(cluster)? & !QCOW2_OFLAG_COPIED;
+            let i = usize::try_from(coff).or_range()?;
+            if entry & QCOW2_OFLAG_COMPRESSED != 0 {
+                self.read_compressed(b, entry & !QCOW2_OFLAG_COMPRESSED, i)?;
+            } else if entry & QCOW2_OFLAG_ZERO != 0 {
+                b.fill(0);
+            } else if entry & QCOW2_OFFSET_MASK == 0 {
+                self.read_unallocated(b, offset)?;
+            } else {
+                self.dev.pread(b, (entry & QCOW2_OFFSET_MASK) + coff)?;
+            }
+            buf = rest;
+            offset += u64::try_from(n).or_range()?;
+        }
+        Ok(())
+    }
+
+    fn pwrite(&self, _buf: &[u8], _offset: u64) -> crate::Result<()> {
+        Err(nix::errno::Errno::EROFS.into())
+    }
+
+    fn get_size(&mut self) -> crate::Result<u64> {
+        Ok(self.size)
+    }
+
+    fn flush(&self) -> crate::Result<()> {
+        Ok(())
+    }
+
+    fn get_metadata(&self) -> crate::Result<Option<std::fs::Metadata>> {
+        self.dev.get_metadata()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use byteorder::ByteOrder;
+
+    const CLUSTER_BITS: u32 = 16;
+    const CLUSTER_SIZE: usize = 1 << CLUSTER_BITS;
+
+    fn create_raw_image(data: &[u8]) -> Vec<u8> {
+        let dev = match crate::device::MemoryDevice::new(128 << 20) {
+            Ok(v) => v,
+            Err(e) => panic!("{e}"),
+        };
+        if let Err(e) = crate::newfs::Newfs::new()
+            .quiet(true)
+            .format_devices(vec![Box::new(dev.clone())])
+        {
+            panic!("{e}");
+        }
+        let mut pmp = match crate::hammer2::Hammer2::mount_devices(
+            vec![Box::new(dev.clone())],
+            "",
+            &["--rw"],
+        ) {
+            Ok(v) => v,
+            Err(e) => panic!("{e}"),
+        };
+        match pmp.create(crate::inode::INUM_PFS_ROOT, "f", 0o644) {
+            Ok(v) => {
+                if let Err(e) = pmp.pwrite(v, data, 0) {
+                    panic!("{e}");
+                }
+            }
+            Err(e) => panic!("{e}"),
+        }
+        if let Err(e) = pmp.unmount() {
+            panic!("{e}");
+        }
+        match dev.to_vec() {
+            Ok(v) => v,
+            Err(e) => panic!("{e}"),
+        }
+    }
+
+    // Header and backing file name in cluster 0, L1 table in cluster 1,
+    // L2 table in cluster 2, and then data clusters.  Every other non-zero
+    // cluster is compressed, and every other zero cluster has zero flag.
+    fn create_qcow2(raw: &[u8], backing: Option<&str>) -> Vec<u8> {
+        assert!(raw.len() <= CLUSTER_SIZE * CLUSTER_SIZE / 8);
+        let mut b = vec![0; CLUSTER_SIZE * 3];
+        b[..4].copy_from_slice(super::QCOW2_MAGIC);
+        byteorder::BigEndian::write_u32(&mut b[4..], 3);
+        if let Some(f) = backing {
+            byteorder::BigEndian::write_u64(&mut b[8..], 512);
+            byteorder::BigEndian::write_u32(&mut b[16..], f.len().try_into().unwrap_or(0));
+            b[512..512 + f.len()].copy_from_slice(f.as_bytes());
+        }
+        byteorder::BigEndian::write_u32(&mut b[20..], CLUSTER_BITS);
+        byteorder::BigEndian::write_u64(&mut b[24..], raw.len().try_into().unwrap_or(0));
+        byteorder::BigEndian::write_u32(&mut b[36..], 1);
+        byteorder::BigEndian::write_u64(&mut b[40..], 1 << CLUSTER_BITS);
+        byteorder::BigEndian::write_u32(&mut b[100..], 104);
+        if backing.is_some() {
+            return b;
+        }
+        let l2 = 2 << CLUSTER_BITS;
+        byteorder::BigEndian::write_u64(&mut b[CLUSTER_SIZE..], l2 | super::QCOW2_OFLAG_COPIED);
+        let mut nzero = 0;
+        for (i, x) in raw.chunks(CLUSTER_SIZE).enumerate() {
+            let entry = if x.iter().all(|&y| y == 0) {
+                nzero += 1;
+                if nzero % 2 == 0 {
+                    super::QCOW2_OFLAG_ZERO
+                } else {
+                    0
+                }
+            } else if i % 2 == 0 {
+                b.resize(b.len().next_multiple_of(CLUSTER_SIZE), 0);
+                let offset = u64::try_from(b.len()).unwrap_or(0);
+                b.extend(x);
+                offset | super::QCOW2_OFLAG_COPIED
+            } else {
+                // compressed data don't need to be aligned
+                b.extend([0; 100]);
+                let offset = u64::try_from(b.len()).unwrap_or(0);
+                let c = miniz_oxide::deflate::compress_to_vec(x, 6);
+                let nsects = (offset % 512 + u64::try_from(c.len()).unwrap_or(0)).div_ceil(512);
+                b.extend(c);
+                let x = 62 - (CLUSTER_BITS - 8);
+                offset | ((nsects - 1) << x) | super::QCOW2_OFLAG_COMPRESSED
+            };
+            byteorder::BigEndian::write_u64(
+                &mut b[usize::try_from(l2).unwrap_or(0) + i * 8..],
+                entry,
+            );
+        }
+        b
+    }
+
+    fn open(f: &str) -> Box<dyn crate::device::BlockDevice> {
+        match crate::device::open(f, true, false) {
+            Ok(v) => v,
+            Err(e) => panic!("{e}"),
+        }
+    }
+
+    fn assert_image(dev: &mut dyn crate::device::BlockDevice, raw: &[u8]) {
+        match dev.get_size() {
+            Ok(v) => assert_eq!(v, u64::try_from(raw.len()).unwrap_or(0)),
+            Err(e) => panic!("{e}"),
+        }
+        // unaligned to clusters
+        let mut buf = vec![0; 3 * CLUSTER_SIZE / 2];
+        for (i, x) in raw.chunks(buf.len()).enumerate() {
+            let buf = &mut buf[..x.len()];
+            if let Err(e) = dev.pread(buf, u64::try_from(i * 3 * CLUSTER_SIZE / 2).unwrap_or(0)) {
+                panic!("{e}");
+            }
+            assert!(buf == x, "{i}");
+        }
+        assert!(
+            dev.pread(&mut buf, u64::try_from(raw.len()).unwrap_or(0) - 1)
+                .is_err()
+        );
+        assert!(dev.pwrite(&buf, 0).is_err());
+    }
+
+    fn assert_mount(f: &str, data: &[u8]) {
+        let mut pmp = match crate::hammer2::Hammer2::mount(f, &[]) {
+            Ok(v) => v,
+            Err(e) => panic!("{e}"),
+        };
+        let inum = match pmp.nresolve_path("/f") {
+            Ok(v) => v,
+            Err(e) => panic!("{e}"),
+        };
+        match pmp.read_all(inum) {
+            Ok(v) => assert!(v == data),
+            Err(e) => panic!("{e}"),
+        }
+        if let Err(e) = pmp.unmount() {
+            panic!("{e}");
+        }
+    }
+
+    #[test]
+    fn test_qcow2() {
+        let data: Vec<u8> = (0..300_000u32).map(|x| (x % 251) as u8).collect();
+        let raw = create_raw_image(&data);
+        let d = std::env::temp_dir().join(format!("libhammer2_qcow2_{}", std::process::id()));
+        if let Err(e) = std::fs::create_dir_all(&d) {
+            panic!("{e}");
+        }
+        let base = d.join("base.qcow2");
+        let overlay = d.join("overlay.qcow2");
+        let (Some(base), Some(overlay)) = (base.to_str(), overlay.to_str()) else {
+            panic!("{d:?}");
+        };
+        if let Err(e) = std::fs::write(base, create_qcow2(&raw, None)) {
+            panic!("{e}");
+        }
+        if let Err(e) = std::fs::write(overlay, create_qcow2(&raw, Some("base.qcow2"))) {
+            panic!("{e}");
+        }
+        for f in [base, overlay] {
+            assert_image(open(f).as_mut(), &raw);
+            assert_mount(f, &data);
+            assert!(crate::device::open(f, false, false).is_err());
+            assert!(crate::hammer2::Hammer2::mount(f, &["--rw"]).is_err());
+        }
+
+        // backing file referring to itself
+        let f = d.join("loop.qcow2");
+        let Some(f) = f.to_str() else {
+            panic!("{f:?}");
+        };
+        if let Err(e) = std::fs::write(f, create_qcow2(&raw, Some("loop.qcow2"))) {
+            panic!("{e}");
+        }
+        assert!(crate::device::open(f, true, false).is_err());
+        let _ = std::fs::remove_dir_all(&d);
+    }
+
+    #[test]
+    fn test_qcow2_header() {
+        let raw = vec![0; 1 << 20];
+        let b = create_qcow2(&raw, None);
+        let new =
+            |b: &[u8]| super::Qcow2::new(Box::new(crate::device::MemoryDevice::from(b.to_vec())));
+        assert!(new(&b).is_ok());
+        for (offset, x) in [
+            (4, 1),       // version
+            (20, 8),      // cluster bits
+            (32, 1),      // encryption
+            (36, 0),      // L1 size
+            (76, 1 << 2), // external data file
+        ] {
+            let mut b = b.clone();
+            byteorder::BigEndian::write_u32(&mut b[offset..], x);
+            assert!(new(&b).is_err(), "{offset}");
+        }
+    }
+}
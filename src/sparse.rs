@@ -0,0 +1,345 @@
+use crate::ErrorExt;
+use byteorder::ByteOrder;
+
+// Header fields are in little-endian.
+const SPARSE_MAGIC: u32 = 0xed26_ff3a;
+const SPARSE_MAJOR_VERSION: u16 = 1;
+const SPARSE_HEADER_SIZE: usize = 28;
+const SPARSE_CHUNK_HEADER_SIZE: usize = 12;
+
+const CHUNK_TYPE_RAW: u16 = 0xcac1;
+const CHUNK_TYPE_FILL: u16 = 0xcac2;
+const CHUNK_TYPE_DONT_CARE: u16 = 0xcac3;
+const CHUNK_TYPE_CRC32: u16 = 0xcac4;
+
+#[must_use]
+pub fn is_sparse(magic: &[u8]) -> bool {
+    magic.len() >= 4 && byteorder::LittleEndian::read_u32(magic) == SPARSE_MAGIC
+}
+
+#[derive(Debug)]
+enum ChunkData {
+    Raw(u64), // offset in the file
+    Fill([u8; 4]),
+    DontCare,
+}
+
+#[derive(Debug)]
+struct Chunk {
+    offset: u64, // offset in the image
+    size: u64,
+    data: ChunkData,
+}
+
+/// Read-only Android sparse image.
+#[derive(Debug)]
+pub struct SparseImage {
+    dev: Box<dyn crate::device::BlockDevice>,
+    size: u64,
+    chunks: Vec<Chunk>,
+}
+
+impl SparseImage {
+    /// # Errors
+    pub fn new(mut dev: Box<dyn crate::device::BlockDevice>) -> crate::Result<Self> {
+        let mut hdr = [0; SPARSE_HEADER_SIZE];
+        dev.pread(&mut hdr, 0)?;
+        if !is_sparse(&hdr) {
+            log::error!("bad sparse image magic {:02x?}", &hdr[..4]);
+            return Err(nix::errno::Errno::EINVAL.into());
+        }
+        let major = byteorder::LittleEndian::read_u16(&hdr[4..]);
+        let file_hdr_sz = byteorder::LittleEndian::read_u16(&hdr[8..]);
+        let chunk_hdr_sz = byteorder::LittleEndian::read_u16(&hdr[10..]);
+        let blk_sz = byteorder::LittleEndian::read_u32(&hdr[12..]);
+        let total_blks = byteorder::LittleEndian::read_u32(&hdr[16..]);
+        let total_chunks = byteorder::LittleEndian::read_u32(&hdr[20..]);
+        if major != SPARSE_MAJOR_VERSION {
+            log::error!("unsupported sparse image version {major}");
+            return Err(nix::errno::Errno::EOPNOTSUPP.into());
+        }
+        if usize::from(file_hdr_sz) < SPARSE_HEADER_SIZE
+            || usize::from(chunk_hdr_sz) < SPARSE_CHUNK_HEADER_SIZE
+            || blk_sz == 0
+            || blk_sz % 4 != 0
+        {
+            log::error!(
+                "bad sparse image header: file header {file_hdr_sz} chunk header {chunk_hdr_sz} block {blk_sz}"
+            );
+            return Err(nix::errno::Errno::EINVAL.into());
+        }
+        let blk_sz = u64::from(blk_sz);
+        let chunk_hdr_sz = u64::from(chunk_hdr_sz);
+
+        let dev_size = dev.get_size()?;
+        let mut chunks = vec![];
+        let mut pos = u64::from(file_hdr_sz);
+        let mut offset = 0;
+        for i in 0..total_chunks {
+            let mut b = [0; SPARSE_CHUNK_HEADER_SIZE];
+            dev.pread(&mut b, pos)?;
+            let typ = byteorder::LittleEndian::read_u16(&b);
+            let size = u64::from(byteorder::LittleEndian::read_u32(&b[4..])) * blk_sz;
+            let total_sz = u64::from(byteorder::LittleEndian::read_u32(&b[8..]));
+            let (data, data_sz) = match typ {
+                CHUNK_TYPE_RAW => (ChunkData::Raw(pos + chunk_hdr_sz), size),
+                CHUNK_TYPE_FILL => {
+                    let mut b = [0; 4];
+                    dev.pread(&mut b, pos + chunk_hdr_sz)?;
+                    (ChunkData::Fill(b), 4)
+                }
+                CHUNK_TYPE_DONT_CARE => (ChunkData::DontCare, 0),
+                CHUNK_TYPE_CRC32 => (ChunkData::DontCare, 4),
+                _ => {
+                    log::error!("sparse image chunk {i}: bad type {typ:#x}");
+                    return Err(nix::errno::Errno::EINVAL.into());
+                }
+            };
+            if total_sz != chunk_hdr_sz + data_sz {
+                log::error!("sparse image chunk {i}: bad size {total_sz}");
+                return Err(nix::errno::Errno::EINVAL.into());
+            }
+            if pos + total_sz > dev_size {
+                log::error!("sparse image chunk {i}: beyond end of file");
+                return Err(nix::errno::Errno::EINVAL.into());
+            }
+            if size != 0 {
+                chunks.push(Chunk { offset, size, data });
+                let Some(x) = offset.checked_add(size) else {
+                    log::error!("sparse image chunk {i}: offset overflow");
+                    return Err(nix::errno::Errno::EINVAL.into());
+                };
+                offset = x;
+            }
+            pos += total_sz;
+        }
+        let size = u64::from(total_blks) * blk_sz;
+        if offset != size {
+            log::error!("sparse image chunks cover {offset:#x} of {size:#x} bytes");
+            return Err(nix::errno::Errno::EINVAL.into());
+        }
+        Ok(Self { dev, size, chunks })
+    }
+}
+
+impl crate::device::BlockDevice for SparseImage {
+    fn pread(&self, buf: &mut [u8], offset: u64) -> crate::Result<()> {
+        if offset
+            .checked_add(buf.len().try_into().or_range()?)
+            .is_none_or(|x| x > self.size)
+        {
+            return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+        }
+        let mut i = self.chunks.partition_point(|x| {
+            x.offset
+                .checked_add(x.size)
+                .is_some_and(|end| end <= offset)
+        });
+        let mut buf = buf;
+        let mut offset = offset;
+        while !buf.is_empty() {
+            let Some(chunk) = self.chunks.get(i) else {
+                return Err(nix::errno::Errno::EINVAL.into());
+            };
+            let coff = offset - chunk.offset;
+            let n = usize::try_from(chunk.size - coff)
+                .unwrap_or(usize::MAX)
+                .min(buf.len());
+            let (b, rest) = buf.split_at_mut(n);
+            match chunk.data {
+                ChunkData::Raw(x) => self.dev.pread(b, x + coff)?,
+                ChunkData::Fill(x) => {
+                    // fill chunks are multiple of 4 bytes
+                    let j = usize::try_from(coff % 4).or_range()?;
+                    for (k, y) in b.iter_mut().enumerate() {
+                        *y = x[(j + k) % 4];
+                    }
+                }
+                ChunkData::DontCare => b.fill(0),
+            }
+            buf = rest;
+            offset += u64::try_from(n).or_range()?;
+            i += 1;
+        }
+        Ok(())
+    }
+
+    fn pwrite(&self, _buf: &[u8], _offset: u64) -> crate::Result<()> {
+        Err(nix::errno::Errno::EROFS.into())
+    }
+
+    fn get_size(&mut self) -> crate::Result<u64> {
+        Ok(self.size)
+    }
+
+    fn flush(&self) -> crate::Result<()> {
+        Ok(())
+    }
+
+    fn get_metadata(&self) -> crate::Result<Option<std::fs::Metadata>> {
+        self.dev.get_metadata()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use byteorder::ByteOrder;
+
+    const BLOCK_SIZE: usize = 4096;
+
+    fn create_raw_image() -> Vec<u8> {
+        let dev = match crate::device::MemoryDevice::new(128 << 20) {
+            Ok(v) => v,
+            Err(e) => panic!("{e}"),
+        };
+        if let Err(e) = crate::newfs::Newfs::new()
+            .quiet(true)
+            .format_devices(vec![Box::new(dev.clone())])
+        {
+            panic!("{e}");
+        }
+        match dev.to_vec() {
+            Ok(v) => v,
+            Err(e) => panic!("{e}"),
+        }
+    }
+
+    fn push_chunk(b: &mut Vec<u8>, typ: u16, nblocks: usize, data: &[u8]) {
+        let mut hdr = [0; super::SPARSE_CHUNK_HEADER_SIZE];
+        byteorder::LittleEndian::write_u16(&mut hdr, typ);
+        byteorder::LittleEndian::write_u32(&mut hdr[4..], nblocks.try_into().unwrap_or(0));
+        byteorder::LittleEndian::write_u32(
+            &mut hdr[8..],
+            (super::SPARSE_CHUNK_HEADER_SIZE + data.len())
+                .try_into()
+                .unwrap_or(0),
+        );
+        b.extend(hdr);
+        b.extend(data);
+    }
+
+    // Zero blocks are alternately "don't care" and zero filled chunks,
+    // followed by a CRC32 chunk.
+    fn create_sparse(raw: &[u8]) -> Vec<u8> {
+        let mut b = vec![0; super::SPARSE_HEADER_SIZE];
+        byteorder::LittleEndian::write_u32(&mut b, super::SPARSE_MAGIC);
+        byteorder::LittleEndian::write_u16(&mut b[4..], super::SPARSE_MAJOR_VERSION);
+        byteorder::LittleEndian::write_u16(&mut b[8..], 28);
+        byteorder::LittleEndian::write_u16(&mut b[10..], 12);
+        byteorder::LittleEndian::write_u32(&mut b[12..], BLOCK_SIZE.try_into().unwrap_or(0));
+        byteorder::LittleEndian::write_u32(
+            &mut b[16..],
+            (raw.len() / BLOCK_SIZE).try_into().unwrap_or(0),
+        );
+        let mut nchunks = 0;
+        let mut nzero = 0;
+        for x in raw.chunks(BLOCK_SIZE) {
+            if x.iter().all(|&y| y == 0) {
+                nzero += 1;
+                if nzero % 2 == 0 {
+                    push_chunk(&mut b, super::CHUNK_TYPE_DONT_CARE, 1, &[]);
+                } else {
+                    push_chunk(&mut b, super::CHUNK_TYPE_FILL, 1, &[0; 4]);
+                }
+            } else {
+                push_chunk(&mut b, super::CHUNK_TYPE_RAW, 1, x);
+            }
+            nchunks += 1;
+        }
+        push_chunk(&mut b, super::CHUNK_TYPE_CRC32, 0, &[0; 4]);
+        nchunks += 1;
+        byteorder::LittleEndian::write_u32(&mut b[20..], nchunks);
+        b
+    }
+
+    fn new_sparse(b: &[u8]) -> crate::Result<super::SparseImage> {
+        super::SparseImage::new(Box::new(crate::device::MemoryDevice::from(b.to_vec())))
+    }
+
+    #[test]
+    fn test_sparse() {
+        let raw = create_raw_image();
+        let f = crate::newfs::get_image_path("sparse");
+        if let Err(e) = std::fs::write(&f, create_sparse(&raw)) {
+            panic!("{e}");
+        }
+        let mut dev = match crate::device::open(&f, true, false) {
+            Ok(v) => v,
+            Err(e) => panic!("{e}"),
+        };
+        match dev.get_size() {
+            Ok(v) => assert_eq!(v, 128 << 20),
+            Err(e) => panic!("{e}"),
+        }
+        // unaligned to blocks
+        let mut buf = vec![0; BLOCK_SIZE * 3 + 1];
+        for (i, x) in raw.chunks(buf.len()).enumerate() {
+            let buf = &mut buf[..x.len()];
+            if let Err(e) = dev.pread(buf, u64::try_from(i * (BLOCK_SIZE * 3 + 1)).unwrap_or(0)) {
+                panic!("{e}");
+            }
+            assert!(buf == x, "{i}");
+        }
+        assert!(dev.pread(&mut buf, (128 << 20) - 1).is_err());
+        assert!(dev.pwrite(&buf, 0).is_err());
+        drop(dev);
+
+        assert!(crate::device::open(&f, false, false).is_err());
+        match crate::hammer2::Hammer2::mount(&f, &[]) {
+            Ok(mut pmp) => {
+                assert!(pmp.nresolve_path("/").is_ok());
+                if let Err(e) = pmp.unmount() {
+                    panic!("{e}");
+                }
+            }
+            Err(e) => panic!("{e}"),
+        }
+        let _ = std::fs::remove_file(&f);
+    }
+
+    #[test]
+    fn test_sparse_fill() {
+        let raw = vec![0; BLOCK_SIZE * 2];
+        let mut b = create_sparse(&raw);
+        // replace the first chunk with a pattern
+        b[super::SPARSE_HEADER_SIZE + 12..][..4].copy_from_slice(&[1, 2, 3, 4]);
+        let dev = match new_sparse(&b) {
+            Ok(v) => v,
+            Err(e) => panic!("{e}"),
+        };
+        let mut buf = [0; 7];
+        if let Err(e) = crate::device::BlockDevice::pread(&dev, &mut buf, 4094) {
+            panic!("{e}");
+        }
+        assert_eq!(buf, [3, 4, 0, 0, 0, 0, 0]);
+
+        // chunks must cover the image
+        let mut x = b.clone();
+        byteorder::LittleEndian::write_u32(&mut x[16..], 3);
+        assert!(new_sparse(&x).is_err());
+        // bad chunk size
+        let mut x = b.clone();
+        byteorder::LittleEndian::write_u32(&mut x[super::SPARSE_HEADER_SIZE + 8..], 12);
+        assert!(new_sparse(&x).is_err());
+        // truncated
+        assert!(new_sparse(&b[..b.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn test_sparse_overflow() {
+        // two chunks of nearly 2^64 bytes each
+        let blk_sz = 0xffff_fffc_u32;
+        let mut b = create_sparse(&[]);
+        byteorder::LittleEndian::write_u32(&mut b[12..], blk_sz);
+        byteorder::LittleEndian::write_u32(&mut b[16..], u32::MAX);
+        byteorder::LittleEndian::write_u32(&mut b[20..], 2);
+        b.truncate(super::SPARSE_HEADER_SIZE);
+        for _ in 0..2 {
+            push_chunk(&mut b, super::CHUNK_TYPE_DONT_CARE, 0xffff_ffff, &[]);
+        }
+        match new_sparse(&b) {
+            Err(crate::Error::Errno(nix::errno::Errno::EINVAL)) => (),
+            v => panic!("{v:?}"),
+        }
+    }
+}
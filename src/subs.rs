@@ -1,3 +1,4 @@
+use crate::ErrorExt;
 use byteorder::ByteOrder;
 use std::os::unix::fs::FileTypeExt;
 
@@ -243,21 +244,21 @@ pub fn get_volume_size(fp: &mut std::fs::File) -> crate::Result<u64> {
 #[must_use]
 pub fn dirhash(aname: &[u8]) -> u64 {
     // m32
-    let mut crcx = 0;
+    let mut crcx = 0u32;
     let mut i = 0;
     let mut j = 0;
     while i < aname.len() {
         let x = aname[i] as char;
         if x == '.' || x == '-' || x == '_' || x == '~' {
             if i != j {
-                crcx += icrc32::iscsi_crc32(&aname[j..i]);
+                crcx = crcx.wrapping_add(icrc32::iscsi_crc32(&aname[j..i]));
             }
             j = i + 1;
         }
         i += 1;
     }
     if i != j {
-        crcx += icrc32::iscsi_crc32(&aname[j..i]);
+        crcx = crcx.wrapping_add(icrc32::iscsi_crc32(&aname[j..i]));
     }
 
     // The directory hash utilizes the top 32 bits of the 64-bit key.
@@ -300,6 +301,17 @@ pub fn get_uuid_from_str(s: &str) -> Result<uuid::Uuid, uuid::Error> {
     }
 }
 
+// Inverse of get_uuid_from_str.
+#[must_use]
+pub fn get_uuid_from_bytes(b: &uuid::Bytes) -> uuid::Uuid {
+    // use host byteorder, no fixed endianness
+    if is_le() {
+        uuid::Uuid::from_bytes_le(*b)
+    } else {
+        uuid::Uuid::from_bytes(*b)
+    }
+}
+
 #[must_use]
 pub fn get_uuid_string(u: &uuid::Uuid) -> String {
     get_uuid_string_from_bytes(u.as_bytes())
@@ -307,17 +319,21 @@ pub fn get_uuid_string(u: &uuid::Uuid) -> String {
 
 #[must_use]
 pub fn get_uuid_string_from_bytes(b: &uuid::Bytes) -> String {
-    // use host byteorder, no fixed endianness
-    let u = if is_le() {
-        uuid::Uuid::from_bytes_le(*b)
-    } else {
-        uuid::Uuid::from_bytes(*b)
-    };
+    let u = get_uuid_from_bytes(b);
     (*(u.as_hyphenated()
         .encode_lower(&mut uuid::Uuid::encode_buffer())))
     .to_string()
 }
 
+// current time in microseconds since the epoch
+/// # Errors
+pub fn get_current_time() -> crate::Result<u64> {
+    match std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH) {
+        Ok(v) => v.as_micros().try_into().or_range(),
+        Err(e) => Err(crate::Error::Dyn(Box::new(e))),
+    }
+}
+
 pub(crate) fn conv_time_to_timespec(t: u64) -> u64 {
     t / 1_000_000 // sec
 }
@@ -438,6 +454,9 @@ mod tests {
                 "This code is derived from software contributed to The DragonFly Project",
                 0xf8df_95ed_6d32_8000,
             ),
+            // sum of component crcs wraps
+            ("a.b", 0x9450_f3f4_307d_8000),
+            ("x.y.z", 0xcc9b_6b87_9d83_8000),
         ];
         for t in &l {
             assert_eq!(super::dirhash(t.0.as_bytes()), t.1, "{}", t.0);
@@ -464,6 +483,10 @@ mod tests {
             super::get_uuid_string_from_bytes(u.as_bytes()),
             crate::fs::HAMMER2_UUID_STRING
         );
+        assert_eq!(
+            super::get_uuid_from_bytes(u.as_bytes()).to_string(),
+            crate::fs::HAMMER2_UUID_STRING
+        );
     }
 
     #[test]
@@ -0,0 +1,424 @@
+// Byte-swap ondisk structures between host and reverse byte order.
+// Each swap is its own inverse, so the same function both decodes
+// reverse-endian (ABO) media and encodes host byte order (HBO) media.
+
+fn swap_u16(x: &mut u16) {
+    *x = x.swap_bytes();
+}
+
+fn swap_u32(x: &mut u32) {
+    *x = x.swap_bytes();
+}
+
+fn swap_u64(x: &mut u64) {
+    *x = x.swap_bytes();
+}
+
+// uuid is stored as struct uuid in host byte order.
+fn swap_uuid(b: &mut [u8; 16]) {
+    b[..4].reverse(); // time_low
+    b[4..6].reverse(); // time_mid
+    b[6..8].reverse(); // time_hi_and_version
+}
+
+// uid / gid additionally embed a host byte order unix xid.
+fn swap_xid_uuid(b: &mut [u8; 16]) {
+    swap_uuid(b);
+    b[12..].reverse();
+}
+
+impl crate::fs::Hammer2DirentHead {
+    pub fn swap_bytes(&mut self) {
+        swap_u64(&mut self.inum);
+        swap_u16(&mut self.namlen);
+    }
+}
+
+impl crate::fs::Hammer2BlockrefEmbedStats {
+    pub fn swap_bytes(&mut self) {
+        swap_u64(&mut self.data_count);
+        swap_u64(&mut self.inode_count);
+    }
+}
+
+impl crate::fs::Hammer2Blockref {
+    pub fn swap_bytes(&mut self) {
+        swap_u16(&mut self.leaf_count);
+        swap_u64(&mut self.key);
+        swap_u64(&mut self.mirror_tid);
+        swap_u64(&mut self.modify_tid);
+        swap_u64(&mut self.data_off);
+        swap_u64(&mut self.update_tid);
+        if self.typ == crate::fs::HAMMER2_BREF_TYPE_DIRENT {
+            self.embed_as_mut::<crate::fs::Hammer2DirentHead>()
+                .swap_bytes();
+            // Short names are embedded in the check field.
+            if self.data_off == 0 {
+                return;
+            }
+        } else {
+            self.embed_as_mut::<crate::fs::Hammer2BlockrefEmbedStats>()
+                .swap_bytes();
+        }
+        match crate::fs::dec_check(self.methods) {
+            crate::fs::HAMMER2_CHECK_ISCSI32 => {
+                swap_u32(
+                    &mut self
+                        .check_as_mut::<crate::fs::Hammer2BlockrefCheckIscsi>()
+                        .value,
+                );
+            }
+            crate::fs::HAMMER2_CHECK_XXHASH64 => {
+                swap_u64(
+                    &mut self
+                        .check_as_mut::<crate::fs::Hammer2BlockrefCheckXxhash64>()
+                        .value,
+                );
+            }
+            crate::fs::HAMMER2_CHECK_FREEMAP => {
+                let check = self.check_as_mut::<crate::fs::Hammer2BlockrefCheckFreemap>();
+                swap_u32(&mut check.icrc32);
+                swap_u32(&mut check.bigmask);
+                swap_u64(&mut check.avail);
+            }
+            _ => (), // byte array or unused
+        }
+    }
+}
+
+impl crate::fs::Hammer2Blockset {
+    pub fn swap_bytes(&mut self) {
+        for bref in &mut self.blockref {
+            bref.swap_bytes();
+        }
+    }
+}
+
+impl crate::fs::Hammer2BmapData {
+    pub fn swap_bytes(&mut self) {
+        swap_u32(&mut self.linear);
+        swap_u16(&mut self.class);
+        swap_u32(&mut self.reserved08);
+        swap_u32(&mut self.reserved0c);
+        swap_u32(&mut self.reserved10);
+        swap_u32(&mut self.reserved14);
+        swap_u32(&mut self.reserved18);
+        swap_u32(&mut self.avail);
+        self.reserved20.iter_mut().for_each(swap_u32);
+        self.bitmapq.iter_mut().for_each(swap_u64);
+    }
+}
+
+impl crate::fs::Hammer2InodeMeta {
+    pub fn swap_bytes(&mut self) {
+        swap_u16(&mut self.version);
+        swap_u32(&mut self.uflags);
+        swap_u32(&mut self.rmajor);
+        swap_u32(&mut self.rminor);
+        swap_u64(&mut self.ctime);
+        swap_u64(&mut self.mtime);
+        swap_u64(&mut self.atime);
+        swap_u64(&mut self.btime);
+        swap_xid_uuid(&mut self.uid);
+        swap_xid_uuid(&mut self.gid);
+        swap_u16(&mut self.cap_flags);
+        swap_u32(&mut self.mode);
+        swap_u64(&mut self.inum);
+        swap_u64(&mut self.size);
+        swap_u64(&mut self.nlinks);
+        swap_u64(&mut self.iparent);
+        swap_u64(&mut self.name_key);
+        swap_u16(&mut self.name_len);
+        swap_u64(&mut self.pfs_inum);
+        swap_uuid(&mut self.pfs_clid);
+        swap_uuid(&mut self.pfs_fsid);
+        swap_u64(&mut self.data_quota);
+        swap_u64(&mut self.unusedb8);
+        swap_u64(&mut self.inode_quota);
+        swap_u64(&mut self.unusedc8);
+        swap_u64(&mut self.pfs_lsnap_tid);
+        swap_u64(&mut self.reservedd8);
+        swap_u64(&mut self.decrypt_check);
+        self.reservede8.iter_mut().for_each(swap_u64);
+    }
+}
+
+impl crate::fs::Hammer2InodeData {
+    pub fn swap_bytes(&mut self) {
+        self.meta.swap_bytes();
+        // Direct data is a byte array.
+        if self.meta.is_sup_root() || !self.meta.has_direct_data() {
+            self.u_as_mut::<crate::fs::Hammer2Blockset>().swap_bytes();
+        }
+    }
+}
+
+impl crate::fs::Hammer2VolumeData {
+    // magic is left as is, so that is_hbo() tells the media byte order.
+    // CRC's are not recomputed.
+    pub fn swap_bytes(&mut self) {
+        swap_u64(&mut self.boot_beg);
+        swap_u64(&mut self.boot_end);
+        swap_u64(&mut self.aux_beg);
+        swap_u64(&mut self.aux_end);
+        swap_u64(&mut self.volu_size);
+        swap_u32(&mut self.version);
+        swap_u32(&mut self.flags);
+        swap_u16(&mut self.reserved003e);
+        swap_uuid(&mut self.fsid);
+        swap_uuid(&mut self.fstype);
+        swap_u64(&mut self.allocator_size);
+        swap_u64(&mut self.allocator_free);
+        swap_u64(&mut self.allocator_beg);
+        swap_u64(&mut self.mirror_tid);
+        swap_u64(&mut self.reserved0080);
+        swap_u64(&mut self.reserved0088);
+        swap_u64(&mut self.freemap_tid);
+        swap_u64(&mut self.bulkfree_tid);
+        self.reserved00a0.iter_mut().for_each(swap_u64);
+        swap_u64(&mut self.total_size);
+        self.copyexists.iter_mut().for_each(swap_u32);
+        self.icrc_sects.iter_mut().for_each(swap_u32);
+        self.sroot_blockset.swap_bytes();
+        self.freemap_blockset.swap_bytes();
+        self.volu_loff.iter_mut().for_each(swap_u64);
+        swap_u32(&mut self.icrc_volheader);
+    }
+}
+
+// Rewrite a host byte order blockref subtree in reverse byte order.
+// bref is in host byte order, and gets updated with the new check code.
+#[cfg(test)]
+fn swap_tree(
+    fso: &mut crate::ondisk::Ondisk,
+    bref: &mut crate::fs::Hammer2Blockref,
+) -> crate::Result<()> {
+    if bref.typ == crate::fs::HAMMER2_BREF_TYPE_EMPTY || bref.get_radix()? == 0 {
+        return Ok(());
+    }
+    let mut media = fso.read_media(bref)?;
+    let (offset, count) = match bref.typ {
+        crate::fs::HAMMER2_BREF_TYPE_INODE
+        | crate::fs::HAMMER2_BREF_TYPE_INDIRECT
+        | crate::fs::HAMMER2_BREF_TYPE_FREEMAP_NODE => {
+            crate::ondisk::media_as_blockref_range(bref, &media)?
+        }
+        _ => (0, 0),
+    };
+    crate::ondisk::swap_media(bref, &mut media);
+    let n = std::mem::size_of::<crate::fs::Hammer2Blockref>();
+    for i in 0..count {
+        let beg = offset + i * n;
+        let x = libfs::cast::align_to_mut::<crate::fs::Hammer2Blockref>(&mut media[beg..beg + n]);
+        x.swap_bytes();
+        swap_tree(fso, x)?;
+        x.swap_bytes();
+    }
+    fso.write_media(bref, &media)?;
+    crate::ondisk::set_media_check(bref, &media)
+}
+
+// Rewrite a host byte order image in reverse byte order for tests.
+#[cfg(test)]
+pub(crate) fn swap_image(f: &str) -> crate::Result<()> {
+    let mut fso = crate::ondisk::init_quiet(f, false)?;
+    let (index, mut voldata) = crate::volume::read_volume_data_with_index(f)?;
+    for bref in &mut voldata.sroot_blockset.blockref {
+        swap_tree(&mut fso, bref)?;
+    }
+    for bref in &mut voldata.freemap_blockset.blockref {
+        swap_tree(&mut fso, bref)?;
+    }
+    voldata.swap_bytes();
+    voldata.magic = crate::fs::HAMMER2_VOLUME_ID_ABO;
+    voldata.icrc_sects[crate::fs::HAMMER2_VOL_ICRC_SECT1] = voldata
+        .get_crc(
+            crate::fs::HAMMER2_VOLUME_ICRC1_OFF,
+            crate::fs::HAMMER2_VOLUME_ICRC1_SIZE,
+        )?
+        .swap_bytes();
+    voldata.icrc_sects[crate::fs::HAMMER2_VOL_ICRC_SECT0] = voldata
+        .get_crc(
+            crate::fs::HAMMER2_VOLUME_ICRC0_OFF,
+            crate::fs::HAMMER2_VOLUME_ICRC0_SIZE,
+        )?
+        .swap_bytes();
+    voldata.icrc_volheader = voldata
+        .get_crc(
+            crate::fs::HAMMER2_VOLUME_ICRCVH_OFF,
+            crate::fs::HAMMER2_VOLUME_ICRCVH_SIZE,
+        )?
+        .swap_bytes();
+    let vol = fso.get_root_volume_mut().ok_or(nix::errno::Errno::ENODEV)?;
+    vol.pwrite(
+        libfs::cast::as_u8_slice(&voldata),
+        crate::volume::get_volume_data_offset(index)?,
+    )?;
+    vol.fsync()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    #[test]
+    fn test_swap_bytes() {
+        let mut bref = crate::fs::Hammer2Blockref::new(crate::fs::HAMMER2_BREF_TYPE_INODE);
+        bref.key = 0x0102_0304_0506_0708;
+        bref.data_off = 0x10_000a;
+        bref.methods = crate::fs::enc_check(crate::fs::HAMMER2_CHECK_XXHASH64);
+        bref.check_as_mut::<crate::fs::Hammer2BlockrefCheckXxhash64>()
+            .value = 0x1122;
+        let orig = bref;
+        bref.swap_bytes();
+        assert_eq!(bref.key, 0x0807_0605_0403_0201);
+        assert_eq!(bref.data_off, 0x0a00_1000_0000_0000);
+        assert_eq!(
+            bref.check_as::<crate::fs::Hammer2BlockrefCheckXxhash64>()
+                .value,
+            0x2211_0000_0000_0000
+        );
+        bref.swap_bytes();
+        assert_eq!(bref, orig);
+
+        // embedded dirent name is a byte array
+        let mut bref = crate::fs::Hammer2Blockref::new(crate::fs::HAMMER2_BREF_TYPE_DIRENT);
+        let dirent = bref.embed_as_mut::<crate::fs::Hammer2DirentHead>();
+        dirent.inum = 0x1234;
+        dirent.namlen = 3;
+        bref.methods = crate::fs::enc_check(crate::fs::HAMMER2_CHECK_ISCSI32);
+        bref.check[..3].copy_from_slice(b"abc");
+        bref.swap_bytes();
+        let dirent = bref.embed_as::<crate::fs::Hammer2DirentHead>();
+        assert_eq!(dirent.inum, 0x3412_0000_0000_0000);
+        assert_eq!(dirent.namlen, 0x0300);
+        assert_eq!(&bref.check[..4], b"abc\0");
+
+        let mut meta = crate::fs::Hammer2InodeMeta::new();
+        meta.mode = 0o644;
+        meta.uid = crate::subs::conv_unix_xid_to_uuid_bytes(1000);
+        meta.pfs_clid = *uuid::Uuid::new_v4().as_bytes();
+        let orig = meta;
+        meta.swap_bytes();
+        assert_eq!(meta.mode, 0o644u32.swap_bytes());
+        assert_eq!(
+            crate::subs::conv_uuid_to_unix_xid_from_bytes(&meta.uid),
+            1000u32.swap_bytes()
+        );
+        meta.swap_bytes();
+        assert_eq!(meta.mode, orig.mode);
+        assert_eq!(meta.uid, orig.uid);
+        assert_eq!(meta.pfs_clid, orig.pfs_clid);
+    }
+
+    fn collect(
+        pmp: &crate::hammer2::Hammer2,
+    ) -> Vec<(String, u64, crate::hammer2::StatMode, u32, u64, Vec<u8>)> {
+        let w = match pmp.walk("/") {
+            Ok(v) => v,
+            Err(e) => panic!("{e}"),
+        };
+        let mut l = vec![];
+        for x in w {
+            let (path, dirent, st) = match x {
+                Ok(v) => v,
+                Err(e) => panic!("{e}"),
+            };
+            let data = if dirent.typ == crate::fs::HAMMER2_OBJTYPE_REGFILE {
+                let mut b = vec![];
+                match pmp.open(&path) {
+                    Ok(mut v) => {
+                        if let Err(e) = v.read_to_end(&mut b) {
+                            panic!("{e}");
+                        }
+                    }
+                    Err(e) => panic!("{e}"),
+                }
+                b
+            } else {
+                vec![]
+            };
+            l.push((path, st.st_ino, st.st_mode, st.st_uid, st.st_mtime, data));
+        }
+        l
+    }
+
+    #[test]
+    fn test_abo() {
+        let f = crate::newfs::create_newfs_image("abo", 128 << 20);
+        let mut pmp = match crate::hammer2::Hammer2::mount(&f, &["--rw"]) {
+            Ok(v) => v,
+            Err(e) => panic!("{e}"),
+        };
+        let dinum = match pmp.mkdir(crate::inode::INUM_PFS_ROOT, "d", 0o755) {
+            Ok(v) => v,
+            Err(e) => panic!("{e}"),
+        };
+        // compressible, incompressible and sparse
+        let text = b"HAMMER2".repeat(40000);
+        let rand: Vec<u8> = (0..200_000u64)
+            .map(|x| (x.wrapping_mul(0x9e37_79b9_7f4a_7c15) >> 56) as u8)
+            .collect();
+        for (name, data, offset) in [
+            ("text", &text, 0),
+            ("rand", &rand, 0),
+            ("sparse", &text, 1 << 20),
+        ] {
+            let inum = match pmp.create(dinum, name, 0o640) {
+                Ok(v) => v,
+                Err(e) => panic!("{e}"),
+            };
+            if let Err(e) = pmp.pwrite(inum, data, offset) {
+                panic!("{e}");
+            }
+        }
+        // long names are not embedded in the blockref
+        if let Err(e) = pmp.create(dinum, &"x".repeat(100), 0o600) {
+            panic!("{e}");
+        }
+        if let Err(e) = pmp.unmount() {
+            panic!("{e}");
+        }
+
+        let mut pmp = match crate::hammer2::Hammer2::mount(&f, &[]) {
+            Ok(v) => v,
+            Err(e) => panic!("{e}"),
+        };
+        let v1 = collect(&pmp);
+        if let Err(e) = pmp.unmount() {
+            panic!("{e}");
+        }
+        assert_eq!(v1.len(), 5);
+
+        if let Err(e) = super::swap_image(&f) {
+            panic!("{e}");
+        }
+        match crate::volume::read_volume_data(&f) {
+            Ok(v) => assert!(!matches!(v.is_hbo(), Ok(true))),
+            Err(e) => panic!("{e}"),
+        }
+        match crate::fsck::fsck(&f) {
+            Ok(v) => assert!(v.is_clean(), "{v:?}"),
+            Err(e) => panic!("{e}"),
+        }
+        match crate::hammer2::Hammer2::mount(&f, &["--rw"]) {
+            Err(crate::Error::Errno(nix::errno::Errno::EROFS)) => (),
+            Ok(v) => panic!("{v:?}"),
+            Err(e) => panic!("{e}"),
+        }
+        let mut pmp = match crate::hammer2::Hammer2::mount(&f, &[]) {
+            Ok(v) => v,
+            Err(e) => panic!("{e}"),
+        };
+        assert_eq!(collect(&pmp), v1);
+        match pmp.list_pfs() {
+            Ok(v) => assert_eq!(v.len(), 2),
+            Err(e) => panic!("{e}"),
+        }
+        if let Err(e) = pmp.unmount() {
+            panic!("{e}");
+        }
+        let _ = std::fs::remove_file(f);
+    }
+}
@@ -0,0 +1,520 @@
+use crate::ErrorExt;
+use crate::OptionExt;
+
+const BLOCK_SIZE: usize = 512;
+
+const TYPE_REG: u8 = b'0';
+const TYPE_LNK: u8 = b'1';
+const TYPE_SYM: u8 = b'2';
+const TYPE_CHR: u8 = b'3';
+const TYPE_BLK: u8 = b'4';
+const TYPE_DIR: u8 = b'5';
+const TYPE_FIFO: u8 = b'6';
+const TYPE_PAX: u8 = b'x';
+
+// ustar header field offset and length
+const NAME: (usize, usize) = (0, 100);
+const MODE: (usize, usize) = (100, 8);
+const UID: (usize, usize) = (108, 8);
+const GID: (usize, usize) = (116, 8);
+const SIZE: (usize, usize) = (124, 12);
+const MTIME: (usize, usize) = (136, 12);
+const CHKSUM: (usize, usize) = (148, 8);
+const TYPEFLAG: usize = 156;
+const LINKNAME: (usize, usize) = (157, 100);
+const MAGIC: (usize, usize) = (257, 8);
+const DEVMAJOR: (usize, usize) = (329, 8);
+const DEVMINOR: (usize, usize) = (337, 8);
+
+struct Header([u8; BLOCK_SIZE]);
+
+impl Header {
+    fn new(typ: u8) -> Self {
+        let mut b = [0; BLOCK_SIZE];
+        b[TYPEFLAG] = typ;
+        b[MAGIC.0..MAGIC.0 + MAGIC.1].copy_from_slice(b"ustar\x0000");
+        Self(b)
+    }
+
+    // Return false if the string doesn't fit, which then goes to pax.
+    fn set_str(&mut self, field: (usize, usize), s: &str) -> bool {
+        if s.len() > field.1 || !s.is_ascii() {
+            return false;
+        }
+        self.0[field.0..field.0 + s.len()].copy_from_slice(s.as_bytes());
+        true
+    }
+
+    // Return false if the number doesn't fit, which then goes to pax.
+    fn set_octal(&mut self, field: (usize, usize), v: u64) -> bool {
+        let s = format!("{v:0w$o}", w = field.1 - 1);
+        if s.len() >= field.1 {
+            return false;
+        }
+        self.0[field.0..field.0 + s.len()].copy_from_slice(s.as_bytes());
+        true
+    }
+
+    fn set_chksum(&mut self) {
+        self.0[CHKSUM.0..CHKSUM.0 + CHKSUM.1].fill(b' ');
+        let sum: u64 = self.0.iter().map(|&x| u64::from(x)).sum();
+        let s = format!("{sum:06o}\0 ");
+        self.0[CHKSUM.0..CHKSUM.0 + CHKSUM.1].copy_from_slice(s.as_bytes());
+    }
+}
+
+// "<len> <key>=<value>\n" where len includes itself.
+fn get_pax_record(key: &str, value: &str) -> String {
+    let n = key.len() + value.len() + 3;
+    let mut len = n + 1;
+    while len != n + len.to_string().len() {
+        len = n + len.to_string().len();
+    }
+    format!("{len} {key}={value}\n")
+}
+
+fn write_padding<W: std::io::Write>(w: &mut W, size: u64) -> crate::Result<()> {
+    let n = (BLOCK_SIZE - (size % BLOCK_SIZE as u64) as usize) % BLOCK_SIZE;
+    w.write_all(&[0; BLOCK_SIZE][..n])?;
+    Ok(())
+}
+
+struct Entry<'a> {
+    path: &'a str,
+    typ: u8,
+    meta: &'a crate::fs::Hammer2InodeMeta,
+    size: u64,
+    linkname: &'a str,
+}
+
+fn write_header<W: std::io::Write>(w: &mut W, e: &Entry) -> crate::Result<()> {
+    let meta = e.meta;
+    let mut pax = String::new();
+    let mut h = Header::new(e.typ);
+    if !h.set_str(NAME, e.path) {
+        pax.push_str(&get_pax_record("path", e.path));
+    }
+    if !h.set_str(LINKNAME, e.linkname) {
+        pax.push_str(&get_pax_record("linkpath", e.linkname));
+    }
+    h.set_octal(MODE, (meta.mode & 0o7777).into());
+    let uid = crate::subs::conv_uuid_to_unix_xid_from_bytes(&meta.uid);
+    if !h.set_octal(UID, uid.into()) {
+        pax.push_str(&get_pax_record("uid", &uid.to_string()));
+    }
+    let gid = crate::subs::conv_uuid_to_unix_xid_from_bytes(&meta.gid);
+    if !h.set_octal(GID, gid.into()) {
+        pax.push_str(&get_pax_record("gid", &gid.to_string()));
+    }
+    if !h.set_octal(SIZE, e.size) {
+        pax.push_str(&get_pax_record("size", &e.size.to_string()));
+    }
+    // Sub-second mtime is only available via pax.
+    let mtime = crate::subs::conv_time_to_timespec(meta.mtime);
+    h.set_octal(MTIME, mtime);
+    if !meta.mtime.is_multiple_of(1_000_000) {
+        let s = format!("{mtime}.{:06}", meta.mtime % 1_000_000);
+        pax.push_str(&get_pax_record("mtime", &s));
+    }
+    if e.typ == TYPE_CHR || e.typ == TYPE_BLK {
+        h.set_octal(DEVMAJOR, meta.rmajor.into());
+        h.set_octal(DEVMINOR, meta.rminor.into());
+    }
+
+    if !pax.is_empty() {
+        let mut x = Header::new(TYPE_PAX);
+        let name = format!(
+            "PaxHeaders/{}",
+            e.path.rsplit('/').next().unwrap_or_default()
+        );
+        let name: String = name.chars().filter(char::is_ascii).take(NAME.1).collect();
+        x.set_str(NAME, &name);
+        x.set_octal(MODE, 0o644);
+        x.set_octal(MTIME, mtime);
+        let size = pax.len().try_into().or_range()?;
+        x.set_octal(SIZE, size);
+        x.set_chksum();
+        w.write_all(&x.0)?;
+        w.write_all(pax.as_bytes())?;
+        write_padding(w, size)?;
+    }
+    h.set_chksum();
+    w.write_all(&h.0)?;
+    Ok(())
+}
+
+impl crate::hammer2::Hammer2 {
+    fn export_tar_entry<W: std::io::Write>(
+        &self,
+        w: &mut W,
+        inum: u64,
+        path: &str,
+        links: &mut std::collections::HashMap<u64, String>,
+    ) -> crate::Result<()> {
+        let meta = self.read().get_inode(inum).or_range()?.meta;
+        let mut e = Entry {
+            path,
+            typ: TYPE_REG,
+            meta: &meta,
+            size: 0,
+            linkname: "",
+        };
+        if meta.typ != crate::fs::HAMMER2_OBJTYPE_DIRECTORY && meta.nlinks > 1 {
+            if let Some(x) = links.get(&inum) {
+                let x = x.clone();
+                e.typ = TYPE_LNK;
+                e.linkname = &x;
+                return write_header(w, &e);
+            }
+            links.insert(inum, path.to_string());
+        }
+        let target;
+        let dir;
+        match meta.typ {
+            crate::fs::HAMMER2_OBJTYPE_DIRECTORY => {
+                dir = format!("{path}/");
+                e.path = &dir;
+                e.typ = TYPE_DIR;
+            }
+            crate::fs::HAMMER2_OBJTYPE_REGFILE => e.size = meta.size,
+            crate::fs::HAMMER2_OBJTYPE_SOFTLINK => {
+                target = self.readlinkx(inum)?;
+                e.typ = TYPE_SYM;
+                e.linkname = &target;
+            }
+            crate::fs::HAMMER2_OBJTYPE_CDEV => e.typ = TYPE_CHR,
+            crate::fs::HAMMER2_OBJTYPE_BDEV => e.typ = TYPE_BLK,
+            crate::fs::HAMMER2_OBJTYPE_FIFO => e.typ = TYPE_FIFO,
+            v => {
+                log::warn!("{path}: skip inode type {v}");
+                return Ok(());
+            }
+        }
+        write_header(w, &e)?;
+        if e.typ != TYPE_REG {
+            return Ok(());
+        }
+
+        let mut buf = vec![0; crate::fs::HAMMER2_PBUFSIZE.try_into().or_range()?];
+        let mut offset = 0;
+        while offset < meta.size {
+            let n = self.pread(inum, &mut buf, offset)?;
+            if n == 0 {
+                // The header already has the size.
+                log::error!("{path}: short read at {offset}");
+                return Err(nix::errno::Errno::EIO.into());
+            }
+            w.write_all(&buf[..n.try_into().or_range()?])?;
+            offset += n;
+        }
+        write_padding(w, meta.size)
+    }
+
+    /// Write a file or a directory tree at `src_path` as a pax archive.
+    /// Entries are named relative to `src_path`, or its last component
+    /// if it's not a directory.
+    /// # Errors
+    pub fn export_tar<W: std::io::Write>(&self, src_path: &str, mut w: W) -> crate::Result<()> {
+        let inum = self.nresolve_path(src_path)?;
+        let mut links = std::collections::HashMap::new();
+        if self.read().get_inode(inum).or_range()?.is_directory() {
+            for x in self.walk(src_path)? {
+                let (path, dirent, _) = x?;
+                crate::hammer2::check_name(&dirent.name)?;
+                let path = path[src_path.len()..].trim_start_matches('/');
+                self.export_tar_entry(&mut w, dirent.inum, path, &mut links)?;
+            }
+        } else {
+            let Some(name) = libfs::fs::split_path(src_path).pop() else {
+                return Err(nix::errno::Errno::EINVAL.into());
+            };
+            self.export_tar_entry(&mut w, inum, &name, &mut links)?;
+        }
+        w.write_all(&[0; BLOCK_SIZE * 2])?;
+        w.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ErrorExt;
+    use crate::OptionExt;
+
+    #[derive(Debug, Default)]
+    struct TarEntry {
+        path: String,
+        typ: u8,
+        mode: u64,
+        uid: u64,
+        size: u64,
+        mtime: String,
+        linkname: String,
+        devmajor: u64,
+        data: Vec<u8>,
+    }
+
+    fn get_str(b: &[u8]) -> String {
+        let n = b.iter().position(|&x| x == 0).unwrap_or(b.len());
+        String::from_utf8_lossy(&b[..n]).to_string()
+    }
+
+    fn get_octal(b: &[u8]) -> u64 {
+        let s = get_str(b);
+        if s.is_empty() {
+            return 0;
+        }
+        match u64::from_str_radix(s.trim(), 8) {
+            Ok(v) => v,
+            Err(e) => panic!("{e}"),
+        }
+    }
+
+    fn parse(b: &[u8]) -> crate::Result<Vec<TarEntry>> {
+        let mut v = vec![];
+        let mut pax = std::collections::HashMap::new();
+        let mut i = 0;
+        loop {
+            let h = &b[i..i + super::BLOCK_SIZE];
+            i += super::BLOCK_SIZE;
+            if h.iter().all(|&x| x == 0) {
+                assert!(b[i..].iter().all(|&x| x == 0));
+                assert_eq!(b.len() - i, super::BLOCK_SIZE);
+                return Ok(v);
+            }
+            let f = |x: (usize, usize)| &h[x.0..x.0 + x.1];
+            assert_eq!(f(super::MAGIC), b"ustar\x0000");
+            let sum: u64 = h
+                .iter()
+                .enumerate()
+                .map(|(i, &x)| {
+                    if (super::CHKSUM.0..super::CHKSUM.0 + super::CHKSUM.1).contains(&i) {
+                        u64::from(b' ')
+                    } else {
+                        u64::from(x)
+                    }
+                })
+                .sum();
+            assert_eq!(get_octal(f(super::CHKSUM)), sum);
+            let size = get_octal(f(super::SIZE));
+            let n = usize::try_from(size).or_range()?;
+            let data = b[i..i + n].to_vec();
+            i += n.div_ceil(super::BLOCK_SIZE) * super::BLOCK_SIZE;
+            if h[super::TYPEFLAG] == super::TYPE_PAX {
+                let mut s = String::from_utf8_lossy(&data).to_string();
+                while !s.is_empty() {
+                    let (len, _) = s.split_once(' ').or_range()?;
+                    let len = len.parse::<usize>().or_range()?;
+                    let (_, kv) = s[..len - 1].split_once(' ').or_range()?;
+                    let (key, value) = kv.split_once('=').or_range()?;
+                    pax.insert(key.to_string(), value.to_string());
+                    s = s[len..].to_string();
+                }
+                continue;
+            }
+            let mut e = TarEntry {
+                path: get_str(f(super::NAME)),
+                typ: h[super::TYPEFLAG],
+                mode: get_octal(f(super::MODE)),
+                uid: get_octal(f(super::UID)),
+                size,
+                mtime: get_octal(f(super::MTIME)).to_string(),
+                linkname: get_str(f(super::LINKNAME)),
+                devmajor: get_octal(f(super::DEVMAJOR)),
+                data,
+            };
+            for (key, value) in pax.drain() {
+                match key.as_str() {
+                    "path" => e.path = value,
+                    "linkpath" => e.linkname = value,
+                    "mtime" => e.mtime = value,
+                    _ => panic!("{key}"),
+                }
+            }
+            v.push(e);
+        }
+    }
+
+    #[test]
+    fn test_get_pax_record() {
+        assert_eq!(super::get_pax_record("path", "a"), "9 path=a\n");
+        assert_eq!(super::get_pax_record("path", "abcd"), "13 path=abcd\n");
+        // the length crosses a digit boundary
+        let s = super::get_pax_record("path", &"x".repeat(90));
+        assert_eq!(s.len(), 99);
+        assert!(s.starts_with("99 "));
+        let s = super::get_pax_record("path", &"x".repeat(91));
+        assert_eq!(s.len(), 101);
+        assert!(s.starts_with("101 "));
+    }
+
+    #[test]
+    fn test_export_tar() {
+        let f = crate::newfs::create_newfs_image("tar", 128 << 20);
+        let data: Vec<u8> = (0..100_000u32)
+            .map(|i| (i.wrapping_mul(2_654_435_761) >> 13) as u8)
+            .collect();
+        let long = "d".repeat(120);
+        let mut pmp = match crate::hammer2::Hammer2::mount(&f, &["--rw"]) {
+            Ok(v) => v,
+            Err(e) => panic!("{e}"),
+        };
+        let a = match pmp.mkdir(crate::inode::INUM_PFS_ROOT, "a", 0o750) {
+            Ok(v) => v,
+            Err(e) => panic!("{e}"),
+        };
+        let create = |pmp: &mut crate::hammer2::Hammer2, name, typ, b: &[u8]| {
+            let pmp = pmp.get_mut();
+            let inum = match pmp.create_inode(a, name, crate::fs::HAMMER2_OBJTYPE_REGFILE, 0o640) {
+                Ok(v) => v,
+                Err(e) => panic!("{e}"),
+            };
+            if let Err(e) = pmp.pwrite(inum, b, 0) {
+                panic!("{e}");
+            }
+            if let Err(e) = pmp.modify_inode_meta(inum, |meta| {
+                meta.typ = typ;
+                meta.mtime = 1_234_567_890_000_000;
+                if typ != crate::fs::HAMMER2_OBJTYPE_REGFILE {
+                    meta.size = 0;
+                }
+                if typ == crate::fs::HAMMER2_OBJTYPE_CDEV {
+                    meta.rmajor = 1;
+                    meta.rminor = 3;
+                }
+            }) {
+                panic!("{e}");
+            }
+            inum
+        };
+        create(&mut pmp, "file", crate::fs::HAMMER2_OBJTYPE_REGFILE, &data);
+        let inum = create(&mut pmp, "f", crate::fs::HAMMER2_OBJTYPE_REGFILE, b"f");
+        if let Err(e) =
+            pmp.get_mut()
+                .create_dirent(a, "g", inum, crate::fs::HAMMER2_OBJTYPE_REGFILE)
+        {
+            panic!("{e}");
+        }
+        if let Err(e) = pmp
+            .get_mut()
+            .modify_inode_meta(inum, |meta| meta.nlinks = 2)
+        {
+            panic!("{e}");
+        }
+        let inum = create(&mut pmp, "l", crate::fs::HAMMER2_OBJTYPE_REGFILE, b"file");
+        if let Err(e) = pmp.get_mut().modify_inode_meta(inum, |meta| {
+            meta.typ = crate::fs::HAMMER2_OBJTYPE_SOFTLINK;
+        }) {
+            panic!("{e}");
+        }
+        create(&mut pmp, "null", crate::fs::HAMMER2_OBJTYPE_CDEV, b"");
+        create(&mut pmp, &long, crate::fs::HAMMER2_OBJTYPE_REGFILE, b"long");
+        if let Err(e) = pmp
+            .get_mut()
+            .modify_inode_meta(a, |meta| meta.mtime = 1_000_000_500_000)
+        {
+            panic!("{e}");
+        }
+        if let Err(e) = pmp.unmount() {
+            panic!("{e}");
+        }
+
+        let mut pmp = match crate::hammer2::Hammer2::mount(&f, &[]) {
+            Ok(v) => v,
+            Err(e) => panic!("{e}"),
+        };
+        let mut b = vec![];
+        if let Err(e) = pmp.export_tar("/", &mut b) {
+            panic!("{e}");
+        }
+        assert_eq!(b.len() % super::BLOCK_SIZE, 0);
+        let v = match parse(&b) {
+            Ok(v) => v,
+            Err(e) => panic!("{e}"),
+        };
+        let get = |path: &str| match v.iter().find(|x| x.path == path) {
+            Some(v) => v,
+            None => panic!("{path} {v:?}"),
+        };
+        assert_eq!(v.len(), 7);
+        let e = get("a/");
+        assert_eq!((e.typ, e.mode, e.size), (super::TYPE_DIR, 0o750, 0));
+        assert_eq!(e.mtime, "1000000.500000");
+        assert_eq!(e.uid, u64::from(unsafe { libc::getuid() }));
+        let e = get("a/file");
+        assert_eq!((e.typ, e.mode), (super::TYPE_REG, 0o640));
+        assert_eq!(e.data, data);
+        assert_eq!(e.mtime, "1234567890");
+        // hardlinks, the first one found has the data
+        let (x, y) = (get("a/f"), get("a/g"));
+        let (x, y) = if x.typ == super::TYPE_LNK {
+            (y, x)
+        } else {
+            (x, y)
+        };
+        assert_eq!(
+            (x.typ, x.data.as_slice()),
+            (super::TYPE_REG, b"f".as_slice())
+        );
+        assert_eq!((y.typ, y.size), (super::TYPE_LNK, 0));
+        assert_eq!(y.linkname, x.path);
+        let e = get("a/l");
+        assert_eq!((e.typ, e.linkname.as_str()), (super::TYPE_SYM, "file"));
+        let e = get("a/null");
+        assert_eq!((e.typ, e.devmajor), (super::TYPE_CHR, 1));
+        let e = get(&format!("a/{long}"));
+        assert_eq!(e.data, b"long");
+
+        // single file
+        let mut b = vec![];
+        if let Err(e) = pmp.export_tar("a/file", &mut b) {
+            panic!("{e}");
+        }
+        match parse(&b) {
+            Ok(v) => {
+                assert_eq!(v.len(), 1);
+                assert_eq!(v[0].path, "file");
+                assert_eq!(v[0].data, data);
+            }
+            Err(e) => panic!("{e}"),
+        }
+        if let Err(e) = pmp.unmount() {
+            panic!("{e}");
+        }
+        let _ = std::fs::remove_file(f);
+    }
+
+    #[test]
+    fn test_export_tar_bad_name() {
+        let f = crate::newfs::create_newfs_image("tar_bad_name", 128 << 20);
+        let names = ["", ".", "..", "../x", "/x"];
+        let mut pmp = match crate::hammer2::Hammer2::mount(&f, &["--rw"]) {
+            Ok(v) => v,
+            Err(e) => panic!("{e}"),
+        };
+        for (i, name) in names.iter().enumerate() {
+            let b = match pmp.mkdir(crate::inode::INUM_PFS_ROOT, &format!("b{i}"), 0o755) {
+                Ok(v) => v,
+                Err(e) => panic!("{e}"),
+            };
+            let pmp = pmp.get_mut();
+            let inum = match pmp.create_inode(b, "x", crate::fs::HAMMER2_OBJTYPE_REGFILE, 0o644) {
+                Ok(v) => v,
+                Err(e) => panic!("{e}"),
+            };
+            if let Err(e) = pmp.create_dirent(b, name, inum, crate::fs::HAMMER2_OBJTYPE_REGFILE) {
+                panic!("{e}");
+            }
+        }
+        for (i, name) in names.iter().enumerate() {
+            match pmp.export_tar(&format!("b{i}"), std::io::sink()) {
+                Err(crate::Error::Errno(nix::errno::Errno::EINVAL)) => (),
+                v => panic!("{name:?} {v:?}"),
+            }
+        }
+        if let Err(e) = pmp.unmount() {
+            panic!("{e}");
+        }
+        let _ = std::fs::remove_file(f);
+    }
+}
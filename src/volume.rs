@@ -1,12 +1,10 @@
 use crate::ErrorExt;
-use std::io::Read;
-use std::io::Write;
 
 #[derive(Debug)]
 pub struct Volume {
     id: u8,
     path: String,
-    fp: std::fs::File,
+    dev: Box<dyn crate::device::BlockDevice>,
     offset: u64,
     size: u64,
 }
@@ -22,13 +20,31 @@ impl Drop for Volume {
 impl Volume {
     /// # Errors
     pub fn new(id: u8, path: &str, readonly: bool, offset: u64, size: u64) -> crate::Result<Self> {
-        Ok(Self {
+        Ok(Self::new_with_device(
+            id,
+            path,
+            crate::partition::open(path, readonly, false)?,
+            offset,
+            size,
+        ))
+    }
+
+    // path is only used as a name of the device.
+    #[must_use]
+    pub fn new_with_device(
+        id: u8,
+        path: &str,
+        dev: Box<dyn crate::device::BlockDevice>,
+        offset: u64,
+        size: u64,
+    ) -> Self {
+        Self {
             id,
             path: path.to_string(),
-            fp: libfs::fs::open(path, readonly)?,
+            dev,
             offset,
             size,
-        })
+        }
     }
 
     #[must_use]
@@ -41,6 +57,12 @@ impl Volume {
         &self.path
     }
 
+    // offset of the volume within the underlying file
+    #[must_use]
+    pub fn get_base(&self) -> u64 {
+        self.dev.get_base()
+    }
+
     #[must_use]
     pub fn get_offset(&self) -> u64 {
         self.offset
@@ -52,36 +74,55 @@ impl Volume {
     }
 
     /// # Errors
-    pub fn fsync(&mut self) -> std::io::Result<()> {
-        self.fp.flush()
+    pub fn get_device_size(&mut self) -> crate::Result<u64> {
+        self.dev.get_size()
     }
 
     /// # Errors
-    pub fn pread(&mut self, buf: &mut [u8], offset: u64) -> crate::Result<()> {
+    pub fn get_device_metadata(&self) -> crate::Result<Option<std::fs::Metadata>> {
+        self.dev.get_metadata()
+    }
+
+    pub(crate) fn get_device_mut(&mut self) -> &mut dyn crate::device::BlockDevice {
+        self.dev.as_mut()
+    }
+
+    /// # Errors
+    pub fn fsync(&self) -> crate::Result<()> {
+        self.dev.flush()
+    }
+
+    /// # Errors
+    pub fn pread(&self, buf: &mut [u8], offset: u64) -> crate::Result<()> {
         if offset & (u64::try_from(crate::fs::HAMMER2_ALLOC_MIN).or_range()? - 1) != 0 {
             log::error!("invalid offset {offset:x}");
             return Err(nix::errno::Errno::EINVAL.into());
         }
-        libfs::fs::seek_set(&mut self.fp, offset)?;
-        Ok(self.fp.read_exact(buf)?)
+        self.dev.pread(buf, offset)
     }
 
     /// # Errors
-    pub fn pwrite(&mut self, buf: &[u8], offset: u64) -> crate::Result<()> {
+    pub fn pwrite(&self, buf: &[u8], offset: u64) -> crate::Result<()> {
         if offset & (u64::try_from(crate::fs::HAMMER2_ALLOC_MIN).or_range()? - 1) != 0 {
             log::error!("invalid offset {offset:x}");
             return Err(nix::errno::Errno::EINVAL.into());
         }
-        libfs::fs::seek_set(&mut self.fp, offset)?;
-        Ok(self.fp.write_all(buf)?)
+        self.dev.pwrite(buf, offset)
     }
 
     /// # Errors
-    pub fn preadx(&mut self, size: u64, offset: u64) -> crate::Result<Vec<u8>> {
+    pub fn preadx(&self, size: u64, offset: u64) -> crate::Result<Vec<u8>> {
         let mut buf = vec![0; size.try_into().or_range()?];
         self.pread(&mut buf, offset)?;
         Ok(buf)
     }
+
+    // Same as read_volume_headers, but reads from this volume.
+    pub(crate) fn read_volume_headers(
+        &self,
+    ) -> crate::Result<Vec<(usize, crate::fs::Hammer2VolumeData)>> {
+        read_volume_headers_impl(self.dev.as_ref(), self.size, &self.path)
+    }
 }
 
 // get volume data offset relative to a volume
@@ -92,11 +133,63 @@ pub fn get_volume_data_offset(index: usize) -> nix::Result<u64> {
 
 // Locate a valid volume header.  If any of the four volume headers is good,
 // we have a valid volume header and choose the best one based on mirror_tid.
+#[cfg(test)]
 pub(crate) fn read_volume_data(path: &str) -> crate::Result<crate::fs::Hammer2VolumeData> {
-    let mut fp = libfs::fs::open_ro(path)?;
-    let size = crate::subs::get_volume_size(&mut fp)?;
-    let mut zone = usize::MAX;
-    let mut mirror_tid = u64::MAX;
+    Ok(read_volume_data_with_index(path)?.1)
+}
+
+pub(crate) fn read_volume_data_from(
+    dev: &mut dyn crate::device::BlockDevice,
+    name: &str,
+) -> crate::Result<crate::fs::Hammer2VolumeData> {
+    Ok(read_volume_data_with_index_from(dev, name)?.1)
+}
+
+// Same as above, but also returns index of the chosen volume header.
+#[cfg(test)]
+pub(crate) fn read_volume_data_with_index(
+    path: &str,
+) -> crate::Result<(usize, crate::fs::Hammer2VolumeData)> {
+    read_volume_data_with_index_from(crate::partition::open(path, true, false)?.as_mut(), path)
+}
+
+pub(crate) fn read_volume_data_with_index_from(
+    dev: &mut dyn crate::device::BlockDevice,
+    name: &str,
+) -> crate::Result<(usize, crate::fs::Hammer2VolumeData)> {
+    let mut best: Option<(usize, crate::fs::Hammer2VolumeData)> = None;
+    for (i, vd) in read_volume_headers_from(dev, name)? {
+        if best.as_ref().is_none_or(|x| x.1.mirror_tid < vd.mirror_tid) {
+            best = Some((i, vd));
+        }
+    }
+    best.ok_or_else(|| nix::errno::Errno::ENODEV.into())
+}
+
+// Returns all valid volume headers with their index.
+// Older ones are consistent states as of their mirror_tid.
+/// # Errors
+pub fn read_volume_headers(
+    path: &str,
+) -> crate::Result<Vec<(usize, crate::fs::Hammer2VolumeData)>> {
+    read_volume_headers_from(crate::partition::open(path, true, false)?.as_mut(), path)
+}
+
+// Same as above, but reads from a device.  name is used in log messages.
+/// # Errors
+pub fn read_volume_headers_from(
+    dev: &mut dyn crate::device::BlockDevice,
+    name: &str,
+) -> crate::Result<Vec<(usize, crate::fs::Hammer2VolumeData)>> {
+    let size = dev.get_size()?;
+    read_volume_headers_impl(dev, size, name)
+}
+
+fn read_volume_headers_impl(
+    dev: &dyn crate::device::BlockDevice,
+    size: u64,
+    name: &str,
+) -> crate::Result<Vec<(usize, crate::fs::Hammer2VolumeData)>> {
     let mut v = vec![];
 
     for i in 0..crate::fs::HAMMER2_NUM_VOLHDRS {
@@ -104,59 +197,68 @@ pub(crate) fn read_volume_data(path: &str) -> crate::Result<crate::fs::Hammer2Vo
         if offset >= size {
             break;
         }
-        libfs::fs::seek_set(&mut fp, offset)?;
         let mut buf = vec![0; crate::fs::HAMMER2_VOLUME_BYTES.try_into().or_range()?];
-        fp.read_exact(&mut buf)?;
-        let vd = crate::ondisk::media_as_volume_data(&buf);
+        dev.pread(&mut buf, offset)?;
+        let mut bref = crate::fs::Hammer2Blockref::new(crate::fs::HAMMER2_BREF_TYPE_VOLUME);
+        bref.data_off = offset;
+        let vd = crate::ondisk::media_as_volume_data(&bref, &buf)?;
         // verify volume header magic
         if vd.magic != crate::fs::HAMMER2_VOLUME_ID_HBO
             && vd.magic != crate::fs::HAMMER2_VOLUME_ID_ABO
         {
-            log::error!("{path} #{i}: bad magic {:#018x}", vd.magic);
-            continue;
-        }
-        if vd.magic == crate::fs::HAMMER2_VOLUME_ID_ABO {
-            // XXX: Reversed-endianness filesystem
-            log::error!("{path} #{i}: reverse-endian filesystem detected");
+            log::error!("{name} #{i}: bad magic {:#018x}", vd.magic);
             continue;
         }
+        // CRC's are computed on media bytes, but stored in media byte order.
+        let abo = vd.magic == crate::fs::HAMMER2_VOLUME_ID_ABO;
+        let conv = |x: u32| if abo { x.swap_bytes() } else { x };
         // verify volume header CRC's
-        let a = vd.icrc_sects[crate::fs::HAMMER2_VOL_ICRC_SECT0];
+        let a = conv(vd.icrc_sects[crate::fs::HAMMER2_VOL_ICRC_SECT0]);
         let b = vd.get_crc(
             crate::fs::HAMMER2_VOLUME_ICRC0_OFF,
             crate::fs::HAMMER2_VOLUME_ICRC0_SIZE,
         )?;
         if a != b {
-            log::error!("{path} #{i}: volume header crc mismatch sect0 {a:08x}/{b:08x}");
+            log::error!("{name} #{i}: volume header crc mismatch sect0 {a:08x}/{b:08x}");
             continue;
         }
-        let a = vd.icrc_sects[crate::fs::HAMMER2_VOL_ICRC_SECT1];
+        let a = conv(vd.icrc_sects[crate::fs::HAMMER2_VOL_ICRC_SECT1]);
         let b = vd.get_crc(
             crate::fs::HAMMER2_VOLUME_ICRC1_OFF,
             crate::fs::HAMMER2_VOLUME_ICRC1_SIZE,
         )?;
         if a != b {
-            log::error!("{path} #{i}: volume header crc mismatch sect1 {a:08x}/{b:08x}");
+            log::error!("{name} #{i}: volume header crc mismatch sect1 {a:08x}/{b:08x}");
             continue;
         }
-        let a = vd.icrc_volheader;
+        let a = conv(vd.icrc_volheader);
         let b = vd.get_crc(
             crate::fs::HAMMER2_VOLUME_ICRCVH_OFF,
             crate::fs::HAMMER2_VOLUME_ICRCVH_SIZE,
         )?;
         if a != b {
-            log::error!("{path} #{i}: volume header crc mismatch vh {a:08x}/{b:08x}");
+            log::error!("{name} #{i}: volume header crc mismatch vh {a:08x}/{b:08x}");
             continue;
         }
-        if zone == usize::MAX || mirror_tid < vd.mirror_tid {
-            zone = i;
-            mirror_tid = vd.mirror_tid;
-            v.push(*vd);
+        let mut vd = *vd;
+        if abo {
+            log::debug!("{name} #{i}: reverse-endian filesystem detected");
+            vd.swap_bytes();
         }
+        v.push((i, vd));
     }
-    if zone == usize::MAX {
-        Err(nix::errno::Errno::ENODEV.into())
-    } else {
-        Ok(v[zone])
+    Ok(v)
+}
+
+// Returns number of volume headers available for a volume of given size.
+#[must_use]
+pub fn get_volume_data_count(size: u64) -> usize {
+    let mut n = 0;
+    while n < crate::fs::HAMMER2_NUM_VOLHDRS {
+        match get_volume_data_offset(n) {
+            Ok(v) if v < size => n += 1,
+            _ => break,
+        }
     }
+    n
 }
@@ -0,0 +1,371 @@
+use crate::OptionExt;
+
+const MAXSYMLINKS: usize = 32;
+
+type Filter<'a> = Box<dyn FnMut(&str, &crate::hammer2::Dirent, &crate::hammer2::Stat) -> bool + 'a>;
+
+#[derive(Debug)]
+struct Frame {
+    dinum: u64,
+    path: String,
+    depth: usize,
+    lkey: Option<u64>,
+    ancestors: Vec<u64>, // cycle protection
+}
+
+/// A recursive walk under a directory, yielding `(path, Dirent, Stat)`
+/// for each entry except `.` and `..`.
+/// Directories are read lazily, see `ReadDir`.
+pub struct Walk<'a> {
+    pmp: &'a crate::hammer2::Hammer2,
+    frames: std::collections::VecDeque<Frame>,
+    breadth_first: bool,
+    max_depth: Option<usize>,
+    follow_links: bool,
+    filter: Option<Filter<'a>>,
+}
+
+impl std::fmt::Debug for Walk<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("Walk")
+            .field("frames", &self.frames)
+            .field("breadth_first", &self.breadth_first)
+            .field("max_depth", &self.max_depth)
+            .field("follow_links", &self.follow_links)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<'a> Walk<'a> {
+    /// Walk breadth-first instead of depth-first.
+    #[must_use]
+    pub fn breadth_first(mut self, breadth_first: bool) -> Self {
+        self.breadth_first = breadth_first;
+        self
+    }
+
+    /// Entries directly under the starting directory have depth 1.
+    #[must_use]
+    pub fn max_depth(mut self, max_depth: usize) -> Self {
+        self.max_depth = Some(max_depth);
+        self
+    }
+
+    /// Yield the target of a symlink, and descend if it's a directory.
+    /// Absolute targets are resolved from the PFS root.
+    #[must_use]
+    pub fn follow_links(mut self, follow_links: bool) -> Self {
+        self.follow_links = follow_links;
+        self
+    }
+
+    /// Entries for which the filter returns false are skipped,
+    /// including their descendants.
+    #[must_use]
+    pub fn filter<F>(mut self, filter: F) -> Self
+    where
+        F: FnMut(&str, &crate::hammer2::Dirent, &crate::hammer2::Stat) -> bool + 'a,
+    {
+        self.filter = Some(Box::new(filter));
+        self
+    }
+
+    fn get_typ(&self, inum: u64) -> crate::Result<u8> {
+        Ok(self.pmp.read().get_inode(inum).or_range()?.meta.typ)
+    }
+
+    // Resolve a symlink in directory dinum, or None if it's broken.
+    fn follow_link(&mut self, dinum: u64, inum: u64) -> crate::Result<Option<u64>> {
+        let (mut dinum, mut inum) = (dinum, inum);
+        for _ in 0..MAXSYMLINKS {
+            if self.get_typ(inum)? != crate::fs::HAMMER2_OBJTYPE_SOFTLINK {
+                return Ok(Some(inum));
+            }
+            let target = self.pmp.readlinkx(inum)?;
+            let mut x = if target.starts_with('/') {
+                crate::inode::INUM_PFS_ROOT
+            } else {
+                dinum
+            };
+            for cnp in &libfs::fs::split_path(&target) {
+                dinum = x;
+                x = match self.pmp.nresolve(x, cnp) {
+                    Ok(v) => v,
+                    Err(crate::Error::Errno(
+                        nix::errno::Errno::ENOENT | nix::errno::Errno::ENOTDIR,
+                    )) => return Ok(None),
+                    Err(e) => return Err(e),
+                };
+            }
+            inum = x;
+        }
+        Err(nix::errno::Errno::ELOOP.into())
+    }
+
+    fn next_impl(
+        &mut self,
+    ) -> crate::Result<Option<(String, crate::hammer2::Dirent, crate::hammer2::Stat)>> {
+        loop {
+            let frame = if self.breadth_first {
+                self.frames.front_mut()
+            } else {
+                self.frames.back_mut()
+            };
+            let Some(frame) = frame else {
+                return Ok(None);
+            };
+            let lkey = match frame.lkey {
+                Some(v) if self.max_depth.is_none_or(|x| frame.depth < x) => Some(v),
+                _ => None,
+            };
+            let Some(lkey) = lkey else {
+                if self.breadth_first {
+                    self.frames.pop_front();
+                } else {
+                    self.frames.pop_back();
+                }
+                continue;
+            };
+            let (dinum, depth) = (frame.dinum, frame.depth + 1);
+            let mut ancestors = frame.ancestors.clone();
+            let Some(dirent) = self.pmp.with_lock(|pmp| pmp.readdir_next(dinum, lkey))? else {
+                frame.lkey = None;
+                continue;
+            };
+            frame.lkey = dirent.key.checked_add(1);
+            let path = if frame.path.ends_with('/') {
+                format!("{}{}", frame.path, dirent.name)
+            } else {
+                format!("{}/{}", frame.path, dirent.name)
+            };
+
+            // Don't trust the dirent type, the inode is loaded for stat anyway.
+            let mut inum = self.pmp.nresolve(dinum, &dirent.name)?;
+            if self.follow_links
+                && self.get_typ(inum)? == crate::fs::HAMMER2_OBJTYPE_SOFTLINK
+                && let Some(v) = self.follow_link(dinum, inum)?
+            {
+                inum = v;
+            }
+            let dirent =
+                crate::hammer2::Dirent::new(inum, self.get_typ(inum)?, &dirent.name, dirent.key);
+            let stat = self.pmp.stat(inum)?;
+            if let Some(filter) = &mut self.filter
+                && !filter(&path, &dirent, &stat)
+            {
+                continue;
+            }
+            if dirent.typ == crate::fs::HAMMER2_OBJTYPE_DIRECTORY
+                && self.max_depth.is_none_or(|x| depth < x)
+            {
+                if ancestors.contains(&inum) {
+                    log::warn!("{path}: directory cycle, not descending");
+                } else {
+                    ancestors.push(inum);
+                    self.frames.push_back(Frame {
+                        dinum: inum,
+                        path: path.clone(),
+                        depth,
+                        lkey: Some(2),
+                        ancestors,
+                    });
+                }
+            }
+            return Ok(Some((path, dirent, stat)));
+        }
+    }
+}
+
+impl Iterator for Walk<'_> {
+    type Item = crate::Result<(String, crate::hammer2::Dirent, crate::hammer2::Stat)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.next_impl() {
+            Ok(v) => v.map(Ok),
+            Err(e) => {
+                self.frames.clear();
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+impl crate::hammer2::Hammer2 {
+    /// # Errors
+    pub fn walk(&self, path: &str) -> crate::Result<Walk<'_>> {
+        let dinum = self.nresolve_path(path)?;
+        if self.read().get_inode(dinum).or_range()?.meta.typ != crate::fs::HAMMER2_OBJTYPE_DIRECTORY
+        {
+            return Err(nix::errno::Errno::ENOTDIR.into());
+        }
+        Ok(Walk {
+            pmp: self,
+            frames: [Frame {
+                dinum,
+                path: path.to_string(),
+                depth: 0,
+                lkey: Some(2),
+                ancestors: vec![dinum],
+            }]
+            .into(),
+            breadth_first: false,
+            max_depth: None,
+            follow_links: false,
+            filter: None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    fn walk<'a>(pmp: &'a crate::hammer2::Hammer2, path: &str) -> super::Walk<'a> {
+        match pmp.walk(path) {
+            Ok(v) => v,
+            Err(e) => panic!("{e}"),
+        }
+    }
+
+    fn collect(w: super::Walk) -> Vec<String> {
+        match w.collect::<crate::Result<Vec<_>>>() {
+            Ok(v) => v.into_iter().map(|x| x.0).collect(),
+            Err(e) => panic!("{e}"),
+        }
+    }
+
+    #[test]
+    fn test_walk() {
+        let f = crate::newfs::create_newfs_image("walk", 128 << 20);
+        // /a/b/c, /a/x, /y, /l -> a, /a/b/up -> ../.. (cycle), /a/broken -> z
+        let mut pmp = match crate::hammer2::Hammer2::mount(&f, &["--rw"]) {
+            Ok(v) => v,
+            Err(e) => panic!("{e}"),
+        };
+        let mkdir =
+            |pmp: &mut crate::hammer2::Hammer2, dinum, name| match pmp.mkdir(dinum, name, 0o755) {
+                Ok(v) => v,
+                Err(e) => panic!("{e}"),
+            };
+        let create = |pmp: &mut crate::hammer2::Hammer2, dinum, name, b: &[u8]| {
+            let inum = match pmp.create(dinum, name, 0o644) {
+                Ok(v) => v,
+                Err(e) => panic!("{e}"),
+            };
+            if let Err(e) = pmp.pwrite(inum, b, 0) {
+                panic!("{e}");
+            }
+            inum
+        };
+        let a = mkdir(&mut pmp, crate::inode::INUM_PFS_ROOT, "a");
+        let b = mkdir(&mut pmp, a, "b");
+        mkdir(&mut pmp, b, "c");
+        create(&mut pmp, a, "x", b"xxx");
+        create(&mut pmp, crate::inode::INUM_PFS_ROOT, "y", b"yy");
+        for (dinum, name, target) in [
+            (crate::inode::INUM_PFS_ROOT, "l", "a"),
+            (b, "up", "../.."),
+            (a, "broken", "z"),
+        ] {
+            let inum = create(&mut pmp, dinum, name, target.as_bytes());
+            if let Err(e) = pmp.get_mut().modify_inode_meta(inum, |meta| {
+                meta.typ = crate::fs::HAMMER2_OBJTYPE_SOFTLINK;
+            }) {
+                panic!("{e}");
+            }
+        }
+        if let Err(e) = pmp.unmount() {
+            panic!("{e}");
+        }
+
+        let mut pmp = match crate::hammer2::Hammer2::mount(&f, &[]) {
+            Ok(v) => v,
+            Err(e) => panic!("{e}"),
+        };
+        // depth-first
+        let mut v = collect(walk(&pmp, "/"));
+        v.sort();
+        assert_eq!(
+            v,
+            [
+                "/a",
+                "/a/b",
+                "/a/b/c",
+                "/a/b/up",
+                "/a/broken",
+                "/a/x",
+                "/l",
+                "/y"
+            ]
+        );
+        let v = collect(walk(&pmp, "/"));
+        let i = |s| match v.iter().position(|x| x == s) {
+            Some(v) => v,
+            None => panic!("{s}"),
+        };
+        assert_eq!(i("/a/b") + 1, i("/a/b/c").min(i("/a/b/up")));
+        // breadth-first
+        let v = collect(walk(&pmp, "/").breadth_first(true));
+        let depth = |s: &String| s.matches('/').count();
+        assert!(v.windows(2).all(|x| depth(&x[0]) <= depth(&x[1])), "{v:?}");
+        // max depth
+        let mut v = collect(walk(&pmp, "a").max_depth(1));
+        v.sort();
+        assert_eq!(v, ["a/b", "a/broken", "a/x"]);
+        assert!(collect(walk(&pmp, "a").max_depth(0)).is_empty());
+        // filter prunes subtrees
+        let mut v = collect(walk(&pmp, "/").filter(|path, _, _| !path.ends_with("/b")));
+        v.sort();
+        assert_eq!(v, ["/a", "/a/broken", "/a/x", "/l", "/y"]);
+        let mut v = collect(walk(&pmp, "/").filter(|_, dirent, stat| {
+            dirent.typ == crate::fs::HAMMER2_OBJTYPE_DIRECTORY || stat.st_size == 3
+        }));
+        v.sort();
+        assert_eq!(v, ["/a", "/a/b", "/a/b/c", "/a/x"]);
+        // follow symlinks, the cycle through up is not descended
+        let v = match walk(&pmp, "/")
+            .follow_links(true)
+            .collect::<crate::Result<Vec<_>>>()
+        {
+            Ok(v) => v,
+            Err(e) => panic!("{e}"),
+        };
+        let mut paths: Vec<_> = v.iter().map(|x| x.0.as_str()).collect();
+        paths.sort_unstable();
+        assert_eq!(
+            paths,
+            [
+                "/a",
+                "/a/b",
+                "/a/b/c",
+                "/a/b/up",
+                "/a/broken",
+                "/a/x",
+                "/l",
+                "/l/b",
+                "/l/b/c",
+                "/l/b/up",
+                "/l/broken",
+                "/l/x",
+                "/y"
+            ]
+        );
+        for (path, dirent, stat) in &v {
+            match path.as_str() {
+                "/a/b/up" => assert_eq!(dirent.inum, crate::inode::INUM_PFS_ROOT),
+                "/a/broken" => assert_eq!(dirent.typ, crate::fs::HAMMER2_OBJTYPE_SOFTLINK),
+                "/l" => assert_eq!(dirent.inum, a),
+                _ => (),
+            }
+            assert_eq!(dirent.inum, stat.st_ino);
+        }
+        // error
+        match pmp.walk("y") {
+            Err(crate::Error::Errno(nix::errno::Errno::ENOTDIR)) => (),
+            Ok(v) => panic!("{v:?}"),
+            Err(e) => panic!("{e}"),
+        }
+        if let Err(e) = pmp.unmount() {
+            panic!("{e}");
+        }
+        let _ = std::fs::remove_file(f);
+    }
+}